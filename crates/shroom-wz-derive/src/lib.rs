@@ -0,0 +1,137 @@
+//! Derive macros for `shroom-wz`.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, LitStr, Token};
+
+/// Default of a field, which is missing in the object
+enum FieldDefault {
+    Trait,
+    Expr(Box<Expr>),
+}
+
+/// Parsed `#[wz(...)]` attributes of a field
+#[derive(Default)]
+struct FieldAttrs {
+    path: Option<String>,
+    canvas: bool,
+    link_resolve: bool,
+    default: Option<FieldDefault>,
+}
+
+impl FieldAttrs {
+    fn parse(attrs: &[syn::Attribute]) -> syn::Result<Self> {
+        let mut res = Self::default();
+        for attr in attrs.iter().filter(|attr| attr.path().is_ident("wz")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("path") {
+                    res.path = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.path.is_ident("canvas") {
+                    res.canvas = true;
+                } else if meta.path.is_ident("link_resolve") {
+                    res.link_resolve = true;
+                } else if meta.path.is_ident("default") {
+                    res.default = Some(if meta.input.peek(Token![=]) {
+                        FieldDefault::Expr(meta.value()?.parse()?)
+                    } else {
+                        FieldDefault::Trait
+                    });
+                } else {
+                    return Err(meta.error("unknown wz attribute"));
+                }
+                Ok(())
+            })?;
+        }
+        Ok(res)
+    }
+}
+
+/// Implements `FromWzNode` and `ToWzValue` for a struct with named fields.
+///
+/// Every field is read from the property with It's name, the attributes are:
+/// * `#[wz(path = "info/icon")]` reads the field from a relative path instead
+/// * `#[wz(canvas)]` requires a canvas and follows It's `_inlink`s
+/// * `#[wz(link_resolve)]` follows UOL links along the path
+///
+/// Fields of the type `WzLinked<T>` keep the link they were read through and write It
+/// back, other fields write the target
+/// * `#[wz(default)]` or `#[wz(default = expr)]` is used If the property is missing
+#[proc_macro_derive(WzObject, attributes(wz))]
+pub fn derive_wz_object(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "WzObject can only be derived for structs",
+        ));
+    };
+    let Fields::Named(fields) = &data.fields else {
+        return Err(syn::Error::new_spanned(
+            &input.ident,
+            "WzObject requires named fields",
+        ));
+    };
+
+    let mut reads = Vec::new();
+    let mut writes = Vec::new();
+    for field in fields.named.iter() {
+        let attrs = FieldAttrs::parse(&field.attrs)?;
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+        let path = attrs.path.unwrap_or_else(|| ident.to_string());
+        let (resolve, canvas) = (attrs.link_resolve, attrs.canvas);
+        let opts = quote! {
+            ::shroom_wz::object::WzFieldOpts {
+                resolve: #resolve,
+                canvas: #canvas,
+            }
+        };
+
+        let read = match attrs.default {
+            None => quote! { node.must_field::<#ty>(#path, #opts)? },
+            Some(FieldDefault::Trait) => {
+                quote! { node.field::<#ty>(#path, #opts)?.unwrap_or_default() }
+            }
+            Some(FieldDefault::Expr(expr)) => {
+                quote! { node.field::<#ty>(#path, #opts)?.unwrap_or_else(|| #expr) }
+            }
+        };
+        reads.push(quote! { #ident: #read });
+        writes.push(quote! {
+            if let Some(val) = ::shroom_wz::object::ToWzValue::to_wz_value(&self.#ident)? {
+                ::shroom_wz::object::insert_path(&mut obj, #path, val)?;
+            }
+        });
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::shroom_wz::object::FromWzNode for #name #ty_generics #where_clause {
+            fn from_wz_node(
+                node: &::shroom_wz::object::WzNodeRef<'_>,
+            ) -> ::shroom_wz::object::WzObjResult<Self> {
+                Ok(Self {
+                    #(#reads,)*
+                })
+            }
+        }
+
+        impl #impl_generics ::shroom_wz::object::ToWzValue for #name #ty_generics #where_clause {
+            fn to_wz_value(
+                &self,
+            ) -> ::shroom_wz::object::WzObjResult<Option<::shroom_wz::val::WzValue>> {
+                let mut obj = ::shroom_wz::val::ObjectVal::default();
+                #(#writes)*
+                Ok(Some(::shroom_wz::val::WzValue::Object(obj)))
+            }
+        }
+    })
+}
@@ -0,0 +1,117 @@
+use shroom_wz::{
+    l1::canvas::WzCanvasScaling,
+    object::{insert_path, FromWzNode, ToWzValue, WzLinked},
+    val::{CanvasVal, ObjectVal, WzValue},
+    WzObject,
+};
+
+#[derive(Debug, PartialEq, WzObject)]
+struct Info {
+    #[wz(path = "info/mobName")]
+    name: String,
+    #[wz(path = "info/speed", default = -10)]
+    speed: i32,
+    #[wz(path = "info/boss")]
+    boss: Option<bool>,
+}
+
+#[derive(Debug, PartialEq, WzObject)]
+struct Frame {
+    origin: WzValue,
+}
+
+#[derive(Debug, PartialEq, WzObject)]
+struct Mob {
+    #[wz(path = "stand/0")]
+    stand: CanvasVal,
+    #[wz(path = "move/0", canvas)]
+    move_canvas: WzLinked<CanvasVal>,
+    #[wz(path = "hit/0", link_resolve)]
+    hit: WzLinked<Frame>,
+    #[wz(path = "die/0", link_resolve)]
+    die: Frame,
+}
+
+fn build(entries: &[(&str, WzValue)]) -> WzValue {
+    let mut obj = ObjectVal::default();
+    for (path, val) in entries {
+        insert_path(&mut obj, path, val.clone()).unwrap();
+    }
+    WzValue::Object(obj)
+}
+
+fn canvas(sub: WzValue) -> WzValue {
+    WzValue::Canvas(CanvasVal::detached(WzCanvasScaling(0), Some(Box::new(sub))))
+}
+
+fn roundtrip<T: FromWzNode + ToWzValue + PartialEq + std::fmt::Debug>(val: &WzValue) -> WzValue {
+    let obj = T::from_wz_value(val).unwrap();
+    let written = obj.to_wz_value().unwrap().unwrap();
+    assert_eq!(T::from_wz_value(&written).unwrap(), obj);
+    written
+}
+
+#[test]
+fn rename_and_optional() {
+    let val = build(&[
+        ("info/mobName", WzValue::String("Snail".to_string())),
+        ("info/speed", WzValue::Int(20)),
+    ]);
+    let info = Info::from_wz_value(&val).unwrap();
+    assert_eq!(
+        info,
+        Info {
+            name: "Snail".to_string(),
+            speed: 20,
+            boss: None,
+        }
+    );
+    // Missing options are left out
+    assert_eq!(roundtrip::<Info>(&val), val);
+
+    let val = build(&[
+        ("info/mobName", WzValue::String("Snail".to_string())),
+        ("info/boss", WzValue::Int(1)),
+    ]);
+    let info = Info::from_wz_value(&val).unwrap();
+    assert_eq!((info.speed, info.boss), (-10, Some(true)));
+    let written = roundtrip::<Info>(&val);
+    assert_eq!(written.get_path("info/speed"), Some(&WzValue::Int(-10)));
+
+    // The error points to the renamed path
+    let err = Info::from_wz_value(&build(&[("info/speed", WzValue::Int(1))])).unwrap_err();
+    assert!(err.to_string().contains("info/mobName"), "{err}");
+}
+
+#[test]
+fn canvas_and_links() {
+    let origin = || build(&[("origin", WzValue::Vec((1, 2).into()))]);
+    let inlink = build(&[("_inlink", WzValue::String("stand/0".to_string()))]);
+    let val = build(&[
+        ("stand/0", canvas(origin())),
+        ("move/0", canvas(inlink.clone())),
+        ("hit/0", WzValue::Link("../stand/0".to_string())),
+        ("die/0", WzValue::Link("../stand/0".to_string())),
+    ]);
+
+    let mob = Mob::from_wz_value(&val).unwrap();
+    assert_eq!(mob.move_canvas.val, mob.stand);
+    assert_eq!(mob.move_canvas.link, Some(canvas(inlink)));
+    assert_eq!(mob.hit.val, mob.die);
+    assert_eq!(mob.hit.link, Some(WzValue::Link("../stand/0".to_string())));
+
+    // Linked fields keep their link, the other fields are written resolved
+    let written = roundtrip::<Mob>(&val);
+    assert_eq!(written.get_path("move/0"), val.get_path("move/0"));
+    assert_eq!(written.get_path("hit/0"), val.get_path("hit/0"));
+    assert_eq!(
+        written.get_path("die/0/origin"),
+        Some(&WzValue::Vec((1, 2).into()))
+    );
+
+    // Changing the value of an unlinked field writes It
+    let mut mob = mob;
+    mob.move_canvas = WzLinked::new(mob.stand.clone());
+    let written = mob.to_wz_value().unwrap().unwrap();
+    assert_eq!(written.get_path("move/0"), written.get_path("stand/0"));
+}
@@ -1,21 +1,33 @@
 use std::{
-    collections::VecDeque,
     fs::File,
-    io::{Cursor, Read},
+    io::{BufWriter, Cursor, Read, Write},
     path::{Path, PathBuf},
+    sync::Mutex,
 };
 
 use anyhow::Context;
 use clap::{Parser, Subcommand};
 use image::ImageFormat;
 use shroom_wz::{
+    canvas::Canvas,
+    canvas_link::{WzArchiveSet, WzOutlinkResolver},
+    diff,
     file::{WzIO, WzImgReader},
-    l0::WzImgHeader,
-    l1::canvas::WzCanvas,
-    val::WzValue,
+    l0::{tree::WzTree, WzImgHeader},
+    nx::WzNxExporter,
+    overlay::{WzOverlay, UNPACKED_SOUND_EXT},
+    patch::WzPatch,
+    query::{WzQuery, WzQueryMatch},
+    search::WzSearchIndex,
+    util::animation::{Animation, AnimationRepeat},
+    val::{CanvasVal, SoundVal, WzValue},
+    verify,
     version::{WzRegion, WzVersion},
+    xml::{WzXmlWriter, XML_IMG_EXT},
     WzConfig, WzReader,
 };
+#[cfg(feature = "sqlite")]
+use shroom_wz::sqlite::WzSqliteExporter;
 use glob::glob;
 
 use rayon::prelude::*;
@@ -29,7 +41,11 @@ struct ImgUnpacker<R> {
 impl<R: WzIO> ImgUnpacker<R> {
     fn new(mut img_rdr: WzImgReader<R>, path: impl AsRef<Path>) -> anyhow::Result<Self> {
         std::fs::create_dir_all(&path)?;
-        let root = WzValue::read(&mut img_rdr)?;
+        // Salvage what's readable from damaged images
+        let (root, diagnostics) = WzValue::read_lenient(&mut img_rdr)?;
+        for diag in diagnostics {
+            eprintln!("Warning: {diag}");
+        }
         Ok(Self {
             img_rdr,
             path: path.as_ref().to_path_buf(),
@@ -40,38 +56,58 @@ impl<R: WzIO> ImgUnpacker<R> {
     fn write_canvas(
         r: &mut WzImgReader<R>,
         mut path: PathBuf,
-        canvas: &WzCanvas,
+        root: &WzValue,
+        canvas: &CanvasVal,
+        outlinks: Option<&mut dyn WzOutlinkResolver>,
     ) -> anyhow::Result<()> {
         let file = path.with_extension("png");
         path.pop();
         std::fs::create_dir_all(&path)?;
         let mut file = std::fs::File::create(file)?;
-        let img = r.read_canvas(canvas)?;
+        let img = canvas.read_canvas_resolved(r, root, outlinks)?;
         let img = img.to_raw_rgba_image()?;
         img.write_to(&mut file, ImageFormat::Png)?;
         Ok(())
     }
 
-    fn unpack_media(&mut self) -> anyhow::Result<()> {
-        let mut q = VecDeque::new();
-        let p = self.path.join("data");
-        q.push_back((p, &self.root));
+    fn write_sound(
+        r: &mut WzImgReader<R>,
+        path: PathBuf,
+        sound: &SoundVal,
+    ) -> anyhow::Result<()> {
+        let data = sound.read_data(r)?;
+        std::fs::create_dir_all(path.parent().unwrap_or(&path))?;
+        std::fs::write(path.with_extension(UNPACKED_SOUND_EXT), data)?;
+        Ok(())
+    }
 
-        while let Some((p, obj)) = q.pop_front() {
-            match obj {
-                WzValue::Object(v) => {
-                    for (name, val) in v.0.iter() {
-                        q.push_back((p.join(name), val));
+    fn unpack_media(
+        &mut self,
+        mut outlinks: Option<&mut dyn WzOutlinkResolver>,
+    ) -> anyhow::Result<()> {
+        let data_dir = self.path.join("data");
+        for entry in self.root.walk() {
+            let p = data_dir.join(&entry.path);
+            // A broken canvas or sound shouldn't prevent unpacking the others
+            match entry.value {
+                WzValue::Canvas(val) => {
+                    if let Err(err) = Self::write_canvas(
+                        &mut self.img_rdr,
+                        p.clone(),
+                        &self.root,
+                        val,
+                        outlinks.as_mut().map(|o| &mut **o as &mut dyn WzOutlinkResolver),
+                    ) {
+                        eprintln!("Warning: skipped canvas {p:?}: {err:#}");
                     }
                 }
-                WzValue::Canvas(val) => {
-                    Self::write_canvas(&mut self.img_rdr, p.clone(), &val.canvas)
-                        .context(anyhow::format_err!("err: {p:?}"))?;
+                WzValue::Sound(val) => {
+                    if let Err(err) = Self::write_sound(&mut self.img_rdr, p.clone(), val) {
+                        eprintln!("Warning: skipped sound {p:?}: {err:#}");
+                    }
                 }
                 _ => {}
             }
-
-            //println!("unpack: {p:?}");
         }
 
         Ok(())
@@ -89,15 +125,15 @@ fn unpack_img<R: WzIO>(
     path: String,
     //img: WzImgHeader,
     out_dir: &Path,
+    outlinks: Option<&mut dyn WzOutlinkResolver>,
 ) -> anyhow::Result<()> {
-    let path = path.strip_prefix("/root/").unwrap_or(&path);
     let path = out_dir.join(path);
 
     let p = format!("{path:?}");
     let mut unpacker = ImgUnpacker::new(img_reader, path.clone()).context(p)?;
 
     unpacker.write_json()?;
-    unpacker.unpack_media()?;
+    unpacker.unpack_media(outlinks)?;
 
     println!("Unpacked: {path:?}");
     Ok(())
@@ -108,24 +144,60 @@ fn unpack_wz_img<R: WzIO>(
     path: String,
     img: WzImgHeader,
     out_dir: &Path,
+    outlinks: Option<&mut dyn WzOutlinkResolver>,
 ) -> anyhow::Result<()> {
-    let img_reader = r.img_reader(&img)?;
-    unpack_img(img_reader, path, out_dir)
+    let img_reader = r.img_reader(&img)?.with_path(&path);
+    unpack_img(img_reader, path, out_dir, outlinks)
+}
+
+/// Archive set shared by the unpack workers, outlinks are rare so the lock is fine
+struct SharedOutlinks<'a, R>(&'a Mutex<WzArchiveSet<R>>);
+
+impl<R: WzIO> WzOutlinkResolver for SharedOutlinks<'_, R> {
+    fn read_outlink(&mut self, path: &str, depth: usize) -> anyhow::Result<Canvas> {
+        self.0
+            .lock()
+            .map_err(|_| anyhow::format_err!("Outlink resolver poisoned"))?
+            .read_outlink(path, depth)
+    }
 }
 
+/// Unpacks all images, canvas outlinks are resolved with the archives in `client_dir`
 fn unpack<R: WzIO + Clone + Send + Sync>(
     file: WzReader<R>,
     out_dir: impl AsRef<Path>,
+    client_dir: Option<&Path>,
+    cfg: WzConfig,
 ) -> anyhow::Result<()> {
     let out_dir = out_dir.as_ref();
     let mut file = file;
-    let imgs = file.traverse_images().collect::<anyhow::Result<Vec<_>>>()?;
+    // Broken entries are reported with the other errors
+    let mut imgs = Vec::new();
+    let mut errs = Vec::new();
+    for img in file.walk_images() {
+        match img {
+            Ok(img) => imgs.push((img.path, img.hdr)),
+            Err(err) => errs.push(err),
+        }
+    }
 
-    let errs = imgs
+    let outlinks = client_dir
+        .map(|dir| WzArchiveSet::open_dir(dir, cfg))
+        .transpose()?
+        .map(Mutex::new);
+    let unpack_errs = imgs
         .into_iter()
         .par_bridge()
-        .flat_map(|(path, img)| unpack_wz_img(file.clone(), path, img, out_dir).err())
+        .map(|(path, img)| {
+            let mut outlinks = outlinks.as_ref().map(SharedOutlinks);
+            let outlinks = outlinks
+                .as_mut()
+                .map(|set| set as &mut dyn WzOutlinkResolver);
+            unpack_wz_img(file.clone(), path, img, out_dir, outlinks).err()
+        })
+        .flatten()
         .collect::<Vec<anyhow::Error>>();
+    errs.extend(unpack_errs);
 
     if !errs.is_empty() {
         println!("Errors:");
@@ -137,6 +209,362 @@ fn unpack<R: WzIO + Clone + Send + Sync>(
     Ok(())
 }
 
+fn verify_file(file: impl AsRef<Path>, cfg: WzConfig) -> anyhow::Result<bool> {
+    let mut r = WzReader::open_file(file, cfg)?;
+    let report = verify::verify(&mut r)?;
+
+    for issue in report.issues.iter() {
+        println!("Archive: {issue}");
+    }
+    for img in report.images.iter() {
+        if img.is_ok() {
+            println!("OK: {}", img.path);
+            continue;
+        }
+        println!("FAILED: {}", img.path);
+        for issue in img.issues.iter() {
+            println!("  {issue}");
+        }
+    }
+
+    let failed = report.failed_images().count();
+    println!("Verified {} images, {failed} failed", report.images.len());
+    Ok(report.is_ok())
+}
+
+/// Merges the layers into a new archive, a layer is an archive or a directory
+fn overlay_files(target_file: &Path, layers: &[PathBuf], cfg: WzConfig) -> anyhow::Result<()> {
+    let mut overlay = WzOverlay::new(cfg);
+    for layer in layers {
+        if layer.is_dir() {
+            overlay.push_dir(layer)?;
+        } else {
+            overlay.push_archive(WzReader::open_file(layer, cfg)?)?;
+        }
+        println!("Layer: {layer:?}");
+    }
+
+    let mut file = BufWriter::new(File::create(target_file)?);
+    overlay.write_archive(&mut file)?;
+    file.flush()?;
+    println!("Wrote {} images to {target_file:?}", overlay.images().count());
+    Ok(())
+}
+
+fn print_changes(changes: &[diff::WzPropChange]) {
+    for change in changes {
+        println!("  {change}");
+    }
+}
+
+/// Prints the changes between two archives or two `.img` files
+fn diff_files(old: &Path, new: &Path, cfg: WzConfig) -> anyhow::Result<()> {
+    if old.extension() == Some("img".as_ref()) {
+        let mut old = WzReader::open_img(Cursor::new(std::fs::read(old)?), cfg);
+        let mut new = WzReader::open_img(Cursor::new(std::fs::read(new)?), cfg);
+        let changes = diff::diff_imgs(&mut old.root_img_reader()?, &mut new.root_img_reader()?)?;
+        print_changes(&changes);
+        println!("{} changes", changes.len());
+        return Ok(());
+    }
+
+    let diff = diff::diff_archives(
+        &mut WzReader::open_file(old, cfg)?,
+        &mut WzReader::open_file(new, cfg)?,
+    )?;
+    for path in diff.removed.iter() {
+        println!("Removed: {path}");
+    }
+    for path in diff.added.iter() {
+        println!("Added: {path}");
+    }
+    for img in diff.modified.iter() {
+        println!("Modified: {}", img.path);
+        print_changes(&img.changes);
+    }
+    println!(
+        "{} added, {} removed, {} modified",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.modified.len()
+    );
+    Ok(())
+}
+
+/// Writes the patch from the old to the new archive
+fn make_patch(old: &Path, new: &Path, patch_file: &Path, cfg: WzConfig) -> anyhow::Result<()> {
+    let patch = WzPatch::generate(
+        &mut WzReader::open_file(old, cfg)?,
+        &mut WzReader::open_file(new, cfg)?,
+    )?;
+    let mut file = BufWriter::new(File::create(patch_file)?);
+    patch.write_to(&mut file)?;
+    file.flush()?;
+    println!("Wrote {} changed images to {patch_file:?}", patch.changes());
+    Ok(())
+}
+
+/// Rebuilds the target archive from the source archive and the patch
+fn apply_patch(src: &Path, patch_file: &Path, target: &Path, cfg: WzConfig) -> anyhow::Result<()> {
+    let patch = WzPatch::read_from(&mut Cursor::new(std::fs::read(patch_file)?))?;
+    let mut src = WzReader::open_file(src, cfg)?;
+    // Write into a temporary file first, so the target isn't touched If the patch doesn't apply
+    let mut tmp = target.as_os_str().to_owned();
+    tmp.push(".part");
+    let tmp = PathBuf::from(tmp);
+    let res = File::create(&tmp).map_err(anyhow::Error::from).and_then(|file| {
+        let mut w = BufWriter::new(file);
+        patch.apply(&mut src, cfg, &mut w)?;
+        w.flush()?;
+        Ok(())
+    });
+    if let Err(err) = res {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(err);
+    }
+    std::fs::rename(&tmp, target)?;
+    println!("Applied {} changed images to {target:?}", patch.changes());
+    Ok(())
+}
+
+fn print_match(m: &WzQueryMatch<'_>) -> anyhow::Result<()> {
+    if m.fields.is_empty() {
+        println!("{} = {}", m.path, serde_json::to_string(m.value)?);
+        return Ok(());
+    }
+
+    let fields = m
+        .fields
+        .iter()
+        .map(|(name, val)| Ok(format!("{name} = {}", serde_json::to_string(val)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    println!("{}: {}", m.path, fields.join(", "));
+    Ok(())
+}
+
+/// Prints the matches of the query in an archive or an `.img` file
+fn query_file(src: &Path, query: &str, cfg: WzConfig) -> anyhow::Result<()> {
+    let query = WzQuery::parse(query)?;
+    let mut count = 0;
+    if src.extension() == Some("img".as_ref()) {
+        let mut r = WzReader::open_img(Cursor::new(std::fs::read(src)?), cfg);
+        let root = WzValue::read(&mut r.root_img_reader()?)?;
+        for m in query.eval(&root) {
+            print_match(&m)?;
+            count += 1;
+        }
+    } else {
+        let mut r = WzReader::open_file(src, cfg)?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+        let mut res = Ok(());
+        let skipped = query.eval_tree(&mut r, &tree, |m| {
+            if res.is_ok() {
+                res = print_match(&m);
+                count += 1;
+            }
+        })?;
+        res?;
+        for (path, err) in skipped {
+            eprintln!("Warning: skipped image {path}: {err:#}");
+        }
+    }
+    println!("{count} matches");
+    Ok(())
+}
+
+/// Builds the search index of the archive, an existing index is updated
+fn build_index(src: &Path, index_file: &Path, cfg: WzConfig) -> anyhow::Result<()> {
+    let mut index = if index_file.exists() {
+        WzSearchIndex::load(index_file)?
+    } else {
+        WzSearchIndex::default()
+    };
+    let mut r = WzReader::open_file(src, cfg)?;
+    let res = index.update(&mut r)?;
+    for (path, err) in res.skipped.iter() {
+        eprintln!("Warning: skipped image {path}: {err:#}");
+    }
+    index.save(index_file)?;
+    println!(
+        "Indexed {} of {} images, {} terms",
+        res.indexed,
+        index.imgs.len(),
+        index.terms.len()
+    );
+    Ok(())
+}
+
+/// Prints the properties containing the term
+fn search_index(index_file: &Path, term: &str, prefix: bool) -> anyhow::Result<()> {
+    let index = WzSearchIndex::load(index_file)?;
+    let hits = if prefix {
+        index.search_prefix(term)
+    } else {
+        index.search(term)
+    };
+    for hit in hits.iter() {
+        println!("{}: {} ({} {})", hit.img_path, hit.prop_path, hit.kind, hit.term);
+    }
+    println!("{} hits", hits.len());
+    Ok(())
+}
+
+/// Encodes the animation object at `prop` of the image, the format is taken from the
+/// extension of the target. Canvas outlinks are resolved with `outlinks`
+fn write_anim<R: WzIO>(
+    img_r: &mut WzImgReader<R>,
+    outlinks: Option<&mut dyn WzOutlinkResolver>,
+    prop: &str,
+    target: &Path,
+    repeat: AnimationRepeat,
+    crop: bool,
+) -> anyhow::Result<()> {
+    let root = WzValue::read(img_r)?;
+    let obj = root
+        .get_path(prop)
+        .and_then(WzValue::as_object)
+        .ok_or_else(|| anyhow::format_err!("No animation at {prop}"))?;
+    let anim = Animation::from_obj_value(obj)?;
+    let data = match target.extension().and_then(|ext| ext.to_str()) {
+        Some("gif") => anim.to_gif(img_r, &root, outlinks, repeat)?,
+        Some("png" | "apng") => anim.to_apng(img_r, &root, outlinks, repeat, crop)?,
+        ext => anyhow::bail!("Unsupported animation format: {ext:?}"),
+    };
+    std::fs::write(target, data)?;
+    println!("Exported {} frames to {target:?}", anim.len());
+    Ok(())
+}
+
+/// Exports the animation at `path` like `Mob/100100.img/stand`, for an image file the
+/// path is relative to the image. Outlinks of an archive are resolved with the other
+/// archives in It's directory
+fn export_anim(
+    src: &Path,
+    path: &str,
+    target: &Path,
+    repeat: AnimationRepeat,
+    crop: bool,
+    cfg: WzConfig,
+) -> anyhow::Result<()> {
+    if src.extension() == Some("img".as_ref()) {
+        let mut r = WzReader::open_img(Cursor::new(std::fs::read(src)?), cfg);
+        return write_anim(&mut r.root_img_reader()?, None, path, target, repeat, crop);
+    }
+
+    let (img, prop) = match path.split_once(".img/") {
+        Some((img, prop)) => (format!("{img}.img"), prop),
+        None => (path.to_string(), ""),
+    };
+    let mut r = WzReader::open_file(src, cfg)?;
+    let tree = WzTree::from_reader(&mut r, None)?;
+    let hdr = tree
+        .get_img_by_path(&img)
+        .ok_or_else(|| anyhow::format_err!("Image not found: {img}"))?;
+    let client_dir = src.parent().filter(|dir| !dir.as_os_str().is_empty());
+    let mut outlinks = WzArchiveSet::open_dir(client_dir.unwrap_or(".".as_ref()), cfg)?;
+    write_anim(
+        &mut r.img_reader(hdr)?.with_path(&img),
+        Some(&mut outlinks),
+        prop,
+        target,
+        repeat,
+        crop,
+    )
+}
+
+/// Exports the archive or every archive in the directory as NX files
+fn export_nx(
+    src: &Path,
+    target: &Path,
+    exporter: WzNxExporter,
+    cfg: WzConfig,
+) -> anyhow::Result<()> {
+    let results = if src.is_dir() {
+        exporter.export_dir(src, target, cfg)?
+    } else {
+        let mut r = WzReader::open_file(src, cfg)?;
+        vec![(target.to_path_buf(), exporter.export_file(&mut r, target))]
+    };
+
+    for (file, res) in results {
+        match res {
+            Ok(stats) => {
+                println!(
+                    "Exported {} images with {} nodes, {} bitmaps and {} sounds to {file:?}",
+                    stats.images, stats.nodes, stats.bitmaps, stats.audio
+                );
+                if stats.errors > 0 {
+                    println!("{} entries couldn't be read", stats.errors);
+                }
+                if stats.dangling_links > 0 {
+                    println!("{} links couldn't be resolved", stats.dangling_links);
+                }
+            }
+            Err(err) => println!("Failed to export {file:?}: {err:?}"),
+        }
+    }
+    Ok(())
+}
+
+/// Exports all images into a new SQLite database
+#[cfg(feature = "sqlite")]
+fn export_sqlite(src: &Path, target: &Path, pngs: bool, cfg: WzConfig) -> anyhow::Result<()> {
+    let mut r = WzReader::open_file(src, cfg)?;
+    let stats = WzSqliteExporter::new()
+        .with_pngs(pngs)
+        .export_file(&mut r, target)?;
+    println!(
+        "Exported {} images, {} properties and {} canvases to {target:?}",
+        stats.images, stats.props, stats.canvases
+    );
+    if stats.canvas_errors > 0 {
+        println!("{} canvases couldn't be decoded", stats.canvas_errors);
+    }
+    for (path, err) in stats.skipped.iter() {
+        eprintln!("Warning: skipped image {path}: {err:#}");
+    }
+    if !stats.skipped.is_empty() {
+        println!("{} images couldn't be read", stats.skipped.len());
+    }
+    Ok(())
+}
+
+/// Writes an XML dump `<path>.xml` for every image of the archive
+fn unpack_xml(src: &Path, out_dir: &Path, cfg: WzConfig) -> anyhow::Result<()> {
+    let mut r = WzReader::open_file(src, cfg)?;
+    let imgs = r.walk_images().collect::<Vec<_>>();
+    let mut errs = Vec::new();
+    for img in imgs {
+        let res = img.and_then(|img| {
+            let file = out_dir.join(format!("{}.{XML_IMG_EXT}", img.path));
+            std::fs::create_dir_all(file.parent().unwrap_or(out_dir))?;
+            let mut img_r = r.img_reader(&img.hdr)?.with_path(&img.path);
+            let root = WzValue::read(&mut img_r)?;
+            let root = root
+                .as_object()
+                .ok_or_else(|| anyhow::format_err!("Root of {} is not an object", img.path))?;
+
+            let name = img.path.rsplit('/').next().unwrap_or_default();
+            let mut w = WzXmlWriter::new(BufWriter::new(File::create(&file)?));
+            w.write_img(name, root, &mut img_r)?;
+            w.into_inner().flush()?;
+            println!("Unpacked: {file:?}");
+            Ok(())
+        });
+        if let Err(err) = res {
+            errs.push(err);
+        }
+    }
+
+    if !errs.is_empty() {
+        println!("Errors:");
+        for err in errs {
+            println!("{:?}", err);
+        }
+    }
+    Ok(())
+}
+
 fn img_file_unpack(file: impl AsRef<Path>, out_dir: PathBuf, cfg: WzConfig) -> anyhow::Result<()> {
     let mut data = vec![];
     let mut img_buf = File::open(file.as_ref())?;
@@ -147,7 +575,7 @@ fn img_file_unpack(file: impl AsRef<Path>, out_dir: PathBuf, cfg: WzConfig) -> a
 
     let img_r = r.root_img_reader()?;
     std::fs::create_dir_all(&out_dir)?;
-    unpack_img(img_r, "".to_string(), &out_dir)?;
+    unpack_img(img_r, "".to_string(), &out_dir, None)?;
 
     Ok(())
 }
@@ -184,6 +612,7 @@ struct Cli {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
+    /// Packs a directory with `.img` files, unpacked images and XML dumps
     Pack {
         #[arg(short, long, value_name = "file")]
         target_file: PathBuf,
@@ -196,6 +625,13 @@ enum Commands {
         #[arg(short, long, value_name = "file")]
         src_file: PathBuf,
     },
+    /// Unpacks all images as XML dumps, they can be packed again with `pack`
+    UnpackXml {
+        #[arg(short, long, value_name = "dir")]
+        target_dir: PathBuf,
+        #[arg(short, long, value_name = "file")]
+        src_file: PathBuf,
+    },
     UnpackImg {
         #[arg(short, long, value_name = "dir")]
         target_dir: PathBuf,
@@ -208,6 +644,111 @@ enum Commands {
         #[arg(short, long, value_name = "file")]
         src_dir: PathBuf,
     },
+    Verify {
+        #[arg(short, long, value_name = "file")]
+        src_file: PathBuf,
+    },
+    /// Merges archives and directories, later layers override the images of earlier ones
+    Overlay {
+        #[arg(short, long, value_name = "file")]
+        target_file: PathBuf,
+        #[arg(short, long = "layer", value_name = "file|dir", required = true)]
+        layers: Vec<PathBuf>,
+    },
+    /// Shows the changes between two archives or two `.img` files
+    Diff {
+        #[arg(short, long, value_name = "file")]
+        old_file: PathBuf,
+        #[arg(short, long, value_name = "file")]
+        new_file: PathBuf,
+    },
+    /// Creates a patch with the changed images between two archives
+    MakePatch {
+        #[arg(short, long, value_name = "file")]
+        old_file: PathBuf,
+        #[arg(short, long, value_name = "file")]
+        new_file: PathBuf,
+        #[arg(short, long, value_name = "file")]
+        patch_file: PathBuf,
+    },
+    /// Rebuilds an archive from the source archive and a patch
+    ApplyPatch {
+        #[arg(short, long, value_name = "file")]
+        src_file: PathBuf,
+        #[arg(short, long, value_name = "file")]
+        patch_file: PathBuf,
+        #[arg(short, long, value_name = "file")]
+        target_file: PathBuf,
+    },
+    /// Prints the values matching a path query like `Mob/*.img/info[level>100]/maxHP`
+    Query {
+        #[arg(short, long, value_name = "file")]
+        src_file: PathBuf,
+        #[arg(short, long)]
+        query: String,
+    },
+    /// Builds or updates the search index of an archive
+    Index {
+        #[arg(short, long, value_name = "file")]
+        src_file: PathBuf,
+        #[arg(short, long, value_name = "file")]
+        index_file: PathBuf,
+    },
+    /// Finds keys, strings, numbers and UOLs in a search index
+    Search {
+        #[arg(short, long, value_name = "file")]
+        index_file: PathBuf,
+        #[arg(short, long)]
+        term: String,
+        /// Matches all terms starting with the term
+        #[arg(short, long)]
+        prefix: bool,
+    },
+    /// Exports the properties of all images into a new SQLite database
+    #[cfg(feature = "sqlite")]
+    ExportSqlite {
+        #[arg(short, long, value_name = "file")]
+        src_file: PathBuf,
+        #[arg(short, long, value_name = "file")]
+        target_file: PathBuf,
+        /// Also stores the canvases as png
+        #[arg(short, long)]
+        pngs: bool,
+    },
+    /// Exports an animation like `Mob/100100.img/stand` as GIF or APNG
+    ExportAnim {
+        #[arg(short, long, value_name = "file")]
+        src_file: PathBuf,
+        #[arg(short, long)]
+        path: String,
+        /// Target file, the format is taken from the extension: `gif`, `png` or `apng`
+        #[arg(short, long, value_name = "file")]
+        target_file: PathBuf,
+        /// Number of repetitions, the animation loops forever without
+        #[arg(short, long)]
+        repeat: Option<u16>,
+        /// Crops APNGs to the visible pixels of all frames
+        #[arg(short, long)]
+        crop: bool,
+    },
+    /// Converts an archive or all archives of a client directory into NX files
+    ExportNx {
+        /// Archive or client directory
+        #[arg(short, long, value_name = "path")]
+        src_path: PathBuf,
+        /// Target file or directory for a client directory
+        #[arg(short, long, value_name = "path")]
+        target_path: PathBuf,
+        /// Resolves the links instead of storing them as strings
+        #[arg(short, long)]
+        links: bool,
+        /// Also exports the bitmaps
+        #[arg(short, long)]
+        bitmaps: bool,
+        /// Also exports the sounds
+        #[arg(short, long)]
+        audio: bool,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
@@ -222,15 +763,21 @@ fn main() -> anyhow::Result<()> {
             src_dir,
         } => {
             println!("pack: {target_file:?}, {src_dir:?}");
-            unimplemented!("packing not supported yet")
+            overlay_files(&target_file, &[src_dir], cfg)?;
         }
         Commands::Unpack {
             target_dir,
             src_file,
         } => {
-            let file = WzReader::open_file_mmap_shared(src_file, cfg)?;
+            let file = WzReader::open_file_mmap_shared(&src_file, cfg)?;
             std::fs::create_dir_all(&target_dir)?;
-            unpack(file, target_dir)?;
+            unpack(file, target_dir, src_file.parent(), cfg)?;
+        }
+        Commands::UnpackXml {
+            target_dir,
+            src_file,
+        } => {
+            unpack_xml(&src_file, &target_dir, cfg)?;
         }
         Commands::UnpackImg {
             target_dir,
@@ -253,6 +800,81 @@ fn main() -> anyhow::Result<()> {
                     }
                 });
         }
+        Commands::Verify { src_file } => {
+            if !verify_file(&src_file, cfg)? {
+                std::process::exit(1);
+            }
+        }
+        Commands::Overlay {
+            target_file,
+            layers,
+        } => {
+            overlay_files(&target_file, &layers, cfg)?;
+        }
+        Commands::Diff { old_file, new_file } => {
+            diff_files(&old_file, &new_file, cfg)?;
+        }
+        Commands::MakePatch {
+            old_file,
+            new_file,
+            patch_file,
+        } => {
+            make_patch(&old_file, &new_file, &patch_file, cfg)?;
+        }
+        Commands::ApplyPatch {
+            src_file,
+            patch_file,
+            target_file,
+        } => {
+            apply_patch(&src_file, &patch_file, &target_file, cfg)?;
+        }
+        Commands::Query { src_file, query } => {
+            query_file(&src_file, &query, cfg)?;
+        }
+        Commands::Index {
+            src_file,
+            index_file,
+        } => {
+            build_index(&src_file, &index_file, cfg)?;
+        }
+        Commands::Search {
+            index_file,
+            term,
+            prefix,
+        } => {
+            search_index(&index_file, &term, prefix)?;
+        }
+        #[cfg(feature = "sqlite")]
+        Commands::ExportSqlite {
+            src_file,
+            target_file,
+            pngs,
+        } => {
+            export_sqlite(&src_file, &target_file, pngs, cfg)?;
+        }
+        Commands::ExportAnim {
+            src_file,
+            path,
+            target_file,
+            repeat,
+            crop,
+        } => {
+            let repeat = repeat.map_or(AnimationRepeat::Infinite, AnimationRepeat::Finite);
+            export_anim(&src_file, &path, &target_file, repeat, crop, cfg)?;
+        }
+        Commands::ExportNx {
+            src_path,
+            target_path,
+            links,
+            bitmaps,
+            audio,
+        } => {
+            let exporter = WzNxExporter::new()
+                .with_resolve_links(links)
+                .with_bitmaps(bitmaps)
+                .with_audio(audio);
+            export_nx(&src_path, &target_path, exporter, cfg)?;
+        }
     };
 
     Ok(())
@@ -5,9 +5,9 @@ use id_tree::{NodeId, Tree};
 use image::RgbaImage;
 use shroom_wz::{
     l0::{tree::WzTree, WzDirNode, WzImgHeader},
-    l1::{canvas::WzCanvas, sound::WzSound, tree::WzValueNode, tree::WzValueTree},
+    l1::{sound::WzSound, tree::WzValueNode, tree::WzValueTree},
     util::animation::Animation,
-    val::WzValue,
+    val::{CanvasVal, WzValue},
     version::{WzRegion, WzVersion},
     WzConfig,
 };
@@ -56,6 +56,7 @@ impl<'a> TreeData for WzValueNode<'a> {
             WzValue::Sound(_) => format!("♫ {name}").into(),
             WzValue::Canvas(_) => format!("🖼 {name}").into(),
             WzValue::Link(link) => format!("🔗 {name}: {link}").into(),
+            WzValue::Error(err) => format!("⚠ {name}: {}", err.reason).into(),
         }
     }
 
@@ -125,7 +126,9 @@ impl WzData {
     }
 
     fn load_anim(&self, img: &WzImgHeader, anim: Animation) -> anyhow::Result<WzAnimationData> {
-        let frames = anim.load_all_frames(&mut self.reader.borrow_mut().img_reader(img)?)?;
+        let root = self.load_tree(img)?.borrow_root();
+        let mut reader = self.reader.borrow_mut();
+        let frames = anim.load_all_frames(&mut reader.img_reader(img)?, root, None)?;
         let frames = frames
             .into_iter()
             .map(|frame| frame.to_raw_rgba_image().unwrap())
@@ -133,11 +136,11 @@ impl WzData {
         Ok(WzAnimationData { anim, frames })
     }
 
-    fn load_canvas(&self, img: &WzImgHeader, canvas: &WzCanvas) -> anyhow::Result<RgbaImage> {
-        self.reader
-            .borrow_mut()
-            .img_reader(img)?
-            .read_canvas(canvas)?
+    fn load_canvas(&self, img: &WzImgHeader, canvas: &CanvasVal) -> anyhow::Result<RgbaImage> {
+        let root = self.load_tree(img)?.borrow_root();
+        let mut reader = self.reader.borrow_mut();
+        canvas
+            .read_canvas_resolved(&mut reader.img_reader(img)?, root, None)?
             .to_raw_rgba_image()
     }
 
@@ -232,13 +235,11 @@ fn WzView<'wz>(cx: Scope<'wz>, wz: &'wz WzData) -> Element {
         };
 
         let img_data = wz.tree.get_tree().get(&node).unwrap().data();
-        let img = match img_data {
-            WzDirNode::Img(img) => img,
-            WzDirNode::Link(link) => &link.link.link_img,
-            _ => return None,
-        };
-
-        Some(img.clone())
+        match img_data {
+            WzDirNode::Img(img) => Some(img.clone()),
+            WzDirNode::Link(link) => Some(link.img_header()),
+            _ => None,
+        }
     });
 
     let on_select_node = |(tree, node_id, node): (
@@ -258,7 +259,7 @@ fn WzView<'wz>(cx: Scope<'wz>, wz: &'wz WzData) -> Element {
                     }
                 }
                 let img = selected_img.as_ref().unwrap();
-                let img = wz.load_canvas(img, &canvas.canvas).unwrap();
+                let img = wz.load_canvas(img, canvas).unwrap();
                 content.set(WzContentData::Image(Rc::new(img)));
             }
             WzValue::Sound(sound) => {
@@ -0,0 +1,24 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use shroom_wz::{l0::tree::WzTree, val::WzValue, verify::verify_media, WzReader, GMS95};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut r) = WzReader::open(Cursor::new(data), GMS95) else {
+        return;
+    };
+    let _ = r.walk_images().count();
+    let Ok(tree) = WzTree::from_reader(&mut r, None) else {
+        return;
+    };
+    for (_, hdr) in tree.images() {
+        let Ok(mut img) = r.img_reader(&hdr) else {
+            continue;
+        };
+        if let Ok(val) = WzValue::read(&mut img) {
+            let _ = verify_media(&mut img, &val);
+        }
+    }
+});
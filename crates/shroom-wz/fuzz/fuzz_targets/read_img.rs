@@ -0,0 +1,16 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use libfuzzer_sys::fuzz_target;
+use shroom_wz::{val::WzValue, verify::verify_media, WzReader, GMS95};
+
+fuzz_target!(|data: &[u8]| {
+    let mut r = WzReader::open_img(Cursor::new(data), GMS95);
+    let Ok(mut img) = r.root_img_reader() else {
+        return;
+    };
+    if let Ok(val) = WzValue::read(&mut img) {
+        let _ = verify_media(&mut img, &val);
+    }
+});
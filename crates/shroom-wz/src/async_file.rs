@@ -0,0 +1,466 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Cursor, Read, Seek, SeekFrom},
+    sync::Arc,
+};
+
+use binrw::BinRead;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+
+use crate::{
+    canvas::Canvas,
+    crypto::WzCrypto,
+    ctx::WzContext,
+    error::WzError,
+    file::{read_canvas_data, WzIO, WzImgReader},
+    l0::{WzDir, WzDirHeader, WzHeader, WzImgHeader},
+    l1::{canvas::WzCanvas, obj::WzObject, sound::WzSound},
+    ty::WzOffset,
+    util::MAX_PREALLOC,
+    val::WzValue,
+    WzConfig,
+};
+
+pub trait WzAsyncIO: AsyncRead + AsyncSeek + Unpin {}
+impl<T> WzAsyncIO for T where T: AsyncRead + AsyncSeek + Unpin {}
+
+/// Initial size of the file prefix, which is read to parse the header and directories
+const PREFIX_WINDOW: u64 = 16 * 1024;
+
+/// Size of the pages in which the property tree of an image is read
+const IMG_PAGE_SIZE: u64 = 4096;
+
+/// Limit for the pages which are fetched at once, while the parser reads sequentially
+const MAX_PAGE_RUN: u64 = 256;
+
+/// Checks If the parser failed because the buffered data was too short
+fn is_truncated(err: &binrw::Error) -> bool {
+    match err {
+        binrw::Error::Io(err) => err.kind() == std::io::ErrorKind::UnexpectedEof,
+        binrw::Error::EnumErrors { variant_errors, .. } => {
+            variant_errors.iter().any(|(_, err)| is_truncated(err))
+        }
+        binrw::Error::Backtrace(bt) => is_truncated(&bt.error),
+        _ => false,
+    }
+}
+
+/// Async counterpart of `WzReader`.
+///
+/// Directories are parsed from a buffered prefix of the file, which grows on demand.
+/// The property tree of an image is read in pages while It's parsed, canvas and sound
+/// data is read on demand from the underlying source.
+pub struct WzAsyncReader<R> {
+    inner: R,
+    data_offset: u64,
+    file_len: u64,
+    crypto: Arc<WzCrypto>,
+    prefix: Vec<u8>,
+}
+
+impl<R: WzAsyncIO> WzAsyncReader<R> {
+    pub async fn open(mut rdr: R, cfg: WzConfig) -> anyhow::Result<Self> {
+        let file_len = rdr.seek(SeekFrom::End(0)).await?;
+        let mut r = Self {
+            inner: rdr,
+            data_offset: 0,
+            file_len,
+            crypto: WzCrypto::from_cfg(cfg, 0).into(),
+            prefix: Vec::new(),
+        };
+
+        let hdr = r.parse_prefix(0, |r| WzHeader::read_le(r)).await?;
+        let data_offset = hdr.data_offset as u64;
+        let encrypted_version = r.parse_prefix(data_offset, |r| u16::read_le(r)).await?;
+        let ver = cfg.version;
+        if ver.encrypted_version() != encrypted_version {
+            return Err(WzError::version_mismatch(
+                ver.0,
+                ver.encrypted_version(),
+                encrypted_version,
+            )
+            .into());
+        }
+
+        r.data_offset = data_offset;
+        r.crypto = WzCrypto::from_cfg(cfg, hdr.data_offset).into();
+        Ok(r)
+    }
+
+    /// Grows the buffered prefix to contain atleast `len` bytes
+    async fn fill_prefix(&mut self, len: u64) -> std::io::Result<()> {
+        let len = len.min(self.file_len);
+        let cur = self.prefix.len() as u64;
+        if len <= cur {
+            return Ok(());
+        }
+
+        self.inner.seek(SeekFrom::Start(cur)).await?;
+        self.prefix.resize(len as usize, 0);
+        self.inner
+            .read_exact(&mut self.prefix[cur as usize..])
+            .await?;
+        Ok(())
+    }
+
+    /// Runs the parser at the offset, the prefix is extended until the parser succeeds
+    async fn parse_prefix<T>(
+        &mut self,
+        offset: u64,
+        parse: impl Fn(&mut Cursor<&[u8]>) -> binrw::BinResult<T>,
+    ) -> anyhow::Result<T> {
+        let mut window = PREFIX_WINDOW;
+        loop {
+            self.fill_prefix(offset + window).await?;
+            let mut r = Cursor::new(self.prefix.as_slice());
+            r.set_position(offset);
+            match parse(&mut r) {
+                Ok(v) => return Ok(v),
+                Err(err) if is_truncated(&err) && (self.prefix.len() as u64) < self.file_len => {
+                    window *= 2;
+                }
+                Err(err) => return Err(WzError::from(err).into()),
+            }
+        }
+    }
+
+    pub fn root_offset(&self) -> WzOffset {
+        WzOffset(self.data_offset as u32 + 2)
+    }
+
+    pub async fn read_root_dir(&mut self) -> anyhow::Result<WzDir> {
+        // Skip encrypted version at the start
+        self.read_dir(self.root_offset().0 as u64).await
+    }
+
+    pub async fn read_dir_node(&mut self, hdr: &WzDirHeader) -> anyhow::Result<WzDir> {
+        self.read_dir(hdr.offset.0 as u64).await
+    }
+
+    async fn read_dir(&mut self, offset: u64) -> anyhow::Result<WzDir> {
+        let crypto = self.crypto.clone();
+        self.parse_prefix(offset, |r| WzDir::read_le_args(r, WzContext::new(&crypto)))
+            .await
+    }
+
+    pub fn img_reader(&mut self, hdr: &WzImgHeader) -> WzAsyncImgReader<'_, R> {
+        let size = hdr.blob_size.0.max(0) as u64;
+        WzAsyncImgReader {
+            r: &mut self.inner,
+            offset: hdr.offset.into(),
+            size,
+            crypto: self.crypto.clone(),
+            path: None,
+            pages: ImgPages::new(size),
+        }
+    }
+
+    pub async fn checksum(&mut self, offset: u64, ln: u64) -> anyhow::Result<i32> {
+        self.inner.seek(SeekFrom::Start(offset)).await?;
+        let mut checksum = 0;
+        let mut buf = vec![0; 4096];
+        let mut n = ln;
+        while n > 0 {
+            let chunk = n.min(buf.len() as u64) as usize;
+            self.inner.read_exact(&mut buf[..chunk]).await?;
+            checksum = crate::util::wz_checksum(checksum, &buf[..chunk]);
+            n -= chunk as u64;
+        }
+        Ok(checksum)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+/// Pages of an image which were read so far.
+///
+/// Reading a page which wasn't fetched yet fails and records the page, so the async
+/// reader can fetch It and parse again.
+struct ImgPages {
+    pages: HashMap<u64, Vec<u8>>,
+    size: u64,
+    pos: u64,
+    missing: Option<u64>,
+}
+
+impl ImgPages {
+    fn new(size: u64) -> Self {
+        Self {
+            pages: HashMap::new(),
+            size,
+            pos: 0,
+            missing: None,
+        }
+    }
+}
+
+impl BufRead for ImgPages {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos >= self.size {
+            return Ok(&[]);
+        }
+        let page = self.pos / IMG_PAGE_SIZE;
+        if !self.pages.contains_key(&page) {
+            self.missing = Some(page);
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Image page is not loaded",
+            ));
+        }
+        Ok(&self.pages[&page][(self.pos - page * IMG_PAGE_SIZE) as usize..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl Read for ImgPages {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = Read::read(&mut self.fill_buf()?, buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl Seek for ImgPages {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(off) => self.size.checked_add_signed(off),
+            SeekFrom::Current(off) => self.pos.checked_add_signed(off),
+        };
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Seek to a negative position")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Async reader for a single image, offsets are relative to the image like for `WzImgReader`
+pub struct WzAsyncImgReader<'a, R> {
+    r: &'a mut R,
+    offset: u64,
+    size: u64,
+    crypto: Arc<WzCrypto>,
+    /// Path of the image, used for errors
+    path: Option<String>,
+    pages: ImgPages,
+}
+
+impl<'a, R: WzAsyncIO> WzAsyncImgReader<'a, R> {
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    fn img_reader<T: WzIO>(&self, r: T) -> WzImgReader<T> {
+        let img = WzImgReader::new(r, self.crypto.clone());
+        match self.path.as_deref() {
+            Some(path) => img.with_path(path),
+            None => img,
+        }
+    }
+
+    async fn read_range(&mut self, offset: u64, len: usize) -> anyhow::Result<Vec<u8>> {
+        if offset.saturating_add(len as u64) > self.size {
+            anyhow::bail!(
+                "Range {offset}+{len} exceeds image size: {size}",
+                size = self.size
+            );
+        }
+        self.r.seek(SeekFrom::Start(self.offset + offset)).await?;
+        // Grow the buffer while reading, the size might be bogus
+        let mut buf = Vec::with_capacity(len.min(MAX_PREALLOC));
+        (&mut *self.r)
+            .take(len as u64)
+            .read_to_end(&mut buf)
+            .await?;
+        if buf.len() != len {
+            anyhow::bail!("Unexpected end of data, expected {len} bytes");
+        }
+        Ok(buf)
+    }
+
+    /// Loads the whole image into memory, media included
+    pub async fn load(&mut self) -> anyhow::Result<WzImgReader<Cursor<Vec<u8>>>> {
+        let data = self.read_range(0, self.size as usize).await?;
+        Ok(self.img_reader(Cursor::new(data)))
+    }
+
+    /// Runs the parser over the pages of the image, missing pages are fetched and the
+    /// parser is run again. Sequential misses fetch growing runs of pages, so media
+    /// which the parser skips is not read
+    async fn parse<T>(
+        &mut self,
+        parse: impl Fn(&mut WzImgReader<ImgPages>) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        // The pages are kept for later reads of the image
+        let pages = std::mem::replace(&mut self.pages, ImgPages::new(self.size));
+        let mut img = self.img_reader(pages);
+        let page_count = self.size.div_ceil(IMG_PAGE_SIZE);
+        let mut run = 1;
+        let mut next = None;
+        let res = loop {
+            img.get_mut().pos = 0;
+            let res = parse(&mut img);
+            let Some(page) = img.get_mut().missing.take() else {
+                break res;
+            };
+
+            run = if next == Some(page) {
+                (run * 2).min(MAX_PAGE_RUN)
+            } else {
+                1
+            };
+            let end = (page + run).min(page_count);
+            let start = page * IMG_PAGE_SIZE;
+            let len = (end * IMG_PAGE_SIZE).min(self.size) - start;
+            let data = match self.read_range(start, len as usize).await {
+                Ok(data) => data,
+                Err(err) => break Err(err),
+            };
+            for (i, chunk) in data.chunks(IMG_PAGE_SIZE as usize).enumerate() {
+                img.get_mut().pages.insert(page + i as u64, chunk.to_vec());
+            }
+            next = Some(end);
+        };
+        self.pages = img.into_inner();
+        res
+    }
+
+    /// Read the root object for that image
+    pub async fn read_root_obj(&mut self) -> anyhow::Result<WzObject> {
+        self.parse(|img| img.read_root_obj()).await
+    }
+
+    /// Read the value tree for that image
+    pub async fn read_value(&mut self) -> anyhow::Result<WzValue> {
+        self.parse(WzValue::read).await
+    }
+
+    pub async fn read_canvas(&mut self, canvas: &WzCanvas) -> anyhow::Result<Canvas> {
+        let data = self
+            .read_range(canvas.data_offset(), canvas.data_len())
+            .await?;
+        read_canvas_data(Cursor::new(data), &self.crypto, canvas).map_err(|err| {
+            let err = WzError::from_anyhow(err);
+            match self.path.as_deref() {
+                Some(path) => err.with_img_path(path).into(),
+                None => err.into(),
+            }
+        })
+    }
+
+    pub async fn read_sound(&mut self, sound: &WzSound) -> anyhow::Result<Vec<u8>> {
+        self.read_range(sound.offset.pos, sound.data_size()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        l0::WzDirNode,
+        test_util::{build_archive, dir, img, img_blob, obj, sample_archive, TestVal},
+        val::WzValue,
+        WzReader, GMS95,
+    };
+
+    use super::{WzAsyncReader, IMG_PAGE_SIZE};
+
+    #[tokio::test]
+    async fn read_sample() -> anyhow::Result<()> {
+        let data = sample_archive(GMS95);
+        let mut r = WzAsyncReader::open(Cursor::new(data.clone()), GMS95).await?;
+        let mut sync_r = WzReader::open(Cursor::new(data), GMS95)?;
+
+        let root = r.read_root_dir().await?;
+        assert_eq!(root.entries.0, sync_r.read_root_dir()?.entries.0);
+
+        let WzDirNode::Dir(ref item) = root.entries.0[0] else {
+            anyhow::bail!("Expected dir");
+        };
+        let items = r.read_dir_node(item).await?;
+        let WzDirNode::Img(ref img) = items.entries.0[0] else {
+            anyhow::bail!("Expected img");
+        };
+
+        let mut img_r = r.img_reader(img);
+        let val = img_r.read_value().await?;
+        assert_eq!(val, WzValue::read(&mut sync_r.img_reader(img)?)?);
+
+        let icon = val.get_path("info/icon").unwrap().as_canvas().unwrap();
+        let canvas = img_r.read_canvas(&icon.canvas).await?;
+        let sync_canvas = sync_r.img_reader(img)?.read_canvas(&icon.canvas)?;
+        assert_eq!(
+            canvas.to_raw_rgba_image()?,
+            sync_canvas.to_raw_rgba_image()?
+        );
+
+        let WzDirNode::Img(ref bgm) = root.entries.0[1] else {
+            anyhow::bail!("Expected img");
+        };
+        let mut img_r = r.img_reader(bgm);
+        let val = img_r.read_value().await?;
+        let sound = val.get_path("bgm").unwrap().as_sound().unwrap();
+        assert_eq!(
+            img_r.read_sound(&sound.sound).await?,
+            (0..64).collect::<Vec<u8>>()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lazy_img() -> anyhow::Result<()> {
+        let sound = (0..16 * IMG_PAGE_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let data = build_archive(
+            GMS95,
+            vec![dir(
+                "Sound",
+                vec![img(
+                    "Bgm.img",
+                    img_blob(
+                        GMS95,
+                        &obj([
+                            (
+                                "bgm",
+                                TestVal::Sound {
+                                    data: sound.clone(),
+                                    len_ms: 1000,
+                                },
+                            ),
+                            ("hp", TestVal::Int(7)),
+                        ]),
+                    ),
+                )],
+            )],
+        );
+        let mut r = WzAsyncReader::open(Cursor::new(data), GMS95).await?;
+        let root = r.read_root_dir().await?;
+        let WzDirNode::Dir(ref sound_dir) = root.entries.0[0] else {
+            anyhow::bail!("Expected dir");
+        };
+        let WzDirNode::Img(ref bgm) = r.read_dir_node(sound_dir).await?.entries.0[0] else {
+            anyhow::bail!("Expected img");
+        };
+
+        let mut img_r = r.img_reader(bgm).with_path("Sound/Bgm.img");
+        assert_eq!(img_r.path(), Some("Sound/Bgm.img"));
+        let val = img_r.read_value().await?;
+        assert_eq!(val.get_path("hp"), Some(&WzValue::Int(7)));
+        // The sound data between the properties is skipped
+        assert!((img_r.pages.pages.len() as u64) < img_r.size.div_ceil(IMG_PAGE_SIZE) / 2);
+
+        let bgm = val.get_path("bgm").unwrap().as_sound().unwrap();
+        assert_eq!(img_r.read_sound(&bgm.sound).await?, sound);
+        Ok(())
+    }
+}
@@ -28,9 +28,11 @@ fn bgr565_to_rgba8(v: u16) -> Rgba<u8> {
 }
 
 fn bgra8_to_rgba8(v: u32) -> Rgba<u8> {
-    v.to_le_bytes().into()
+    let [b, g, r, a] = v.to_le_bytes();
+    [r, g, b, a].into()
 }
 
+#[derive(Clone, PartialEq, Eq)]
 pub struct Canvas {
     data: Vec<u8>,
     depth: WzCanvasDepth,
@@ -54,51 +56,108 @@ impl Canvas {
         }
     }
 
+    /// Canvas with the BGRA8888 pixels of the image, `img` has the raw dimensions
+    pub fn from_rgba_image(img: &RgbaImage, scale: WzCanvasScaling) -> Self {
+        let data = img
+            .pixels()
+            .flat_map(|Rgba([r, g, b, a])| [*b, *g, *r, *a])
+            .collect();
+        Self::from_bgra(data, img.width(), img.height(), scale)
+    }
+
+    /// Canvas with BGRA8888 pixel data of the raw dimensions
+    pub fn from_bgra(data: Vec<u8>, raw_w: u32, raw_h: u32, scale: WzCanvasScaling) -> Self {
+        Self {
+            data,
+            depth: WzCanvasDepth::BGRA8888,
+            raw_w,
+            raw_h,
+            width: raw_w * scale.factor(),
+            height: raw_h * scale.factor(),
+            scale,
+        }
+    }
+
+    /// The decompressed pixel data in the format of `depth`
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn depth(&self) -> WzCanvasDepth {
+        self.depth
+    }
+
     pub fn to_raw_rgba_image(&self) -> anyhow::Result<image::RgbaImage> {
         let w = self.raw_w;
         let h = self.raw_h;
+        if w == 0 || h == 0 {
+            return Ok(RgbaImage::new(w, h));
+        }
 
+        let pixels = w as usize * h as usize;
         match self.depth {
             WzCanvasDepth::BGRA4444 => {
-                let data: &[u16] = bytemuck::cast_slice(&self.data);
+                let data = self.pixel_data::<2>(pixels)?;
                 Ok(RgbaImage::from_fn(w, h, |x, y| {
-                    bgra4_to_rgba8(data[(x + y * self.width) as usize])
+                    bgra4_to_rgba8(u16::from_le_bytes(data[(x + y * w) as usize]))
                 }))
             }
             WzCanvasDepth::BGRA8888 => {
-                let data: &[u32] = bytemuck::cast_slice(&self.data);
+                let data = self.pixel_data::<4>(pixels)?;
                 Ok(RgbaImage::from_fn(w, h, |x, y| {
-                    bgra8_to_rgba8(data[(x + y * self.width) as usize])
+                    bgra8_to_rgba8(u32::from_le_bytes(data[(x + y * w) as usize]))
                 }))
             }
             WzCanvasDepth::BGR565 => {
-                let data: &[u16] = bytemuck::cast_slice(&self.data);
+                let data = self.pixel_data::<2>(pixels)?;
                 Ok(RgbaImage::from_fn(w, h, |x, y| {
-                    bgr565_to_rgba8(data[(x + y * w) as usize])
+                    bgr565_to_rgba8(u16::from_le_bytes(data[(x + y * w) as usize]))
                 }))
             }
             WzCanvasDepth::DXT3 => {
-                let mut buf = vec![0u8; (w * h * 4) as usize];
-                texpresso::Format::Bc3.decompress(&self.data, w as usize, h as usize, &mut buf);
+                let buf = self.decompress_dxt(texpresso::Format::Bc3)?;
                 Ok(RgbaImage::from_raw(w, h, buf)
                     .ok_or_else(|| anyhow::anyhow!("Failed to convert DXT3 to RGBA image"))?)
             }
             WzCanvasDepth::DXT5 => {
-                let mut buf = vec![0u8; (w * h * 4) as usize];
-                texpresso::Format::Bc5.decompress(
-                    &self.data,
-                    self.width as usize,
-                    self.height as usize,
-                    &mut buf,
-                );
-                Ok(RgbaImage::from_raw(self.width, self.height, buf)
+                let buf = self.decompress_dxt(texpresso::Format::Bc5)?;
+                Ok(RgbaImage::from_raw(w, h, buf)
                     .ok_or_else(|| anyhow::anyhow!("Failed to convert DXT5 to RGBA image"))?)
             }
         }
     }
 
+    /// Splits the data into pixels, the data must contain atleast `pixels` pixels
+    fn pixel_data<const N: usize>(&self, pixels: usize) -> anyhow::Result<&[[u8; N]]> {
+        let (data, _) = self.data.as_chunks::<N>();
+        if data.len() < pixels {
+            anyhow::bail!(
+                "Canvas data too short: {} pixels, expected: {pixels}",
+                data.len()
+            );
+        }
+        Ok(data)
+    }
+
+    fn decompress_dxt(&self, fmt: texpresso::Format) -> anyhow::Result<Vec<u8>> {
+        let (w, h) = (self.raw_w as usize, self.raw_h as usize);
+        let expected = fmt.compressed_size(w, h);
+        if self.data.len() < expected {
+            anyhow::bail!(
+                "Canvas data too short: {}, expected: {expected}",
+                self.data.len()
+            );
+        }
+
+        let mut buf = vec![0u8; w * h * 4];
+        fmt.decompress(&self.data, w, h, &mut buf);
+        Ok(buf)
+    }
+
     pub fn canvas_size(&self) -> u32 {
-        self.height * self.width * self.depth.depth_size()
+        self.height
+            .saturating_mul(self.width)
+            .saturating_mul(self.depth.depth_size())
     }
 }
 
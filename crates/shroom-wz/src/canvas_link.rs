@@ -0,0 +1,312 @@
+//! Resolution of linked canvas bitmaps.
+//!
+//! Later clients replace duplicated bitmaps by a 1x1 placeholder and a string property
+//! in the canvas sub property: `_inlink` is a path inside the same image, `_outlink`
+//! and `source` are absolute paths into another archive like `Mob/100100.img/stand/0`.
+//! Outlinks are resolved by a `WzOutlinkResolver`, like a `WzArchiveSet`.
+
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path};
+
+use crate::{
+    canvas::Canvas,
+    file::{WzIO, WzImgReader},
+    l0::{tree::WzTree, WzImgHeader},
+    uol::DEFAULT_MAX_LINK_DEPTH,
+    util::SubReader,
+    val::{CanvasVal, WzValue},
+    WzConfig, WzReader,
+};
+
+/// Reference to the actual bitmap of a canvas
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanvasLink {
+    /// Path inside the same image
+    In(String),
+    /// Absolute path, starting with the archive name
+    Out(String),
+}
+
+/// Target after following the `_inlink`s of a canvas
+#[derive(Debug)]
+pub enum CanvasTarget<'a> {
+    Canvas(&'a CanvasVal),
+    Outlink(String),
+}
+
+/// Reads canvases referenced by `_outlink`s
+pub trait WzOutlinkResolver {
+    /// Reads the canvas at the absolute path, `depth` is the number of links
+    /// followed so far
+    fn read_outlink(&mut self, path: &str, depth: usize) -> anyhow::Result<Canvas>;
+}
+
+impl CanvasVal {
+    /// The link stored in the sub property, If any
+    pub fn link(&self) -> Option<CanvasLink> {
+        let sub = self.sub.as_deref()?.as_object()?;
+        let str_prop = |name| match sub.get(name) {
+            Some(WzValue::String(s)) => Some(s.clone()),
+            _ => None,
+        };
+
+        str_prop("_inlink")
+            .map(CanvasLink::In)
+            .or_else(|| str_prop("_outlink").map(CanvasLink::Out))
+            .or_else(|| str_prop("source").map(CanvasLink::Out))
+    }
+
+    /// Follows `_inlink`s inside the image with the `root` value
+    pub fn follow_inlinks<'a>(
+        &'a self,
+        root: &'a WzValue,
+        depth: &mut usize,
+    ) -> anyhow::Result<CanvasTarget<'a>> {
+        let mut cur = self;
+        loop {
+            let path = match cur.link() {
+                None => return Ok(CanvasTarget::Canvas(cur)),
+                Some(CanvasLink::Out(path)) => return Ok(CanvasTarget::Outlink(path)),
+                Some(CanvasLink::In(path)) => path,
+            };
+
+            if *depth >= DEFAULT_MAX_LINK_DEPTH {
+                anyhow::bail!("Canvas link depth exceeds {DEFAULT_MAX_LINK_DEPTH} at {path}");
+            }
+            *depth += 1;
+            cur = root
+                .get_path(&path)
+                .and_then(WzValue::as_canvas)
+                .ok_or_else(|| anyhow::format_err!("Invalid canvas inlink: {path}"))?;
+        }
+    }
+
+    /// Reads the canvas, following links. `root` is the root value of the image,
+    /// without an `outlinks` resolver reading an outlinked canvas fails
+    pub fn read_canvas_resolved<R: WzIO>(
+        &self,
+        r: &mut WzImgReader<R>,
+        root: &WzValue,
+        outlinks: Option<&mut dyn WzOutlinkResolver>,
+    ) -> anyhow::Result<Canvas> {
+        let mut depth = 0;
+        match self.follow_inlinks(root, &mut depth)? {
+            CanvasTarget::Canvas(canvas) => canvas.read_canvas(r),
+            CanvasTarget::Outlink(path) => match outlinks {
+                Some(outlinks) => outlinks.read_outlink(&path, depth),
+                None => anyhow::bail!("Can't resolve canvas outlink: {path}"),
+            },
+        }
+    }
+}
+
+/// Multiple archives by their name, resolves outlinks between them.
+/// Read images are cached
+pub struct WzArchiveSet<R> {
+    archives: HashMap<String, (WzReader<R>, WzTree)>,
+    imgs: HashMap<String, (WzImgHeader, WzValue)>,
+}
+
+impl<R> Default for WzArchiveSet<R> {
+    fn default() -> Self {
+        Self {
+            archives: HashMap::new(),
+            imgs: HashMap::new(),
+        }
+    }
+}
+
+impl WzArchiveSet<BufReader<File>> {
+    /// Opens all `.wz` files in the directory
+    pub fn open_dir(dir: impl AsRef<Path>, cfg: WzConfig) -> anyhow::Result<Self> {
+        let mut set = Self::default();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() != Some("wz".as_ref()) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            set.insert(name, WzReader::open_file(&path, cfg)?)?;
+        }
+        Ok(set)
+    }
+}
+
+impl<R: WzIO> WzArchiveSet<R> {
+    /// Adds an archive, `name` is the file name without the extension
+    pub fn insert(&mut self, name: &str, mut r: WzReader<R>) -> anyhow::Result<()> {
+        let tree = WzTree::from_reader_lazy(&mut r, Some(name))?;
+        self.archives.insert(name.to_string(), (r, tree));
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.archives.contains_key(name)
+    }
+
+    /// Names of the archives, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.archives.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn archive_mut(&mut self, name: &str) -> Option<(&mut WzReader<R>, &mut WzTree)> {
+        self.archives.get_mut(name).map(|(r, tree)| (r, tree))
+    }
+
+    /// Reads the root value of the image in the archive, the value is cached
+    pub fn get_img(&mut self, archive: &str, img_path: &str) -> anyhow::Result<&WzValue> {
+        self.load_img(archive, img_path)?;
+        Ok(&self.imgs[&format!("{archive}/{img_path}")].1)
+    }
+
+    /// Reader for the image in the archive, required to read canvas and sound data
+    pub fn img_reader(
+        &mut self,
+        archive: &str,
+        img_path: &str,
+    ) -> anyhow::Result<WzImgReader<SubReader<'_, R>>> {
+        self.load_img(archive, img_path)?;
+        let key = format!("{archive}/{img_path}");
+        let hdr = &self.imgs[&key].0;
+        let (r, _) = self.archives.get_mut(archive).unwrap();
+        Ok(r.img_reader(hdr)?.with_path(key))
+    }
+
+    pub fn clear_cache(&mut self) {
+        self.imgs.clear();
+    }
+
+    /// Reads the image from the archive into the cache
+    fn load_img(&mut self, archive: &str, img_path: &str) -> anyhow::Result<()> {
+        let key = format!("{archive}/{img_path}");
+        if self.imgs.contains_key(&key) {
+            return Ok(());
+        }
+
+        let (r, tree) = self
+            .archives
+            .get_mut(archive)
+            .ok_or_else(|| anyhow::format_err!("Unknown archive: {archive}"))?;
+        let hdr = tree
+            .load_img_by_path(r, img_path)?
+            .ok_or_else(|| anyhow::format_err!("Image not found: {key}"))?
+            .clone();
+        let val = WzValue::read(&mut r.img_reader(&hdr)?.with_path(&key))?;
+        self.imgs.insert(key, (hdr, val));
+        Ok(())
+    }
+}
+
+impl<R: WzIO> WzOutlinkResolver for WzArchiveSet<R> {
+    fn read_outlink(&mut self, path: &str, mut depth: usize) -> anyhow::Result<Canvas> {
+        let mut path = path.to_string();
+        loop {
+            let (archive, img_path, prop_path) = split_outlink(&path)
+                .ok_or_else(|| anyhow::format_err!("Invalid canvas outlink: {path}"))?;
+            self.load_img(archive, img_path)?;
+
+            let key = format!("{archive}/{img_path}");
+            let (hdr, root) = &self.imgs[&key];
+            let canvas = root
+                .get_path(prop_path)
+                .and_then(WzValue::as_canvas)
+                .ok_or_else(|| anyhow::format_err!("Invalid canvas outlink: {path}"))?;
+
+            match canvas.follow_inlinks(root, &mut depth)? {
+                CanvasTarget::Canvas(canvas) => {
+                    let (r, _) = self.archives.get_mut(archive).unwrap();
+                    let mut img = r.img_reader(hdr)?.with_path(key);
+                    return canvas.read_canvas(&mut img);
+                }
+                CanvasTarget::Outlink(next) => {
+                    if depth >= DEFAULT_MAX_LINK_DEPTH {
+                        anyhow::bail!(
+                            "Canvas link depth exceeds {DEFAULT_MAX_LINK_DEPTH} at {next}"
+                        );
+                    }
+                    depth += 1;
+                    path = next;
+                }
+            }
+        }
+    }
+}
+
+/// Splits an outlink into the archive, the image path and the property path
+fn split_outlink(path: &str) -> Option<(&str, &str, &str)> {
+    let (archive, rest) = path.split_once('/')?;
+    let img_end = rest.find(".img/")? + ".img".len();
+    Some((archive, &rest[..img_end], &rest[img_end + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        test_util::{build_archive, canvas_val, dir, img, img_blob, obj, str_val, TestVal},
+        val::WzValue,
+        WzReader, GMS95,
+    };
+
+    use super::{CanvasLink, WzArchiveSet};
+
+    #[test]
+    fn canvas_links() -> anyhow::Result<()> {
+        let mob = img_blob(
+            GMS95,
+            &obj([(
+                "stand",
+                TestVal::Obj(obj([
+                    ("0", canvas_val(3, 2, Vec::new())),
+                    (
+                        "1",
+                        canvas_val(1, 1, obj([("_inlink", str_val("stand/0"))])),
+                    ),
+                ])),
+            )]),
+        );
+        let effect = img_blob(
+            GMS95,
+            &obj([
+                (
+                    "0",
+                    canvas_val(
+                        1,
+                        1,
+                        obj([("_outlink", str_val("Mob/Mob/100100.img/stand/1"))]),
+                    ),
+                ),
+                ("1", canvas_val(1, 1, obj([("_inlink", str_val("0"))]))),
+            ]),
+        );
+
+        let mut set = WzArchiveSet::default();
+        set.insert(
+            "Mob",
+            WzReader::open(
+                Cursor::new(build_archive(
+                    GMS95,
+                    vec![dir("Mob", vec![img("100100.img", mob)])],
+                )),
+                GMS95,
+            )?,
+        )?;
+
+        let mut r = WzReader::open_img(Cursor::new(effect), GMS95);
+        let mut img = r.root_img_reader()?;
+        let root = WzValue::read(&mut img)?;
+        let canvas = root.get_path("1").unwrap().as_canvas().unwrap();
+        assert_eq!(canvas.link(), Some(CanvasLink::In("0".to_string())));
+
+        // Without a resolver the outlink can't be followed
+        assert!(canvas.read_canvas_resolved(&mut img, &root, None).is_err());
+        let resolved = canvas.read_canvas_resolved(&mut img, &root, Some(&mut set))?;
+        assert_eq!((resolved.width, resolved.height), (3, 2));
+        assert_eq!(resolved.to_raw_rgba_image()?.dimensions(), (3, 2));
+        Ok(())
+    }
+}
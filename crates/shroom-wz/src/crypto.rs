@@ -112,7 +112,7 @@ impl WzCrypto {
     }
 
     fn offset_key_at(&self, pos: u32, data_offset: u32) -> u32 {
-        let mut off = Wrapping(!(pos - data_offset));
+        let mut off = Wrapping(!pos.wrapping_sub(data_offset));
         off *= self.version_hash;
         off -= self.offset_magic;
 
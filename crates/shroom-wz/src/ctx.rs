@@ -1,28 +1,28 @@
 use std::{
     cell::RefCell,
     collections::HashMap,
-    io::{Read, Seek},
-    rc::Rc,
+    io::{Read, Seek, Write},
+    sync::Arc,
 };
 
-use binrw::{BinRead, BinResult};
+use binrw::{BinRead, BinResult, BinWrite};
 
 use crate::{crypto::WzCrypto, ty::WzStr};
 
 #[derive(Debug, Default)]
-pub struct WzStrTable(RefCell<HashMap<u32, Rc<WzStr>>>);
+pub struct WzStrTable(RefCell<HashMap<u32, Arc<WzStr>>>);
 
 impl WzStrTable {
-    pub fn get(&self, offset: &u32) -> Option<Rc<WzStr>> {
+    pub fn get(&self, offset: &u32) -> Option<Arc<WzStr>> {
         self.0.borrow().get(offset).cloned()
     }
 
-    pub fn must_get(&self, offset: &u32) -> anyhow::Result<Rc<WzStr>> {
+    pub fn must_get(&self, offset: &u32) -> anyhow::Result<Arc<WzStr>> {
         self.get(offset)
             .ok_or_else(|| anyhow::anyhow!("Missing string at offset {:#x}", offset))
     }
 
-    pub fn insert(&self, offset: u32, s: Rc<WzStr>) {
+    pub fn insert(&self, offset: u32, s: Arc<WzStr>) {
         self.0.borrow_mut().insert(offset, s);
     }
 }
@@ -43,10 +43,17 @@ impl WzStrWriteTable {
 #[derive(Debug, Clone, Copy)]
 pub struct WzContext<'a>(pub &'a WzCrypto);
 
+/// Maximum nesting depth of objects in an image
+pub const MAX_OBJ_DEPTH: usize = 64;
+
 #[derive(Debug, Clone, Copy)]
 pub struct WzImgReadCtx<'a> {
     pub crypto: &'a WzCrypto,
     pub str_table: &'a WzStrTable,
+    /// Nesting depth of the current object
+    pub depth: usize,
+    /// Replace unreadable properties with `WzObject::Invalid` instead of failing
+    pub lenient: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -87,16 +94,40 @@ impl<'a> From<WzImgWriteCtx<'a>> for WzContext<'a> {
 
 impl<'a> WzImgReadCtx<'a> {
     pub fn new(crypto: &'a WzCrypto, str_table: &'a WzStrTable) -> Self {
-        Self { crypto, str_table }
+        Self {
+            crypto,
+            str_table,
+            depth: 0,
+            lenient: false,
+        }
+    }
+
+    /// Context which recovers from corrupt properties, see `WzProperty`
+    pub fn lenient(self) -> Self {
+        Self {
+            lenient: true,
+            ..self
+        }
     }
 
-    pub fn get_str(&self, offset: u32) -> anyhow::Result<Rc<WzStr>> {
+    /// Context for a nested object, fails If the nesting is too deep
+    pub fn nested(self) -> anyhow::Result<Self> {
+        if self.depth >= MAX_OBJ_DEPTH {
+            anyhow::bail!("Object nesting exceeds the limit of {MAX_OBJ_DEPTH}");
+        }
+        Ok(Self {
+            depth: self.depth + 1,
+            ..self
+        })
+    }
+
+    pub fn get_str(&self, offset: u32) -> anyhow::Result<Arc<WzStr>> {
         self.str_table.must_get(&offset)
     }
 
-    pub fn read_str<R: Read + Seek>(&self, mut r: R) -> BinResult<Rc<WzStr>> {
+    pub fn read_str<R: Read + Seek>(&self, mut r: R) -> BinResult<Arc<WzStr>> {
         let offset = r.stream_position()? as u32;
-        let str = Rc::new(WzStr::read_le_args(&mut r, self.into())?);
+        let str = Arc::new(WzStr::read_le_args(&mut r, self.into())?);
         self.str_table.insert(offset, str.clone());
         Ok(str)
     }
@@ -106,4 +137,11 @@ impl<'a> WzImgWriteCtx<'a> {
     pub fn new(crypto: &'a WzCrypto, str_table: &'a WzStrWriteTable) -> Self {
         Self { crypto, str_table }
     }
+
+    pub fn write_str<W: Write + Seek>(&self, mut w: W, s: &WzStr) -> BinResult<()> {
+        let offset = w.stream_position()? as u32;
+        s.write_le_args(&mut w, self.into())?;
+        self.str_table.insert(s.0.clone(), offset);
+        Ok(())
+    }
 }
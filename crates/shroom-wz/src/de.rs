@@ -0,0 +1,931 @@
+//! Deserializing values into user-defined types.
+//!
+//! `from_value` fills any `Deserialize` type from a `WzValue`:
+//! * objects deserialize as structs and maps, map keys may be numbers
+//! * objects with numeric keys deserialize as sequences, ordered by the key
+//! * canvases deserialize as their sub property, vectors as `{x, y}` or `(x, y)`
+//! * numbers stored as strings are parsed, numbers are formatted for strings
+//! * `CanvasVal`, `SoundVal`, `Vec2Val` and `WzValue` fields get the value as it is,
+//!   so canvases and sounds can be read later
+
+use std::{fmt, io::Cursor, sync::Arc};
+
+use binrw::PosValue;
+use serde::{
+    de::{self, value::BorrowedStrDeserializer, IntoDeserializer, SeqAccess, Visitor},
+    forward_to_deserialize_any, Deserialize,
+};
+
+use crate::{
+    canvas::Canvas,
+    l1::{
+        canvas::{WzCanvas, WzCanvasDepth, WzCanvasScaling},
+        sound::{SoundHeader, WzSound},
+        WzPosValue,
+    },
+    ty::WzInt,
+    val::{CanvasVal, ErrorVal, ObjectVal, SoundVal, Vex2Val, WzValue},
+};
+
+/// Newtype struct name, which makes `WzValueDeserializer` pass the value as it is
+const PASSTHROUGH: &str = "$wz::passthrough";
+
+/// Deserialize error with the property path, where It happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WzDeError {
+    pub path: String,
+    pub msg: String,
+}
+
+impl WzDeError {
+    /// Prepends the key of the parent object to the path
+    fn in_key(mut self, key: &str) -> Self {
+        self.path = if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{key}/{}", self.path)
+        };
+        self
+    }
+}
+
+impl fmt::Display for WzDeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            f.write_str(&self.msg)
+        } else {
+            write!(f, "{} at {}", self.msg, self.path)
+        }
+    }
+}
+
+impl std::error::Error for WzDeError {}
+
+impl de::Error for WzDeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self {
+            path: String::new(),
+            msg: msg.to_string(),
+        }
+    }
+}
+
+/// Deserializes the type from the value
+pub fn from_value<'de, T: Deserialize<'de>>(val: &'de WzValue) -> Result<T, WzDeError> {
+    T::deserialize(WzValueDeserializer(val))
+}
+
+/// Deserializes a value as It is, other deserializers than `WzValueDeserializer`
+/// deserialize It like a `WzValue`
+pub(crate) fn deserialize_passthrough<'de, D: de::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<WzValue, D::Error> {
+    deserializer.deserialize_newtype_struct(PASSTHROUGH, PassthroughVisitor)
+}
+
+struct PassthroughVisitor;
+
+impl<'de> Visitor<'de> for PassthroughVisitor {
+    type Value = WzValue;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a WzValue")
+    }
+
+    /// Rebuilds the value from the components of a `PassedSeq`
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        fn next<'de, T: Deserialize<'de>, A: SeqAccess<'de>>(seq: &mut A) -> Result<T, A::Error> {
+            seq.next_element()?
+                .ok_or_else(|| de::Error::custom("Truncated passed value"))
+        }
+
+        let ty: &str = next(&mut seq)?;
+        Ok(match ty {
+            "null" => WzValue::Null,
+            "f32" => WzValue::F32(next(&mut seq)?),
+            "f64" => WzValue::F64(next(&mut seq)?),
+            "short" => WzValue::Short(next(&mut seq)?),
+            "int" => WzValue::Int(next(&mut seq)?),
+            "long" => WzValue::Long(next(&mut seq)?),
+            "string" => WzValue::String(next(&mut seq)?),
+            "link" => WzValue::Link(next(&mut seq)?),
+            "vec" => WzValue::Vec((next(&mut seq)?, next(&mut seq)?).into()),
+            "convex" => {
+                let n: usize = next(&mut seq)?;
+                let mut vex = Vec::new();
+                for _ in 0..n {
+                    vex.push((next(&mut seq)?, next(&mut seq)?).into());
+                }
+                WzValue::Convex(Vex2Val(vex))
+            }
+            "object" => {
+                let n: usize = next(&mut seq)?;
+                let mut obj = ObjectVal(Default::default());
+                for _ in 0..n {
+                    obj.0.insert(next(&mut seq)?, next(&mut seq)?);
+                }
+                WzValue::Object(obj)
+            }
+            "canvas" => {
+                let canvas = WzCanvas {
+                    unknown: next(&mut seq)?,
+                    has_property: next(&mut seq)?,
+                    // Only needed for reading, the sub property holds the values
+                    property: None,
+                    width: WzInt(next(&mut seq)?),
+                    height: WzInt(next(&mut seq)?),
+                    depth: WzCanvasDepth::try_from(WzInt(next(&mut seq)?))
+                        .map_err(de::Error::custom)?,
+                    scale: WzCanvasScaling::try_from(next::<u8, _>(&mut seq)?)
+                        .map_err(de::Error::custom)?,
+                    unknown1: next(&mut seq)?,
+                    len: WzPosValue {
+                        val: next(&mut seq)?,
+                        pos: next(&mut seq)?,
+                    },
+                };
+                let sub = next::<Option<WzValue>, _>(&mut seq)?.map(Box::new);
+                let bitmap = next::<Option<PassedBytes>, _>(&mut seq)?
+                    .map(|data| Arc::new(Canvas::from_data(data.0, &canvas)));
+                WzValue::Canvas(CanvasVal {
+                    canvas,
+                    sub,
+                    bitmap,
+                })
+            }
+            "sound" => {
+                let unknown = next(&mut seq)?;
+                let size = WzInt(next(&mut seq)?);
+                let len_ms = WzInt(next(&mut seq)?);
+                let header = next::<PassedBytes, _>(&mut seq)?;
+                let header = SoundHeader::read_header(&mut Cursor::new(header.0))
+                    .map_err(de::Error::custom)?;
+                let pos = next(&mut seq)?;
+                let data = next::<Option<PassedBytes>, _>(&mut seq)?;
+                WzValue::Sound(SoundVal {
+                    sound: WzSound {
+                        unknown,
+                        size,
+                        len_ms,
+                        header,
+                        offset: PosValue { val: (), pos },
+                    },
+                    data: data.map(|data| Arc::new(data.0)),
+                })
+            }
+            "error" => WzValue::Error(ErrorVal {
+                offset: next(&mut seq)?,
+                reason: next(&mut seq)?,
+            }),
+            _ => return Err(de::Error::unknown_variant(ty, &[])),
+        })
+    }
+
+    fn visit_newtype_struct<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(crate::val::WzValueVisitor)
+    }
+}
+
+fn unexpected(val: &WzValue) -> de::Unexpected<'_> {
+    match val {
+        WzValue::Object(_) => de::Unexpected::Map,
+        WzValue::Null => de::Unexpected::Unit,
+        WzValue::F32(v) => de::Unexpected::Float(*v as f64),
+        WzValue::F64(v) => de::Unexpected::Float(*v),
+        WzValue::Short(v) => de::Unexpected::Signed(*v as i64),
+        WzValue::Int(v) => de::Unexpected::Signed(*v as i64),
+        WzValue::Long(v) => de::Unexpected::Signed(*v),
+        WzValue::String(v) => de::Unexpected::Str(v),
+        WzValue::Vec(_) => de::Unexpected::Other("vector"),
+        WzValue::Convex(_) => de::Unexpected::Other("convex"),
+        WzValue::Sound(_) => de::Unexpected::Other("sound"),
+        WzValue::Canvas(_) => de::Unexpected::Other("canvas"),
+        WzValue::Link(_) => de::Unexpected::Other("link"),
+        WzValue::Error(_) => de::Unexpected::Other("error"),
+    }
+}
+
+/// Deserializer over a borrowed value
+#[derive(Debug, Clone, Copy)]
+pub struct WzValueDeserializer<'de>(pub &'de WzValue);
+
+impl<'de> WzValueDeserializer<'de> {
+    /// Entries of objects and the sub property of canvases
+    fn entries(&self) -> Option<&'de crate::val::ObjectVal> {
+        match self.0 {
+            WzValue::Object(obj) => Some(obj),
+            WzValue::Canvas(canvas) => canvas.sub.as_deref().and_then(WzValue::as_object),
+            _ => None,
+        }
+    }
+
+    fn invalid_type(&self, exp: &dyn de::Expected) -> WzDeError {
+        if let WzValue::Error(err) = self.0 {
+            return de::Error::custom(format!("Unreadable value: {}", err.reason));
+        }
+        de::Error::invalid_type(unexpected(self.0), exp)
+    }
+}
+
+macro_rules! deserialize_num {
+    ($($method:ident => $visit:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0 {
+                    WzValue::String(s) => match s.trim().parse() {
+                        Ok(v) => visitor.$visit(v),
+                        Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(s), &visitor)),
+                    },
+                    _ => self.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for WzValueDeserializer<'de> {
+    type Error = WzDeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            WzValue::Null => visitor.visit_unit(),
+            WzValue::F32(v) => visitor.visit_f32(*v),
+            WzValue::F64(v) => visitor.visit_f64(*v),
+            WzValue::Short(v) => visitor.visit_i16(*v),
+            WzValue::Int(v) => visitor.visit_i32(*v),
+            WzValue::Long(v) => visitor.visit_i64(*v),
+            WzValue::String(v) | WzValue::Link(v) => visitor.visit_borrowed_str(v),
+            WzValue::Vec(v) => visitor.visit_map(VecAccess::new(v.x, v.y)),
+            WzValue::Convex(vex) => visitor.visit_seq(ValSeq {
+                iter: vex.0.iter().enumerate(),
+            }),
+            WzValue::Object(_) | WzValue::Canvas(_) => self.deserialize_map(visitor),
+            WzValue::Sound(_) | WzValue::Error(_) => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    deserialize_num!(
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+    );
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            WzValue::Short(v) => visitor.visit_bool(*v != 0),
+            WzValue::Int(v) => visitor.visit_bool(*v != 0),
+            WzValue::Long(v) => visitor.visit_bool(*v != 0),
+            WzValue::String(s) => match s.trim() {
+                "1" | "true" => visitor.visit_bool(true),
+                "0" | "false" => visitor.visit_bool(false),
+                _ => Err(de::Error::invalid_value(de::Unexpected::Str(s), &visitor)),
+            },
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            WzValue::Short(v) => visitor.visit_string(v.to_string()),
+            WzValue::Int(v) => visitor.visit_string(v.to_string()),
+            WzValue::Long(v) => visitor.visit_string(v.to_string()),
+            WzValue::F32(v) => visitor.visit_string(v.to_string()),
+            WzValue::F64(v) => visitor.visit_string(v.to_string()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            WzValue::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        if name == PASSTHROUGH {
+            return visitor.visit_seq(PassedSeq::new(self.0)?);
+        }
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if let WzValue::Convex(_) = self.0 {
+            return self.deserialize_any(visitor);
+        }
+        let Some(obj) = self.entries() else {
+            return Err(self.invalid_type(&visitor));
+        };
+
+        let mut items = obj
+            .0
+            .iter()
+            .map(|(key, val)| match key.parse::<i64>() {
+                Ok(ix) => Ok((ix, key.as_str(), val)),
+                Err(_) => Err(
+                    <WzDeError as de::Error>::custom("Non-numeric key in a sequence").in_key(key),
+                ),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        items.sort_by_key(|(ix, _, _)| *ix);
+        visitor.visit_seq(KeyedSeq {
+            iter: items.into_iter(),
+        })
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            WzValue::Vec(v) if len == 2 => {
+                visitor.visit_seq(de::value::SeqDeserializer::new([v.x, v.y].into_iter()))
+            }
+            _ => self.deserialize_seq(visitor),
+        }
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if let WzValue::Vec(_) = self.0 {
+            return self.deserialize_any(visitor);
+        }
+        // Canvases without sub property are empty
+        if let WzValue::Canvas(canvas) = self.0 {
+            if canvas.sub.is_none() {
+                return visitor.visit_map(de::value::MapDeserializer::new(std::iter::empty::<(
+                    &str,
+                    i32,
+                )>()));
+            }
+        }
+        match self.entries() {
+            Some(obj) => visitor.visit_map(ObjAccess {
+                iter: obj.0.iter(),
+                cur: None,
+            }),
+            None => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self.0 {
+            WzValue::String(s) => visitor.visit_enum(s.as_str().into_deserializer()),
+            _ => Err(self.invalid_type(&visitor)),
+        }
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    forward_to_deserialize_any! {
+        char bytes byte_buf unit unit_struct identifier i128 u128
+    }
+}
+
+/// Component of a value, which is passed as It is
+enum Passed<'de> {
+    Str(&'de str),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Bytes(Vec<u8>),
+    Val(Option<&'de WzValue>),
+}
+
+/// The components of a value for the `PassthroughVisitor`, media values carry their
+/// position in the image and replaced data along, so they can be rebuilt as they are
+struct PassedSeq<'de> {
+    iter: std::vec::IntoIter<Passed<'de>>,
+}
+
+impl<'de> PassedSeq<'de> {
+    fn new(val: &'de WzValue) -> Result<Self, WzDeError> {
+        use Passed::*;
+        let items = match val {
+            WzValue::Null => vec![Str("null")],
+            WzValue::F32(v) => vec![Str("f32"), Float(*v as f64)],
+            WzValue::F64(v) => vec![Str("f64"), Float(*v)],
+            WzValue::Short(v) => vec![Str("short"), Int(*v as i64)],
+            WzValue::Int(v) => vec![Str("int"), Int(*v as i64)],
+            WzValue::Long(v) => vec![Str("long"), Int(*v)],
+            WzValue::String(v) => vec![Str("string"), Str(v)],
+            WzValue::Link(v) => vec![Str("link"), Str(v)],
+            WzValue::Vec(v) => vec![Str("vec"), Int(v.x as i64), Int(v.y as i64)],
+            WzValue::Convex(vex) => {
+                let mut items = vec![Str("convex"), UInt(vex.0.len() as u64)];
+                for v in vex.0.iter() {
+                    items.extend([Int(v.x as i64), Int(v.y as i64)]);
+                }
+                items
+            }
+            WzValue::Object(obj) => {
+                let mut items = vec![Str("object"), UInt(obj.0.len() as u64)];
+                for (key, val) in obj.0.iter() {
+                    items.extend([Str(key), Val(Some(val))]);
+                }
+                items
+            }
+            WzValue::Canvas(canvas) => {
+                let c = &canvas.canvas;
+                vec![
+                    Str("canvas"),
+                    UInt(c.unknown as u64),
+                    UInt(c.has_property as u64),
+                    Int(c.width.0 as i64),
+                    Int(c.height.0 as i64),
+                    Int(WzInt::from(c.depth).0 as i64),
+                    UInt(c.scale.0 as u64),
+                    UInt(c.unknown1 as u64),
+                    UInt(c.len.val as u64),
+                    UInt(c.len.pos),
+                    Val(canvas.sub.as_deref()),
+                    canvas
+                        .bitmap
+                        .as_ref()
+                        .map_or(Val(None), |bitmap| Bytes(bitmap.data().to_vec())),
+                ]
+            }
+            WzValue::Sound(sound) => {
+                let s = &sound.sound;
+                let mut header = Cursor::new(Vec::new());
+                s.header
+                    .write_header(&mut header)
+                    .map_err(de::Error::custom)?;
+                vec![
+                    Str("sound"),
+                    UInt(s.unknown as u64),
+                    Int(s.size.0 as i64),
+                    Int(s.len_ms.0 as i64),
+                    Bytes(header.into_inner()),
+                    UInt(s.offset.pos),
+                    sound
+                        .data
+                        .as_ref()
+                        .map_or(Val(None), |data| Bytes(data.to_vec())),
+                ]
+            }
+            WzValue::Error(err) => vec![Str("error"), UInt(err.offset), Str(&err.reason)],
+        };
+        Ok(Self {
+            iter: items.into_iter(),
+        })
+    }
+}
+
+impl<'de> SeqAccess<'de> for PassedSeq<'de> {
+    type Error = WzDeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        self.iter
+            .next()
+            .map(|item| seed.deserialize(item))
+            .transpose()
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Passed<'de> {
+    type Error = WzDeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Passed::Str(v) => visitor.visit_borrowed_str(v),
+            Passed::Int(v) => visitor.visit_i64(v),
+            Passed::UInt(v) => visitor.visit_u64(v),
+            Passed::Float(v) => visitor.visit_f64(v),
+            Passed::Bytes(v) => visitor.visit_byte_buf(v),
+            Passed::Val(None) => visitor.visit_none(),
+            Passed::Val(Some(val)) => WzValueDeserializer(val).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Passed::Val(None) => visitor.visit_none(),
+            Passed::Val(Some(val)) => visitor.visit_some(WzValueDeserializer(val)),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        match self {
+            Passed::Val(Some(val)) => {
+                WzValueDeserializer(val).deserialize_newtype_struct(name, visitor)
+            }
+            _ => visitor.visit_newtype_struct(self),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+        byte_buf unit unit_struct seq tuple tuple_struct map struct enum identifier
+        ignored_any
+    }
+}
+
+/// Bytes of a `Passed::Bytes`
+struct PassedBytes(Vec<u8>);
+
+impl<'de> Deserialize<'de> for PassedBytes {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BytesVisitor;
+
+        impl Visitor<'_> for BytesVisitor {
+            type Value = PassedBytes;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("bytes")
+            }
+
+            fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+                Ok(PassedBytes(v))
+            }
+        }
+
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
+/// Map access over the entries of an object
+struct ObjAccess<'de, I> {
+    iter: I,
+    cur: Option<(&'de str, &'de WzValue)>,
+}
+
+impl<'de, I: Iterator<Item = (&'de String, &'de WzValue)>> de::MapAccess<'de>
+    for ObjAccess<'de, I>
+{
+    type Error = WzDeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some((key, val)) = self.iter.next() else {
+            return Ok(None);
+        };
+        self.cur = Some((key, val));
+        seed.deserialize(KeyDeserializer(key))
+            .map(Some)
+            .map_err(|err| err.in_key(key))
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (key, val) = self
+            .cur
+            .take()
+            .ok_or_else(|| <WzDeError as de::Error>::custom("Value without a key"))?;
+        seed.deserialize(WzValueDeserializer(val))
+            .map_err(|err| err.in_key(key))
+    }
+}
+
+/// Map access over the components of a vector
+struct VecAccess {
+    entries: std::array::IntoIter<(&'static str, i32), 2>,
+    cur: Option<i32>,
+}
+
+impl VecAccess {
+    fn new(x: i32, y: i32) -> Self {
+        Self {
+            entries: [("x", x), ("y", y)].into_iter(),
+            cur: None,
+        }
+    }
+}
+
+impl<'de> de::MapAccess<'de> for VecAccess {
+    type Error = WzDeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        let Some((key, val)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.cur = Some(val);
+        seed.deserialize(BorrowedStrDeserializer::new(key))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let val = self
+            .cur
+            .take()
+            .ok_or_else(|| <WzDeError as de::Error>::custom("Value without a key"))?;
+        seed.deserialize(val.into_deserializer())
+    }
+}
+
+/// Sequence of the vectors of a convex
+struct ValSeq<I> {
+    iter: I,
+}
+
+impl<'de, I: Iterator<Item = (usize, &'de crate::val::Vec2Val)>> de::SeqAccess<'de> for ValSeq<I> {
+    type Error = WzDeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        let Some((ix, v)) = self.iter.next() else {
+            return Ok(None);
+        };
+        seed.deserialize(de::value::MapAccessDeserializer::new(VecAccess::new(
+            v.x, v.y,
+        )))
+        .map(Some)
+        .map_err(|err| err.in_key(&ix.to_string()))
+    }
+}
+
+/// Sequence of object entries sorted by their numeric key
+struct KeyedSeq<I> {
+    iter: I,
+}
+
+impl<'de, I: Iterator<Item = (i64, &'de str, &'de WzValue)>> de::SeqAccess<'de> for KeyedSeq<I> {
+    type Error = WzDeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Self::Error> {
+        let Some((_, key, val)) = self.iter.next() else {
+            return Ok(None);
+        };
+        seed.deserialize(WzValueDeserializer(val))
+            .map(Some)
+            .map_err(|err| err.in_key(key))
+    }
+}
+
+/// Deserializer for object keys, numeric keys are parsed
+struct KeyDeserializer<'de>(&'de str);
+
+macro_rules! deserialize_key_num {
+    ($($method:ident => $visit:ident),* $(,)?) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                match self.0.parse() {
+                    Ok(v) => visitor.$visit(v),
+                    Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(self.0), &visitor)),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer<'de> {
+    type Error = WzDeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    deserialize_key_num!(
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+    );
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf option unit unit_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any i128 u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use indexmap::indexmap;
+    use serde::Deserialize;
+
+    use crate::{
+        l1::canvas::WzCanvasScaling,
+        test_util::{img_blob, obj, TestVal},
+        val::{CanvasVal, ErrorVal, SoundVal, Vec2Val, Vex2Val, WzValue},
+        WzReader, GMS95,
+    };
+
+    use super::from_value;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct Info {
+        level: u8,
+        #[serde(rename = "maxHP")]
+        max_hp: u32,
+        boss: bool,
+        #[serde(default)]
+        exp: i32,
+        speed: Option<i32>,
+        name: String,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Frame {
+        origin: Vec2Val,
+        delay: Option<u16>,
+        head: (i32, i32),
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Mob {
+        info: Info,
+        stand: Vec<Frame>,
+        skills: BTreeMap<u32, String>,
+        icon: WzValue,
+        canvas: CanvasVal,
+    }
+
+    #[test]
+    fn deserialize() {
+        let frame = |delay: Option<i32>| {
+            let mut frame = indexmap! {
+                "origin".to_string() => WzValue::Vec((1, 2).into()),
+                "head".to_string() => WzValue::Vec((3, 4).into()),
+            };
+            if let Some(delay) = delay {
+                frame.insert("delay".to_string(), WzValue::Int(delay));
+            }
+            WzValue::from(frame)
+        };
+        let root = WzValue::from(indexmap! {
+            "info".to_string() => WzValue::from(indexmap! {
+                "level".to_string() => WzValue::Short(10),
+                "maxHP".to_string() => WzValue::String("1500".to_string()),
+                "boss".to_string() => WzValue::Int(1),
+                "name".to_string() => WzValue::Int(100100),
+            }),
+            "stand".to_string() => WzValue::from(indexmap! {
+                "10".to_string() => frame(None),
+                "2".to_string() => frame(Some(120)),
+            }),
+            "skills".to_string() => WzValue::from(indexmap! {
+                "7".to_string() => WzValue::String("b".to_string()),
+                "3".to_string() => WzValue::String("a".to_string()),
+            }),
+            "icon".to_string() => WzValue::Link("../info".to_string()),
+            "canvas".to_string() => WzValue::Canvas(CanvasVal::detached(
+                WzCanvasScaling(0),
+                Some(Box::new(frame(Some(90)))),
+            )),
+        });
+
+        let mob: Mob = from_value(&root).unwrap();
+        assert_eq!(
+            mob.info,
+            Info {
+                level: 10,
+                max_hp: 1500,
+                boss: true,
+                exp: 0,
+                speed: None,
+                name: "100100".to_string(),
+            }
+        );
+        assert_eq!(mob.stand.len(), 2);
+        assert_eq!(mob.stand[0].delay, Some(120));
+        assert_eq!(mob.stand[1].origin, (1, 2).into());
+        assert_eq!(mob.stand[1].head, (3, 4));
+        assert_eq!(
+            mob.skills.into_iter().collect::<Vec<_>>(),
+            [(3, "a".to_string()), (7, "b".to_string())]
+        );
+        assert_eq!(mob.icon, WzValue::Link("../info".to_string()));
+        assert_eq!(
+            mob.canvas,
+            *root.get_path("canvas").unwrap().as_canvas().unwrap()
+        );
+        // Canvases deserialize as their sub property
+        let frame: Frame = from_value(root.get_path("canvas").unwrap()).unwrap();
+        assert_eq!(frame.delay, Some(90));
+
+        let err = from_value::<Mob>(&WzValue::from(indexmap! {
+            "info".to_string() => WzValue::from(indexmap! {
+                "level".to_string() => WzValue::Int(1000),
+            }),
+        }))
+        .unwrap_err();
+        assert_eq!(err.path, "info/level");
+    }
+
+    #[test]
+    fn passthrough() -> anyhow::Result<()> {
+        let mut canvas = CanvasVal::detached(
+            WzCanvasScaling(0),
+            Some(Box::new(WzValue::from(indexmap! {
+                "_inlink".to_string() => WzValue::String("0".to_string()),
+            }))),
+        );
+        canvas.set_image(&image::RgbaImage::new(2, 1));
+        let blob = img_blob(
+            GMS95,
+            &obj([(
+                "0",
+                TestVal::Sound {
+                    data: vec![1; 8],
+                    len_ms: 10,
+                },
+            )]),
+        );
+        let mut r = WzReader::open_img(std::io::Cursor::new(blob), GMS95);
+        let sound = WzValue::read(&mut r.root_img_reader()?)?;
+        let sound = sound.get_path("0").unwrap().as_sound().unwrap();
+        let sound = SoundVal::detached(sound.sound.header.clone(), 10, vec![2; 8])?;
+        let root = WzValue::from(indexmap! {
+            "short".to_string() => WzValue::Short(-3),
+            "long".to_string() => WzValue::Long(1 << 40),
+            "f32".to_string() => WzValue::F32(1.5),
+            "link".to_string() => WzValue::Link("../a".to_string()),
+            "vex".to_string() => WzValue::Convex(Vex2Val(vec![(0, 1).into(), (2, 3).into()])),
+            "canvas".to_string() => WzValue::Canvas(canvas),
+            "sound".to_string() => WzValue::Sound(sound),
+            "err".to_string() => WzValue::Error(ErrorVal {
+                offset: 0x10,
+                reason: "Truncated data".to_string(),
+            }),
+        });
+
+        // Values, which aren't `Int`s or strings, keep their type
+        let val: WzValue = from_value(&root)?;
+        assert_eq!(val, root);
+        let val: BTreeMap<String, WzValue> = from_value(&root)?;
+        assert_eq!(val["short"], WzValue::Short(-3));
+        assert!(matches!(&val["sound"], WzValue::Sound(s) if s.data.is_some()));
+        Ok(())
+    }
+}
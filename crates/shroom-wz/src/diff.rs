@@ -0,0 +1,432 @@
+//! Structural diff of archives and images.
+//!
+//! Images are compared by their value trees, so re-encoded images without changes
+//! are not reported. Canvases and sounds are compared by the hash of their decoded
+//! content, the `PartialEq` of `CanvasVal` and `SoundVal` only compares the offset.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+use crate::{
+    file::{WzIO, WzImgReader},
+    l0::tree::WzTree,
+    ty::WzInt,
+    util::join_path,
+    val::{CanvasVal, ObjectVal, SoundVal, WzValue},
+    writer::WzMediaSource,
+    WzReader,
+};
+
+/// Change of a property, `path` is relative to the image root
+#[derive(Debug, Clone, PartialEq)]
+pub enum WzPropChange {
+    Added {
+        path: String,
+        val: WzValue,
+    },
+    Removed {
+        path: String,
+        val: WzValue,
+    },
+    Modified {
+        path: String,
+        old: WzValue,
+        new: WzValue,
+    },
+}
+
+impl WzPropChange {
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Added { path, .. } | Self::Removed { path, .. } | Self::Modified { path, .. } => {
+                path
+            }
+        }
+    }
+}
+
+impl fmt::Display for WzPropChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added { path, val } => write!(f, "+ {path} = {}", ValSummary(val)),
+            Self::Removed { path, val } => write!(f, "- {path} = {}", ValSummary(val)),
+            Self::Modified { path, old, new } => {
+                write!(f, "~ {path}: {} -> {}", ValSummary(old), ValSummary(new))
+            }
+        }
+    }
+}
+
+/// Short description of a value, objects are not expanded
+struct ValSummary<'a>(&'a WzValue);
+
+impl fmt::Display for ValSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            WzValue::Object(obj) => write!(f, "object ({} entries)", obj.0.len()),
+            WzValue::Null => write!(f, "null"),
+            WzValue::F32(v) => write!(f, "{v}"),
+            WzValue::F64(v) => write!(f, "{v}"),
+            WzValue::Short(v) => write!(f, "{v}"),
+            WzValue::Int(v) => write!(f, "{v}"),
+            WzValue::Long(v) => write!(f, "{v}"),
+            WzValue::String(v) => write!(f, "{v:?}"),
+            WzValue::Vec(v) => write!(f, "vec2({v})"),
+            WzValue::Convex(v) => write!(f, "convex ({} points)", v.0.len()),
+            WzValue::Sound(v) => write!(f, "sound ({}ms)", v.sound.len_ms.0),
+            WzValue::Canvas(v) => write!(f, "canvas ({}x{})", v.canvas.width(), v.canvas.height()),
+            WzValue::Link(v) => write!(f, "link {v}"),
+            WzValue::Error(v) => write!(f, "error: {}", v.reason),
+        }
+    }
+}
+
+/// Changes of a modified image
+#[derive(Debug, Clone)]
+pub struct WzImgDiff {
+    pub path: String,
+    pub changes: Vec<WzPropChange>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WzArchiveDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<WzImgDiff>,
+}
+
+impl WzArchiveDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+/// Diffs the value trees, canvas and sound data is read from the media sources.
+/// Media which can't be decoded is always reported as modified
+pub fn diff_values(
+    old: &WzValue,
+    new: &WzValue,
+    old_media: &mut dyn WzMediaSource,
+    new_media: &mut dyn WzMediaSource,
+) -> Vec<WzPropChange> {
+    let mut diff = ValueDiff {
+        old_media,
+        new_media,
+        changes: Vec::new(),
+    };
+    diff.diff_val("", old, new);
+    diff.changes
+}
+
+/// Reads and diffs both images
+pub fn diff_imgs<R1: WzIO, R2: WzIO>(
+    old: &mut WzImgReader<R1>,
+    new: &mut WzImgReader<R2>,
+) -> anyhow::Result<Vec<WzPropChange>> {
+    // Unreadable properties are compared as error values
+    let (old_val, _) = WzValue::read_lenient(old)?;
+    let (new_val, _) = WzValue::read_lenient(new)?;
+    Ok(diff_values(&old_val, &new_val, old, new))
+}
+
+/// Diffs all images of the trees, images with identical blobs are skipped
+pub fn diff_trees<R1: WzIO, R2: WzIO>(
+    old_r: &mut WzReader<R1>,
+    old: &WzTree,
+    new_r: &mut WzReader<R2>,
+    new: &WzTree,
+) -> anyhow::Result<WzArchiveDiff> {
+    let mut diff = WzArchiveDiff::default();
+    let new_imgs = new.images().collect::<HashMap<_, _>>();
+    let old_imgs = old.images().collect::<HashMap<_, _>>();
+
+    for (path, old_hdr) in old.images() {
+        let Some(new_hdr) = new_imgs.get(&path) else {
+            diff.removed.push(path);
+            continue;
+        };
+
+        if old_hdr.blob_size == new_hdr.blob_size
+            && old_hdr.checksum == new_hdr.checksum
+            && old_r.read_img_blob(&old_hdr)? == new_r.read_img_blob(new_hdr)?
+        {
+            continue;
+        }
+
+        let mut old_img = old_r.img_reader(&old_hdr)?.with_path(&path);
+        let mut new_img = new_r.img_reader(new_hdr)?.with_path(&path);
+        let changes = diff_imgs(&mut old_img, &mut new_img)?;
+        if !changes.is_empty() {
+            diff.modified.push(WzImgDiff { path, changes });
+        }
+    }
+
+    diff.added = new
+        .images()
+        .map(|(path, _)| path)
+        .filter(|path| !old_imgs.contains_key(path))
+        .collect();
+
+    Ok(diff)
+}
+
+/// Diffs the archives
+pub fn diff_archives<R1: WzIO, R2: WzIO>(
+    old: &mut WzReader<R1>,
+    new: &mut WzReader<R2>,
+) -> anyhow::Result<WzArchiveDiff> {
+    let old_tree = WzTree::from_reader(old, None)?;
+    let new_tree = WzTree::from_reader(new, None)?;
+    diff_trees(old, &old_tree, new, &new_tree)
+}
+
+struct ValueDiff<'a> {
+    old_media: &'a mut dyn WzMediaSource,
+    new_media: &'a mut dyn WzMediaSource,
+    changes: Vec<WzPropChange>,
+}
+
+fn canvas_hash(media: &mut dyn WzMediaSource, path: &str, canvas: &CanvasVal) -> Option<u64> {
+    let img = media.canvas(path, canvas).ok()?.to_raw_rgba_image().ok()?;
+    let mut h = DefaultHasher::new();
+    img.dimensions().hash(&mut h);
+    img.as_raw().hash(&mut h);
+    Some(h.finish())
+}
+
+/// Hash of the undecoded data and the format, for bitmaps which can't be decoded
+fn raw_canvas_hash(media: &mut dyn WzMediaSource, path: &str, canvas: &CanvasVal) -> Option<u64> {
+    let data = media.raw_canvas(path, canvas).ok()?;
+    let c = &canvas.canvas;
+    let mut h = DefaultHasher::new();
+    (c.width.0, c.height.0, c.scale.0).hash(&mut h);
+    WzInt::from(c.depth).0.hash(&mut h);
+    data.hash(&mut h);
+    Some(h.finish())
+}
+
+fn sound_hash(media: &mut dyn WzMediaSource, path: &str, sound: &SoundVal) -> Option<u64> {
+    let data = media.sound(path, sound).ok()?;
+    let mut h = DefaultHasher::new();
+    sound.sound.len_ms.0.hash(&mut h);
+    data.hash(&mut h);
+    Some(h.finish())
+}
+
+impl ValueDiff<'_> {
+    fn modified(&mut self, path: &str, old: &WzValue, new: &WzValue) {
+        self.changes.push(WzPropChange::Modified {
+            path: path.to_string(),
+            old: old.clone(),
+            new: new.clone(),
+        });
+    }
+
+    /// Media which can't be read has no hash, so It can't be equal
+    fn media_modified(
+        &mut self,
+        path: &str,
+        old: &WzValue,
+        new: &WzValue,
+        old_hash: Option<u64>,
+        new_hash: Option<u64>,
+    ) {
+        if old_hash.is_none() || old_hash != new_hash {
+            self.modified(path, old, new);
+        }
+    }
+
+    fn diff_obj(&mut self, path: &str, old: &ObjectVal, new: &ObjectVal) {
+        for (name, old_val) in old.0.iter() {
+            let child = join_path(path, name);
+            match new.get(name) {
+                Some(new_val) => self.diff_val(&child, old_val, new_val),
+                None => self.changes.push(WzPropChange::Removed {
+                    path: child,
+                    val: old_val.clone(),
+                }),
+            }
+        }
+
+        for (name, new_val) in new.0.iter() {
+            if old.get(name).is_none() {
+                self.changes.push(WzPropChange::Added {
+                    path: join_path(path, name),
+                    val: new_val.clone(),
+                });
+            }
+        }
+    }
+
+    fn diff_val(&mut self, path: &str, old: &WzValue, new: &WzValue) {
+        match (old, new) {
+            (WzValue::Object(a), WzValue::Object(b)) => self.diff_obj(path, a, b),
+            (WzValue::Canvas(a), WzValue::Canvas(b)) => {
+                let empty = ObjectVal(Default::default());
+                let sub = |c: &CanvasVal| match c.sub.as_deref() {
+                    Some(WzValue::Object(obj)) => obj.clone(),
+                    _ => empty.clone(),
+                };
+                self.diff_obj(path, &sub(a), &sub(b));
+
+                let hashes = (
+                    canvas_hash(self.old_media, path, a),
+                    canvas_hash(self.new_media, path, b),
+                );
+                let (old_hash, new_hash) = match hashes {
+                    (Some(old_hash), Some(new_hash)) => (Some(old_hash), Some(new_hash)),
+                    // Bitmaps which can't be decoded are compared by their raw data
+                    _ => (
+                        raw_canvas_hash(self.old_media, path, a),
+                        raw_canvas_hash(self.new_media, path, b),
+                    ),
+                };
+                self.media_modified(path, old, new, old_hash, new_hash);
+            }
+            (WzValue::Sound(a), WzValue::Sound(b)) => {
+                let old_hash = sound_hash(self.old_media, path, a);
+                let new_hash = sound_hash(self.new_media, path, b);
+                self.media_modified(path, old, new, old_hash, new_hash);
+            }
+            (
+                WzValue::Object(_) | WzValue::Canvas(_) | WzValue::Sound(_),
+                WzValue::Object(_) | WzValue::Canvas(_) | WzValue::Sound(_),
+            ) => self.modified(path, old, new),
+            _ if old != new => self.modified(path, old, new),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        l1::canvas::WzCanvasScaling,
+        test_util::{build_archive, dir, img, img_blob, obj, str_val, TestVal},
+        val::{CanvasVal, WzValue},
+        writer::WzNoMedia,
+        WzReader, GMS95,
+    };
+
+    use super::WzPropChange;
+
+    fn canvas(px: u8, sub: Vec<(String, TestVal)>) -> TestVal {
+        TestVal::Canvas {
+            w: 2,
+            h: 1,
+            bgra: vec![px, 0, 0, 255, 0, px, 0, 255],
+            sub,
+        }
+    }
+
+    #[test]
+    fn diff_archives() -> anyhow::Result<()> {
+        let same = img_blob(GMS95, &obj([("name", str_val("Snail"))]));
+        let old = build_archive(
+            GMS95,
+            vec![dir(
+                "Mob",
+                vec![
+                    img("0.img", same.clone()),
+                    img(
+                        "1.img",
+                        img_blob(
+                            GMS95,
+                            &obj([
+                                ("hp", TestVal::Int(10)),
+                                ("old", TestVal::Int(1)),
+                                ("icon", canvas(1, obj([("z", TestVal::Int(0))]))),
+                                ("same", canvas(7, Vec::new())),
+                            ]),
+                        ),
+                    ),
+                    img("2.img", img_blob(GMS95, &[])),
+                ],
+            )],
+        );
+        let new = build_archive(
+            GMS95,
+            vec![dir(
+                "Mob",
+                vec![
+                    img("3.img", img_blob(GMS95, &[])),
+                    img(
+                        "1.img",
+                        img_blob(
+                            GMS95,
+                            &obj([
+                                ("same", canvas(7, Vec::new())),
+                                ("hp", TestVal::Int(20)),
+                                ("icon", canvas(2, obj([("z", TestVal::Int(1))]))),
+                                ("new", str_val("x")),
+                            ]),
+                        ),
+                    ),
+                    img("0.img", same),
+                ],
+            )],
+        );
+
+        let mut old = WzReader::open(Cursor::new(old), GMS95)?;
+        let mut new = WzReader::open(Cursor::new(new), GMS95)?;
+        let diff = super::diff_archives(&mut old, &mut new)?;
+
+        assert_eq!(diff.removed, ["Mob/2.img"]);
+        assert_eq!(diff.added, ["Mob/3.img"]);
+        assert_eq!(diff.modified.len(), 1);
+        let img = &diff.modified[0];
+        assert_eq!(img.path, "Mob/1.img");
+        let paths = img.changes.iter().map(|c| c.path()).collect::<Vec<_>>();
+        assert_eq!(paths, ["hp", "old", "icon/z", "icon", "new"]);
+        assert_eq!(
+            img.changes[0],
+            WzPropChange::Modified {
+                path: "hp".to_string(),
+                old: WzValue::Int(10),
+                new: WzValue::Int(20),
+            }
+        );
+        assert_eq!(img.changes[0].to_string(), "~ hp: 10 -> 20");
+        assert_eq!(img.changes[4].to_string(), "+ new = \"x\"");
+        Ok(())
+    }
+
+    #[test]
+    fn undecodable_media() {
+        let canvas = WzValue::Canvas(CanvasVal::detached(WzCanvasScaling(0), None));
+        let changes = super::diff_values(&canvas, &canvas, &mut WzNoMedia, &mut WzNoMedia);
+        assert_eq!(
+            changes,
+            [WzPropChange::Modified {
+                path: String::new(),
+                old: canvas.clone(),
+                new: canvas,
+            }]
+        );
+    }
+
+    #[test]
+    fn undecodable_canvas() -> anyhow::Result<()> {
+        // The data is too short for the size
+        let broken = |px: u8| TestVal::Canvas {
+            w: 2,
+            h: 1,
+            bgra: vec![px, 0, 0, 255],
+            sub: Vec::new(),
+        };
+        let old = img_blob(GMS95, &obj([("a", broken(1)), ("b", broken(1))]));
+        let new = img_blob(GMS95, &obj([("a", broken(1)), ("b", broken(2))]));
+
+        let mut old = WzReader::open_img(Cursor::new(old), GMS95);
+        let mut new = WzReader::open_img(Cursor::new(new), GMS95);
+        let changes = super::diff_imgs(&mut old.root_img_reader()?, &mut new.root_img_reader()?)?;
+        let paths = changes.iter().map(|c| c.path()).collect::<Vec<_>>();
+        assert_eq!(paths, ["b"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,300 @@
+//! Editing of value trees.
+//!
+//! Paths are slash-separated like for `WzValue::get_path`, the properties of a canvas
+//! are edited in It's sub property. The order of the entries is kept, since the client
+//! relies on It for some lists.
+
+use crate::val::{ObjectVal, WzValue};
+
+impl ObjectVal {
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut WzValue> {
+        self.0.get_mut(key)
+    }
+
+    /// Inserts the entry, a replaced entry keeps It's position
+    pub fn insert(&mut self, key: impl Into<String>, val: WzValue) -> Option<WzValue> {
+        self.0.insert(key.into(), val)
+    }
+
+    /// Removes the entry, the order of the other entries is kept
+    pub fn remove(&mut self, key: &str) -> Option<WzValue> {
+        self.0.shift_remove(key)
+    }
+
+    /// Renames the entry in place
+    pub fn rename(&mut self, key: &str, new_key: impl Into<String>) -> anyhow::Result<()> {
+        let new_key = new_key.into();
+        if key == new_key {
+            return Ok(());
+        }
+        if self.0.contains_key(&new_key) {
+            anyhow::bail!("Entry already exists: {new_key}");
+        }
+        let (ix, _, val) = self
+            .0
+            .shift_remove_full(key)
+            .ok_or_else(|| anyhow::format_err!("Missing entry {key}"))?;
+        self.0.shift_insert(ix, new_key, val);
+        Ok(())
+    }
+
+    /// Inserts the new entry before the entry `anchor`
+    pub fn insert_before(
+        &mut self,
+        anchor: &str,
+        key: impl Into<String>,
+        val: WzValue,
+    ) -> anyhow::Result<()> {
+        self.insert_at_anchor(anchor, 0, key.into(), val)
+    }
+
+    /// Inserts the new entry after the entry `anchor`
+    pub fn insert_after(
+        &mut self,
+        anchor: &str,
+        key: impl Into<String>,
+        val: WzValue,
+    ) -> anyhow::Result<()> {
+        self.insert_at_anchor(anchor, 1, key.into(), val)
+    }
+
+    fn insert_at_anchor(
+        &mut self,
+        anchor: &str,
+        offset: usize,
+        key: String,
+        val: WzValue,
+    ) -> anyhow::Result<()> {
+        if self.0.contains_key(&key) {
+            anyhow::bail!("Entry already exists: {key}");
+        }
+        let ix = self
+            .0
+            .get_index_of(anchor)
+            .ok_or_else(|| anyhow::format_err!("Missing entry {anchor}"))?;
+        self.0.shift_insert(ix + offset, key, val);
+        Ok(())
+    }
+}
+
+/// Splits the path into the parent path and the name of the entry
+fn split_parent(path: &str) -> anyhow::Result<(&str, &str)> {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        anyhow::bail!("Empty path");
+    }
+    Ok(path.rsplit_once('/').unwrap_or(("", path)))
+}
+
+impl WzValue {
+    /// Entries of an object or the sub property of a canvas, `create` adds a missing
+    /// sub property
+    pub(crate) fn entries_mut(&mut self, create: bool) -> Option<&mut ObjectVal> {
+        match self {
+            WzValue::Object(obj) => Some(obj),
+            WzValue::Canvas(canvas) => {
+                if create && canvas.sub.is_none() {
+                    canvas.sub = Some(Box::new(WzValue::Object(ObjectVal::default())));
+                }
+                canvas.sub.as_deref_mut()?.entries_mut(false)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn get_path_mut(&mut self, path: &str) -> Option<&mut WzValue> {
+        let mut cur = self;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            cur = cur.entries_mut(false)?.get_mut(part)?;
+        }
+        Some(cur)
+    }
+
+    /// Object, which holds the entry at the path
+    fn parent_mut(&mut self, path: &str, create: bool) -> anyhow::Result<&mut ObjectVal> {
+        let mut cur = self;
+        let mut cur_path = Vec::new();
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            cur_path.push(part);
+            let obj = cur
+                .entries_mut(create)
+                .ok_or_else(|| anyhow::format_err!("Not an object: {}", cur_path.join("/")))?;
+            cur = if create {
+                obj.0
+                    .entry(part.to_string())
+                    .or_insert_with(|| WzValue::Object(ObjectVal::default()))
+            } else {
+                obj.get_mut(part)
+                    .ok_or_else(|| anyhow::format_err!("Missing entry {}", cur_path.join("/")))?
+            };
+        }
+        cur.entries_mut(create)
+            .ok_or_else(|| anyhow::format_err!("Not an object: {path}"))
+    }
+
+    /// Sets the value at the path, missing objects along the path are created.
+    /// Returns the replaced value
+    pub fn set_path(&mut self, path: &str, val: WzValue) -> anyhow::Result<Option<WzValue>> {
+        let (parent, name) = split_parent(path)?;
+        Ok(self.parent_mut(parent, true)?.insert(name, val))
+    }
+
+    /// Removes the value at the path
+    pub fn remove_path(&mut self, path: &str) -> Option<WzValue> {
+        let (parent, name) = split_parent(path).ok()?;
+        self.get_path_mut(parent)?.entries_mut(false)?.remove(name)
+    }
+
+    /// Renames the entry at the path in place
+    pub fn rename_path(&mut self, path: &str, new_name: &str) -> anyhow::Result<()> {
+        let (parent, name) = split_parent(path)?;
+        self.parent_mut(parent, false)?.rename(name, new_name)
+    }
+
+    /// Inserts the new entry `name` before the entry at the path
+    pub fn insert_before_path(
+        &mut self,
+        path: &str,
+        name: &str,
+        val: WzValue,
+    ) -> anyhow::Result<()> {
+        let (parent, anchor) = split_parent(path)?;
+        self.parent_mut(parent, false)?
+            .insert_before(anchor, name, val)
+    }
+
+    /// Inserts the new entry `name` after the entry at the path
+    pub fn insert_after_path(
+        &mut self,
+        path: &str,
+        name: &str,
+        val: WzValue,
+    ) -> anyhow::Result<()> {
+        let (parent, anchor) = split_parent(path)?;
+        self.parent_mut(parent, false)?
+            .insert_after(anchor, name, val)
+    }
+
+    /// Moves the subtree at `from` to `to`, the target must not exist yet
+    pub fn move_path(&mut self, from: &str, to: &str) -> anyhow::Result<()> {
+        let (from, to) = (from.trim_matches('/'), to.trim_matches('/'));
+        if to == from || to.starts_with(&format!("{from}/")) {
+            anyhow::bail!("Can't move {from} into itself");
+        }
+        if self.get_path(to).is_some() {
+            anyhow::bail!("Entry already exists: {to}");
+        }
+        // Check the target first, so the subtree isn't lost If It can't be inserted
+        let (to_parent, _) = split_parent(to)?;
+        let mut prefix = String::new();
+        for part in to_parent.split('/').filter(|p| !p.is_empty()) {
+            if !prefix.is_empty() {
+                prefix.push('/');
+            }
+            prefix.push_str(part);
+            match self.get_path(&prefix) {
+                Some(WzValue::Object(_) | WzValue::Canvas(_)) => {}
+                Some(_) => anyhow::bail!("Not an object: {prefix}"),
+                None => break,
+            }
+        }
+        let val = self
+            .remove_path(from)
+            .ok_or_else(|| anyhow::format_err!("Missing entry {from}"))?;
+        self.set_path(to, val)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{Rgba, RgbaImage};
+    use indexmap::indexmap;
+
+    use crate::{
+        l1::canvas::WzCanvasScaling,
+        val::{CanvasVal, WzValue},
+        writer::WzNoMedia,
+        WzImgBuilder, WzReader, GMS95,
+    };
+
+    fn keys(val: &WzValue) -> Vec<&str> {
+        val.as_object()
+            .unwrap()
+            .0
+            .keys()
+            .map(String::as_str)
+            .collect()
+    }
+
+    #[test]
+    fn edit_paths() -> anyhow::Result<()> {
+        let mut root = WzValue::from(indexmap! {
+            "info".to_string() => WzValue::from(indexmap! {
+                "a".to_string() => WzValue::Int(1),
+                "b".to_string() => WzValue::Int(2),
+                "c".to_string() => WzValue::Int(3),
+            }),
+            "name".to_string() => WzValue::String("x".to_string()),
+        });
+
+        assert_eq!(root.set_path("new/sub/level", WzValue::Int(5))?, None);
+        assert_eq!(root.get_path("new/sub/level"), Some(&WzValue::Int(5)));
+        assert!(root.set_path("name/sub", WzValue::Int(5)).is_err());
+
+        root.rename_path("info/b", "bb")?;
+        assert!(root.rename_path("info/a", "c").is_err());
+        root.insert_before_path("info/a", "first", WzValue::Null)?;
+        root.insert_after_path("info/bb", "mid", WzValue::Null)?;
+        let info = root.get_path("info").unwrap();
+        assert_eq!(keys(info), ["first", "a", "bb", "mid", "c"]);
+
+        assert_eq!(root.remove_path("info/a"), Some(WzValue::Int(1)));
+        assert_eq!(root.remove_path("info/a"), None);
+        assert_eq!(
+            keys(root.get_path("info").unwrap()),
+            ["first", "bb", "mid", "c"]
+        );
+
+        root.move_path("new/sub", "info/moved")?;
+        assert_eq!(root.get_path("info/moved/level"), Some(&WzValue::Int(5)));
+        assert!(root.get_path("new/sub").is_none());
+        assert!(root.move_path("info", "info/x").is_err());
+        assert!(root.move_path("name", "info/c").is_err());
+        assert!(root.move_path("info/c", "name/c").is_err());
+        assert_eq!(root.get_path("info/c"), Some(&WzValue::Int(3)));
+        Ok(())
+    }
+
+    #[test]
+    fn edit_canvas() -> anyhow::Result<()> {
+        let mut root = WzValue::from(indexmap! {
+            "icon".to_string() => WzValue::Canvas(CanvasVal::detached(WzCanvasScaling(0), None)),
+        });
+        root.set_path("icon/origin", WzValue::Vec((1, 2).into()))?;
+
+        let img = RgbaImage::from_fn(3, 2, |x, y| Rgba([x as u8, y as u8, 0x7f, 0xff]));
+        let WzValue::Canvas(icon) = root.get_path_mut("icon").unwrap() else {
+            unreachable!()
+        };
+        icon.set_image(&img);
+
+        // The replaced bitmap is written without a media source
+        let mut builder = WzImgBuilder::new(Cursor::new(Vec::new()));
+        builder.write_img(root.as_object().unwrap(), &mut WzNoMedia)?;
+        let data = builder.into_inner().into_inner();
+
+        let mut r = WzReader::open_img(Cursor::new(data), GMS95);
+        let mut img_r = r.root_img_reader()?;
+        let val = WzValue::read(&mut img_r)?;
+        assert_eq!(
+            val.get_path("icon/origin"),
+            Some(&WzValue::Vec((1, 2).into()))
+        );
+        let canvas = val.get_path("icon").unwrap().as_canvas().unwrap();
+        assert_eq!(canvas.read_canvas(&mut img_r)?.to_raw_rgba_image()?, img);
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+//! Structured errors for reading archives.
+//!
+//! The readers still return `anyhow::Result`, but errors from parsing are `WzError`s,
+//! so they can be matched via `anyhow::Error::downcast_ref::<WzError>()`.
+
+use std::{fmt, io};
+
+/// Location of an error
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WzErrorCtx {
+    /// Path of the image in the archive
+    pub img_path: Option<String>,
+    /// Path of the property inside the image
+    pub prop_path: Vec<String>,
+    /// Offset where the error occurred, relative to the image for errors inside an image
+    pub offset: Option<u64>,
+}
+
+impl WzErrorCtx {
+    pub fn is_empty(&self) -> bool {
+        self.img_path.is_none() && self.prop_path.is_empty() && self.offset.is_none()
+    }
+
+    pub fn prop_path(&self) -> String {
+        self.prop_path.join("/")
+    }
+}
+
+impl fmt::Display for WzErrorCtx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let mut parts = Vec::new();
+        if let Some(img) = &self.img_path {
+            parts.push(format!("img: {img}"));
+        }
+        if !self.prop_path.is_empty() {
+            parts.push(format!("prop: {}", self.prop_path()));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("offset: {offset:#x}"));
+        }
+        write!(f, " ({})", parts.join(", "))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WzError {
+    /// The encrypted version in the archive doesn't match the configured version
+    #[error("Wrong version: {found}, expected: {expected} for version {version}{ctx}")]
+    VersionMismatch {
+        version: u16,
+        expected: u16,
+        found: u16,
+        ctx: WzErrorCtx,
+    },
+    /// Data which only makes sense with the right key, like invalid strings or chunks
+    #[error("Crypto failure: {msg}{ctx}")]
+    Crypto { msg: String, ctx: WzErrorCtx },
+    #[error("Bad magic: {found}{ctx}")]
+    BadMagic { found: String, ctx: WzErrorCtx },
+    #[error("Unsupported canvas depth: {depth}{ctx}")]
+    UnsupportedCanvasDepth { depth: i32, ctx: WzErrorCtx },
+    #[error("Unsupported sound format: {format}{ctx}")]
+    UnsupportedSoundFormat { format: String, ctx: WzErrorCtx },
+    #[error("Truncated data{ctx}")]
+    Truncated { ctx: WzErrorCtx },
+    /// Any other corrupt data
+    #[error("Invalid data: {msg}{ctx}")]
+    Invalid { msg: String, ctx: WzErrorCtx },
+    #[error("IO error: {source}{ctx}")]
+    Io { source: io::Error, ctx: WzErrorCtx },
+}
+
+impl WzError {
+    pub fn version_mismatch(version: u16, expected: u16, found: u16) -> Self {
+        Self::VersionMismatch {
+            version,
+            expected,
+            found,
+            ctx: WzErrorCtx::default(),
+        }
+    }
+
+    pub fn crypto(msg: impl ToString) -> Self {
+        Self::Crypto {
+            msg: msg.to_string(),
+            ctx: WzErrorCtx::default(),
+        }
+    }
+
+    pub fn bad_magic(found: impl ToString) -> Self {
+        Self::BadMagic {
+            found: found.to_string(),
+            ctx: WzErrorCtx::default(),
+        }
+    }
+
+    pub fn unsupported_canvas_depth(depth: i32) -> Self {
+        Self::UnsupportedCanvasDepth {
+            depth,
+            ctx: WzErrorCtx::default(),
+        }
+    }
+
+    pub fn unsupported_sound_format(format: impl ToString) -> Self {
+        Self::UnsupportedSoundFormat {
+            format: format.to_string(),
+            ctx: WzErrorCtx::default(),
+        }
+    }
+
+    pub fn truncated() -> Self {
+        Self::Truncated {
+            ctx: WzErrorCtx::default(),
+        }
+    }
+
+    pub fn invalid(msg: impl ToString) -> Self {
+        Self::Invalid {
+            msg: msg.to_string(),
+            ctx: WzErrorCtx::default(),
+        }
+    }
+
+    pub fn ctx(&self) -> &WzErrorCtx {
+        match self {
+            Self::VersionMismatch { ctx, .. }
+            | Self::Crypto { ctx, .. }
+            | Self::BadMagic { ctx, .. }
+            | Self::UnsupportedCanvasDepth { ctx, .. }
+            | Self::UnsupportedSoundFormat { ctx, .. }
+            | Self::Truncated { ctx }
+            | Self::Invalid { ctx, .. }
+            | Self::Io { ctx, .. } => ctx,
+        }
+    }
+
+    pub fn ctx_mut(&mut self) -> &mut WzErrorCtx {
+        match self {
+            Self::VersionMismatch { ctx, .. }
+            | Self::Crypto { ctx, .. }
+            | Self::BadMagic { ctx, .. }
+            | Self::UnsupportedCanvasDepth { ctx, .. }
+            | Self::UnsupportedSoundFormat { ctx, .. }
+            | Self::Truncated { ctx }
+            | Self::Invalid { ctx, .. }
+            | Self::Io { ctx, .. } => ctx,
+        }
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        matches!(self, Self::Truncated { .. })
+    }
+
+    /// Sets the image path, If It's not set yet
+    pub fn with_img_path(mut self, path: impl ToString) -> Self {
+        let ctx = self.ctx_mut();
+        if ctx.img_path.is_none() {
+            ctx.img_path = Some(path.to_string());
+        }
+        self
+    }
+
+    /// Sets the offset, If It's not set yet
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.ctx_mut().offset.get_or_insert(offset);
+        self
+    }
+
+    /// Prepends the name of the parent property to the property path
+    pub fn with_parent_prop(mut self, name: impl ToString) -> Self {
+        self.ctx_mut().prop_path.insert(0, name.to_string());
+        self
+    }
+
+    /// Converts an error from the readers, errors which are not `WzError`s are
+    /// classified by their type
+    pub fn from_anyhow(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<Self>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        let err = match err.downcast::<binrw::Error>() {
+            Ok(err) => return err.into(),
+            Err(err) => err,
+        };
+        match err.downcast::<io::Error>() {
+            Ok(err) => err.into(),
+            Err(err) => Self::invalid(format!("{err:#}")),
+        }
+    }
+
+    /// Wraps the error, so It can be returned from a binrw parser
+    pub fn into_binrw(self, pos: u64) -> binrw::Error {
+        binrw::Error::Custom {
+            pos,
+            err: Box::new(self.with_offset(pos)),
+        }
+    }
+}
+
+impl From<io::Error> for WzError {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            return Self::truncated();
+        }
+
+        Self::Io {
+            source: err,
+            ctx: WzErrorCtx::default(),
+        }
+    }
+}
+
+impl From<binrw::Error> for WzError {
+    fn from(err: binrw::Error) -> Self {
+        match err {
+            binrw::Error::BadMagic { pos, found } => {
+                Self::bad_magic(format!("{found:?}")).with_offset(pos)
+            }
+            binrw::Error::AssertFail { pos, message } => Self::invalid(message).with_offset(pos),
+            binrw::Error::Io(err) => err.into(),
+            binrw::Error::Custom { pos, err } => match err.downcast::<WzError>() {
+                Ok(err) => err.with_offset(pos),
+                Err(err) => match err.downcast::<anyhow::Error>() {
+                    Ok(err) => Self::from_anyhow(*err).with_offset(pos),
+                    Err(err) => Self::invalid(err).with_offset(pos),
+                },
+            },
+            binrw::Error::NoVariantMatch { pos } => {
+                Self::invalid("No variant matched").with_offset(pos)
+            }
+            binrw::Error::EnumErrors {
+                pos,
+                variant_errors,
+            } => {
+                // The variant with a matching magic failed, report that error
+                let err = variant_errors.into_iter().find_map(|(_, err)| {
+                    (!matches!(err.root_cause(), binrw::Error::BadMagic { .. })).then_some(err)
+                });
+                match err {
+                    Some(err) => Self::from(err).with_offset(pos),
+                    None => Self::bad_magic("No variant matched").with_offset(pos),
+                }
+            }
+            binrw::Error::Backtrace(bt) => (*bt.error).into(),
+            err => Self::invalid(err),
+        }
+    }
+}
+
+/// A value which was skipped by a lenient read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WzDiagnostic {
+    pub img_path: Option<String>,
+    /// Path of the replaced value inside the image
+    pub prop_path: String,
+    /// Offset of the value in the image
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for WzDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(img) = &self.img_path {
+            write!(f, "{img}: ")?;
+        }
+        write!(
+            f,
+            "skipped {} at {:#x}: {}",
+            self.prop_path, self.offset, self.reason
+        )
+    }
+}
+
+/// Converts the error of a property value into a `WzError` and prepends the property
+/// name to the path, `pos` is the start of the property entry
+pub(crate) fn prop_binrw_error(err: binrw::Error, name: &str, pos: u64) -> binrw::Error {
+    WzError::from(err).with_parent_prop(name).into_binrw(pos)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::WzError;
+
+    #[test]
+    fn classify() {
+        let err = WzError::from(binrw::Error::Io(io::ErrorKind::UnexpectedEof.into()));
+        assert!(err.is_truncated());
+
+        let err = WzError::unsupported_canvas_depth(3).into_binrw(0x10);
+        let err = super::prop_binrw_error(err, "icon", 0x8);
+        let err = super::prop_binrw_error(err, "info", 0x2);
+        let err = WzError::from_anyhow(anyhow::Error::from(err)).with_img_path("Item/0200.img");
+        assert!(matches!(
+            err,
+            WzError::UnsupportedCanvasDepth { depth: 3, .. }
+        ));
+        assert_eq!(err.ctx().prop_path(), "info/icon");
+        assert_eq!(err.ctx().offset, Some(0x10));
+        assert_eq!(
+            err.to_string(),
+            "Unsupported canvas depth: 3 (img: Item/0200.img, prop: info/icon, offset: 0x10)"
+        );
+    }
+}
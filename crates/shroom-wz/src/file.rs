@@ -1,24 +1,23 @@
 use std::{
-    collections::VecDeque,
     fs::File,
     io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom},
     path::Path,
     sync::Arc,
 };
 
-use anyhow::Context;
 use binrw::BinRead;
 
 use crate::{
     canvas::Canvas,
     crypto::WzCrypto,
     ctx::{WzContext, WzImgReadCtx, WzStrTable},
+    error::WzError,
     l0::{WzDir, WzDirHeader, WzDirNode, WzHeader, WzImgHeader},
     l1::{
         canvas::WzCanvas, obj::WzObject, prop::WzPropValue, ser::WzImgSerializer, sound::WzSound,
     },
     ty::WzOffset,
-    util::{BufReadExt, PeekExt, SubReader},
+    util::{read_exact_vec, BufReadExt, PeekExt, SubReader},
     WzConfig,
 };
 pub trait WzIO: BufRead + Seek {}
@@ -28,6 +27,8 @@ pub struct WzImgReader<R> {
     r: R,
     crypto: Arc<WzCrypto>,
     str_table: WzStrTable,
+    /// Path of the image, used for errors
+    path: Option<String>,
 }
 
 impl<R> WzImgReader<R>
@@ -39,20 +40,59 @@ where
             r,
             crypto,
             str_table: Default::default(),
+            path: None,
         }
     }
 
+    /// Sets the path of the image, which is reported in errors
+    pub fn with_path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn path(&self) -> Option<&str> {
+        self.path.as_deref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.r
+    }
+
+    pub fn into_inner(self) -> R {
+        self.r
+    }
+
+    /// Converts the error into a `WzError` with the image path
+    fn wz_err(&self, err: impl Into<anyhow::Error>) -> anyhow::Error {
+        let err = WzError::from_anyhow(err.into());
+        match self.path.as_deref() {
+            Some(path) => err.with_img_path(path),
+            None => err,
+        }
+        .into()
+    }
+
     pub fn ctx(&self) -> WzImgReadCtx<'_> {
         WzImgReadCtx::new(&self.crypto, &self.str_table)
     }
 
     /// Read the root object for that image
     pub fn read_root_obj(&mut self) -> anyhow::Result<WzObject> {
-        self.r.rewind()?;
-        Ok(WzObject::read_le_args(
-            &mut self.r,
-            WzImgReadCtx::new(&self.crypto, &self.str_table),
-        ).context("Root")?)
+        self.read_root(false)
+    }
+
+    /// Read the root object, replacing unreadable properties with `WzObject::Invalid`
+    pub fn read_root_obj_lenient(&mut self) -> anyhow::Result<WzObject> {
+        self.read_root(true)
+    }
+
+    fn read_root(&mut self, lenient: bool) -> anyhow::Result<WzObject> {
+        self.r.rewind().map_err(|err| self.wz_err(err))?;
+        let mut ctx = WzImgReadCtx::new(&self.crypto, &self.str_table);
+        if lenient {
+            ctx = ctx.lenient();
+        }
+        WzObject::read_le_args(&mut self.r, ctx).map_err(|err| self.wz_err(err))
     }
 
     /// Read an object with the given object header
@@ -72,39 +112,27 @@ where
         )?)
     }*/
 
-    fn read_canvas_from<T: BufRead>(mut r: T, canvas: &WzCanvas) -> anyhow::Result<Canvas> {
-        let sz = canvas.raw_bitmap_size() as usize;
-        let mut img_buf = Vec::with_capacity(sz);
-        r.decompress_flate_size(&mut img_buf, sz)?;
-        Ok(Canvas::from_data(img_buf, canvas))
+    pub fn read_canvas(&mut self, canvas: &WzCanvas) -> anyhow::Result<Canvas> {
+        self.r
+            .seek(SeekFrom::Start(canvas.data_offset()))
+            .map_err(|err| self.wz_err(err))?;
+        read_canvas_data(&mut self.r, &self.crypto, canvas).map_err(|err| self.wz_err(err))
     }
 
-    pub fn read_canvas(&mut self, canvas: &WzCanvas) -> anyhow::Result<Canvas> {
-        let len = canvas.data_len();
-        let off = canvas.data_offset();
-        self.r.seek(SeekFrom::Start(off))?;
-
-        let hdr = self.r.peek_u16()?;
-        // 5th bit => 3rd bit from the end -> 16-13
-        let is_zlib = (hdr & 0xFF) == 0x78;
-        let with_preset = hdr & (1 << 13) != 0;
-        // For some reason the is_preset flag is used for chunked encoding
-        if is_zlib && !with_preset {
-            let mut sub = (&mut self.r).take(len as u64);
-            Self::read_canvas_from(&mut sub, canvas)
-        } else {
-            let buf = self.r.read_chunked_data(&self.crypto, len)?;
-            Self::read_canvas_from(Cursor::new(buf), canvas)
-        }
+    /// Reads the bitmap data as It's stored in the image, without decoding It
+    pub fn read_canvas_raw(&mut self, canvas: &WzCanvas) -> anyhow::Result<Vec<u8>> {
+        self.r
+            .seek(SeekFrom::Start(canvas.data_offset()))
+            .map_err(|err| self.wz_err(err))?;
+        read_exact_vec(&mut self.r, canvas.data_len()).map_err(|err| self.wz_err(err))
     }
 
     pub fn read_sound(&mut self, sound: &WzSound) -> anyhow::Result<Vec<u8>> {
         let ln = sound.data_size();
-        self.r.seek(SeekFrom::Start(sound.offset.pos))?;
-        let mut data = vec![0; ln];
-        self.r.read_exact(&mut data)?;
-
-        Ok(data)
+        self.r
+            .seek(SeekFrom::Start(sound.offset.pos))
+            .map_err(|err| self.wz_err(err))?;
+        read_exact_vec(&mut self.r, ln).map_err(|err| self.wz_err(err))
     }
 
     pub fn read_path<'obj>(
@@ -141,6 +169,68 @@ where
     }
 }
 
+fn read_canvas_from<T: BufRead>(mut r: T, canvas: &WzCanvas) -> anyhow::Result<Canvas> {
+    let sz = canvas.raw_bitmap_size() as usize;
+    let mut img_buf = Vec::new();
+    r.decompress_flate_size(&mut img_buf, sz)
+        .map_err(data_err)?;
+    Ok(Canvas::from_data(img_buf, canvas))
+}
+
+/// Invalid compressed or chunked data is caused by a wrong key, other IO errors are kept
+fn data_err(err: io::Error) -> WzError {
+    match err.kind() {
+        io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => {
+            WzError::crypto(format!("Invalid canvas data: {err}"))
+        }
+        _ => err.into(),
+    }
+}
+
+/// Upper bound for the decompressed size of a canvas, larger sizes are corrupt headers
+pub const MAX_CANVAS_SIZE: usize = 256 * 1024 * 1024;
+
+/// Zlib can't compress data by more than this factor
+const MAX_ZLIB_RATIO: usize = 1032;
+
+/// Checks the size from the header before inflating, so a corrupt header can't
+/// inflate gigabytes from a small blob
+fn check_canvas_size(canvas: &WzCanvas) -> Result<(), WzError> {
+    let size = canvas.raw_bitmap_size() as usize;
+    let max = canvas
+        .data_len()
+        .saturating_mul(MAX_ZLIB_RATIO)
+        .min(MAX_CANVAS_SIZE);
+    if size > max {
+        return Err(WzError::invalid(format!(
+            "Canvas size {size} exceeds the maximum of {max}"
+        )));
+    }
+    Ok(())
+}
+
+/// Decodes the canvas data, the reader must be positioned at the start of the data
+pub(crate) fn read_canvas_data<T: BufRead + Seek>(
+    mut r: T,
+    crypto: &WzCrypto,
+    canvas: &WzCanvas,
+) -> anyhow::Result<Canvas> {
+    check_canvas_size(canvas)?;
+    let len = canvas.data_len();
+    let hdr = r.peek_u16()?;
+    // 5th bit => 3rd bit from the end -> 16-13
+    let is_zlib = (hdr & 0xFF) == 0x78;
+    let with_preset = hdr & (1 << 13) != 0;
+    // For some reason the is_preset flag is used for chunked encoding
+    if is_zlib && !with_preset {
+        let mut sub = (&mut r).take(len as u64);
+        read_canvas_from(&mut sub, canvas)
+    } else {
+        let buf = r.read_chunked_data(crypto, len).map_err(data_err)?;
+        read_canvas_from(Cursor::new(buf), canvas)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct WzReader<R> {
     inner: R,
@@ -162,13 +252,19 @@ where
     R: WzIO,
 {
     pub fn open(mut rdr: R, cfg: WzConfig) -> anyhow::Result<Self> {
-        let hdr = WzHeader::read_le(&mut rdr)?;
-        rdr.seek(SeekFrom::Start(hdr.data_offset as u64))?;
+        let hdr = WzHeader::read_le(&mut rdr).map_err(WzError::from)?;
+        rdr.seek(SeekFrom::Start(hdr.data_offset as u64))
+            .map_err(WzError::from)?;
 
-        let encrypted_version = u16::read_le(&mut rdr)?;
+        let encrypted_version = u16::read_le(&mut rdr).map_err(WzError::from)?;
         let ver = cfg.version;
         if ver.encrypted_version() != encrypted_version {
-            anyhow::bail!("Wrong version: {}, expected: {ver:?}", encrypted_version);
+            return Err(WzError::version_mismatch(
+                ver.0,
+                ver.encrypted_version(),
+                encrypted_version,
+            )
+            .into());
         }
 
         Ok(Self::new(rdr, cfg, hdr.data_offset as u64))
@@ -190,6 +286,17 @@ where
         SubReader::new(&mut self.inner, offset, size)
     }
 
+    /// Reads the archive header at the start of the file
+    pub fn read_header(&mut self) -> anyhow::Result<WzHeader> {
+        self.set_pos(0)?;
+        Ok(WzHeader::read_le(&mut self.inner)?)
+    }
+
+    /// Size of the underlying file
+    pub fn file_len(&mut self) -> io::Result<u64> {
+        self.inner.seek(SeekFrom::End(0))
+    }
+
     pub fn root_offset(&self) -> WzOffset {
         WzOffset(self.data_offset as u32 + 2)
     }
@@ -204,11 +311,11 @@ where
     }
 
     fn read_dir(&mut self, offset: u64) -> anyhow::Result<WzDir> {
-        self.set_pos(offset)?;
-        Ok(WzDir::read_le_args(
-            &mut self.inner,
-            WzContext::new(&self.crypto),
-        )?)
+        self.set_pos(offset).map_err(WzError::from)?;
+        Ok(
+            WzDir::read_le_args(&mut self.inner, WzContext::new(&self.crypto))
+                .map_err(WzError::from)?,
+        )
     }
 
     pub fn root_img_reader(&mut self) -> io::Result<WzImgReader<SubReader<'_, R>>> {
@@ -227,11 +334,17 @@ where
         let crypto = self.crypto.clone();
 
         Ok(WzImgReader::new(
-            self.sub_reader(off, hdr.blob_size.0 as u64),
+            self.sub_reader(off, hdr.blob_size.0.max(0) as u64),
             crypto,
         ))
     }
 
+    /// Reads the raw blob of the image
+    pub fn read_img_blob(&mut self, hdr: &WzImgHeader) -> io::Result<Vec<u8>> {
+        self.set_pos(hdr.offset.into())?;
+        read_exact_vec(&mut self.inner, hdr.blob_size.0.max(0) as usize)
+    }
+
     pub fn checksum(&mut self, offset: u64, ln: u64) -> anyhow::Result<i32> {
         let old = self.inner.stream_position()?;
         self.set_pos(offset)?;
@@ -256,15 +369,6 @@ where
             })
         }
     */
-    pub fn traverse_images(&mut self) -> WzImgTraverser<'_, R> {
-        let mut q = VecDeque::new();
-        q.push_back((
-            Arc::new("".to_string()),
-            WzDirNode::Dir(WzDirHeader::root("root", 1, self.root_offset())),
-        ));
-        WzImgTraverser { r: self, q }
-    }
-
     pub fn read_path(&mut self, root: &WzDirNode, path: &str) -> anyhow::Result<WzDirNode> {
         let mut cur = root.clone();
 
@@ -289,64 +393,6 @@ where
     }
 }
 
-pub struct WzImgTraverser<'r, R> {
-    r: &'r mut WzReader<R>,
-    q: VecDeque<(Arc<String>, WzDirNode)>,
-}
-
-impl<'r, R: WzIO> WzImgTraverser<'r, R> {
-    fn handle_dir(
-        &mut self,
-        root_name: &str,
-        dir: &WzDirHeader,
-    ) -> anyhow::Result<(Arc<String>, WzDir)> {
-        let node = self.r.read_dir_node(dir)?;
-        let node_name = Arc::new(format!("{}/{}", root_name, dir.name.as_str()));
-
-        self.q.extend(
-            node.entries
-                .0
-                .iter()
-                .map(|x| (node_name.clone(), x.clone())),
-        );
-
-        Ok((node_name.clone(), node))
-    }
-}
-
-impl<'r, R> Iterator for WzImgTraverser<'r, R>
-where
-    R: WzIO,
-{
-    type Item = anyhow::Result<(String, WzImgHeader)>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        while let Some((root_name, node)) = self.q.pop_front() {
-            match node {
-                WzDirNode::Dir(dir) => {
-                    if let Err(err) = self.handle_dir(root_name.as_str(), &dir) {
-                        return Some(Err(err));
-                    }
-                }
-                WzDirNode::Img(img) => {
-                    let name = format!("{}/{}", root_name, img.name.as_str());
-                    return Some(Ok((name, img)));
-                }
-                WzDirNode::Link(link) => {
-                    let img = link.link.link_img;
-                    let name = format!("{}/{}", root_name, img.name.as_str());
-                    return Some(Ok((name, img)));
-                }
-                _ => {
-                    continue;
-                }
-            }
-        }
-
-        None
-    }
-}
-
 #[cfg(feature = "mmap")]
 pub mod mmap {
     use std::{fs::File, io::Cursor, path::Path, sync::Arc};
@@ -393,3 +439,231 @@ pub mod mmap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor};
+
+    use binrw::BinRead;
+
+    use crate::{
+        ctx::WzContext,
+        error::WzError,
+        l0::{tree::WzTree, WzDirNode},
+        test_util::{canvas_val, img_blob, obj, sample_archive, Rng, TestVal},
+        ty::WzStr,
+        val::WzValue,
+        verify::verify_media,
+        WzConfig, WzReader, GMS95,
+    };
+
+    use super::data_err;
+
+    /// Reads everything from the archive, errors are fine but It must not panic
+    fn read_all(data: Vec<u8>) {
+        let Ok(mut r) = WzReader::open(Cursor::new(data), GMS95) else {
+            return;
+        };
+        let _ = r.walk_images().count();
+        let Ok(tree) = WzTree::from_reader(&mut r, None) else {
+            return;
+        };
+        for (_, hdr) in tree.images() {
+            let Ok(mut img) = r.img_reader(&hdr) else {
+                continue;
+            };
+            if let Ok(val) = WzValue::read(&mut img) {
+                verify_media(&mut img, &val);
+            }
+        }
+    }
+
+    #[test]
+    fn read_mutated() {
+        let data = sample_archive(GMS95);
+        let mut rng = Rng::new(0x5EED);
+        for _ in 0..2000 {
+            let mut data = data.clone();
+            for _ in 0..rng.below(4) + 1 {
+                if data.is_empty() {
+                    break;
+                }
+                let ix = rng.below(data.len());
+                match rng.below(4) {
+                    0 => data[ix] ^= 1 << rng.below(8),
+                    1 => data[ix] = rng.below(256) as u8,
+                    2 => {
+                        let v: [u8; 4] = [i32::MAX, i32::MIN, -1, 0x80][rng.below(4)].to_le_bytes();
+                        let n = v.len().min(data.len() - ix);
+                        data[ix..ix + n].copy_from_slice(&v[..n]);
+                    }
+                    _ => data.truncate(ix),
+                }
+            }
+            read_all(data);
+        }
+    }
+
+    /// Writes the synthetic archives and their images as seed corpus for the fuzz targets
+    #[test]
+    #[ignore]
+    fn write_fuzz_corpus() -> anyhow::Result<()> {
+        let corpus = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus");
+        let archive_dir = corpus.join("read_archive");
+        let img_dir = corpus.join("read_img");
+        std::fs::create_dir_all(&archive_dir)?;
+        std::fs::create_dir_all(&img_dir)?;
+
+        let data = sample_archive(GMS95);
+        std::fs::write(archive_dir.join("sample"), &data)?;
+
+        let mut r = WzReader::open(Cursor::new(data.as_slice()), GMS95)?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+        for (path, hdr) in tree.images() {
+            let start = hdr.offset.0 as usize;
+            let blob = &data[start..start + hdr.blob_size.0 as usize];
+            std::fs::write(img_dir.join(path.replace('/', "_")), blob)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn error_context() -> anyhow::Result<()> {
+        let data = sample_archive(GMS95);
+        let err = WzReader::open(Cursor::new(data), WzConfig::gms(83)).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<WzError>(),
+            Some(WzError::VersionMismatch { version: 83, .. })
+        ));
+
+        let mut blob = img_blob(
+            GMS95,
+            &obj([(
+                "info",
+                TestVal::Obj(obj([("icon", canvas_val(77, 55, Vec::new()))])),
+            )]),
+        );
+        // Patch the depth of the canvas
+        let ix = blob.windows(3).position(|w| w == [77, 55, 2]).unwrap();
+        blob[ix + 2] = 3;
+
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img = r.root_img_reader()?.with_path("Item/0200.img");
+        let err = WzValue::read(&mut img).unwrap_err();
+        let Some(WzError::UnsupportedCanvasDepth { depth: 3, ctx }) = err.downcast_ref() else {
+            panic!("Unexpected error: {err:?}");
+        };
+        assert_eq!(ctx.img_path.as_deref(), Some("Item/0200.img"));
+        assert_eq!(ctx.prop_path(), "info/icon");
+        assert!(ctx.offset.is_some());
+
+        // Only data which can't be decoded is a crypto failure
+        let err = io::Error::new(io::ErrorKind::InvalidInput, "corrupt deflate stream");
+        assert!(matches!(data_err(err), WzError::Crypto { .. }));
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(matches!(data_err(err), WzError::Io { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn canvas_size_bound() -> anyhow::Result<()> {
+        // The header claims a 3.6 GB bitmap for a few bytes of data
+        let canvas = TestVal::Canvas {
+            w: 30000,
+            h: 30000,
+            bgra: vec![0; 16],
+            sub: Vec::new(),
+        };
+        let blob = img_blob(GMS95, &obj([("icon", canvas)]));
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img = r.root_img_reader()?;
+        let val = WzValue::read(&mut img)?;
+        let canvas = val.get_path("icon").unwrap().as_canvas().unwrap();
+        let Err(err) = canvas.read_canvas(&mut img) else {
+            panic!("Oversized canvas was read");
+        };
+        assert!(matches!(
+            err.downcast_ref::<WzError>(),
+            Some(WzError::Invalid { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn lenient_read() -> anyhow::Result<()> {
+        let mut blob = img_blob(
+            GMS95,
+            &obj([
+                (
+                    "info",
+                    TestVal::Obj(obj([
+                        ("icon", canvas_val(77, 55, Vec::new())),
+                        ("price", TestVal::Int(100)),
+                    ])),
+                ),
+                ("name", TestVal::Str("Sword".to_string())),
+            ]),
+        );
+        let ix = blob.windows(3).position(|w| w == [77, 55, 2]).unwrap();
+        blob[ix + 2] = 3;
+
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img = r.root_img_reader()?.with_path("Item/0200.img");
+        assert!(WzValue::read(&mut img).is_err());
+
+        let (val, diagnostics) = WzValue::read_lenient(&mut img)?;
+        assert!(matches!(val.get_path("info/icon"), Some(WzValue::Error(_))));
+        assert_eq!(val.get_path("info/price"), Some(&WzValue::Int(100)));
+        assert_eq!(
+            val.get_path("name"),
+            Some(&WzValue::String("Sword".to_string()))
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].img_path.as_deref(), Some("Item/0200.img"));
+        assert_eq!(diagnostics[0].prop_path, "info/icon");
+        assert!(diagnostics[0].reason.contains("canvas depth"));
+        Ok(())
+    }
+
+    #[test]
+    fn bogus_str_len() {
+        let crypto = crate::crypto::WzCrypto::from_cfg(GMS95, 0);
+        // Long latin1 and unicode strings with lengths exceeding the data
+        for data in [
+            [0x80, 0xFF, 0xFF, 0xFF, 0x7F, 1, 2].as_slice(),
+            &[0x80, 0x00, 0x00, 0x00, 0x80, 1, 2],
+            &[0x7F, 0xFF, 0xFF, 0xFF, 0x7F, 1, 2],
+            &[0x20, 1, 2],
+        ] {
+            assert!(WzStr::read_le_args(&mut Cursor::new(data), WzContext::new(&crypto)).is_err());
+        }
+    }
+
+    #[test]
+    fn read_sample() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(sample_archive(GMS95)), GMS95)?;
+        let root = WzDirNode::Dir(crate::l0::WzDirHeader::root("root", 1, r.root_offset()));
+
+        let WzDirNode::Img(img) = r.read_path(&root, "Item/0200.img")? else {
+            anyhow::bail!("Expected img");
+        };
+        let mut img_r = r.img_reader(&img)?;
+        let val = WzValue::read(&mut img_r)?;
+        assert_eq!(val.get_path("info/price"), Some(&WzValue::Int(100)));
+        assert_eq!(
+            val.get_path("info/icon/origin"),
+            Some(&WzValue::Vec((1, 2).into()))
+        );
+
+        let icon = val.get_path("info/icon").unwrap().as_canvas().unwrap();
+        let icon = img_r.read_canvas(&icon.canvas)?.to_raw_rgba_image()?;
+        assert_eq!(icon.dimensions(), (4, 3));
+
+        let imgs = r
+            .walk_images()
+            .map(|img| img.map(|img| img.path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(imgs, ["Item/0200.img", "Bgm.img", "Mob/100100.img"]);
+        Ok(())
+    }
+}
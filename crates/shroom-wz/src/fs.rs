@@ -0,0 +1,290 @@
+//! Virtual filesystem over all archives of a client directory.
+//!
+//! Every archive is mounted under it's name, `Base.wz` forms the root and It's empty
+//! directory stubs like `Character` are replaced by the mounted archives. Paths go down
+//! to single properties, like `Mob/100100.img/stand/0/delay`, which is also the format
+//! of canvas outlinks.
+
+use std::{fs::File, io::BufReader, path::Path};
+
+use crate::{
+    canvas::Canvas,
+    canvas_link::{CanvasTarget, WzArchiveSet, WzOutlinkResolver},
+    file::{WzIO, WzImgReader},
+    uol::DEFAULT_MAX_LINK_DEPTH,
+    util::SubReader,
+    val::WzValue,
+    WzConfig, WzReader,
+};
+
+/// Name of the archive which forms the root
+pub const BASE_ARCHIVE: &str = "Base";
+
+/// Node in the filesystem
+#[derive(Debug)]
+pub enum WzFsNode<'a> {
+    /// A directory with the names of It's entries
+    Dir(Vec<String>),
+    /// An image or a value inside an image
+    Value(&'a WzValue),
+}
+
+/// Location of a path inside an archive
+struct WzFsPath<'a> {
+    archive: &'a str,
+    /// Path of the directory or image in the archive
+    node: &'a str,
+    /// Path of the value inside the image, `None` for directories
+    prop: Option<&'a str>,
+}
+
+pub struct WzFileSystem<R> {
+    archives: WzArchiveSet<R>,
+}
+
+impl WzFileSystem<BufReader<File>> {
+    /// Mounts all `.wz` files in the client directory
+    pub fn open_dir(dir: impl AsRef<Path>, cfg: WzConfig) -> anyhow::Result<Self> {
+        Ok(Self::from_archives(WzArchiveSet::open_dir(dir, cfg)?))
+    }
+}
+
+impl<R> Default for WzFileSystem<R> {
+    fn default() -> Self {
+        Self::from_archives(WzArchiveSet::default())
+    }
+}
+
+impl<R> WzFileSystem<R> {
+    pub fn from_archives(archives: WzArchiveSet<R>) -> Self {
+        Self { archives }
+    }
+}
+
+impl<R: WzIO> WzFileSystem<R> {
+    /// Mounts the archive under `name`, the file name without extension
+    pub fn mount(&mut self, name: &str, r: WzReader<R>) -> anyhow::Result<()> {
+        self.archives.insert(name, r)
+    }
+
+    pub fn archives(&mut self) -> &mut WzArchiveSet<R> {
+        &mut self.archives
+    }
+
+    fn locate<'p>(&self, path: &'p str) -> Option<WzFsPath<'p>> {
+        let path = path.trim_matches('/');
+        let (first, rest) = path.split_once('/').unwrap_or((path, ""));
+
+        // Mounted archives take precedence over the stubs in the base archive
+        let (archive, inner) = if first != BASE_ARCHIVE && self.archives.contains(first) {
+            (first, rest)
+        } else if self.archives.contains(BASE_ARCHIVE) {
+            (BASE_ARCHIVE, path)
+        } else {
+            return None;
+        };
+
+        // The first segment ending with .img is the image
+        let mut end = 0;
+        for part in inner.split('/') {
+            end += part.len();
+            if part.ends_with(".img") {
+                return Some(WzFsPath {
+                    archive,
+                    node: &inner[..end],
+                    prop: Some(inner.get(end + 1..).unwrap_or_default()),
+                });
+            }
+            end += 1;
+        }
+
+        Some(WzFsPath {
+            archive,
+            node: inner,
+            prop: None,
+        })
+    }
+
+    /// Looks up the node at the path, images are read and cached on access
+    pub fn get(&mut self, path: &str) -> anyhow::Result<Option<WzFsNode<'_>>> {
+        let path = path.trim_matches('/');
+        let Some(loc) = self.locate(path) else {
+            return Ok(None);
+        };
+
+        let Some(prop) = loc.prop else {
+            let Some((r, tree)) = self.archives.archive_mut(loc.archive) else {
+                return Ok(None);
+            };
+            let Some(mut entries) = tree.load_children(r, loc.node)? else {
+                return Ok(None);
+            };
+            if path.is_empty() {
+                // Add the mounted archives to the root
+                for name in self.archives.names() {
+                    if name != BASE_ARCHIVE && !entries.iter().any(|e| e == name) {
+                        entries.push(name.to_string());
+                    }
+                }
+            }
+            return Ok(Some(WzFsNode::Dir(entries)));
+        };
+
+        let Some((r, tree)) = self.archives.archive_mut(loc.archive) else {
+            return Ok(None);
+        };
+        if tree.load_img_by_path(r, loc.node)?.is_none() {
+            return Ok(None);
+        }
+        let root = self.archives.get_img(loc.archive, loc.node)?;
+        let val = if prop.is_empty() {
+            Some(root)
+        } else {
+            root.get_path(prop)
+        };
+        Ok(val.map(WzFsNode::Value))
+    }
+
+    pub fn get_value(&mut self, path: &str) -> anyhow::Result<Option<&WzValue>> {
+        Ok(match self.get(path)? {
+            Some(WzFsNode::Value(v)) => Some(v),
+            _ => None,
+        })
+    }
+
+    /// Names of the entries of a directory, an image or an object value
+    pub fn list(&mut self, path: &str) -> anyhow::Result<Option<Vec<String>>> {
+        Ok(match self.get(path)? {
+            Some(WzFsNode::Dir(entries)) => Some(entries),
+            Some(WzFsNode::Value(WzValue::Object(obj))) => Some(obj.0.keys().cloned().collect()),
+            Some(WzFsNode::Value(WzValue::Canvas(canvas))) => Some(
+                canvas
+                    .sub
+                    .as_deref()
+                    .and_then(WzValue::as_object)
+                    .map(|obj| obj.0.keys().cloned().collect())
+                    .unwrap_or_default(),
+            ),
+            _ => None,
+        })
+    }
+
+    /// Reader for the image at the path
+    pub fn img_reader(&mut self, path: &str) -> anyhow::Result<WzImgReader<SubReader<'_, R>>> {
+        let loc = self
+            .locate(path)
+            .filter(|loc| loc.prop.is_some())
+            .ok_or_else(|| anyhow::format_err!("Not an image: {path}"))?;
+        self.archives.img_reader(loc.archive, loc.node)
+    }
+
+    /// Reads the canvas at the path, following inlinks and outlinks
+    pub fn read_canvas(&mut self, path: &str) -> anyhow::Result<Canvas> {
+        self.read_outlink(path, 0)
+    }
+}
+
+impl<R: WzIO> WzOutlinkResolver for WzFileSystem<R> {
+    fn read_outlink(&mut self, path: &str, depth: usize) -> anyhow::Result<Canvas> {
+        let loc = self
+            .locate(path)
+            .ok_or_else(|| anyhow::format_err!("Invalid canvas path: {path}"))?;
+        let Some(prop) = loc.prop else {
+            anyhow::bail!("Invalid canvas path: {path}");
+        };
+
+        let (archive, node) = (loc.archive.to_string(), loc.node.to_string());
+        let root = self.archives.get_img(&archive, &node)?;
+        let canvas = root
+            .get_path(prop)
+            .and_then(WzValue::as_canvas)
+            .ok_or_else(|| anyhow::format_err!("Not a canvas: {path}"))?;
+
+        let mut depth = depth;
+        match canvas.follow_inlinks(root, &mut depth)? {
+            CanvasTarget::Canvas(canvas) => {
+                let canvas = canvas.clone();
+                let mut img = self.archives.img_reader(&archive, &node)?;
+                canvas.read_canvas(&mut img)
+            }
+            CanvasTarget::Outlink(next) => {
+                if depth >= DEFAULT_MAX_LINK_DEPTH {
+                    anyhow::bail!("Canvas link depth exceeds {DEFAULT_MAX_LINK_DEPTH} at {next}");
+                }
+                self.read_outlink(&next, depth + 1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        test_util::{build_archive, canvas_val, dir, img, img_blob, obj, str_val, TestVal},
+        val::WzValue,
+        WzReader, GMS95,
+    };
+
+    use super::{WzFileSystem, WzFsNode};
+
+    #[test]
+    fn mount() -> anyhow::Result<()> {
+        let smap = img_blob(
+            GMS95,
+            &obj([
+                ("body", str_val("character")),
+                (
+                    "icon",
+                    canvas_val(1, 1, obj([("_outlink", str_val("Mob/100100.img/stand/1"))])),
+                ),
+            ]),
+        );
+        let base = build_archive(GMS95, vec![dir("Mob", vec![]), img("smap.img", smap)]);
+        let mob = img_blob(
+            GMS95,
+            &obj([(
+                "stand",
+                TestVal::Obj(obj([
+                    ("0", canvas_val(3, 2, obj([("delay", TestVal::Int(120))]))),
+                    (
+                        "1",
+                        canvas_val(1, 1, obj([("_inlink", str_val("stand/0"))])),
+                    ),
+                ])),
+            )]),
+        );
+        let mob = build_archive(GMS95, vec![img("100100.img", mob)]);
+
+        let mut fs = WzFileSystem::default();
+        fs.mount("Base", WzReader::open(Cursor::new(base), GMS95)?)?;
+        fs.mount("Mob", WzReader::open(Cursor::new(mob), GMS95)?)?;
+
+        assert_eq!(
+            fs.list("")?,
+            Some(vec!["Mob".to_string(), "smap.img".to_string()])
+        );
+        // The stub is replaced by the archive
+        assert_eq!(fs.list("Mob")?, Some(vec!["100100.img".to_string()]));
+        assert_eq!(
+            fs.list("Mob/100100.img/stand/0")?,
+            Some(vec!["delay".to_string()])
+        );
+        assert_eq!(
+            fs.get_value("Mob/100100.img/stand/0/delay")?,
+            Some(&WzValue::Int(120))
+        );
+        assert_eq!(
+            fs.get_value("smap.img/body")?,
+            Some(&WzValue::String("character".to_string()))
+        );
+        assert!(matches!(fs.get("smap.img")?, Some(WzFsNode::Value(_))));
+        assert!(fs.get("Mob/missing.img")?.is_none());
+        assert!(fs.get("Missing")?.is_none());
+
+        let canvas = fs.read_canvas("smap.img/icon")?;
+        assert_eq!((canvas.width, canvas.height), (3, 2));
+        Ok(())
+    }
+}
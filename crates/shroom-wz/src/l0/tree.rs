@@ -1,20 +1,38 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet};
 
-use id_tree::{InsertBehavior, Node, Tree};
+use id_tree::{InsertBehavior, Node, NodeId, Tree};
 
-use crate::{file::WzIO, WzReader};
+use crate::{file::WzIO, util::join_path, WzReader};
 
 use super::{WzDirHeader, WzDirNode, WzImgHeader};
 
+/// Directory tree of an archive.
+///
+/// Every loaded node is kept in a path index, so lookups are O(depth).
+/// A lazy tree only reads the root directory up front, further directories are
+/// read when they are first accessed via `load_path`.
 #[derive(Debug)]
 pub struct WzTree {
     tree: Tree<WzDirNode>,
+    index: HashMap<String, NodeId>,
+    /// Directories which were not read yet
+    pending: HashSet<NodeId>,
 }
 
 impl WzTree {
+    /// Reads the whole directory tree
     pub fn from_reader<R: WzIO>(r: &mut WzReader<R>, name: Option<&str>) -> anyhow::Result<Self> {
-        let mut tree = Tree::new();
+        let mut tree = Self::from_reader_lazy(r, name)?;
+        tree.load_all(r)?;
+        Ok(tree)
+    }
 
+    /// Reads only the root directory, sub directories are loaded on demand
+    pub fn from_reader_lazy<R: WzIO>(
+        r: &mut WzReader<R>,
+        name: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut tree = Tree::new();
         let off = r.root_offset();
 
         let root_id = tree.insert(
@@ -25,55 +43,296 @@ impl WzTree {
             ))),
             InsertBehavior::AsRoot,
         )?;
-        let root = r.read_root_dir()?;
-        let mut q = VecDeque::new();
-        q.push_back((root_id, root));
-
-        while let Some((parent_id, dir)) = q.pop_front() {
-            for val in dir.entries.0.iter() {
-                let new_node = tree
-                    .insert(
-                        Node::new(val.clone()),
-                        InsertBehavior::UnderNode(&parent_id),
-                    )
-                    .unwrap();
-
-                if let WzDirNode::Dir(dir) = val {
-                    q.push_back((new_node, r.read_dir_node(dir)?));
+
+        let mut tree = Self {
+            tree,
+            index: HashMap::new(),
+            pending: HashSet::from([root_id.clone()]),
+        };
+        tree.load_dir(r, &root_id, "")?;
+        Ok(tree)
+    }
+
+    /// Builds a fully loaded tree from an existing node tree
+    pub fn from_tree(tree: Tree<WzDirNode>) -> Self {
+        let mut index = HashMap::new();
+        let mut q = Vec::new();
+        if let Some(root) = tree.root_node_id() {
+            q.push((String::new(), root.clone()));
+        }
+
+        while let Some((path, id)) = q.pop() {
+            for child in tree.children_ids(&id).unwrap() {
+                let Some(name) = tree.get(child).unwrap().data().name() else {
+                    continue;
+                };
+                let child_path = join_path(&path, name);
+                index
+                    .entry(child_path.clone())
+                    .or_insert_with(|| child.clone());
+                q.push((child_path, child.clone()));
+            }
+        }
+
+        Self {
+            tree,
+            index,
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Reads the entries of a pending directory and adds them to the tree
+    fn load_dir<R: WzIO>(
+        &mut self,
+        r: &mut WzReader<R>,
+        node_id: &NodeId,
+        path: &str,
+    ) -> anyhow::Result<()> {
+        if !self.pending.contains(node_id) {
+            return Ok(());
+        }
+
+        let WzDirNode::Dir(hdr) = self.tree.get(node_id)?.data() else {
+            anyhow::bail!("Only directories can be loaded");
+        };
+        // Directories can be shared between entries, only an ancestor with the same
+        // offset forms a cycle
+        for ancestor in self.tree.ancestors(node_id)? {
+            if matches!(ancestor.data(), WzDirNode::Dir(dir) if dir.offset == hdr.offset) {
+                anyhow::bail!("Cyclic directory {path} at {:#x}", hdr.offset.0);
+            }
+        }
+        let dir = r.read_dir_node(hdr)?;
+        self.pending.remove(node_id);
+
+        for val in dir.entries.0 {
+            let name = val.name().map(|name| join_path(path, name));
+            let is_dir = matches!(val, WzDirNode::Dir(_));
+            let child = self
+                .tree
+                .insert(Node::new(val), InsertBehavior::UnderNode(node_id))?;
+
+            if let Some(name) = name {
+                // Keep the first entry on duplicate names
+                self.index.entry(name).or_insert_with(|| child.clone());
+            }
+            if is_dir {
+                self.pending.insert(child);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads all pending directories
+    pub fn load_all<R: WzIO>(&mut self, r: &mut WzReader<R>) -> anyhow::Result<()> {
+        // Collect the paths once, the index doesn't change for loaded nodes
+        let mut q = self
+            .index
+            .iter()
+            .filter(|(_, id)| self.pending.contains(id))
+            .map(|(path, id)| (path.clone(), id.clone()))
+            .collect::<Vec<_>>();
+        if let Some(root) = self.tree.root_node_id() {
+            if self.pending.contains(root) {
+                q.push((String::new(), root.clone()));
+            }
+        }
+
+        while let Some((path, id)) = q.pop() {
+            self.load_dir(r, &id, &path)?;
+            for child in self.tree.children_ids(&id)? {
+                if self.pending.contains(child) {
+                    let name = self.tree.get(child)?.data().name().unwrap_or_default();
+                    let child_path = join_path(&path, name);
+                    q.push((child_path, child.clone()));
                 }
             }
         }
 
-        Ok(Self { tree })
+        Ok(())
+    }
+
+    /// Checks whether every directory was read
+    pub fn is_fully_loaded(&self) -> bool {
+        self.pending.is_empty()
     }
 
     pub fn get_tree(&self) -> &Tree<WzDirNode> {
         &self.tree
     }
 
+    /// Iterates over all loaded nodes except the root in pre-order with their paths
+    pub fn nodes(&self) -> impl Iterator<Item = (String, &WzDirNode)> + '_ {
+        let mut q = Vec::new();
+        if let Some(root) = self.tree.root_node_id() {
+            q.push((String::new(), root.clone()));
+        }
+
+        std::iter::from_fn(move || {
+            let (path, id) = q.pop()?;
+            let children = self.tree.children_ids(&id).unwrap().collect::<Vec<_>>();
+            // Reversed so the first child is popped first
+            for child in children.into_iter().rev() {
+                let Some(name) = self.tree.get(child).unwrap().data().name() else {
+                    continue;
+                };
+                q.push((join_path(&path, name), child.clone()));
+            }
+            Some((path, id))
+        })
+        .skip(1)
+        .map(|(path, id)| (path, self.tree.get(&id).unwrap().data()))
+    }
+
+    /// Iterates over all loaded images in pre-order with their paths,
+    /// link nodes yield the header of the linked image
+    pub fn images(&self) -> impl Iterator<Item = (String, WzImgHeader)> + '_ {
+        self.nodes().filter_map(|(path, node)| match node {
+            WzDirNode::Img(img) => Some((path, img.clone())),
+            WzDirNode::Link(link) => Some((path, link.img_header())),
+            WzDirNode::Dir(_) | WzDirNode::Nil(_) => None,
+        })
+    }
+
+    /// Looks up the path in the already loaded part of the tree
     pub fn get_by_path(&self, path: &str) -> Option<&WzDirNode> {
-        let mut cur = self.tree.root_node_id()?;
-        for part in path.split('/') {
-            let sub = self
-                .tree
-                .children_ids(cur)
-                .unwrap()
-                .find(|x| self.tree.get(x).unwrap().data().name() == Some(part));
-
-            if let Some(sub) = sub {
-                cur = sub;
-            } else {
-                return None;
+        let id = self.index.get(path)?;
+        Some(self.tree.get(id).ok()?.data())
+    }
+
+    pub fn get_img_by_path(&self, path: &str) -> Option<&WzImgHeader> {
+        self.get_by_path(path).and_then(as_img)
+    }
+
+    /// Looks up the path, directories along the path are read If they are not loaded yet
+    pub fn load_path<R: WzIO>(
+        &mut self,
+        r: &mut WzReader<R>,
+        path: &str,
+    ) -> anyhow::Result<Option<&WzDirNode>> {
+        let Some(mut cur) = self.tree.root_node_id().cloned() else {
+            return Ok(None);
+        };
+
+        let mut end = 0;
+        for (i, part) in path.split('/').enumerate() {
+            let parent = &path[..end];
+            end += part.len() + if i == 0 { 0 } else { 1 };
+
+            self.load_dir(r, &cur, parent)?;
+            match self.index.get(&path[..end]) {
+                Some(id) => cur = id.clone(),
+                None => return Ok(None),
             }
         }
 
-        Some(self.tree.get(cur).unwrap().data())
+        Ok(Some(self.tree.get(&cur)?.data()))
     }
 
-    pub fn get_img_by_path(&self, path: &str) -> Option<&WzImgHeader> {
-        self.get_by_path(path).and_then(|x| match x {
-            WzDirNode::Img(img) => Some(img),
-            _ => None,
-        })
+    /// Names of the entries in the directory at `path`, the root is `""`.
+    /// The directory is read If It's not loaded yet
+    pub fn load_children<R: WzIO>(
+        &mut self,
+        r: &mut WzReader<R>,
+        path: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let id = if path.is_empty() {
+            self.tree.root_node_id().cloned()
+        } else {
+            self.load_path(r, path)?;
+            self.index.get(path).cloned()
+        };
+        let Some(id) = id else {
+            return Ok(None);
+        };
+        if !matches!(self.tree.get(&id)?.data(), WzDirNode::Dir(_)) {
+            return Ok(None);
+        }
+
+        self.load_dir(r, &id, path)?;
+        Ok(Some(
+            self.tree
+                .children(&id)?
+                .filter_map(|child| child.data().name().map(str::to_string))
+                .collect(),
+        ))
+    }
+
+    pub fn load_img_by_path<R: WzIO>(
+        &mut self,
+        r: &mut WzReader<R>,
+        path: &str,
+    ) -> anyhow::Result<Option<&WzImgHeader>> {
+        Ok(self.load_path(r, path)?.and_then(as_img))
+    }
+}
+
+fn as_img(node: &WzDirNode) -> Option<&WzImgHeader> {
+    match node {
+        WzDirNode::Img(img) => Some(img),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        test_util::{sample_archive, shared_dir_archive},
+        WzReader, GMS95,
+    };
+
+    use super::WzTree;
+
+    #[test]
+    fn lazy_tree() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(sample_archive(GMS95)), GMS95)?;
+
+        let mut tree = WzTree::from_reader_lazy(&mut r, None)?;
+        assert!(!tree.is_fully_loaded());
+        assert!(tree.get_img_by_path("Bgm.img").is_some());
+        assert!(tree.get_img_by_path("Mob/100100.img").is_none());
+
+        assert!(tree.load_img_by_path(&mut r, "Mob/100100.img")?.is_some());
+        assert!(tree.get_img_by_path("Mob/100100.img").is_some());
+        assert!(tree.load_path(&mut r, "Mob/missing.img")?.is_none());
+        assert!(tree.load_path(&mut r, "Bgm.img/sub")?.is_none());
+        assert!(tree.get_by_path("Item/0200.img").is_none());
+        assert_eq!(
+            tree.load_children(&mut r, "Item")?,
+            Some(vec!["0200.img".to_string(), "Empty".to_string()])
+        );
+        assert_eq!(tree.load_children(&mut r, "Bgm.img")?, None);
+
+        tree.load_all(&mut r)?;
+        assert!(tree.is_fully_loaded());
+        assert!(tree.get_img_by_path("Item/0200.img").is_some());
+        assert!(tree.get_by_path("Item/Empty").is_some());
+
+        let imgs = tree.images().map(|(path, _)| path).collect::<Vec<_>>();
+        assert_eq!(imgs, ["Item/0200.img", "Bgm.img", "Mob/100100.img"]);
+
+        let eager = WzTree::from_reader(&mut r, None)?;
+        assert!(eager.is_fully_loaded());
+        assert_eq!(eager.index.len(), tree.index.len());
+        assert_eq!(
+            eager.get_img_by_path("Item/0200.img"),
+            tree.get_img_by_path("Item/0200.img")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn shared_dir() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(shared_dir_archive(GMS95, false)), GMS95)?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+        assert!(tree.get_img_by_path("A/a.img").is_some());
+        assert!(tree.get_img_by_path("B/a.img").is_some());
+
+        let mut r = WzReader::open(Cursor::new(shared_dir_archive(GMS95, true)), GMS95)?;
+        assert!(WzTree::from_reader(&mut r, None).is_err());
+        Ok(())
     }
 }
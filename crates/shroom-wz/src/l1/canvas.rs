@@ -1,12 +1,13 @@
 use binrw::binrw;
 
 use crate::ctx::{WzImgReadCtx, WzImgWriteCtx};
+use crate::error::WzError;
 use crate::ty::WzInt;
 
 use super::prop::WzProperty;
 use super::WzPosValue;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct WzCanvasScaling(pub u8);
 
 impl WzCanvasScaling {
@@ -17,13 +18,13 @@ impl WzCanvasScaling {
 }
 
 impl TryFrom<u8> for WzCanvasScaling {
-    type Error = anyhow::Error;
+    type Error = WzError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         let n = value;
         Ok(Self(match n {
             0 | 4 => n,
-            _ => anyhow::bail!("Invalid scaling: {n}"),
+            _ => return Err(WzError::invalid(format!("Invalid scaling: {n}"))),
         }))
     }
 }
@@ -33,7 +34,7 @@ impl From<WzCanvasScaling> for u8 {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum WzCanvasDepth {
     BGRA4444,
     BGRA8888,
@@ -55,7 +56,7 @@ impl WzCanvasDepth {
 }
 
 impl TryFrom<WzInt> for WzCanvasDepth {
-    type Error = anyhow::Error;
+    type Error = WzError;
 
     fn try_from(value: WzInt) -> Result<Self, Self::Error> {
         Ok(match value.0 as u16 {
@@ -64,7 +65,7 @@ impl TryFrom<WzInt> for WzCanvasDepth {
             513 => Self::BGR565,
             1026 => Self::DXT3,
             2050 => Self::DXT5,
-            depth => anyhow::bail!("Invalid canvas depth: {depth}"),
+            _ => return Err(WzError::unsupported_canvas_depth(value.0)),
         })
     }
 }
@@ -90,10 +91,12 @@ pub struct WzCanvas {
     pub has_property: u8,
     #[brw(if(has_property.eq(&1)), args_raw(ctx))]
     pub property: Option<WzProperty>,
+    #[br(assert(width.0 >= 0, "Invalid canvas width: {}", width.0))]
     pub width: WzInt,
+    #[br(assert(height.0 >= 0, "Invalid canvas height: {}", height.0))]
     pub height: WzInt,
     #[br(try_map = |x: WzInt| x.try_into())]
-    #[bw(map = |x: &WzCanvasDepth| WzInt(x.depth_size() as i32))]
+    #[bw(map = |x: &WzCanvasDepth| WzInt::from(*x))]
     pub depth: WzCanvasDepth,
     #[br(try_map = |x: u8| x.try_into())]
     #[bw(map = |x: &WzCanvasScaling| u8::from(*x))]
@@ -104,11 +107,11 @@ pub struct WzCanvas {
 
 impl WzCanvas {
     pub fn pixels(&self) -> u32 {
-        self.width() * self.height()
+        self.width().saturating_mul(self.height())
     }
 
     pub fn raw_pixels(&self) -> u32 {
-        self.raw_width() * self.raw_height()
+        self.raw_width().saturating_mul(self.raw_height())
     }
 
     pub fn height(&self) -> u32 {
@@ -128,15 +131,15 @@ impl WzCanvas {
     }
 
     pub fn bitmap_size(&self) -> u32 {
-        self.pixels() * self.depth.depth_size()
+        self.pixels().saturating_mul(self.depth.depth_size())
     }
 
     pub fn raw_bitmap_size(&self) -> u32 {
-        self.raw_pixels() * self.depth.depth_size()
+        self.raw_pixels().saturating_mul(self.depth.depth_size())
     }
 
     pub fn data_len(&self) -> usize {
-        self.len.val as usize - 1
+        (self.len.val as usize).saturating_sub(1)
     }
 
     pub fn data_offset(&self) -> u64 {
@@ -1,7 +1,10 @@
 use binrw::{BinRead, BinWrite};
 use derive_more::Unwrap;
 
-use crate::ctx::{WzImgReadCtx, WzImgWriteCtx};
+use crate::{
+    ctx::{WzImgReadCtx, WzImgWriteCtx},
+    error::WzError,
+};
 
 use super::{
     canvas::WzCanvas,
@@ -18,6 +21,16 @@ pub enum WzObject {
     Vec2(WzVector2D),
     Convex2D(WzConvex2D),
     SoundDX8(WzSound),
+    /// Placeholder for an object which couldn't be read in lenient mode
+    Invalid(WzInvalidObj),
+}
+
+/// An object which failed to parse, It's never read from the data
+#[derive(Debug, Clone)]
+pub struct WzInvalidObj {
+    /// Offset of the value in the image
+    pub offset: u64,
+    pub reason: String,
 }
 
 pub const OBJ_TYPE_PROPERTY: &[u8] = b"Property";
@@ -45,10 +58,11 @@ impl BinRead for WzObject {
             OBJ_TYPE_CONVEX2D => Self::Convex2D(WzConvex2D::read_options(reader, endian, args)?),
             OBJ_TYPE_SOUND_DX8 => Self::SoundDX8(WzSound::read_options(reader, endian, args)?),
             _ => {
-                return Err(binrw::Error::Custom {
-                    pos: reader.stream_position().unwrap_or(0),
-                    err: Box::new(anyhow::format_err!("Invalid obj: {ty_name:?}")),
-                })
+                return Err(WzError::invalid(format!(
+                    "Unknown object type: {}",
+                    ty_name.0.as_str()
+                ))
+                .into_binrw(reader.stream_position().unwrap_or(0)))
             }
         })
     }
@@ -92,6 +106,11 @@ impl BinWrite for WzObject {
                 wz_ty_str(OBJ_TYPE_SOUND_DX8).write_le_args(writer, args)?;
                 v.write_options(writer, endian, args)
             }
+            WzObject::Invalid(v) => Err(WzError::invalid(format!(
+                "Can't write invalid object: {}",
+                v.reason
+            ))
+            .into_binrw(writer.stream_position()?)),
         }
     }
 }
@@ -1,18 +1,23 @@
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, SeekFrom};
 
-use binrw::{binrw, BinRead, BinWrite};
+use binrw::{binrw, binwrite, BinRead, BinWrite};
 use derive_more::Unwrap;
 
 use crate::{
     ctx::{WzImgReadCtx, WzImgWriteCtx},
+    error::{prop_binrw_error, WzError},
     ty::{WzF32, WzInt, WzLong, WzVec},
+    util::{custom_binrw_error, MAX_PREALLOC},
 };
 
 use super::{
-    obj::WzObject,
+    obj::{WzInvalidObj, WzObject},
     str::{WzImgStr, WzTypeStr},
 };
 
+/// Magic of `WzPropValue::Obj`
+const OBJ_VALUE_MAGIC: u8 = 9;
+
 #[derive(Debug, Clone)]
 pub struct WzObjectValue {
     pub len: u32,
@@ -27,6 +32,9 @@ impl BinRead for WzObjectValue {
         endian: binrw::Endian,
         args: Self::Args<'_>,
     ) -> binrw::BinResult<Self> {
+        let args = args
+            .nested()
+            .map_err(|err| custom_binrw_error(reader, err))?;
         let len = u32::read_options(reader, endian, ())? as u64;
         let pos = reader.stream_position()?;
 
@@ -44,6 +52,24 @@ impl BinRead for WzObjectValue {
     }
 }
 
+impl WzObjectValue {
+    /// Skips the object value at `pos` by It's length, this fails for other values
+    /// as their size is unknown
+    fn skip<R: Read + Seek>(reader: &mut R, pos: u64) -> binrw::BinResult<()> {
+        reader.seek(SeekFrom::Start(pos))?;
+        let magic = u8::read_le(reader)?;
+        if magic != OBJ_VALUE_MAGIC {
+            return Err(binrw::Error::BadMagic {
+                pos,
+                found: Box::new(magic),
+            });
+        }
+        let len = u32::read_le(reader)?;
+        reader.seek(SeekFrom::Start(pos + 5 + len as u64))?;
+        Ok(())
+    }
+}
+
 impl BinWrite for WzObjectValue {
     type Args<'a> = WzImgWriteCtx<'a>;
 
@@ -104,27 +130,112 @@ pub enum WzPropValue {
     Obj(#[brw(args_raw(ctx))] WzObjectValue),
 }
 
-#[binrw]
-#[br(little, import_raw(ctx: WzImgReadCtx<'_>))]
+#[binwrite]
 #[bw(little, import_raw(ctx: WzImgWriteCtx<'_>))]
 #[derive(Debug, Clone)]
 pub struct WzPropertyEntry {
-    #[brw(args_raw(ctx))]
+    #[bw(args_raw(ctx))]
     pub name: WzImgStr,
-    #[brw(args_raw(ctx))]
+    #[bw(args_raw(ctx))]
     pub val: WzPropValue,
 }
 
-#[binrw]
-#[br(little, import_raw(ctx: WzImgReadCtx<'_>))]
+impl BinRead for WzPropertyEntry {
+    type Args<'a> = WzImgReadCtx<'a>;
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        endian: binrw::Endian,
+        args: Self::Args<'_>,
+    ) -> binrw::BinResult<Self> {
+        let pos = reader.stream_position()?;
+        let name = WzImgStr::read_options(reader, endian, args)?;
+        // Track the property path for errors
+        let val = WzPropValue::read_options(reader, endian, args)
+            .map_err(|err| prop_binrw_error(err, name.0.as_str(), pos))?;
+        Ok(Self { name, val })
+    }
+}
+
+impl WzPropertyEntry {
+    /// Placeholder entry for a value which failed to read at `pos`
+    fn invalid(name: WzImgStr, pos: u64, err: binrw::Error) -> Self {
+        Self {
+            name,
+            val: WzPropValue::Obj(WzObjectValue {
+                len: 0,
+                obj: Box::new(WzObject::Invalid(WzInvalidObj {
+                    offset: pos,
+                    reason: WzError::from(err).to_string(),
+                })),
+            }),
+        }
+    }
+}
+
+#[binwrite]
 #[bw(little, import_raw(ctx: WzImgWriteCtx<'_>))]
 #[derive(Debug, Clone)]
 pub struct WzProperty {
     pub unknown: u16,
-    #[brw(args_raw(ctx))]
+    #[bw(args_raw(ctx))]
     pub entries: WzVec<WzPropertyEntry>,
 }
 
+impl BinRead for WzProperty {
+    type Args<'a> = WzImgReadCtx<'a>;
+
+    fn read_options<R: Read + Seek>(
+        reader: &mut R,
+        endian: binrw::Endian,
+        args: Self::Args<'_>,
+    ) -> binrw::BinResult<Self> {
+        let unknown = u16::read_options(reader, endian, ())?;
+        if !args.lenient {
+            let entries = WzVec::read_options(reader, endian, args)?;
+            return Ok(Self { unknown, entries });
+        }
+
+        // In lenient mode a broken value is replaced by a placeholder, objects
+        // can be skipped by their length, for anything else the following entries
+        // can't be located, so the property ends there
+        let n = WzInt::read_options(reader, endian, ())?.0.max(0) as usize;
+        let mut entries =
+            Vec::with_capacity(n.min(MAX_PREALLOC / std::mem::size_of::<WzPropertyEntry>()));
+        for ix in 0..n {
+            let pos = reader.stream_position()?;
+            let name = match WzImgStr::read_options(reader, endian, args) {
+                Ok(name) => name,
+                Err(err) => {
+                    entries.push(WzPropertyEntry::invalid(
+                        WzImgStr::new(format!("#{ix}")),
+                        pos,
+                        err,
+                    ));
+                    break;
+                }
+            };
+
+            let val_pos = reader.stream_position()?;
+            match WzPropValue::read_options(reader, endian, args) {
+                Ok(val) => entries.push(WzPropertyEntry { name, val }),
+                Err(err) => {
+                    let resync = WzObjectValue::skip(reader, val_pos);
+                    entries.push(WzPropertyEntry::invalid(name, val_pos, err));
+                    if resync.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            unknown,
+            entries: WzVec(entries),
+        })
+    }
+}
+
 #[binrw]
 #[br(little, import_raw(ctx: WzImgReadCtx<'_>))]
 #[bw(little, import_raw(ctx: WzImgWriteCtx<'_>))]
@@ -154,8 +265,11 @@ impl BinRead for WzConvex2D {
         _endian: binrw::Endian,
         args: Self::Args<'_>,
     ) -> binrw::BinResult<Self> {
-        let len = WzInt::read_le(reader)?.0 as usize;
-        let mut v = Vec::with_capacity(len);
+        let len = WzInt::read_le(reader)?.0;
+        let len = usize::try_from(len).map_err(|_| {
+            custom_binrw_error(reader, anyhow::format_err!("Invalid convex length: {len}"))
+        })?;
+        let mut v = Vec::with_capacity(len.min(MAX_PREALLOC));
 
         for _ in 0..len {
             let _ = WzTypeStr::read_le_args(reader, args)?;
@@ -126,6 +126,7 @@ impl<'r, R: WzIO> Serialize for WzObjectSerializer<'r, R> {
             super::obj::WzObject::Vec2(vec) => vec.serialize(ser),
             super::obj::WzObject::Convex2D(vex) => vex.serialize(ser),
             super::obj::WzObject::SoundDX8(_) => ser.serialize_none(),
+            super::obj::WzObject::Invalid(_) => ser.serialize_none(),
         }
     }
 }
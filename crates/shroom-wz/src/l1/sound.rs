@@ -5,8 +5,8 @@ use uuid::uuid;
 
 use crate::{
     ctx::{WzImgReadCtx, WzImgWriteCtx},
+    error::WzError,
     ty::WzInt,
-    util::custom_binrw_error,
 };
 
 // TODO verify paddings
@@ -55,21 +55,16 @@ pub struct SoundHeader {
     pub fmt: SoundFormat,
 }
 
-impl BinRead for SoundHeader {
-    type Args<'a> = WzImgReadCtx<'a>;
-
-    fn read_options<R: std::io::Read + std::io::Seek>(
-        reader: &mut R,
-        _endian: binrw::Endian,
-        _args: Self::Args<'_>,
-    ) -> binrw::BinResult<Self> {
+impl SoundHeader {
+    /// Reads the header, It doesn't depend on the image context
+    pub fn read_header<R: std::io::Read + std::io::Seek>(reader: &mut R) -> binrw::BinResult<Self> {
         let media_header: MediaHeader = reader.read_le()?;
         let major = media_header.major_type.0;
         if major != MEDIA_TYPE_STREAM {
-            return Err(custom_binrw_error(
-                reader,
-                anyhow::format_err!("Invalid sound major type: {major}"),
-            ));
+            return Err(
+                WzError::unsupported_sound_format(format!("Major type: {major}"))
+                    .into_binrw(reader.stream_position()?),
+            );
         }
 
         let mut hdr = [0u8; u8::MAX as usize];
@@ -79,30 +74,76 @@ impl BinRead for SoundHeader {
 
         let sub = media_header.sub_type.0;
         Ok(match sub {
-            MEDIA_SUBTYPE_MPEG1_PACKET => Self {
-                media_header,
-                fmt: SoundFormat::Mpeg1(hdr.try_into().unwrap()), // TODO check properly
-            },
+            MEDIA_SUBTYPE_MPEG1_PACKET => {
+                let hdr = hdr.try_into().map_err(|_| {
+                    WzError::invalid(format!("Invalid mpeg1 header size: {hdr_len}"))
+                        .into_binrw(reader.stream_position().unwrap_or(0))
+                })?;
+                Self {
+                    media_header,
+                    fmt: SoundFormat::Mpeg1(hdr),
+                }
+            }
             MEDIA_SUBTYPE_WAVE => {
                 let mut sub = Cursor::new(hdr);
                 let wave: WaveHeader = sub.read_le()?;
-                sub.rewind().unwrap();
+                sub.rewind()?;
 
                 let fmt = match wave.format {
                     WAVE_FORMAT_PCM => SoundFormat::Pcm(wave),
                     WAVE_FORMAT_MP3 => SoundFormat::Mpeg3(sub.read_le()?),
-                    n => todo!("Invalid wave format: {n}"),
+                    n => {
+                        return Err(WzError::unsupported_sound_format(format!(
+                            "Wave format: {n:#x}"
+                        ))
+                        .into_binrw(reader.stream_position()?))
+                    }
                 };
                 Self { media_header, fmt }
             }
             _ => {
-                return Err(custom_binrw_error(
-                    reader,
-                    anyhow::format_err!("Unknown sound sub type: {major}"),
-                ))
+                return Err(
+                    WzError::unsupported_sound_format(format!("Sub type: {sub}"))
+                        .into_binrw(reader.stream_position()?),
+                )
             }
         })
     }
+
+    pub fn write_header<W: std::io::Write + std::io::Seek>(
+        &self,
+        writer: &mut W,
+    ) -> binrw::BinResult<()> {
+        self.media_header.write_le(writer)?;
+        match &self.fmt {
+            SoundFormat::Mpeg1(hdr) => {
+                (hdr.len() as u8).write_le(writer)?;
+                hdr.write_le(writer)
+            }
+            SoundFormat::Mpeg3(mp3) => {
+                (WAVE_HEADER_SIZE as u8 + mp3.wav.extra_size as u8).write_le(writer)?;
+                mp3.write_le(writer)
+            }
+            SoundFormat::Pcm(wave) => {
+                // The extra data is not kept, so It's zeroed
+                (WAVE_HEADER_SIZE as u8 + wave.extra_size as u8).write_le(writer)?;
+                wave.write_le(writer)?;
+                vec![0u8; wave.extra_size as usize].write_le(writer)
+            }
+        }
+    }
+}
+
+impl BinRead for SoundHeader {
+    type Args<'a> = WzImgReadCtx<'a>;
+
+    fn read_options<R: std::io::Read + std::io::Seek>(
+        reader: &mut R,
+        _endian: binrw::Endian,
+        _args: Self::Args<'_>,
+    ) -> binrw::BinResult<Self> {
+        Self::read_header(reader)
+    }
 }
 
 impl BinWrite for SoundHeader {
@@ -110,11 +151,11 @@ impl BinWrite for SoundHeader {
 
     fn write_options<W: std::io::Write + std::io::Seek>(
         &self,
-        _writer: &mut W,
+        writer: &mut W,
         _endian: binrw::Endian,
         _args: Self::Args<'_>,
     ) -> binrw::BinResult<()> {
-        todo!()
+        self.write_header(writer)
     }
 }
 
@@ -130,8 +171,6 @@ pub struct WaveHeader {
     pub avg_bytes_per_sec: u32,
     pub block_align: u16,
     pub bits_per_sample: u16,
-    // Align tail, struct is not packed
-    #[bw(pad_size_to = 4)]
     pub extra_size: u16,
 }
 
@@ -148,12 +187,10 @@ impl WaveHeader {
 #[derive(Debug, Clone)]
 pub struct Mpeg3WaveHeader {
     pub wav: WaveHeader,
-    #[bw(pad_size_to = 4)]
     pub id: u16,
     pub flags: u32,
     pub block_size: u16,
     pub frames_per_block: u16,
-    #[bw(pad_size_to = 4)]
     pub codec_delay: u16,
 }
 
@@ -269,6 +306,7 @@ impl WzSound {
             SoundFormat::Pcm(_) => PCM_HEADER_SIZE,
             SoundFormat::Mpeg1(_) => 0,
         };
-        (self.size.0 as usize) + extra
+        // A negative size is invalid, reading It fails on the empty range
+        (self.size.0.max(0) as usize) + extra
     }
 }
@@ -1,18 +1,19 @@
-use std::rc::Rc;
+use std::sync::Arc;
 
 use binrw::{BinRead, BinWrite};
 
 use crate::{
     ctx::{WzImgReadCtx, WzImgWriteCtx},
+    error::WzError,
     ty::WzStr,
 };
 
 #[derive(Debug, Clone)]
-pub struct WzTypeStr(pub Rc<WzStr>);
+pub struct WzTypeStr(pub Arc<WzStr>);
 
 impl WzTypeStr {
     pub fn new(s: String) -> Self {
-        Self(Rc::new(WzStr(s)))
+        Self(Arc::new(WzStr(s)))
     }
 }
 
@@ -36,10 +37,10 @@ impl BinRead for WzTypeStr {
                 })?
             }
             _ => {
-                return Err(binrw::Error::Custom {
-                    pos: reader.stream_position().unwrap_or(0),
-                    err: Box::new(anyhow::format_err!("Invalid type str magic: {:#x}", magic)),
-                })
+                return Err(
+                    WzError::bad_magic(format!("Invalid type str magic: {magic:#x}"))
+                        .into_binrw(reader.stream_position().unwrap_or(0)),
+                )
             }
         }))
     }
@@ -55,21 +56,21 @@ impl BinWrite for WzTypeStr {
         args: Self::Args<'_>,
     ) -> binrw::BinResult<()> {
         if let Some(offset) = args.str_table.get(self.0.as_str()) {
-            (0x73u8).write_options(writer, endian, ())?;
+            (0x1Bu8).write_options(writer, endian, ())?;
             offset.write_options(writer, endian, ())
         } else {
-            (0x1Bu8).write_options(writer, endian, ())?;
-            self.0.write_options(writer, endian, args.into())
+            (0x73u8).write_options(writer, endian, ())?;
+            args.write_str(writer, &self.0)
         }
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct WzImgStr(pub Rc<WzStr>);
+pub struct WzImgStr(pub Arc<WzStr>);
 
 impl WzImgStr {
     pub fn new(s: String) -> Self {
-        Self(Rc::new(WzStr(s)))
+        Self(Arc::new(WzStr(s)))
     }
 }
 
@@ -93,10 +94,8 @@ impl BinRead for WzImgStr {
                 })?
             }
             _ => {
-                return Err(binrw::Error::Custom {
-                    pos: reader.stream_position().unwrap_or(0),
-                    err: Box::new(anyhow::format_err!("Invalid str magic: {:#x}", magic)),
-                })
+                return Err(WzError::bad_magic(format!("Invalid str magic: {magic:#x}"))
+                    .into_binrw(reader.stream_position().unwrap_or(0)))
             }
         }))
     }
@@ -116,7 +115,7 @@ impl BinWrite for WzImgStr {
             offset.write_options(writer, endian, ())
         } else {
             (0u8).write_options(writer, endian, ())?;
-            self.0.write_options(writer, endian, args.into())
+            args.write_str(writer, &self.0)
         }
     }
 }
@@ -1,6 +1,4 @@
-use std::collections::VecDeque;
-
-use id_tree::Tree;
+use id_tree::{InsertBehavior, Node, NodeId, Tree};
 
 use crate::{l0::WzImgHeader, val::WzValue};
 
@@ -22,41 +20,25 @@ impl WzValueTree {
     pub fn build_from_img(img_hdr: WzImgHeader, root: WzValue) -> Self {
         Self::new(img_hdr, root, |root| {
             let mut tree = Tree::new();
-            let node = tree
-                .insert(
-                    id_tree::Node::new(WzValueNode {
-                        name: "root",
-                        value: root,
-                    }),
-                    id_tree::InsertBehavior::AsRoot,
-                )
-                .unwrap();
-
-            let mut q = VecDeque::new();
-            q.push_back((root, node));
-
-            while let Some((val, node)) = q.pop_front() {
-                let obj = match val {
-                    WzValue::Object(obj) => obj,
-                    WzValue::Canvas(canvas) => {
-                        if let Some(WzValue::Object(obj)) = canvas.sub.as_deref() {
-                            obj
-                        } else {
-                            continue;
-                        }
-                    }
-                    _ => continue,
+            // Ids of the parents of the current entry by depth
+            let mut parents: Vec<NodeId> = Vec::new();
+
+            for entry in root.walk() {
+                parents.truncate(entry.depth);
+                let (name, behavior) = match parents.last() {
+                    Some(parent) => (entry.name, InsertBehavior::UnderNode(parent)),
+                    None => ("root", InsertBehavior::AsRoot),
                 };
-
-                for (k, v) in obj.0.iter() {
-                    let child = tree
-                        .insert(
-                            id_tree::Node::new(WzValueNode { name: k, value: v }),
-                            id_tree::InsertBehavior::UnderNode(&node),
-                        )
-                        .unwrap();
-                    q.push_back((v, child));
-                }
+                let node = tree
+                    .insert(
+                        Node::new(WzValueNode {
+                            name,
+                            value: entry.value,
+                        }),
+                        behavior,
+                    )
+                    .unwrap();
+                parents.push(node);
             }
 
             tree
@@ -1,34 +1,55 @@
+// Lets the derive macros refer to `::shroom_wz` inside of this crate
+extern crate self as shroom_wz;
+
+#[cfg(feature = "async")]
+pub mod async_file;
 pub mod canvas;
+pub mod canvas_link;
 pub mod crypto;
 pub mod ctx;
+pub mod de;
+pub mod diff;
+pub mod edit;
+pub mod error;
 pub mod file;
+pub mod fs;
 pub mod keys;
 pub mod l0;
 pub mod l1;
+pub mod nx;
+pub mod nx_file;
+pub mod object;
+pub mod overlay;
+pub mod patch;
+pub mod query;
+pub mod search;
+pub mod snapshot;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 pub mod ty;
+pub mod uol;
 pub mod util;
 pub mod val;
+pub mod verify;
 pub mod version;
+pub mod walk;
+pub mod writer;
+pub mod xml;
 
-use std::{
-    io::{Seek, Write},
-    rc::Rc,
-};
+#[cfg(test)]
+pub(crate) mod test_util;
 
-use binrw::BinWrite;
-use crypto::WzCrypto;
-use ctx::{WzImgWriteCtx, WzStrWriteTable};
+#[cfg(feature = "async")]
+pub use async_file::{WzAsyncImgReader, WzAsyncReader};
 #[cfg(feature = "mmap")]
 pub use file::mmap::{WzReaderMmap, WzReaderSharedMmap};
 pub use file::WzReader;
-use l1::{
-    obj::{wz_ty_str, WzObject, OBJ_TYPE_PROPERTY},
-    prop::{WzConvex2D, WzPropValue, WzUOL, WzVector2D},
-    str::WzImgStr,
-};
-use ty::{WzF32, WzInt, WzLong, WzStr};
-use val::{ObjectVal, WzValue};
+pub use nx_file::NxReader;
+#[cfg(feature = "mmap")]
+pub use nx_file::NxReaderMmap;
+pub use shroom_wz_derive::WzObject;
 use version::WzVersion;
+pub use writer::{WzImgBuilder, WzMediaSource};
 
 #[derive(Debug, Clone, Copy)]
 pub struct WzConfig {
@@ -57,98 +78,6 @@ impl WzConfig {
 
 pub const GMS95: WzConfig = WzConfig::gms(95);
 
-pub struct WzImgBuilder<W> {
-    crypto: WzCrypto,
-    string_table: WzStrWriteTable,
-    writer: W,
-}
-
-impl<W: Write + Seek> WzImgBuilder<W> {
-    pub fn new(writer: W) -> Self {
-        Self {
-            crypto: WzCrypto::from_cfg(GMS95, 0),
-            string_table: WzStrWriteTable::default(),
-            writer,
-        }
-    }
-
-    fn write_property(&mut self, obj: &ObjectVal) -> anyhow::Result<()> {
-        wz_ty_str(OBJ_TYPE_PROPERTY).write_le_args(
-            &mut self.writer,
-            WzImgWriteCtx {
-                crypto: &self.crypto,
-                str_table: &self.string_table,
-            },
-        )?;
-        (0u16).write_le_args(&mut self.writer, ())?;
-        for (key, value) in obj.0.iter() {
-            WzImgStr::new(key.clone()).write_le_args(
-                &mut self.writer,
-                WzImgWriteCtx {
-                    crypto: &self.crypto,
-                    str_table: &self.string_table,
-                },
-            )?;
-            self.write_value(&value)?;
-        }
-
-        Ok(())
-    }
-
-    pub fn write_value(&mut self, value: &WzValue) -> anyhow::Result<()> {
-        let ctx = WzImgWriteCtx {
-            crypto: &self.crypto,
-            str_table: &self.string_table,
-        };
-
-        match value {
-            WzValue::Object(obj) => self.write_property(obj)?,
-            WzValue::Sound(_) => todo!(),
-            WzValue::Canvas(_canvas) => {}
-            WzValue::Link(link) => {
-                let entry_link = WzImgStr::new(link.clone());
-                WzObject::UOL(WzUOL {
-                    unknown: 0,
-                    entries: entry_link,
-                })
-                .write_le_args(&mut self.writer, ctx)?
-            }
-            WzValue::Convex(v) => {
-                let vex = WzConvex2D(
-                    v.0.iter()
-                        .map(|v| WzVector2D {
-                            x: WzInt(v.x),
-                            y: WzInt(v.y),
-                        })
-                        .collect(),
-                );
-                WzObject::Convex2D(vex).write_le_args(&mut self.writer, ctx)?;
-            }
-            WzValue::Vec(v) => {
-                WzObject::Vec2(WzVector2D {
-                    x: WzInt(v.x),
-                    y: WzInt(v.y),
-                })
-                .write_le_args(&mut self.writer, ctx)?;
-            }
-            WzValue::Null => {
-                WzPropValue::Null.write_le_args(&mut self.writer, ctx)?;
-            }
-            WzValue::F32(v) => WzPropValue::F32(WzF32(*v)).write_le_args(&mut self.writer, ctx)?,
-            WzValue::F64(v) => WzPropValue::F64(*v).write_le_args(&mut self.writer, ctx)?,
-            WzValue::Short(v) => WzPropValue::Short1(*v).write_le_args(&mut self.writer, ctx)?,
-            WzValue::Int(v) => WzPropValue::Int1(WzInt(*v)).write_le_args(&mut self.writer, ctx)?,
-            WzValue::Long(v) => {
-                WzPropValue::Long(WzLong(*v)).write_le_args(&mut self.writer, ctx)?
-            }
-            WzValue::String(v) => WzPropValue::Str(WzImgStr(Rc::new(WzStr(v.clone()))))
-                .write_le_args(&mut self.writer, ctx)?,
-        };
-
-        Ok(())
-    }
-}
-
 #[cfg(test)]
 mod tests {
     use rodio::{OutputStream, Source};
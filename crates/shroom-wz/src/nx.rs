@@ -0,0 +1,821 @@
+//! Export of archives into the NX (PKG4) format.
+//!
+//! A NX file is a flat and uncompressed archive, which can be mapped into memory:
+//! * Header with the counts and offsets of the tables
+//! * Node table, the children of a node are stored contiguous and sorted by name
+//! * String table, every string is only stored once
+//! * Bitmap table, every bitmap is BGRA8888 and LZ4 compressed
+//! * Audio table, every sound is stored with It's WZ sound header in front of the data
+//!
+//! Directories and images become nodes with children, convex shapes are stored as
+//! vector children named by their index. UOLs are stored as strings or are optionally
+//! resolved, a resolved UOL node shares the data and children of It's target.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Cursor, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use binrw::{binrw, BinWrite};
+use indexmap::IndexSet;
+
+use crate::{
+    file::{WzIO, WzImgReader},
+    l0::{WzDirHeader, WzDirNode, WzImgHeader},
+    uol::WzLinkResolver,
+    util::join_path,
+    val::{CanvasVal, SoundVal, WzValue},
+    WzConfig, WzReader,
+};
+
+pub const NX_EXT: &str = "nx";
+
+#[binrw]
+#[brw(little, magic = b"PKG4")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NxHeader {
+    pub node_count: u32,
+    pub node_offset: u64,
+    pub string_count: u32,
+    /// Offset of the string offset table
+    pub string_offset: u64,
+    pub bitmap_count: u32,
+    /// Offset of the bitmap offset table
+    pub bitmap_offset: u64,
+    pub audio_count: u32,
+    /// Offset of the audio offset table
+    pub audio_offset: u64,
+}
+
+impl NxHeader {
+    pub const SIZE: u64 = 52;
+}
+
+#[binrw]
+#[brw(little, repr = u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NxNodeType {
+    None = 0,
+    Int = 1,
+    Real = 2,
+    String = 3,
+    Vector = 4,
+    Bitmap = 5,
+    Audio = 6,
+}
+
+/// Decoded data of a node
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NxData {
+    None,
+    Int(i64),
+    Real(f64),
+    /// Id of the string
+    String(u32),
+    Vector(i32, i32),
+    Bitmap {
+        id: u32,
+        width: u16,
+        height: u16,
+    },
+    /// Id and length in bytes of the audio
+    Audio {
+        id: u32,
+        len: u32,
+    },
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NxNode {
+    /// Id of the name string
+    pub name: u32,
+    /// Id of the first child
+    pub children: u32,
+    pub count: u16,
+    pub ty: NxNodeType,
+    pub data: [u8; 8],
+}
+
+impl NxNode {
+    pub const SIZE: u64 = 20;
+
+    pub fn new(name: u32, data: NxData) -> Self {
+        let mut node = Self {
+            name,
+            children: 0,
+            count: 0,
+            ty: NxNodeType::None,
+            data: [0; 8],
+        };
+        node.set_data(data);
+        node
+    }
+
+    pub fn set_data(&mut self, data: NxData) {
+        let (ty, lo, hi) = match data {
+            NxData::None => (NxNodeType::None, [0; 4], [0; 4]),
+            NxData::Int(v) => {
+                self.data = v.to_le_bytes();
+                self.ty = NxNodeType::Int;
+                return;
+            }
+            NxData::Real(v) => {
+                self.data = v.to_le_bytes();
+                self.ty = NxNodeType::Real;
+                return;
+            }
+            NxData::String(id) => (NxNodeType::String, id.to_le_bytes(), [0; 4]),
+            NxData::Vector(x, y) => (NxNodeType::Vector, x.to_le_bytes(), y.to_le_bytes()),
+            NxData::Bitmap { id, width, height } => {
+                let [w0, w1] = width.to_le_bytes();
+                let [h0, h1] = height.to_le_bytes();
+                (NxNodeType::Bitmap, id.to_le_bytes(), [w0, w1, h0, h1])
+            }
+            NxData::Audio { id, len } => (NxNodeType::Audio, id.to_le_bytes(), len.to_le_bytes()),
+        };
+        self.ty = ty;
+        self.data[..4].copy_from_slice(&lo);
+        self.data[4..].copy_from_slice(&hi);
+    }
+
+    pub fn data(&self) -> NxData {
+        let d = self.data;
+        let lo = u32::from_le_bytes([d[0], d[1], d[2], d[3]]);
+        let hi = u32::from_le_bytes([d[4], d[5], d[6], d[7]]);
+        match self.ty {
+            NxNodeType::None => NxData::None,
+            NxNodeType::Int => NxData::Int(i64::from_le_bytes(d)),
+            NxNodeType::Real => NxData::Real(f64::from_le_bytes(d)),
+            NxNodeType::String => NxData::String(lo),
+            NxNodeType::Vector => NxData::Vector(lo as i32, hi as i32),
+            NxNodeType::Bitmap => NxData::Bitmap {
+                id: lo,
+                width: u16::from_le_bytes([d[4], d[5]]),
+                height: u16::from_le_bytes([d[6], d[7]]),
+            },
+            NxNodeType::Audio => NxData::Audio { id: lo, len: hi },
+        }
+    }
+}
+
+/// Counts of the exported entries
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct WzNxStats {
+    pub images: usize,
+    pub nodes: usize,
+    pub strings: usize,
+    pub bitmaps: usize,
+    pub audio: usize,
+    /// Images, canvases and sounds which couldn't be read, they are exported as
+    /// empty nodes
+    pub errors: usize,
+    /// UOLs which couldn't be resolved, they are kept as strings
+    pub dangling_links: usize,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct WzNxExporter {
+    resolve_links: bool,
+    bitmaps: bool,
+    audio: bool,
+}
+
+impl WzNxExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolves UOLs inside an image instead of storing them as strings
+    pub fn with_resolve_links(mut self, resolve_links: bool) -> Self {
+        self.resolve_links = resolve_links;
+        self
+    }
+
+    /// Also exports the canvas bitmaps
+    pub fn with_bitmaps(mut self, bitmaps: bool) -> Self {
+        self.bitmaps = bitmaps;
+        self
+    }
+
+    /// Also exports the sound data
+    pub fn with_audio(mut self, audio: bool) -> Self {
+        self.audio = audio;
+        self
+    }
+
+    /// Converts every `.wz` file of the client directory into `<name>.nx` in `out_dir`,
+    /// returns the result for every file, so a failing file doesn't stop the others
+    pub fn export_dir(
+        &self,
+        dir: impl AsRef<Path>,
+        out_dir: impl AsRef<Path>,
+        cfg: WzConfig,
+    ) -> anyhow::Result<Vec<(PathBuf, anyhow::Result<WzNxStats>)>> {
+        let out_dir = out_dir.as_ref();
+        std::fs::create_dir_all(out_dir)?;
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension() == Some("wz".as_ref()) {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        Ok(files
+            .into_iter()
+            .map(|path| {
+                let target = out_dir.join(path.with_extension(NX_EXT).file_name().unwrap());
+                let res = WzReader::open_file(&path, cfg)
+                    .and_then(|mut r| self.export_file(&mut r, &target));
+                (target, res)
+            })
+            .collect())
+    }
+
+    /// Creates the file, an existing file is overwritten
+    pub fn export_file<R: WzIO>(
+        &self,
+        r: &mut WzReader<R>,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<WzNxStats> {
+        let mut w = BufWriter::new(File::create(path)?);
+        let stats = self.export(r, &mut w)?;
+        w.flush()?;
+        Ok(stats)
+    }
+
+    /// Exports the archive, the root node has the directories and images of the
+    /// root directory as children
+    pub fn export<R: WzIO, W: Write + Seek>(
+        &self,
+        r: &mut WzReader<R>,
+        w: &mut W,
+    ) -> anyhow::Result<WzNxStats> {
+        let start = w.stream_position()?;
+        // Written again at the end, when the offsets are known
+        NxHeader::default().write(w)?;
+
+        let mut nx = NxWriter {
+            exporter: self,
+            w,
+            pos: start + NxHeader::SIZE,
+            start,
+            nodes: Vec::new(),
+            strings: IndexSet::new(),
+            bitmaps: Vec::new(),
+            audio: Vec::new(),
+            ancestors: Vec::new(),
+            stats: WzNxStats::default(),
+        };
+        let name = nx.string_id("");
+        nx.nodes.push(NxNode::new(name, NxData::None));
+        let root = WzDirHeader::root("root", 1, r.root_offset());
+        nx.add_dir(r, 0, "", &root)?;
+        nx.finish()
+    }
+}
+
+struct NxWriter<'a, W> {
+    exporter: &'a WzNxExporter,
+    w: &'a mut W,
+    /// Current position in the writer
+    pos: u64,
+    /// Position of the header
+    start: u64,
+    nodes: Vec<NxNode>,
+    strings: IndexSet<String>,
+    /// Offsets of the bitmaps and audio
+    bitmaps: Vec<u64>,
+    audio: Vec<u64>,
+    /// Offsets of the directories on the current path, to detect cycles
+    ancestors: Vec<u32>,
+    stats: WzNxStats,
+}
+
+impl<W: Write + Seek> NxWriter<'_, W> {
+    fn string_id(&mut self, s: &str) -> u32 {
+        match self.strings.get_index_of(s) {
+            Some(ix) => ix as u32,
+            None => self.strings.insert_full(s.to_string()).0 as u32,
+        }
+    }
+
+    fn align(&mut self, n: u64) -> anyhow::Result<()> {
+        let pad = (n - (self.pos - self.start) % n) % n;
+        self.write_all(&vec![0; pad as usize])
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.w.write_all(data)?;
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    /// Offset relative to the header
+    fn offset(&self) -> u64 {
+        self.pos - self.start
+    }
+
+    /// Allocates the contiguous children of the node at `ix`, returns the id of the
+    /// first child
+    fn alloc_children(&mut self, ix: usize, names: &[&str]) -> anyhow::Result<usize> {
+        let first = self.nodes.len();
+        let count = u16::try_from(names.len())
+            .map_err(|_| anyhow::format_err!("Too many children: {}", names.len()))?;
+        self.nodes[ix].children = first as u32;
+        self.nodes[ix].count = count;
+        for name in names {
+            let name = self.string_id(name);
+            self.nodes.push(NxNode::new(name, NxData::None));
+        }
+        Ok(first)
+    }
+
+    fn add_dir<R: WzIO>(
+        &mut self,
+        r: &mut WzReader<R>,
+        ix: usize,
+        path: &str,
+        hdr: &WzDirHeader,
+    ) -> anyhow::Result<()> {
+        // Directories can be shared between entries, only an ancestor forms a cycle
+        if self.ancestors.contains(&hdr.offset.0) {
+            anyhow::bail!("Cyclic directory {path} at {:#x}", hdr.offset.0);
+        }
+        let dir = r.read_dir_node(hdr)?;
+        // Nil entries have no name and are skipped
+        let mut entries = dir
+            .entries
+            .0
+            .into_iter()
+            .filter_map(|e| Some((e.name()?.to_string(), e)))
+            .collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let names = entries.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        let first = self.alloc_children(ix, &names)?;
+        self.ancestors.push(hdr.offset.0);
+        for (i, (name, entry)) in entries.iter().enumerate() {
+            let path = join_path(path, name);
+            match entry {
+                WzDirNode::Dir(dir) => self.add_dir(r, first + i, &path, dir)?,
+                WzDirNode::Img(img) => self.add_img(r, first + i, &path, img)?,
+                WzDirNode::Link(link) => self.add_img(r, first + i, &path, &link.img_header())?,
+                WzDirNode::Nil(_) => unreachable!(),
+            }
+        }
+        self.ancestors.pop();
+        Ok(())
+    }
+
+    fn add_img<R: WzIO>(
+        &mut self,
+        r: &mut WzReader<R>,
+        ix: usize,
+        path: &str,
+        hdr: &WzImgHeader,
+    ) -> anyhow::Result<()> {
+        self.stats.images += 1;
+        let mut img_r = r.img_reader(hdr)?.with_path(path);
+        // Unreadable parts are exported as empty nodes
+        let root = match WzValue::read_lenient(&mut img_r) {
+            Ok((root, _)) => root,
+            Err(_) => {
+                self.stats.errors += 1;
+                return Ok(());
+            }
+        };
+
+        let mut img = NxImg {
+            img_r: &mut img_r,
+            root: &root,
+            links: Vec::new(),
+            paths: HashMap::new(),
+        };
+        self.add_value(&mut img, ix, "", &root)?;
+
+        let resolver = WzLinkResolver::default();
+        for (ix, path) in std::mem::take(&mut img.links) {
+            let target = resolver
+                .resolve_link_path(&root, &path)
+                .ok()
+                .and_then(|target| img.paths.get(&target));
+            let Some(&target) = target else {
+                self.stats.dangling_links += 1;
+                continue;
+            };
+            let target = self.nodes[target];
+            let node = &mut self.nodes[ix];
+            node.children = target.children;
+            node.count = target.count;
+            node.ty = target.ty;
+            node.data = target.data;
+        }
+        Ok(())
+    }
+
+    fn add_value<R: WzIO>(
+        &mut self,
+        img: &mut NxImg<'_, R>,
+        ix: usize,
+        path: &str,
+        val: &WzValue,
+    ) -> anyhow::Result<()> {
+        if self.exporter.resolve_links {
+            img.paths.insert(path.to_string(), ix);
+        }
+        let data = match val {
+            WzValue::Null | WzValue::Object(_) | WzValue::Convex(_) | WzValue::Error(_) => {
+                NxData::None
+            }
+            WzValue::Short(v) => NxData::Int(*v as i64),
+            WzValue::Int(v) => NxData::Int(*v as i64),
+            WzValue::Long(v) => NxData::Int(*v),
+            WzValue::F32(v) => NxData::Real(*v as f64),
+            WzValue::F64(v) => NxData::Real(*v),
+            WzValue::String(v) => NxData::String(self.string_id(v)),
+            WzValue::Vec(v) => NxData::Vector(v.x, v.y),
+            WzValue::Link(v) => {
+                if self.exporter.resolve_links {
+                    img.links.push((ix, path.to_string()));
+                }
+                NxData::String(self.string_id(v))
+            }
+            // Media which can't be decoded is counted and skipped, write errors are fatal
+            WzValue::Canvas(canvas) if self.exporter.bitmaps => match decode_bitmap(img, canvas) {
+                Ok((width, height, data)) => self.add_bitmap(width, height, &data)?,
+                Err(_) => {
+                    self.stats.errors += 1;
+                    NxData::None
+                }
+            },
+            WzValue::Sound(sound) if self.exporter.audio => match decode_audio(img, sound) {
+                Ok(data) => self.add_audio(&data)?,
+                Err(_) => {
+                    self.stats.errors += 1;
+                    NxData::None
+                }
+            },
+            WzValue::Canvas(_) | WzValue::Sound(_) => NxData::None,
+        };
+        self.nodes[ix].set_data(data);
+
+        let convex;
+        let mut children = match val {
+            WzValue::Convex(vex) => {
+                convex = vex
+                    .0
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| (i.to_string(), WzValue::Vec(*v)))
+                    .collect::<Vec<_>>();
+                convex.iter().map(|(n, v)| (n.as_str(), v)).collect()
+            }
+            _ => match val.walk_entries() {
+                Some(obj) => obj.0.iter().map(|(n, v)| (n.as_str(), v)).collect(),
+                None => Vec::new(),
+            },
+        };
+        if children.is_empty() {
+            return Ok(());
+        }
+        children.sort_by(|a, b| a.0.cmp(b.0));
+
+        let names = children.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+        let first = self.alloc_children(ix, &names)?;
+        for (i, (name, val)) in children.into_iter().enumerate() {
+            self.add_value(img, first + i, &join_path(path, name), val)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the compressed bitmap
+    fn add_bitmap(&mut self, width: u16, height: u16, data: &[u8]) -> anyhow::Result<NxData> {
+        self.align(8)?;
+        self.bitmaps.push(self.offset());
+        self.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.write_all(data)?;
+        self.stats.bitmaps += 1;
+        Ok(NxData::Bitmap {
+            id: self.bitmaps.len() as u32 - 1,
+            width,
+            height,
+        })
+    }
+
+    /// Writes the audio data
+    fn add_audio(&mut self, data: &[u8]) -> anyhow::Result<NxData> {
+        let len = u32::try_from(data.len())?;
+
+        self.align(8)?;
+        self.audio.push(self.offset());
+        self.write_all(data)?;
+        self.stats.audio += 1;
+        Ok(NxData::Audio {
+            id: self.audio.len() as u32 - 1,
+            len,
+        })
+    }
+
+    /// Writes the tables and the header
+    fn finish(mut self) -> anyhow::Result<WzNxStats> {
+        let mut hdr = NxHeader {
+            node_count: self.nodes.len() as u32,
+            string_count: self.strings.len() as u32,
+            bitmap_count: self.bitmaps.len() as u32,
+            audio_count: self.audio.len() as u32,
+            ..Default::default()
+        };
+
+        self.align(4)?;
+        hdr.node_offset = self.offset();
+        let mut buf = Cursor::new(Vec::with_capacity(self.nodes.len() * NxNode::SIZE as usize));
+        for node in self.nodes.iter() {
+            node.write(&mut buf)?;
+        }
+        self.write_all(&buf.into_inner())?;
+
+        let strings = std::mem::take(&mut self.strings);
+        let mut string_offsets = Vec::with_capacity(strings.len());
+        for s in strings.iter() {
+            let len = u16::try_from(s.len())
+                .map_err(|_| anyhow::format_err!("String too long: {}", s.len()))?;
+            self.align(2)?;
+            string_offsets.push(self.offset());
+            self.write_all(&len.to_le_bytes())?;
+            self.write_all(s.as_bytes())?;
+        }
+
+        self.align(8)?;
+        hdr.string_offset = self.offset();
+        self.write_offsets(&string_offsets)?;
+        self.align(8)?;
+        hdr.bitmap_offset = self.offset();
+        let bitmaps = std::mem::take(&mut self.bitmaps);
+        self.write_offsets(&bitmaps)?;
+        self.align(8)?;
+        hdr.audio_offset = self.offset();
+        let audio = std::mem::take(&mut self.audio);
+        self.write_offsets(&audio)?;
+
+        let end = self.pos;
+        self.w.seek(SeekFrom::Start(self.start))?;
+        hdr.write(&mut *self.w)?;
+        self.w.seek(SeekFrom::Start(end))?;
+
+        self.stats.nodes = self.nodes.len();
+        self.stats.strings = strings.len();
+        Ok(self.stats)
+    }
+
+    fn write_offsets(&mut self, offsets: &[u64]) -> anyhow::Result<()> {
+        let buf = offsets
+            .iter()
+            .flat_map(|off| off.to_le_bytes())
+            .collect::<Vec<_>>();
+        self.write_all(&buf)
+    }
+}
+
+/// State of the image, which is currently exported
+struct NxImg<'a, R> {
+    img_r: &'a mut WzImgReader<R>,
+    root: &'a WzValue,
+    /// Nodes of the UOLs with their path, resolved after the image
+    links: Vec<(usize, String)>,
+    /// Node of every value path, only collected to resolve links
+    paths: HashMap<String, usize>,
+}
+
+/// Decodes the canvas into lz4 compressed BGRA pixels
+fn decode_bitmap<R: WzIO>(
+    img: &mut NxImg<'_, R>,
+    canvas: &CanvasVal,
+) -> anyhow::Result<(u16, u16, Vec<u8>)> {
+    let rgba = canvas
+        .read_canvas_resolved(img.img_r, img.root, None)?
+        .to_raw_rgba_image()?;
+    let width = u16::try_from(rgba.width())?;
+    let height = u16::try_from(rgba.height())?;
+    let bgra = rgba
+        .pixels()
+        .flat_map(|image::Rgba([r, g, b, a])| [*b, *g, *r, *a])
+        .collect::<Vec<_>>();
+    Ok((width, height, lz4_flex::block::compress(&bgra)))
+}
+
+/// Reads the sound data with It's header in front
+fn decode_audio<R: WzIO>(img: &mut NxImg<'_, R>, sound: &SoundVal) -> anyhow::Result<Vec<u8>> {
+    let mut data = Cursor::new(Vec::new());
+    sound.sound.header.write_header(&mut data)?;
+    let mut data = data.into_inner();
+    data.extend(sound.read_data(img.img_r)?);
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use binrw::BinRead;
+
+    use crate::{
+        test_util::{
+            build_archive, canvas_val, dir, img, img_blob, link_val, obj, shared_dir_archive,
+            str_val, TestVal,
+        },
+        WzReader, GMS95,
+    };
+
+    use super::{NxData, NxHeader, NxNode, WzNxExporter};
+
+    struct Nx {
+        data: Vec<u8>,
+        nodes: Vec<NxNode>,
+        strings: Vec<String>,
+        hdr: NxHeader,
+    }
+
+    impl Nx {
+        fn new(data: Vec<u8>) -> anyhow::Result<Self> {
+            let mut r = Cursor::new(&data);
+            let hdr = NxHeader::read(&mut r)?;
+            assert_eq!(hdr.node_offset % 4, 0);
+            r.set_position(hdr.node_offset);
+            let nodes = (0..hdr.node_count)
+                .map(|_| NxNode::read(&mut r))
+                .collect::<Result<Vec<_>, _>>()?;
+            let strings = (0..hdr.string_count as usize)
+                .map(|i| {
+                    let off = hdr.string_offset as usize + i * 8;
+                    let off = u64::from_le_bytes(data[off..off + 8].try_into().unwrap()) as usize;
+                    let len = u16::from_le_bytes([data[off], data[off + 1]]) as usize;
+                    String::from_utf8(data[off + 2..off + 2 + len].to_vec()).unwrap()
+                })
+                .collect();
+            Ok(Self {
+                data,
+                nodes,
+                strings,
+                hdr,
+            })
+        }
+
+        fn child_names(&self, node: &NxNode) -> Vec<&str> {
+            let first = node.children as usize;
+            self.nodes[first..first + node.count as usize]
+                .iter()
+                .map(|n| self.strings[n.name as usize].as_str())
+                .collect()
+        }
+
+        fn get(&self, path: &str) -> &NxNode {
+            path.split('/').fold(&self.nodes[0], |node, name| {
+                let first = node.children as usize;
+                self.nodes[first..first + node.count as usize]
+                    .iter()
+                    .find(|n| self.strings[n.name as usize] == name)
+                    .unwrap()
+            })
+        }
+
+        fn table_offset(&self, table: u64, id: u32) -> usize {
+            let off = table as usize + id as usize * 8;
+            u64::from_le_bytes(self.data[off..off + 8].try_into().unwrap()) as usize
+        }
+    }
+
+    #[test]
+    fn export() -> anyhow::Result<()> {
+        let data = build_archive(
+            GMS95,
+            vec![
+                dir(
+                    "Item",
+                    vec![img(
+                        "0200.img",
+                        img_blob(
+                            GMS95,
+                            &obj([(
+                                "info",
+                                TestVal::Obj(obj([
+                                    ("price", TestVal::Int(100)),
+                                    ("name", str_val("Red Potion")),
+                                    ("link", link_val("price")),
+                                    ("pos", TestVal::Vec2(3, -4)),
+                                    (
+                                        "icon",
+                                        canvas_val(4, 3, obj([("origin", TestVal::Vec2(1, 2))])),
+                                    ),
+                                ])),
+                            )]),
+                        ),
+                    )],
+                ),
+                img(
+                    "Bgm.img",
+                    img_blob(
+                        GMS95,
+                        &obj([(
+                            "bgm",
+                            TestVal::Sound {
+                                data: (0..64).collect(),
+                                len_ms: 1000,
+                            },
+                        )]),
+                    ),
+                ),
+            ],
+        );
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let mut out = Cursor::new(Vec::new());
+        let stats = WzNxExporter::new()
+            .with_resolve_links(true)
+            .with_bitmaps(true)
+            .with_audio(true)
+            .export(&mut r, &mut out)?;
+        assert_eq!((stats.images, stats.bitmaps, stats.audio), (2, 1, 1));
+        assert_eq!((stats.errors, stats.dangling_links), (0, 0));
+
+        let nx = Nx::new(out.into_inner())?;
+        assert_eq!(nx.hdr.node_count as usize, stats.nodes);
+        assert_eq!(nx.child_names(&nx.nodes[0]), ["Bgm.img", "Item"]);
+        let info = nx.get("Item/0200.img/info");
+        assert_eq!(
+            nx.child_names(info),
+            ["icon", "link", "name", "pos", "price"]
+        );
+        assert_eq!(nx.get("Item/0200.img/info/price").data(), NxData::Int(100));
+        assert_eq!(nx.get("Item/0200.img/info/link").data(), NxData::Int(100));
+        assert_eq!(
+            nx.get("Item/0200.img/info/pos").data(),
+            NxData::Vector(3, -4)
+        );
+        let NxData::String(name) = nx.get("Item/0200.img/info/name").data() else {
+            panic!("Expected string");
+        };
+        assert_eq!(nx.strings[name as usize], "Red Potion");
+
+        let icon = nx.get("Item/0200.img/info/icon");
+        assert_eq!(nx.child_names(icon), ["origin"]);
+        let NxData::Bitmap { id, width, height } = icon.data() else {
+            panic!("Expected bitmap");
+        };
+        assert_eq!((width, height), (4, 3));
+        let off = nx.table_offset(nx.hdr.bitmap_offset, id);
+        let len = u32::from_le_bytes(nx.data[off..off + 4].try_into()?) as usize;
+        let bgra = lz4_flex::block::decompress(&nx.data[off + 4..off + 4 + len], 4 * 3 * 4)?;
+        assert_eq!(&bgra[..8], [0, 0, 0x7f, 0xff, 1, 0, 0x7f, 0xff]);
+
+        let NxData::Audio { id, len } = nx.get("Bgm.img/bgm").data() else {
+            panic!("Expected audio");
+        };
+        let off = nx.table_offset(nx.hdr.audio_offset, id);
+        assert!(nx.data[off..off + len as usize].ends_with(&(0..64).collect::<Vec<u8>>()));
+        Ok(())
+    }
+
+    #[test]
+    fn export_links_as_strings() -> anyhow::Result<()> {
+        let data = build_archive(
+            GMS95,
+            vec![img(
+                "a.img",
+                img_blob(GMS95, &obj([("link", link_val("../x"))])),
+            )],
+        );
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let mut out = Cursor::new(Vec::new());
+        let stats = WzNxExporter::new().export(&mut r, &mut out)?;
+        assert_eq!(stats.dangling_links, 0);
+
+        let nx = Nx::new(out.into_inner())?;
+        let NxData::String(link) = nx.get("a.img/link").data() else {
+            panic!("Expected string");
+        };
+        assert_eq!(nx.strings[link as usize], "../x");
+        Ok(())
+    }
+
+    #[test]
+    fn export_shared_dir() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(shared_dir_archive(GMS95, false)), GMS95)?;
+        let mut out = Cursor::new(Vec::new());
+        let stats = WzNxExporter::new().export(&mut r, &mut out)?;
+        assert_eq!(stats.images, 2);
+        let nx = Nx::new(out.into_inner())?;
+        assert_eq!(nx.get("B/a.img/hp").data(), NxData::Int(1));
+
+        let mut r = WzReader::open(Cursor::new(shared_dir_archive(GMS95, true)), GMS95)?;
+        assert!(WzNxExporter::new()
+            .export(&mut r, &mut Cursor::new(Vec::new()))
+            .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,584 @@
+//! Reader for NX (PKG4) files, see `nx` for the format.
+//!
+//! The reader works on the bytes of the whole file, with the `mmap` feature the file is
+//! mapped into memory. Names, strings and audio are borrowed from the data, bitmaps are
+//! decompressed on access.
+//!
+//! NX doesn't know images, so every node named `*.img` is treated as image. Nodes are
+//! converted into `WzValue`s with `NxNodeRef::to_value`, the entries of an object are
+//! sorted by name like in the file.
+
+use std::{io::Cursor, path::Path};
+
+use binrw::BinRead;
+
+use crate::{
+    canvas::Canvas,
+    file::MAX_CANVAS_SIZE,
+    l1::{canvas::WzCanvasScaling, sound::SoundHeader},
+    nx::{NxData, NxHeader, NxNode, NxNodeType},
+    val::{CanvasVal, ObjectVal, SoundVal, Vec2Val, WzValue},
+};
+
+/// Limit for the depth of a value, resolved links can form cycles
+const MAX_VALUE_DEPTH: usize = 64;
+
+/// Lz4 can't compress data by more than this factor
+const MAX_LZ4_RATIO: usize = 255;
+
+/// Bytes of the file with the parsed header
+#[derive(Debug, Clone, Copy)]
+struct NxView<'a> {
+    data: &'a [u8],
+    hdr: &'a NxHeader,
+}
+
+impl<'a> NxView<'a> {
+    fn bytes(&self, offset: u64, len: u64) -> anyhow::Result<&'a [u8]> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(offset as usize..end as usize))
+            .ok_or_else(|| anyhow::format_err!("Out of bounds: {offset:#x} + {len}"))
+    }
+
+    fn u64_at(&self, offset: u64) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(offset, 8)?.try_into()?))
+    }
+
+    /// Offset of the entry from a table of offsets
+    fn table_entry(&self, table: u64, count: u32, id: u32) -> anyhow::Result<u64> {
+        if id >= count {
+            anyhow::bail!("Invalid id {id}, count is {count}");
+        }
+        self.u64_at(table + id as u64 * 8)
+    }
+
+    fn node(&self, id: u32) -> anyhow::Result<NxNodeRef<'a>> {
+        if id >= self.hdr.node_count {
+            anyhow::bail!("Invalid node {id}, count is {}", self.hdr.node_count);
+        }
+        let data = self.bytes(
+            self.hdr.node_offset + id as u64 * NxNode::SIZE,
+            NxNode::SIZE,
+        )?;
+        let node = NxNode::read(&mut Cursor::new(data))?;
+        // Checked once here, so the ids of the children can't overflow
+        if node.children as u64 + node.count as u64 > self.hdr.node_count as u64 {
+            anyhow::bail!(
+                "Children of node {id} out of bounds: {} + {}",
+                node.children,
+                node.count
+            );
+        }
+        Ok(NxNodeRef {
+            view: *self,
+            id,
+            node,
+        })
+    }
+
+    fn string(&self, id: u32) -> anyhow::Result<&'a str> {
+        let offset = self.table_entry(self.hdr.string_offset, self.hdr.string_count, id)?;
+        let len = u16::from_le_bytes(self.bytes(offset, 2)?.try_into()?);
+        Ok(std::str::from_utf8(self.bytes(offset + 2, len as u64)?)?)
+    }
+
+    fn bitmap(&self, id: u32, width: u16, height: u16) -> anyhow::Result<Canvas> {
+        let offset = self.table_entry(self.hdr.bitmap_offset, self.hdr.bitmap_count, id)?;
+        let len = u32::from_le_bytes(self.bytes(offset, 4)?.try_into()?);
+        let data = self.bytes(offset + 4, len as u64)?;
+        // Checks the size before decompressing, so corrupt dimensions can't allocate gigabytes
+        let size = width as usize * height as usize * 4;
+        let max = data
+            .len()
+            .saturating_mul(MAX_LZ4_RATIO)
+            .min(MAX_CANVAS_SIZE);
+        if size > max {
+            anyhow::bail!("Bitmap size {size} exceeds the maximum of {max}");
+        }
+        let bgra = lz4_flex::block::decompress(data, size)?;
+        Ok(Canvas::from_bgra(
+            bgra,
+            width as u32,
+            height as u32,
+            WzCanvasScaling(0),
+        ))
+    }
+
+    fn audio(&self, id: u32, len: u32) -> anyhow::Result<&'a [u8]> {
+        let offset = self.table_entry(self.hdr.audio_offset, self.hdr.audio_count, id)?;
+        self.bytes(offset, len as u64)
+    }
+}
+
+/// Reader over the bytes of a NX file
+pub struct NxReader<D> {
+    data: D,
+    hdr: NxHeader,
+}
+
+impl NxReader<Vec<u8>> {
+    /// Reads the whole file into memory
+    pub fn open_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::new(std::fs::read(path)?)
+    }
+}
+
+impl<D: AsRef<[u8]>> NxReader<D> {
+    pub fn new(data: D) -> anyhow::Result<Self> {
+        let hdr = NxHeader::read(&mut Cursor::new(data.as_ref()))?;
+        let nx = Self { data, hdr };
+
+        // The tables must be in bounds, the entries are checked on access
+        let view = nx.view();
+        view.bytes(hdr.node_offset, hdr.node_count as u64 * NxNode::SIZE)?;
+        view.bytes(hdr.string_offset, hdr.string_count as u64 * 8)?;
+        view.bytes(hdr.bitmap_offset, hdr.bitmap_count as u64 * 8)?;
+        view.bytes(hdr.audio_offset, hdr.audio_count as u64 * 8)?;
+        if hdr.node_count == 0 {
+            anyhow::bail!("Missing root node");
+        }
+        Ok(nx)
+    }
+
+    fn view(&self) -> NxView<'_> {
+        NxView {
+            data: self.data.as_ref(),
+            hdr: &self.hdr,
+        }
+    }
+
+    pub fn header(&self) -> &NxHeader {
+        &self.hdr
+    }
+
+    pub fn root(&self) -> anyhow::Result<NxNodeRef<'_>> {
+        self.node(0)
+    }
+
+    pub fn node(&self, id: u32) -> anyhow::Result<NxNodeRef<'_>> {
+        self.view().node(id)
+    }
+
+    pub fn string(&self, id: u32) -> anyhow::Result<&str> {
+        self.view().string(id)
+    }
+
+    /// Decompresses the bitmap
+    pub fn bitmap(&self, id: u32, width: u16, height: u16) -> anyhow::Result<Canvas> {
+        self.view().bitmap(id, width, height)
+    }
+
+    /// Audio with It's WZ sound header
+    pub fn audio(&self, id: u32, len: u32) -> anyhow::Result<&[u8]> {
+        self.view().audio(id, len)
+    }
+
+    /// Looks up the node at the slash-separated path
+    pub fn get_by_path(&self, path: &str) -> anyhow::Result<Option<NxNodeRef<'_>>> {
+        self.root()?.get_path(path)
+    }
+
+    /// Looks up the image node at the path
+    pub fn get_img_by_path(&self, path: &str) -> anyhow::Result<Option<NxNodeRef<'_>>> {
+        if !path.ends_with(".img") {
+            return Ok(None);
+        }
+        self.get_by_path(path)
+    }
+
+    /// Reads the image at the path as value
+    pub fn read_img(&self, path: &str) -> anyhow::Result<WzValue> {
+        self.get_img_by_path(path)?
+            .ok_or_else(|| anyhow::format_err!("Image not found: {path}"))?
+            .to_value()
+    }
+
+    /// Iterates over all images in pre-order, the paths are relative to the root
+    pub fn images(&self) -> anyhow::Result<NxImgWalker<'_>> {
+        Ok(NxImgWalker {
+            stack: vec![(String::new(), self.root()?)],
+        })
+    }
+}
+
+#[cfg(feature = "mmap")]
+pub type NxReaderMmap = NxReader<memmap2::Mmap>;
+
+#[cfg(feature = "mmap")]
+impl NxReaderMmap {
+    pub fn open_file_mmap(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: The map is read-only and every access is bounds checked, but the file
+        // must not be modified or truncated by another process while It's mapped
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Self::new(mmap)
+    }
+}
+
+/// Node with access to the file
+#[derive(Debug, Clone, Copy)]
+pub struct NxNodeRef<'a> {
+    view: NxView<'a>,
+    id: u32,
+    node: NxNode,
+}
+
+impl<'a> NxNodeRef<'a> {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn raw(&self) -> &NxNode {
+        &self.node
+    }
+
+    pub fn name(&self) -> anyhow::Result<&'a str> {
+        self.view.string(self.node.name)
+    }
+
+    pub fn ty(&self) -> NxNodeType {
+        self.node.ty
+    }
+
+    pub fn data(&self) -> NxData {
+        self.node.data()
+    }
+
+    pub fn len(&self) -> usize {
+        self.node.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.node.count == 0
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = anyhow::Result<NxNodeRef<'a>>> + 'a {
+        let view = self.view;
+        let node = *self;
+        (0..self.node.count as u32).map(move |i| view.node(node.child_id(i)?))
+    }
+
+    /// Id of the `i`th child
+    fn child_id(&self, i: u32) -> anyhow::Result<u32> {
+        self.node
+            .children
+            .checked_add(i)
+            .ok_or_else(|| anyhow::format_err!("Invalid child {i} of node {}", self.id))
+    }
+
+    /// Looks up the child by name, children are sorted so It's a binary search
+    pub fn child(&self, name: &str) -> anyhow::Result<Option<NxNodeRef<'a>>> {
+        let (mut lo, mut hi) = (0, self.node.count as u32);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let node = self.view.node(self.child_id(mid)?)?;
+            match node.name()?.as_bytes().cmp(name.as_bytes()) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(Some(node)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Looks up the node at the slash-separated path relative to this node
+    pub fn get_path(&self, path: &str) -> anyhow::Result<Option<NxNodeRef<'a>>> {
+        let mut cur = *self;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            match cur.child(part)? {
+                Some(child) => cur = child,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(cur))
+    }
+
+    pub fn string(&self) -> anyhow::Result<Option<&'a str>> {
+        match self.data() {
+            NxData::String(id) => self.view.string(id).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn bitmap(&self) -> anyhow::Result<Option<Canvas>> {
+        match self.data() {
+            NxData::Bitmap { id, width, height } => self.view.bitmap(id, width, height).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn audio(&self) -> anyhow::Result<Option<&'a [u8]>> {
+        match self.data() {
+            NxData::Audio { id, len } => self.view.audio(id, len).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Converts the node with all children into a value, bitmaps are decompressed.
+    /// Nodes without data become objects if they have children, otherwise null. The
+    /// length of sounds is not stored, so It's zero
+    pub fn to_value(self) -> anyhow::Result<WzValue> {
+        self.to_value_inner(0)
+    }
+
+    fn children_value(&self, depth: usize) -> anyhow::Result<ObjectVal> {
+        if depth >= MAX_VALUE_DEPTH {
+            anyhow::bail!("Value depth exceeds {MAX_VALUE_DEPTH} at node {}", self.id);
+        }
+        let mut obj = ObjectVal::default();
+        for child in self.children() {
+            let child = child?;
+            obj.0
+                .insert(child.name()?.to_string(), child.to_value_inner(depth + 1)?);
+        }
+        Ok(obj)
+    }
+
+    fn to_value_inner(self, depth: usize) -> anyhow::Result<WzValue> {
+        Ok(match self.data() {
+            NxData::None if self.is_empty() => WzValue::Null,
+            NxData::None => WzValue::Object(self.children_value(depth)?),
+            NxData::Int(v) => i32::try_from(v).map_or(WzValue::Long(v), WzValue::Int),
+            NxData::Real(v) => WzValue::F64(v),
+            NxData::String(id) => WzValue::String(self.view.string(id)?.to_string()),
+            NxData::Vector(x, y) => WzValue::Vec(Vec2Val { x, y }),
+            NxData::Bitmap { id, width, height } => {
+                let sub = if self.is_empty() {
+                    None
+                } else {
+                    Some(Box::new(WzValue::Object(self.children_value(depth)?)))
+                };
+                let mut canvas = CanvasVal::detached(WzCanvasScaling(0), sub);
+                canvas.set_bitmap(self.view.bitmap(id, width, height)?);
+                WzValue::Canvas(canvas)
+            }
+            NxData::Audio { id, len } => {
+                let mut data = Cursor::new(self.view.audio(id, len)?);
+                let header = SoundHeader::read_header(&mut data)?;
+                let pos = data.position() as usize;
+                WzValue::Sound(SoundVal::detached(
+                    header,
+                    0,
+                    data.into_inner()[pos..].to_vec(),
+                )?)
+            }
+        })
+    }
+}
+
+/// Pre-order iterator over the image nodes, see `NxReader::images`
+pub struct NxImgWalker<'a> {
+    stack: Vec<(String, NxNodeRef<'a>)>,
+}
+
+impl<'a> Iterator for NxImgWalker<'a> {
+    type Item = anyhow::Result<(String, NxNodeRef<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((path, node)) = self.stack.pop() {
+            if path.ends_with(".img") {
+                return Some(Ok((path, node)));
+            }
+            // Reversed so the first child is popped first
+            let children = match node.children().collect::<anyhow::Result<Vec<_>>>() {
+                Ok(children) => children,
+                Err(err) => return Some(Err(err)),
+            };
+            for child in children.into_iter().rev() {
+                let name = match child.name() {
+                    Ok(name) => name,
+                    Err(err) => return Some(Err(err)),
+                };
+                let path = if path.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{path}/{name}")
+                };
+                self.stack.push((path, child));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use binrw::BinWrite;
+
+    use crate::{
+        nx::{NxData, NxHeader, NxNode, WzNxExporter},
+        test_util::{
+            build_archive, canvas_val, dir, img, img_blob, link_val, obj, str_val, TestVal,
+        },
+        val::WzValue,
+        WzReader, GMS95,
+    };
+
+    use super::{NxNodeRef, NxReader, NxView};
+
+    #[test]
+    fn read() -> anyhow::Result<()> {
+        let data = build_archive(
+            GMS95,
+            vec![
+                dir(
+                    "Item",
+                    vec![img(
+                        "0200.img",
+                        img_blob(
+                            GMS95,
+                            &obj([(
+                                "info",
+                                TestVal::Obj(obj([
+                                    ("price", TestVal::Int(100)),
+                                    ("name", str_val("Red Potion")),
+                                    ("link", link_val("price")),
+                                    ("pos", TestVal::Vec2(3, -4)),
+                                    (
+                                        "icon",
+                                        canvas_val(4, 3, obj([("origin", TestVal::Vec2(1, 2))])),
+                                    ),
+                                ])),
+                            )]),
+                        ),
+                    )],
+                ),
+                img(
+                    "Bgm.img",
+                    img_blob(
+                        GMS95,
+                        &obj([(
+                            "bgm",
+                            TestVal::Sound {
+                                data: (0..64).collect(),
+                                len_ms: 1000,
+                            },
+                        )]),
+                    ),
+                ),
+            ],
+        );
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let mut out = Cursor::new(Vec::new());
+        WzNxExporter::new()
+            .with_resolve_links(true)
+            .with_bitmaps(true)
+            .with_audio(true)
+            .export(&mut r, &mut out)?;
+        let nx = NxReader::new(out.into_inner())?;
+
+        let imgs = nx
+            .images()?
+            .map(|img| img.map(|(path, _)| path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(imgs, ["Bgm.img", "Item/0200.img"]);
+        assert!(nx.get_img_by_path("Item")?.is_none());
+        assert!(nx.get_by_path("Item/0200.img/info/missing")?.is_none());
+
+        let item = nx.read_img("Item/0200.img")?;
+        assert_eq!(item.get_path("info/price"), Some(&WzValue::Int(100)));
+        assert_eq!(item.get_path("info/link"), Some(&WzValue::Int(100)));
+        assert_eq!(
+            item.get_path("info/name"),
+            Some(&WzValue::String("Red Potion".to_string()))
+        );
+        assert_eq!(
+            item.get_path("info/pos"),
+            Some(&WzValue::Vec((3, -4).into()))
+        );
+        assert_eq!(
+            item.get_path("info/icon/origin"),
+            Some(&WzValue::Vec((1, 2).into()))
+        );
+
+        let icon = item
+            .get_path("info/icon")
+            .and_then(WzValue::as_canvas)
+            .unwrap();
+        let hdr = r.walk_images().next().unwrap()?.hdr;
+        let mut wz_img = r.img_reader(&hdr)?;
+        let wz_icon = WzValue::read(&mut wz_img)?;
+        let wz_icon = wz_icon
+            .get_path("info/icon")
+            .and_then(WzValue::as_canvas)
+            .unwrap();
+        assert_eq!(
+            icon.bitmap.as_deref().unwrap().to_raw_rgba_image()?,
+            wz_icon.read_canvas(&mut wz_img)?.to_raw_rgba_image()?
+        );
+
+        let bgm = nx.read_img("Bgm.img")?;
+        let bgm = bgm.get_path("bgm").and_then(WzValue::as_sound).unwrap();
+        assert_eq!(bgm.data.as_deref(), Some(&(0..64).collect::<Vec<u8>>()));
+        Ok(())
+    }
+
+    #[test]
+    fn bitmap_size() {
+        // Offset table with a single bitmap of 8 compressed bytes behind it
+        let mut data = 8u64.to_le_bytes().to_vec();
+        data.extend(8u32.to_le_bytes());
+        data.extend([0; 8]);
+        let hdr = NxHeader {
+            bitmap_count: 1,
+            bitmap_offset: 0,
+            ..Default::default()
+        };
+        let view = NxView {
+            data: &data,
+            hdr: &hdr,
+        };
+
+        assert!(view.bitmap(0, u16::MAX, u16::MAX).is_err());
+    }
+
+    #[test]
+    fn children_overflow() -> anyhow::Result<()> {
+        let mut node = NxNode::new(0, NxData::None);
+        node.children = u32::MAX;
+        node.count = 2;
+        let mut data = Cursor::new(Vec::new());
+        node.write(&mut data)?;
+        let data = data.into_inner();
+        let hdr = NxHeader {
+            node_count: 1,
+            node_offset: 0,
+            ..Default::default()
+        };
+        let view = NxView {
+            data: &data,
+            hdr: &hdr,
+        };
+        assert!(view.node(0).is_err());
+
+        // Without the check on read the ids of the children must not wrap around
+        let root = NxNodeRef { view, id: 0, node };
+        assert!(root.children().all(|child| child.is_err()));
+        assert!(root.child("a").is_err());
+        Ok(())
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn read_mmap() -> anyhow::Result<()> {
+        let data = build_archive(
+            GMS95,
+            vec![img(
+                "a.img",
+                img_blob(GMS95, &obj([("hp", TestVal::Int(7))])),
+            )],
+        );
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let path = std::env::temp_dir().join(format!("shroom-nx-{}.nx", std::process::id()));
+        WzNxExporter::new().export_file(&mut r, &path)?;
+
+        let nx = super::NxReaderMmap::open_file_mmap(&path)?;
+        let val = nx.read_img("a.img")?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(val.get_path("hp"), Some(&WzValue::Int(7)));
+        Ok(())
+    }
+}
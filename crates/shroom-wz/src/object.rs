@@ -0,0 +1,601 @@
+//! Typed objects read from image values, usually via `#[derive(WzObject)]`.
+//!
+//! Numbers, flags and strings are coerced like `de::from_value` does: numbers stored
+//! as strings are parsed and numbers are formatted for strings.
+
+use std::{collections::BTreeMap, fmt::Display, ops::Deref, str::FromStr};
+
+use serde::de::DeserializeOwned;
+
+use crate::{
+    canvas_link::CanvasLink,
+    uol::{WzLinkError, WzLinkResolver, DEFAULT_MAX_LINK_DEPTH},
+    util::join_path,
+    val::{CanvasVal, ObjectVal, SoundVal, Vec2Val, Vex2Val, WzValue},
+};
+
+pub type WzObjResult<T> = anyhow::Result<T>;
+
+/// How a field is read
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WzFieldOpts {
+    /// Follow UOL links along the path
+    pub resolve: bool,
+    /// The value must be a canvas, `_inlink`s are followed
+    pub canvas: bool,
+}
+
+/// Value inside an image with It's path, the root is kept to resolve links
+#[derive(Debug, Clone)]
+pub struct WzNodeRef<'a> {
+    pub root: &'a WzValue,
+    pub path: String,
+    pub val: &'a WzValue,
+    /// The link at the requested path, If `val` is It's target
+    pub link: Option<&'a WzValue>,
+}
+
+impl<'a> WzNodeRef<'a> {
+    pub fn root(root: &'a WzValue) -> Self {
+        Self::new(root, String::new(), root)
+    }
+
+    fn new(root: &'a WzValue, path: String, val: &'a WzValue) -> Self {
+        Self {
+            root,
+            path,
+            val,
+            link: None,
+        }
+    }
+
+    /// Node at the relative path, `resolve` follows links along the path
+    pub fn get(&self, path: &str, resolve: bool) -> anyhow::Result<Option<Self>> {
+        let full = join_path(&self.path, path);
+        if !resolve {
+            return Ok(self
+                .val
+                .get_path(path)
+                .map(|val| Self::new(self.root, full, val)));
+        }
+
+        match WzLinkResolver::default().resolve_target(self.root, &full) {
+            Ok((val, path)) => Ok(Some(Self::new(self.root, path, val))),
+            Err(WzLinkError::NotFound(_)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Follows the `_inlink`s of the canvas inside the image
+    pub fn canvas_target(self) -> anyhow::Result<Self> {
+        let mut cur = self;
+        for _ in 0..=DEFAULT_MAX_LINK_DEPTH {
+            let WzValue::Canvas(canvas) = cur.val else {
+                anyhow::bail!("Expected a canvas at {}", cur.path);
+            };
+            let Some(CanvasLink::In(path)) = canvas.link() else {
+                return Ok(cur);
+            };
+            let val = cur
+                .root
+                .get_path(&path)
+                .ok_or_else(|| anyhow::format_err!("Invalid canvas inlink: {path}"))?;
+            cur = Self::new(cur.root, path, val);
+        }
+        anyhow::bail!(
+            "Canvas link depth exceeds {DEFAULT_MAX_LINK_DEPTH} at {}",
+            cur.path
+        )
+    }
+
+    /// Entries of an object or the sub property of a canvas
+    pub fn children(&self) -> Vec<(&'a str, Self)> {
+        let obj = match self.val {
+            WzValue::Object(obj) => obj,
+            WzValue::Canvas(canvas) => match canvas.sub.as_deref() {
+                Some(WzValue::Object(obj)) => obj,
+                _ => return Vec::new(),
+            },
+            _ => return Vec::new(),
+        };
+        obj.0
+            .iter()
+            .map(|(name, val)| {
+                (
+                    name.as_str(),
+                    Self::new(self.root, join_path(&self.path, name), val),
+                )
+            })
+            .collect()
+    }
+
+    /// Reads the field at the relative path, `None` If It's missing
+    pub fn field<T: FromWzNode>(&self, path: &str, opts: WzFieldOpts) -> anyhow::Result<Option<T>> {
+        let Some(mut node) = self.get(path, opts.resolve)? else {
+            return Ok(None);
+        };
+        if opts.canvas {
+            node = node.canvas_target()?;
+        }
+        // Only a link at the path itself is kept, links along the path can't be written back
+        node.link = self
+            .val
+            .get_path(path)
+            .filter(|link| !std::ptr::eq(*link, node.val));
+        T::from_wz_node(&node).map(Some)
+    }
+
+    /// Reads the field at the relative path, fails If It's missing and `T` has no
+    /// value for missing properties
+    pub fn must_field<T: FromWzNode>(&self, path: &str, opts: WzFieldOpts) -> anyhow::Result<T> {
+        match self.field(path, opts)? {
+            Some(v) => Ok(v),
+            None => T::missing().ok_or_else(|| {
+                anyhow::format_err!("Missing property {}", join_path(&self.path, path))
+            }),
+        }
+    }
+
+    fn invalid<T>(&self, expected: &str) -> anyhow::Result<T> {
+        anyhow::bail!("Expected {expected} at {}, found {:?}", self.path, self.val)
+    }
+}
+
+/// Types, which can be read from a value
+pub trait FromWzNode: Sized {
+    fn from_wz_node(node: &WzNodeRef<'_>) -> WzObjResult<Self>;
+
+    /// Value for a missing property, `None` If the property is required
+    fn missing() -> Option<Self> {
+        None
+    }
+
+    /// Reads the type from the root value of an image
+    fn from_wz_value(root: &WzValue) -> WzObjResult<Self> {
+        Self::from_wz_node(&WzNodeRef::root(root))
+    }
+}
+
+/// Types, which can be converted back into a value
+pub trait ToWzValue {
+    /// The value, `None` If the property should be left out
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>>;
+}
+
+/// Inserts the value at the relative path, missing objects along the path are created.
+/// Fails If a value along the path is neither an object nor a canvas
+pub fn insert_path(obj: &mut ObjectVal, path: &str, val: WzValue) -> WzObjResult<()> {
+    let (parents, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut cur = obj;
+    for part in parents.split('/').filter(|p| !p.is_empty()) {
+        let entry = cur
+            .0
+            .entry(part.to_string())
+            .or_insert_with(|| WzValue::Object(ObjectVal::default()));
+        // Properties of a canvas are kept in It's sub property
+        let entry = match entry {
+            WzValue::Canvas(canvas) => canvas
+                .sub
+                .get_or_insert_with(|| Box::new(WzValue::Object(ObjectVal::default())))
+                .as_mut(),
+            entry => entry,
+        };
+        let WzValue::Object(next) = entry else {
+            anyhow::bail!("Can't insert {path}, {part} is not an object");
+        };
+        cur = next;
+    }
+    cur.0.insert(name.to_string(), val);
+    Ok(())
+}
+
+/// Field read through a link, like a `#[wz(canvas)]` or `#[wz(link_resolve)]` field.
+/// The link is written back instead of the value, so reading and writing an object
+/// keeps It's links. Without a link the value is written
+#[derive(Debug, Clone, PartialEq)]
+pub struct WzLinked<T> {
+    /// The link at the field path, like a UOL or a canvas with an `_inlink`
+    pub link: Option<WzValue>,
+    pub val: T,
+}
+
+impl<T> WzLinked<T> {
+    /// Unlinked value
+    pub fn new(val: T) -> Self {
+        Self { link: None, val }
+    }
+}
+
+impl<T> Deref for WzLinked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.val
+    }
+}
+
+impl<T: FromWzNode> FromWzNode for WzLinked<T> {
+    fn from_wz_node(node: &WzNodeRef<'_>) -> WzObjResult<Self> {
+        Ok(Self {
+            link: node.link.cloned(),
+            val: T::from_wz_node(node)?,
+        })
+    }
+
+    fn missing() -> Option<Self> {
+        T::missing().map(Self::new)
+    }
+}
+
+impl<T: ToWzValue> ToWzValue for WzLinked<T> {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        match &self.link {
+            Some(link) => Ok(Some(link.clone())),
+            None => self.val.to_wz_value(),
+        }
+    }
+}
+
+/// Scalars are coerced by the `de` deserializer, so both read values the same way
+fn coerce<T: DeserializeOwned>(node: &WzNodeRef<'_>) -> WzObjResult<T> {
+    crate::de::from_value(node.val).map_err(|err| anyhow::format_err!("{err} at {}", node.path))
+}
+
+macro_rules! scalar_impl {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl FromWzNode for $ty {
+                fn from_wz_node(node: &WzNodeRef<'_>) -> WzObjResult<Self> {
+                    coerce(node)
+                }
+            }
+        )*
+    };
+}
+
+scalar_impl!(i8, u8, i16, u16, i32, u32, i64, u64, f32, f64, bool, String);
+
+macro_rules! int_impl {
+    ($($ty:ty => $variant:ident),* $(,)?) => {
+        $(
+            impl ToWzValue for $ty {
+                fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+                    Ok(Some(WzValue::$variant((*self).into())))
+                }
+            }
+        )*
+    };
+}
+
+int_impl!(
+    i8 => Int,
+    u8 => Int,
+    i16 => Short,
+    u16 => Int,
+    i32 => Int,
+    i64 => Long,
+);
+
+/// Written as `Int` If It fits
+impl ToWzValue for u32 {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        Ok(Some(
+            i32::try_from(*self).map_or(WzValue::Long((*self).into()), WzValue::Int),
+        ))
+    }
+}
+
+/// Fails If It doesn't fit into a `Long`
+impl ToWzValue for u64 {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        let v = i64::try_from(*self).map_err(|_| anyhow::format_err!("{self} exceeds a long"))?;
+        Ok(Some(WzValue::Long(v)))
+    }
+}
+
+impl ToWzValue for f32 {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        Ok(Some(WzValue::F32(*self)))
+    }
+}
+
+impl ToWzValue for f64 {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        Ok(Some(WzValue::F64(*self)))
+    }
+}
+
+impl ToWzValue for bool {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        Ok(Some(WzValue::Int(*self as i32)))
+    }
+}
+
+impl ToWzValue for String {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        Ok(Some(WzValue::String(self.clone())))
+    }
+}
+
+macro_rules! val_impl {
+    ($($ty:ty => $variant:ident, $exp:literal),* $(,)?) => {
+        $(
+            impl FromWzNode for $ty {
+                fn from_wz_node(node: &WzNodeRef<'_>) -> WzObjResult<Self> {
+                    match node.val {
+                        WzValue::$variant(v) => Ok(v.clone()),
+                        _ => node.invalid($exp),
+                    }
+                }
+            }
+
+            impl ToWzValue for $ty {
+                fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+                    Ok(Some(WzValue::$variant(self.clone())))
+                }
+            }
+        )*
+    };
+}
+
+val_impl!(
+    Vec2Val => Vec, "a vector",
+    Vex2Val => Convex, "a convex",
+    CanvasVal => Canvas, "a canvas",
+    SoundVal => Sound, "a sound",
+    ObjectVal => Object, "an object",
+);
+
+impl FromWzNode for WzValue {
+    fn from_wz_node(node: &WzNodeRef<'_>) -> WzObjResult<Self> {
+        Ok(node.val.clone())
+    }
+}
+
+impl ToWzValue for WzValue {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        Ok(Some(self.clone()))
+    }
+}
+
+impl<T: FromWzNode> FromWzNode for Option<T> {
+    fn from_wz_node(node: &WzNodeRef<'_>) -> WzObjResult<Self> {
+        match node.val {
+            WzValue::Null => Ok(None),
+            _ => T::from_wz_node(node).map(Some),
+        }
+    }
+
+    fn missing() -> Option<Self> {
+        Some(None)
+    }
+}
+
+impl<T: ToWzValue> ToWzValue for Option<T> {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        match self {
+            Some(v) => v.to_wz_value(),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Entries with numeric names ordered by the number, other entries are skipped
+impl<T: FromWzNode> FromWzNode for Vec<T> {
+    fn from_wz_node(node: &WzNodeRef<'_>) -> WzObjResult<Self> {
+        let mut items = node
+            .children()
+            .into_iter()
+            .filter_map(|(name, child)| name.parse::<u32>().ok().map(|ix| (ix, child)))
+            .collect::<Vec<_>>();
+        items.sort_by_key(|(ix, _)| *ix);
+        items
+            .iter()
+            .map(|(_, child)| T::from_wz_node(child))
+            .collect()
+    }
+}
+
+impl<T: ToWzValue> ToWzValue for Vec<T> {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        let mut obj = ObjectVal::default();
+        for (ix, item) in self.iter().enumerate() {
+            if let Some(val) = item.to_wz_value()? {
+                obj.0.insert(ix.to_string(), val);
+            }
+        }
+        Ok(Some(WzValue::Object(obj)))
+    }
+}
+
+impl<K, V> FromWzNode for BTreeMap<K, V>
+where
+    K: FromStr + Ord,
+    V: FromWzNode,
+{
+    fn from_wz_node(node: &WzNodeRef<'_>) -> WzObjResult<Self> {
+        node.children()
+            .into_iter()
+            .map(|(name, child)| {
+                let key = name
+                    .parse()
+                    .map_err(|_| anyhow::format_err!("Invalid key at {}", child.path))?;
+                Ok((key, V::from_wz_node(&child)?))
+            })
+            .collect()
+    }
+}
+
+impl<K: Display, V: ToWzValue> ToWzValue for BTreeMap<K, V> {
+    fn to_wz_value(&self) -> WzObjResult<Option<WzValue>> {
+        let mut obj = ObjectVal::default();
+        for (key, item) in self.iter() {
+            if let Some(val) = item.to_wz_value()? {
+                obj.0.insert(key.to_string(), val);
+            }
+        }
+        Ok(Some(WzValue::Object(obj)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use indexmap::indexmap;
+
+    use crate::{
+        l1::canvas::WzCanvasScaling,
+        val::{CanvasVal, ObjectVal, Vec2Val, WzValue},
+        WzObject,
+    };
+
+    use super::{insert_path, FromWzNode, ToWzValue, WzLinked};
+
+    #[derive(Debug, PartialEq, WzObject)]
+    struct Frame {
+        origin: Vec2Val,
+        #[wz(default = 100)]
+        delay: u16,
+    }
+
+    #[derive(Debug, PartialEq, WzObject)]
+    struct Mob {
+        #[wz(path = "info/level")]
+        level: u8,
+        #[wz(path = "info/maxHP")]
+        max_hp: u32,
+        #[wz(path = "info/boss", default)]
+        boss: bool,
+        #[wz(path = "info/name")]
+        name: Option<String>,
+        stand: Vec<Frame>,
+        #[wz(path = "hit/0", link_resolve)]
+        hit: WzLinked<Frame>,
+        #[wz(path = "info/icon")]
+        info_icon: CanvasVal,
+        #[wz(path = "icon", canvas)]
+        icon: WzLinked<CanvasVal>,
+        skills: BTreeMap<u32, i32>,
+    }
+
+    fn canvas(sub: WzValue) -> WzValue {
+        WzValue::Canvas(CanvasVal::detached(WzCanvasScaling(0), Some(Box::new(sub))))
+    }
+
+    #[test]
+    fn derive() {
+        let frame = |delay: Option<i32>| {
+            let mut frame = indexmap! {
+                "origin".to_string() => WzValue::Vec((1, 2).into()),
+            };
+            if let Some(delay) = delay {
+                frame.insert("delay".to_string(), WzValue::Int(delay));
+            }
+            canvas(WzValue::from(frame))
+        };
+        let root = WzValue::from(indexmap! {
+            "info".to_string() => WzValue::from(indexmap! {
+                "level".to_string() => WzValue::Short(10),
+                "maxHP".to_string() => WzValue::String("1500".to_string()),
+                "icon".to_string() => canvas(WzValue::from(indexmap! {
+                    "z".to_string() => WzValue::Int(1),
+                })),
+            }),
+            "stand".to_string() => WzValue::from(indexmap! {
+                "1".to_string() => frame(None),
+                "0".to_string() => frame(Some(120)),
+            }),
+            "hit".to_string() => WzValue::from(indexmap! {
+                "0".to_string() => WzValue::Link("../stand/0".to_string()),
+            }),
+            "icon".to_string() => canvas(WzValue::from(indexmap! {
+                "_inlink".to_string() => WzValue::String("info/icon".to_string()),
+            })),
+            "skills".to_string() => WzValue::from(indexmap! {
+                "3".to_string() => WzValue::Int(1),
+            }),
+        });
+
+        let mob = Mob::from_wz_value(&root).unwrap();
+        assert_eq!(mob.level, 10);
+        assert_eq!(mob.max_hp, 1500);
+        assert!(!mob.boss);
+        assert_eq!(mob.name, None);
+        assert_eq!(
+            mob.stand,
+            [
+                Frame {
+                    origin: (1, 2).into(),
+                    delay: 120
+                },
+                Frame {
+                    origin: (1, 2).into(),
+                    delay: 100
+                }
+            ]
+        );
+        assert_eq!(mob.hit.val, mob.stand[0]);
+        assert_eq!(mob.hit.link, Some(WzValue::Link("../stand/0".to_string())));
+        assert_eq!(mob.icon.val, mob.info_icon);
+        assert_eq!(mob.icon.link.as_ref(), root.get_path("icon"));
+        assert_eq!(mob.skills, BTreeMap::from([(3, 1)]));
+
+        // Writing the object and reading It again yields the same object with It's links
+        let val = mob.to_wz_value().unwrap().unwrap();
+        assert_eq!(val.get_path("info/maxHP"), Some(&WzValue::Int(1500)));
+        assert_eq!(val.get_path("hit/0"), root.get_path("hit/0"));
+        assert_eq!(val.get_path("icon"), root.get_path("icon"));
+        assert_eq!(Mob::from_wz_value(&val).unwrap(), mob);
+
+        let err = Mob::from_wz_value(&WzValue::from(indexmap! {
+            "info".to_string() => WzValue::from(indexmap! {
+                "level".to_string() => WzValue::String("x".to_string()),
+            }),
+        }))
+        .unwrap_err();
+        assert!(err.to_string().contains("info/level"), "{err}");
+    }
+
+    #[test]
+    fn scalars() {
+        let flag = WzValue::String("true".to_string());
+        assert!(bool::from_wz_value(&flag).unwrap());
+        assert_eq!(
+            crate::de::from_value::<bool>(&flag).unwrap(),
+            bool::from_wz_value(&flag).unwrap()
+        );
+        assert!(i32::from_wz_value(&WzValue::String("1.5".to_string())).is_err());
+        assert!(u8::from_wz_value(&WzValue::Int(300)).is_err());
+
+        assert_eq!(7u32.to_wz_value().unwrap(), Some(WzValue::Int(7)));
+        assert_eq!(
+            u32::MAX.to_wz_value().unwrap(),
+            Some(WzValue::Long(u32::MAX.into()))
+        );
+        assert_eq!(
+            (i64::MAX as u64).to_wz_value().unwrap(),
+            Some(WzValue::Long(i64::MAX))
+        );
+        assert!(u64::MAX.to_wz_value().is_err());
+    }
+
+    #[test]
+    fn insert() {
+        let mut obj = ObjectVal::default();
+        insert_path(&mut obj, "a/b", WzValue::Int(1)).unwrap();
+        insert_path(&mut obj, "c", canvas(WzValue::Null)).unwrap();
+        // Canvas properties are inserted into It's sub property
+        let empty = CanvasVal::detached(WzCanvasScaling(0), None);
+        insert_path(&mut obj, "d", WzValue::Canvas(empty)).unwrap();
+        insert_path(&mut obj, "d/x", WzValue::Int(2)).unwrap();
+        let val = WzValue::Object(obj.clone());
+        assert_eq!(val.get_path("a/b"), Some(&WzValue::Int(1)));
+        assert_eq!(val.get_path("d/x"), Some(&WzValue::Int(2)));
+
+        // Values along the path are never replaced
+        assert!(insert_path(&mut obj, "a/b/c", WzValue::Int(3)).is_err());
+        assert!(insert_path(&mut obj, "c/x", WzValue::Int(3)).is_err());
+        assert_eq!(WzValue::Object(obj), val);
+    }
+}
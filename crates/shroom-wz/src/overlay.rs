@@ -0,0 +1,545 @@
+//! Layered overlay of archives and unpacked directories.
+//!
+//! Layers are stacked in the order they are pushed, an image of a later layer replaces
+//! the image with the same path of the earlier layers. A directory layer holds raw
+//! `.img` files and unpacked images as written by the unpack commands, which are
+//! `<name>.img` directories with an `img.json`, the canvas bitmaps and sound data under `data/`.
+//! XML dumps are read from `<name>.img.xml` files.
+//! A `.wh.<name>` file in a directory layer is a whiteout, It deletes the image or
+//! directory `<name>` of the earlier layers.
+//!
+//! Single images are read by their path with `WzOverlay::img_reader`, `into_reader`
+//! builds the merged archive in memory for the `WzTree` and `WzReader` APIs.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{Cursor, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use id_tree::NodeId;
+
+use crate::{
+    canvas::Canvas,
+    crypto::WzCrypto,
+    file::{WzIO, WzImgReader},
+    l0::{tree::WzTree, WzDirNode, WzImgHeader},
+    util::wz_checksum,
+    val::{CanvasVal, SoundVal, WzValue},
+    writer::{WzArchiveImg, WzArchiveNode, WzArchiveWriter, WzImgBuilder, WzMediaSource},
+    xml::{build_xml_img, XML_IMG_EXT},
+    WzConfig, WzReader,
+};
+
+/// Prefix of whiteout files
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+/// Value tree of an unpacked image
+pub const UNPACKED_IMG_JSON: &str = "img.json";
+/// Directory with the canvas bitmaps and sound data of an unpacked image
+pub const UNPACKED_IMG_DATA: &str = "data";
+/// Extension of the sound data files, they hold the data as returned by `SoundVal::read_data`
+pub const UNPACKED_SOUND_EXT: &str = "sound";
+
+/// Source of an image in the overlay
+#[derive(Debug, Clone)]
+enum LayerImg {
+    /// Image in the archive with the index
+    Archive(usize, WzImgHeader),
+    /// Raw `.img` file
+    File(PathBuf),
+    /// Unpacked image directory
+    Unpacked(PathBuf),
+    /// XML dump
+    Xml(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+enum LayerEntry {
+    Dir,
+    Img(LayerImg),
+}
+
+/// Merged view of multiple layers
+pub struct WzOverlay<R> {
+    cfg: WzConfig,
+    archives: Vec<WzReader<R>>,
+    /// Entries by their path components, so a directory is followed by It's entries
+    entries: BTreeMap<Vec<String>, LayerEntry>,
+    /// Blobs of the unpacked images and XML dumps, they are built on first access
+    unpacked: HashMap<PathBuf, Vec<u8>>,
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl<R> WzOverlay<R> {
+    /// Empty overlay, images of all layers must use the version and region of `cfg`
+    pub fn new(cfg: WzConfig) -> Self {
+        Self {
+            cfg,
+            archives: Vec::new(),
+            entries: BTreeMap::new(),
+            unpacked: HashMap::new(),
+        }
+    }
+
+    /// Deletes the image or directory at the path from the current layers
+    pub fn whiteout(&mut self, path: &str) {
+        self.remove(&split_path(path));
+    }
+
+    fn remove(&mut self, path: &[String]) {
+        let sub = self
+            .entries
+            .range(path.to_vec()..)
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for p in sub {
+            self.entries.remove(&p);
+        }
+    }
+
+    fn insert_dir(&mut self, path: Vec<String>) {
+        for i in 1..=path.len() {
+            let parent = &path[..i];
+            if let Some(LayerEntry::Img(_)) = self.entries.get(parent) {
+                self.entries.remove(parent);
+            }
+            self.entries
+                .entry(parent.to_vec())
+                .or_insert(LayerEntry::Dir);
+        }
+    }
+
+    fn insert_img(&mut self, path: Vec<String>, img: LayerImg) {
+        self.remove(&path);
+        self.insert_dir(path[..path.len() - 1].to_vec());
+        self.entries.insert(path, LayerEntry::Img(img));
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.entries.contains_key(&split_path(path))
+    }
+
+    /// Paths of all images in the merged view
+    pub fn images(&self) -> impl Iterator<Item = String> + '_ {
+        self.entries.iter().filter_map(|(path, entry)| match entry {
+            LayerEntry::Img(_) => Some(path.join("/")),
+            LayerEntry::Dir => None,
+        })
+    }
+
+    /// Adds a directory with raw or unpacked images and whiteouts as layer
+    pub fn push_dir(&mut self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.push_dir_entries(dir.as_ref(), &[])
+    }
+
+    fn push_dir_entries(&mut self, dir: &Path, path: &[String]) -> anyhow::Result<()> {
+        let mut entries = std::fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+
+        let child_path = |name: &str| {
+            let mut p = path.to_vec();
+            p.push(name.to_string());
+            p
+        };
+
+        // Whiteouts only delete the entries of the earlier layers
+        for entry in entries.iter() {
+            let name = entry
+                .file_name()
+                .and_then(|s| s.to_str())
+                .unwrap_or_default();
+            if let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) {
+                self.remove(&child_path(target));
+            }
+        }
+
+        for entry in entries.iter() {
+            let Some(name) = entry.file_name().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if name.starts_with(WHITEOUT_PREFIX) {
+                continue;
+            }
+
+            let is_img = name.ends_with(".img");
+            if entry.is_dir() {
+                if is_img && entry.join(UNPACKED_IMG_JSON).is_file() {
+                    self.insert_img(child_path(name), LayerImg::Unpacked(entry.clone()));
+                } else {
+                    self.insert_dir(child_path(name));
+                    self.push_dir_entries(entry, &child_path(name))?;
+                }
+            } else if is_img {
+                self.insert_img(child_path(name), LayerImg::File(entry.clone()));
+            } else if let Some(img_name) = name
+                .strip_suffix(XML_IMG_EXT)
+                .and_then(|name| name.strip_suffix('.'))
+                .filter(|name| name.ends_with(".img"))
+            {
+                self.insert_img(child_path(img_name), LayerImg::Xml(entry.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Directory tree of the merged view, the blob of an image is It's path for `img_reader`
+    pub fn nodes(&mut self) -> anyhow::Result<Vec<WzArchiveNode<String>>> {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect::<Vec<_>>();
+
+        // Stack of the open directories with their entries
+        let mut stack: Vec<(Vec<String>, Vec<WzArchiveNode<String>>)> =
+            vec![(Vec::new(), Vec::new())];
+        let close = |stack: &mut Vec<(Vec<String>, Vec<WzArchiveNode<String>>)>| {
+            let (path, entries) = stack.pop().unwrap();
+            let name = path.last().cloned().unwrap_or_default();
+            stack
+                .last_mut()
+                .unwrap()
+                .1
+                .push(WzArchiveNode::Dir(name, entries));
+        };
+
+        for (path, entry) in entries {
+            while !path.starts_with(&stack.last().unwrap().0) {
+                close(&mut stack);
+            }
+
+            match entry {
+                LayerEntry::Dir => stack.push((path, Vec::new())),
+                LayerEntry::Img(img) => {
+                    let (size, checksum) = self.img_sizes(&img)?;
+                    let name = path.last().cloned().unwrap_or_default();
+                    stack
+                        .last_mut()
+                        .unwrap()
+                        .1
+                        .push(WzArchiveNode::Img(WzArchiveImg {
+                            name,
+                            size,
+                            checksum,
+                            blob: path.join("/"),
+                        }));
+                }
+            }
+        }
+        while stack.len() > 1 {
+            close(&mut stack);
+        }
+
+        Ok(stack.pop().unwrap().1)
+    }
+
+    fn img_sizes(&mut self, img: &LayerImg) -> anyhow::Result<(u32, i32)> {
+        let sizes = |blob: &[u8]| (blob.len() as u32, wz_checksum(0, blob));
+        Ok(match img {
+            LayerImg::Archive(_, hdr) => (hdr.blob_size.0.max(0) as u32, hdr.checksum.0),
+            LayerImg::File(file) => sizes(&std::fs::read(file)?),
+            LayerImg::Unpacked(dir) => sizes(self.unpacked_blob(dir, build_unpacked_img)?),
+            LayerImg::Xml(file) => sizes(self.unpacked_blob(file, build_xml_img)?),
+        })
+    }
+
+    fn unpacked_blob(
+        &mut self,
+        path: &Path,
+        build: fn(&Path, WzConfig) -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<&Vec<u8>> {
+        if !self.unpacked.contains_key(path) {
+            let blob = build(path, self.cfg)
+                .with_context(|| format!("Unpacked image: {}", path.display()))?;
+            self.unpacked.insert(path.to_path_buf(), blob);
+        }
+        Ok(&self.unpacked[path])
+    }
+}
+
+impl<R: WzIO> WzOverlay<R> {
+    /// Adds all images and directories of the archive as layer
+    pub fn push_archive(&mut self, mut r: WzReader<R>) -> anyhow::Result<()> {
+        let tree = WzTree::from_reader(&mut r, None)?;
+        let layer = self.archives.len();
+        let nodes = tree.get_tree();
+
+        let mut q: Vec<(Vec<String>, NodeId)> = Vec::new();
+        if let Some(root) = nodes.root_node_id() {
+            q.push((Vec::new(), root.clone()));
+        }
+        while let Some((path, id)) = q.pop() {
+            for child in nodes.children_ids(&id)? {
+                let node = nodes.get(child)?.data();
+                let Some(name) = node.name() else {
+                    continue;
+                };
+                let mut child_path = path.clone();
+                child_path.push(name.to_string());
+
+                match node {
+                    WzDirNode::Dir(_) => {
+                        self.insert_dir(child_path.clone());
+                        q.push((child_path, child.clone()));
+                    }
+                    WzDirNode::Img(hdr) => {
+                        self.insert_img(child_path, LayerImg::Archive(layer, hdr.clone()))
+                    }
+                    WzDirNode::Link(link) => {
+                        self.insert_img(child_path, LayerImg::Archive(layer, link.img_header()))
+                    }
+                    WzDirNode::Nil(_) => {}
+                }
+            }
+        }
+
+        self.archives.push(r);
+        Ok(())
+    }
+
+    /// Reads the blob of the image from the topmost layer containing It
+    pub fn read_img_blob(&mut self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let img = match self.entries.get(&split_path(path)) {
+            Some(LayerEntry::Img(img)) => img.clone(),
+            _ => anyhow::bail!("Image not found: {path}"),
+        };
+
+        Ok(match img {
+            LayerImg::Archive(layer, hdr) => self.archives[layer].read_img_blob(&hdr)?,
+            LayerImg::File(file) => std::fs::read(file)?,
+            LayerImg::Unpacked(dir) => self.unpacked_blob(&dir, build_unpacked_img)?.clone(),
+            LayerImg::Xml(file) => self.unpacked_blob(&file, build_xml_img)?.clone(),
+        })
+    }
+
+    pub fn img_reader(&mut self, path: &str) -> anyhow::Result<WzImgReader<Cursor<Vec<u8>>>> {
+        let blob = self.read_img_blob(path)?;
+        let crypto = Arc::new(WzCrypto::from_cfg(self.cfg, 0));
+        Ok(WzImgReader::new(Cursor::new(blob), crypto).with_path(path))
+    }
+
+    /// Writes the merged view as a new archive
+    pub fn write_archive<W: Write + Seek>(&mut self, w: &mut W) -> anyhow::Result<()> {
+        let nodes = self.nodes()?;
+        WzArchiveWriter::new(self.cfg).write_with(w, &nodes, |path, w| {
+            w.write_all(&self.read_img_blob(path)?)?;
+            Ok(())
+        })
+    }
+
+    /// Reader over the merged view, the archive is written into memory so It
+    /// holds the blobs of all images
+    pub fn into_reader(mut self) -> anyhow::Result<WzReader<Cursor<Vec<u8>>>> {
+        let mut w = Cursor::new(Vec::new());
+        self.write_archive(&mut w)?;
+        WzReader::open(Cursor::new(w.into_inner()), self.cfg)
+    }
+}
+
+/// Canvas bitmaps and sound data of an unpacked image
+struct UnpackedMedia(PathBuf);
+
+impl WzMediaSource for UnpackedMedia {
+    fn canvas(&mut self, path: &str, canvas: &CanvasVal) -> anyhow::Result<Canvas> {
+        let file = self.0.join(path).with_extension("png");
+        let img = image::open(&file)
+            .with_context(|| format!("Canvas bitmap: {}", file.display()))?
+            .into_rgba8();
+        Ok(Canvas::from_rgba_image(&img, canvas.canvas.scale))
+    }
+
+    fn sound(&mut self, path: &str, _sound: &SoundVal) -> anyhow::Result<Vec<u8>> {
+        let file = self.0.join(path).with_extension(UNPACKED_SOUND_EXT);
+        std::fs::read(&file).with_context(|| format!("Sound data: {}", file.display()))
+    }
+}
+
+/// Builds the image blob of an unpacked image directory
+pub fn build_unpacked_img(dir: &Path, cfg: WzConfig) -> anyhow::Result<Vec<u8>> {
+    let json = std::fs::read(dir.join(UNPACKED_IMG_JSON))?;
+    let root: WzValue = serde_json::from_slice(&json)?;
+    let WzValue::Object(root) = root else {
+        anyhow::bail!("Root value must be an object");
+    };
+
+    let mut b = WzImgBuilder::with_cfg(Cursor::new(Vec::new()), cfg);
+    b.write_img(&root, &mut UnpackedMedia(dir.join(UNPACKED_IMG_DATA)))?;
+    Ok(b.into_inner().into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        l0::tree::WzTree,
+        test_util::{build_archive, dir, img, img_blob, obj, str_val, TestVal},
+        val::WzValue,
+        writer::WzArchiveNode,
+        WzReader, GMS95,
+    };
+
+    use super::{WzOverlay, UNPACKED_IMG_DATA, UNPACKED_IMG_JSON, UNPACKED_SOUND_EXT};
+
+    #[test]
+    fn overlay() -> anyhow::Result<()> {
+        let base = build_archive(
+            GMS95,
+            vec![
+                dir(
+                    "Mob",
+                    vec![
+                        img(
+                            "100100.img",
+                            img_blob(GMS95, &obj([("hp", TestVal::Int(10))])),
+                        ),
+                        img(
+                            "100101.img",
+                            img_blob(GMS95, &obj([("hp", TestVal::Int(20))])),
+                        ),
+                    ],
+                ),
+                dir("Old", vec![img("0.img", img_blob(GMS95, &[]))]),
+            ],
+        );
+
+        let layer = std::env::temp_dir().join(format!("shroom-overlay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&layer);
+        std::fs::create_dir_all(layer.join("Mob/100100.img/data"))?;
+        std::fs::write(layer.join(".wh.Old"), [])?;
+        std::fs::write(layer.join("Mob/.wh.100101.img"), [])?;
+        std::fs::write(
+            layer.join("Mob/100102.img"),
+            img_blob(GMS95, &obj([("name", str_val("Snail"))])),
+        )?;
+        // Unpacked image with a canvas
+        std::fs::write(
+            layer.join("Mob/100100.img/img.json"),
+            r#"{"hp": 50, "icon": {"$ty": "canvas", "scale": 0, "sub": {"delay": 120}}}"#,
+        )?;
+        image::RgbaImage::from_pixel(2, 3, [1, 2, 3, 255].into())
+            .save(layer.join("Mob/100100.img/data/icon.png"))?;
+        std::fs::write(
+            layer.join("Mob/100103.img.xml"),
+            r#"<imgdir name="100103.img"><short name="hp" value="70"/></imgdir>"#,
+        )?;
+
+        let mut overlay = WzOverlay::new(GMS95);
+        overlay.push_archive(WzReader::open(Cursor::new(base), GMS95)?)?;
+        overlay.push_dir(&layer)?;
+
+        assert_eq!(
+            overlay.images().collect::<Vec<_>>(),
+            ["Mob/100100.img", "Mob/100102.img", "Mob/100103.img"]
+        );
+        assert!(!overlay.contains("Old"));
+
+        let mut out = Cursor::new(Vec::new());
+        overlay.write_archive(&mut out)?;
+
+        let mut r = WzReader::open(Cursor::new(out.into_inner()), GMS95)?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+        let hdr = tree.get_img_by_path("Mob/100100.img").unwrap().clone();
+        let mut img_r = r.img_reader(&hdr)?;
+        let val = WzValue::read(&mut img_r)?;
+        assert_eq!(val.get_path("hp"), Some(&WzValue::Int(50)));
+        assert_eq!(val.get_path("icon/delay"), Some(&WzValue::Int(120)));
+        let icon = val.get_path("icon").unwrap().as_canvas().unwrap();
+        let icon = icon.read_canvas(&mut img_r)?.to_raw_rgba_image()?;
+        assert_eq!(icon.dimensions(), (2, 3));
+        assert_eq!(icon.get_pixel(1, 2).0, [1, 2, 3, 255]);
+
+        let nodes = overlay.nodes()?;
+        assert_eq!(nodes.len(), 1);
+        let WzArchiveNode::Dir(name, entries) = &nodes[0] else {
+            panic!("expected a directory: {nodes:?}");
+        };
+        assert_eq!(name, "Mob");
+        let WzArchiveNode::Img(img) = &entries[1] else {
+            panic!("expected an image: {entries:?}");
+        };
+        assert_eq!(img.blob, "Mob/100102.img");
+        let val = WzValue::read(&mut overlay.img_reader("Mob/100102.img")?)?;
+        assert_eq!(
+            val.get_path("name").and_then(WzValue::as_string),
+            Some("Snail")
+        );
+        let val = WzValue::read(&mut overlay.img_reader("Mob/100103.img")?)?;
+        assert_eq!(val.get_path("hp"), Some(&WzValue::Short(70)));
+
+        let mut r = overlay.into_reader()?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+        assert!(tree.get_by_path("Old").is_none());
+        assert!(tree.get_by_path("Mob/100101.img").is_none());
+        let hdr = tree.get_img_by_path("Mob/100103.img").unwrap().clone();
+        let val = WzValue::read(&mut r.img_reader(&hdr)?)?;
+        assert_eq!(val.get_path("hp"), Some(&WzValue::Short(70)));
+        std::fs::remove_dir_all(&layer)?;
+        Ok(())
+    }
+
+    #[test]
+    fn unpacked_types() -> anyhow::Result<()> {
+        let blob = img_blob(
+            GMS95,
+            &obj([(
+                "bgm",
+                TestVal::Sound {
+                    data: vec![0xFF, 0xFB, 1, 2, 3],
+                    len_ms: 1500,
+                },
+            )]),
+        );
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img_r = r.root_img_reader()?;
+        let WzValue::Object(mut root) = WzValue::read(&mut img_r)? else {
+            panic!("expected an object");
+        };
+        let sound = root.get("bgm").and_then(WzValue::as_sound).unwrap().clone();
+        root.0.insert("short".to_string(), WzValue::Short(-7));
+        root.0.insert("long".to_string(), WzValue::Long(1 << 40));
+        root.0.insert("f32".to_string(), WzValue::F32(1.5));
+
+        // Unpack the image like the unpack command does
+        let layer = std::env::temp_dir().join(format!("shroom-unpacked-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&layer);
+        let dir = layer.join("Sound/Bgm.img");
+        std::fs::create_dir_all(dir.join(UNPACKED_IMG_DATA))?;
+        std::fs::write(
+            dir.join(UNPACKED_IMG_JSON),
+            serde_json::to_vec(&WzValue::Object(root.clone()))?,
+        )?;
+        std::fs::write(
+            dir.join(UNPACKED_IMG_DATA)
+                .join("bgm")
+                .with_extension(UNPACKED_SOUND_EXT),
+            sound.read_data(&mut img_r)?,
+        )?;
+
+        let mut overlay = WzOverlay::<Cursor<Vec<u8>>>::new(GMS95);
+        overlay.push_dir(&layer)?;
+        let mut img_r = overlay.img_reader("Sound/Bgm.img")?;
+        let val = WzValue::read(&mut img_r)?;
+        assert_eq!(val.get_path("short"), Some(&WzValue::Short(-7)));
+        assert_eq!(val.get_path("long"), Some(&WzValue::Long(1 << 40)));
+        assert_eq!(val.get_path("f32"), Some(&WzValue::F32(1.5)));
+        let bgm = val.get_path("bgm").and_then(WzValue::as_sound).unwrap();
+        assert_eq!(bgm.sound.len_ms, sound.sound.len_ms);
+        assert_eq!(bgm.read_data(&mut img_r)?, [0xFF, 0xFB, 1, 2, 3]);
+        std::fs::remove_dir_all(&layer)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+//! Binary patches between two archives.
+//!
+//! A patch lists the directory layout of the target archive in pre-order. Unchanged
+//! images are copied from the old archive, changed and new images carry their blob.
+//! Every image taken from or replaced in the old archive is guarded by It's old size
+//! and checksum, so a patch is only applied to the archive It was generated from.
+//! Blobs are copied as they are, so patches work for every `WzConfig`.
+
+use std::{
+    collections::HashMap,
+    io::{Read, Seek, Write},
+};
+
+use binrw::{binrw, BinRead, BinWrite, NullString};
+
+use crate::{
+    file::WzIO,
+    l0::{tree::WzTree, WzDirNode, WzImgHeader},
+    util::wz_checksum,
+    writer::{WzArchiveImg, WzArchiveNode, WzArchiveWriter},
+    WzConfig, WzReader,
+};
+
+/// Utf-8 string with a length prefix
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WzPatchStr {
+    #[bw(calc = data.len() as u32)]
+    len: u32,
+    #[br(count = len)]
+    data: Vec<u8>,
+}
+
+impl WzPatchStr {
+    pub fn new(s: &str) -> Self {
+        Self {
+            data: s.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn as_str(&self) -> anyhow::Result<&str> {
+        Ok(std::str::from_utf8(&self.data)?)
+    }
+}
+
+/// Size and checksum of an image in the old archive
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WzPatchGuard {
+    pub size: u32,
+    pub checksum: i32,
+}
+
+impl From<&WzImgHeader> for WzPatchGuard {
+    fn from(hdr: &WzImgHeader) -> Self {
+        Self {
+            size: hdr.blob_size.0 as u32,
+            checksum: hdr.checksum.0,
+        }
+    }
+}
+
+/// Entry of the target archive
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub enum WzPatchEntry {
+    #[brw(magic(0u8))]
+    Dir { path: WzPatchStr },
+    /// Image copied from the old archive
+    #[brw(magic(1u8))]
+    Keep { path: WzPatchStr, old: WzPatchGuard },
+    /// New image, which must not exist in the old archive
+    #[brw(magic(2u8))]
+    Add {
+        path: WzPatchStr,
+        #[bw(calc = data.len() as u32)]
+        len: u32,
+        #[br(count = len)]
+        data: Vec<u8>,
+    },
+    /// Image replacing the image of the old archive
+    #[brw(magic(3u8))]
+    Replace {
+        path: WzPatchStr,
+        old: WzPatchGuard,
+        #[bw(calc = data.len() as u32)]
+        len: u32,
+        #[br(count = len)]
+        data: Vec<u8>,
+    },
+}
+
+/// Image of the old archive, which is not part of the target
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone)]
+pub struct WzPatchDelete {
+    pub path: WzPatchStr,
+    pub old: WzPatchGuard,
+}
+
+#[binrw]
+#[brw(little, magic = b"WZPT")]
+#[derive(Debug, Clone)]
+pub struct WzPatch {
+    #[br(assert(version == WzPatch::VERSION, "Unsupported patch version: {}", version))]
+    pub version: u16,
+    /// Description of the target archive header
+    pub desc: NullString,
+    #[bw(calc = entries.len() as u32)]
+    entry_count: u32,
+    #[br(count = entry_count)]
+    pub entries: Vec<WzPatchEntry>,
+    #[bw(calc = deleted.len() as u32)]
+    deleted_count: u32,
+    #[br(count = deleted_count)]
+    pub deleted: Vec<WzPatchDelete>,
+}
+
+/// Blob of an image in the target archive
+enum TargetBlob<'a> {
+    Old(&'a WzImgHeader),
+    Patch(&'a [u8]),
+}
+
+impl WzPatch {
+    pub const VERSION: u16 = 1;
+
+    /// Generates the patch from the `old` to the `new` archive
+    pub fn generate<R1: WzIO, R2: WzIO>(
+        old: &mut WzReader<R1>,
+        new: &mut WzReader<R2>,
+    ) -> anyhow::Result<Self> {
+        let old_tree = WzTree::from_reader(old, None)?;
+        let new_tree = WzTree::from_reader(new, None)?;
+        let old_imgs = old_tree.images().collect::<HashMap<_, _>>();
+        let new_imgs = new_tree.images().collect::<HashMap<_, _>>();
+
+        let mut entries = Vec::new();
+        for (path, node) in new_tree.nodes() {
+            let link_hdr;
+            let hdr = match node {
+                WzDirNode::Dir(_) => {
+                    entries.push(WzPatchEntry::Dir {
+                        path: WzPatchStr::new(&path),
+                    });
+                    continue;
+                }
+                WzDirNode::Img(hdr) => hdr,
+                WzDirNode::Link(link) => {
+                    link_hdr = link.img_header();
+                    &link_hdr
+                }
+                WzDirNode::Nil(_) => continue,
+            };
+
+            let entry = match old_imgs.get(&path) {
+                Some(old_hdr) => {
+                    let old_guard = WzPatchGuard::from(old_hdr);
+                    let data = new.read_img_blob(hdr)?;
+                    if old_guard == WzPatchGuard::from(hdr) && old.read_img_blob(old_hdr)? == data {
+                        WzPatchEntry::Keep {
+                            path: WzPatchStr::new(&path),
+                            old: old_guard,
+                        }
+                    } else {
+                        WzPatchEntry::Replace {
+                            path: WzPatchStr::new(&path),
+                            old: old_guard,
+                            data,
+                        }
+                    }
+                }
+                None => WzPatchEntry::Add {
+                    path: WzPatchStr::new(&path),
+                    data: new.read_img_blob(hdr)?,
+                },
+            };
+            entries.push(entry);
+        }
+
+        let deleted = old_tree
+            .images()
+            .filter(|(path, _)| !new_imgs.contains_key(path))
+            .map(|(path, hdr)| WzPatchDelete {
+                path: WzPatchStr::new(&path),
+                old: (&hdr).into(),
+            })
+            .collect();
+
+        Ok(Self {
+            version: Self::VERSION,
+            desc: new.read_header()?.desc,
+            entries,
+            deleted,
+        })
+    }
+
+    pub fn read_from<R: Read + Seek>(r: &mut R) -> anyhow::Result<Self> {
+        Ok(Self::read(r)?)
+    }
+
+    pub fn write_to<W: Write + Seek>(&self, w: &mut W) -> anyhow::Result<()> {
+        Ok(self.write(w)?)
+    }
+
+    /// Number of changed, new and deleted images
+    pub fn changes(&self) -> usize {
+        let changed = self
+            .entries
+            .iter()
+            .filter(|e| matches!(e, WzPatchEntry::Add { .. } | WzPatchEntry::Replace { .. }))
+            .count();
+        changed + self.deleted.len()
+    }
+
+    /// Writes the target archive, fails before writing anything If the old archive
+    /// doesn't match the guards of the patch. Copied images are verified while they
+    /// are written, so a partial output must be discarded on error
+    pub fn apply<R: WzIO, W: Write + Seek>(
+        &self,
+        old: &mut WzReader<R>,
+        cfg: WzConfig,
+        w: &mut W,
+    ) -> anyhow::Result<()> {
+        let old_tree = WzTree::from_reader(old, None)?;
+        let old_imgs = old_tree.images().collect::<HashMap<_, _>>();
+
+        let guarded = |path: &str, guard: &WzPatchGuard| -> anyhow::Result<&WzImgHeader> {
+            match old_imgs.get(path) {
+                Some(hdr) if WzPatchGuard::from(hdr) == *guard => Ok(hdr),
+                Some(hdr) => anyhow::bail!(
+                    "Checksum mismatch for {path}: expected {}, found {}",
+                    guard.checksum,
+                    hdr.checksum.0
+                ),
+                None => anyhow::bail!("Image missing in the old archive: {path}"),
+            }
+        };
+        for del in self.deleted.iter() {
+            guarded(del.path.as_str()?, &del.old)?;
+        }
+
+        // Rebuild the directory tree from the pre-order entries
+        let mut stack: Vec<(String, Vec<WzArchiveNode<TargetBlob>>)> =
+            vec![(String::new(), Vec::new())];
+        let close = |stack: &mut Vec<(String, Vec<WzArchiveNode<TargetBlob<'_>>>)>| {
+            let (path, entries) = stack.pop().unwrap();
+            let name = path.rsplit('/').next().unwrap_or_default().to_string();
+            stack
+                .last_mut()
+                .unwrap()
+                .1
+                .push(WzArchiveNode::Dir(name, entries));
+        };
+
+        for entry in self.entries.iter() {
+            let (path, img) = match entry {
+                WzPatchEntry::Dir { path } => (path.as_str()?, None),
+                WzPatchEntry::Keep { path, old } => {
+                    let path = path.as_str()?;
+                    let hdr = guarded(path, old)?;
+                    (path, Some((old.size, old.checksum, TargetBlob::Old(hdr))))
+                }
+                WzPatchEntry::Replace { path, old, data } => {
+                    let path = path.as_str()?;
+                    guarded(path, old)?;
+                    let checksum = wz_checksum(0, data);
+                    (
+                        path,
+                        Some((data.len() as u32, checksum, TargetBlob::Patch(data))),
+                    )
+                }
+                WzPatchEntry::Add { path, data } => {
+                    let path = path.as_str()?;
+                    if old_imgs.contains_key(path) {
+                        anyhow::bail!("Added image already exists in the old archive: {path}");
+                    }
+                    let checksum = wz_checksum(0, data);
+                    (
+                        path,
+                        Some((data.len() as u32, checksum, TargetBlob::Patch(data))),
+                    )
+                }
+            };
+
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+            while stack.last().unwrap().0 != parent {
+                if stack.len() == 1 {
+                    anyhow::bail!("Entry outside of It's directory: {path}");
+                }
+                close(&mut stack);
+            }
+
+            match img {
+                None => stack.push((path.to_string(), Vec::new())),
+                Some((size, checksum, blob)) => {
+                    stack
+                        .last_mut()
+                        .unwrap()
+                        .1
+                        .push(WzArchiveNode::Img(WzArchiveImg {
+                            name: name.to_string(),
+                            size,
+                            checksum,
+                            blob,
+                        }))
+                }
+            }
+        }
+        while stack.len() > 1 {
+            close(&mut stack);
+        }
+        let root = stack.pop().unwrap().1;
+
+        let desc = self.desc.to_string();
+        WzArchiveWriter::new(cfg)
+            .with_desc(desc)
+            .write_with(w, &root, |blob, w| {
+                match blob {
+                    TargetBlob::Old(hdr) => {
+                        let data = old.read_img_blob(hdr)?;
+                        if wz_checksum(0, &data) != hdr.checksum.0 {
+                            anyhow::bail!("Corrupt image in the old archive: {}", hdr.name);
+                        }
+                        w.write_all(&data)?;
+                    }
+                    TargetBlob::Patch(data) => w.write_all(data)?,
+                }
+                Ok(())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        diff::diff_archives,
+        l0::tree::WzTree,
+        test_util::{build_archive, dir, img, img_blob, link_archive, obj, str_val, TestVal},
+        val::WzValue,
+        version::WzRegion,
+        WzConfig, WzReader, GMS95,
+    };
+
+    use super::WzPatch;
+
+    #[test]
+    fn generate_apply() -> anyhow::Result<()> {
+        // Patches don't depend on the config
+        let cfg = WzConfig::new(WzRegion::SEA, 83);
+        let old = build_archive(
+            cfg,
+            vec![
+                dir(
+                    "Mob",
+                    vec![
+                        img("0.img", img_blob(cfg, &obj([("hp", TestVal::Int(1))]))),
+                        img("1.img", img_blob(cfg, &obj([("hp", TestVal::Int(2))]))),
+                    ],
+                ),
+                img("gone.img", img_blob(cfg, &[])),
+            ],
+        );
+        let new = build_archive(
+            cfg,
+            vec![
+                dir(
+                    "Mob",
+                    vec![
+                        img("0.img", img_blob(cfg, &obj([("hp", TestVal::Int(1))]))),
+                        img("1.img", img_blob(cfg, &obj([("hp", TestVal::Int(3))]))),
+                        dir("Sub", vec![img("2.img", img_blob(cfg, &[]))]),
+                    ],
+                ),
+                img("new.img", img_blob(cfg, &obj([("name", str_val("x"))]))),
+            ],
+        );
+
+        let patch = WzPatch::generate(
+            &mut WzReader::open(Cursor::new(old.clone()), cfg)?,
+            &mut WzReader::open(Cursor::new(new.clone()), cfg)?,
+        )?;
+        assert_eq!(patch.changes(), 4);
+
+        let mut buf = Cursor::new(Vec::new());
+        patch.write_to(&mut buf)?;
+        buf.set_position(0);
+        let patch = WzPatch::read_from(&mut buf)?;
+
+        let mut out = Cursor::new(Vec::new());
+        patch.apply(&mut WzReader::open(Cursor::new(old), cfg)?, cfg, &mut out)?;
+        let diff = diff_archives(
+            &mut WzReader::open(Cursor::new(new.clone()), cfg)?,
+            &mut WzReader::open(Cursor::new(out.into_inner()), cfg)?,
+        )?;
+        assert!(diff.is_empty(), "{diff:?}");
+
+        // The target doesn't match the guards
+        let mut out = Cursor::new(Vec::new());
+        let res = patch.apply(&mut WzReader::open(Cursor::new(new), cfg)?, cfg, &mut out);
+        assert!(res.is_err());
+        assert!(out.into_inner().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn linked_images() -> anyhow::Result<()> {
+        let blob = |hp| img_blob(GMS95, &obj([("hp", TestVal::Int(hp))]));
+        let data = link_archive(GMS95, blob(1), blob(2));
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+
+        // The tree yields the header of the link, not the one of the linked entry
+        let tree = WzTree::from_reader(&mut r, None)?;
+        let (path, hdr) = tree.images().nth(1).unwrap();
+        assert_eq!(path, "Link/a.img");
+        let val = WzValue::read(&mut r.img_reader(&hdr)?)?;
+        assert_eq!(val.get_path("hp"), Some(&WzValue::Int(2)));
+
+        // Patch from an empty archive carries the blob of the link
+        let empty = build_archive(GMS95, Vec::new());
+        let patch = WzPatch::generate(
+            &mut WzReader::open(Cursor::new(empty.clone()), GMS95)?,
+            &mut r,
+        )?;
+        let mut out = Cursor::new(Vec::new());
+        patch.apply(
+            &mut WzReader::open(Cursor::new(empty), GMS95)?,
+            GMS95,
+            &mut out,
+        )?;
+        let mut patched = WzReader::open(Cursor::new(out.into_inner()), GMS95)?;
+        let tree = WzTree::from_reader(&mut patched, None)?;
+        let hdr = tree.get_img_by_path("Link/a.img").unwrap().clone();
+        let val = WzValue::read(&mut patched.img_reader(&hdr)?)?;
+        assert_eq!(val.get_path("hp"), Some(&WzValue::Int(2)));
+        Ok(())
+    }
+}
@@ -0,0 +1,638 @@
+//! Path queries over values and archives.
+//!
+//! A query is a slash-separated list of segments like `Mob/*.img/info[level>100]/maxHP`:
+//! * a name pattern, `*` matches any number of characters and `?` a single one
+//! * `**` matches any number of nested entries, including none
+//! * predicates in brackets filter the matched entries by a property path relative
+//!   to the entry: `[path]` requires the property to exist, `[path=value]` compares it
+//!   with `=`, `!=`, `<`, `<=`, `>` or `>=` and `[path=100..200]` checks an inclusive
+//!   numeric range, either end may be left out. String values may be quoted
+//! * a trailing projection like `{name,info/level}` picks properties of every match
+//!
+//! Directories and images of an archive are matched like objects, so a query over a
+//! `WzTree` continues into the images.
+
+use std::{borrow::Cow, cmp::Ordering, str::FromStr};
+
+use id_tree::NodeId;
+
+use crate::{
+    file::WzIO,
+    l0::{tree::WzTree, WzDirNode},
+    util::join_path,
+    val::WzValue,
+    WzReader,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Num(f64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    /// Operators, two character operators first so they take precedence
+    const ALL: [(&'static str, CmpOp); 6] = [
+        ("!=", CmpOp::Ne),
+        ("<=", CmpOp::Le),
+        (">=", CmpOp::Ge),
+        ("=", CmpOp::Eq),
+        ("<", CmpOp::Lt),
+        (">", CmpOp::Gt),
+    ];
+
+    fn test(self, ord: Ordering) -> bool {
+        match self {
+            CmpOp::Eq => ord.is_eq(),
+            CmpOp::Ne => ord.is_ne(),
+            CmpOp::Lt => ord.is_lt(),
+            CmpOp::Le => ord.is_le(),
+            CmpOp::Gt => ord.is_gt(),
+            CmpOp::Ge => ord.is_ge(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Cond {
+    Exists,
+    Cmp(CmpOp, Literal),
+    Range(Option<f64>, Option<f64>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Predicate {
+    path: String,
+    cond: Cond,
+}
+
+impl Predicate {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let op = s.char_indices().find_map(|(i, _)| {
+            CmpOp::ALL
+                .iter()
+                .find(|(op, _)| s[i..].starts_with(op))
+                .map(|(op, cmp)| (i, op.len(), *cmp))
+        });
+        let Some((i, op_len, op)) = op else {
+            return Ok(Self {
+                path: s.trim().to_string(),
+                cond: Cond::Exists,
+            });
+        };
+
+        let path = s[..i].trim().to_string();
+        if path.is_empty() {
+            anyhow::bail!("Missing property path in predicate: [{s}]");
+        }
+        let lit = s[i + op_len..].trim();
+        let cond = match parse_range(lit) {
+            Some((lo, hi)) if op == CmpOp::Eq => Cond::Range(lo, hi),
+            Some(_) => anyhow::bail!("Ranges only support `=`: [{s}]"),
+            None => Cond::Cmp(op, parse_literal(lit)),
+        };
+        Ok(Self { path, cond })
+    }
+
+    fn test(&self, val: &WzValue) -> bool {
+        let Some(v) = val.get_path(&self.path) else {
+            return false;
+        };
+        match &self.cond {
+            Cond::Exists => true,
+            Cond::Cmp(op, Literal::Num(n)) => num_val(v)
+                .and_then(|v| v.partial_cmp(n))
+                .is_some_and(|ord| op.test(ord)),
+            Cond::Cmp(op, Literal::Str(s)) => {
+                str_val(v).is_some_and(|v| op.test(v.as_ref().cmp(s.as_str())))
+            }
+            Cond::Range(lo, hi) => num_val(v)
+                .is_some_and(|v| lo.is_none_or(|lo| v >= lo) && hi.is_none_or(|hi| v <= hi)),
+        }
+    }
+}
+
+/// Parses `lo..hi`, where either end may be empty
+fn parse_range(s: &str) -> Option<(Option<f64>, Option<f64>)> {
+    let (lo, hi) = s.split_once("..")?;
+    let bound = |s: &str| -> Option<Option<f64>> {
+        let s = s.trim();
+        if s.is_empty() {
+            Some(None)
+        } else {
+            s.parse().ok().map(Some)
+        }
+    };
+    Some((bound(lo)?, bound(hi)?))
+}
+
+fn parse_literal(s: &str) -> Literal {
+    for quote in ['"', '\''] {
+        if let Some(s) = s.strip_prefix(quote).and_then(|s| s.strip_suffix(quote)) {
+            return Literal::Str(s.to_string());
+        }
+    }
+    s.parse()
+        .map(Literal::Num)
+        .unwrap_or_else(|_| Literal::Str(s.to_string()))
+}
+
+/// Numeric value, numbers stored as strings are parsed
+fn num_val(v: &WzValue) -> Option<f64> {
+    match v {
+        WzValue::Short(v) => Some(*v as f64),
+        WzValue::Int(v) => Some(*v as f64),
+        WzValue::Long(v) => Some(*v as f64),
+        WzValue::F32(v) => Some(*v as f64),
+        WzValue::F64(v) => Some(*v),
+        WzValue::String(v) => v.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// String value, numbers are formatted
+fn str_val(v: &WzValue) -> Option<Cow<'_, str>> {
+    match v {
+        WzValue::String(v) | WzValue::Link(v) => Some(Cow::Borrowed(v)),
+        WzValue::Short(v) => Some(v.to_string().into()),
+        WzValue::Int(v) => Some(v.to_string().into()),
+        WzValue::Long(v) => Some(v.to_string().into()),
+        WzValue::F32(v) => Some(v.to_string().into()),
+        WzValue::F64(v) => Some(v.to_string().into()),
+        _ => None,
+    }
+}
+
+/// Matches the name against a pattern with `*` and `?` wildcards
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n) = (pattern.as_bytes(), name.as_bytes());
+    let (mut pi, mut ni) = (0, 0);
+    // Position after the last `*` and the name position it matched up to
+    let mut star = None;
+    while ni < n.len() {
+        match p.get(pi) {
+            Some(b'*') => {
+                star = Some((pi + 1, ni));
+                pi += 1;
+            }
+            Some(b'?') => {
+                pi += 1;
+                ni += 1;
+            }
+            Some(c) if *c == n[ni] => {
+                pi += 1;
+                ni += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    pi = sp;
+                    ni = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|c| *c == b'*')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    /// `**`
+    Any,
+    Name {
+        pattern: String,
+        preds: Vec<Predicate>,
+    },
+}
+
+/// Parsed path query
+#[derive(Debug, Clone, PartialEq)]
+pub struct WzQuery {
+    segments: Vec<Segment>,
+    fields: Vec<String>,
+}
+
+/// Matched value with It's path and the projected properties which exist
+#[derive(Debug, Clone)]
+pub struct WzQueryMatch<'a> {
+    pub path: String,
+    pub value: &'a WzValue,
+    pub fields: Vec<(String, &'a WzValue)>,
+}
+
+impl FromStr for WzQuery {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// State of a query over an archive tree
+struct TreeEval<'a, R> {
+    r: &'a mut WzReader<R>,
+    tree: &'a WzTree,
+    /// Images which couldn't be read
+    skipped: Vec<(String, anyhow::Error)>,
+}
+
+impl WzQuery {
+    pub fn parse(query: &str) -> anyhow::Result<Self> {
+        let mut segments = Vec::new();
+        let mut fields = Vec::new();
+        let mut rest = query.trim().trim_start_matches('/');
+
+        loop {
+            let end = rest.find(['/', '[', '{']).unwrap_or(rest.len());
+            let pattern = &rest[..end];
+            rest = &rest[end..];
+            if pattern.is_empty() {
+                anyhow::bail!("Empty segment in query: {query}");
+            }
+
+            let mut preds = Vec::new();
+            while let Some(pred) = rest.strip_prefix('[') {
+                let end = pred
+                    .find(']')
+                    .ok_or_else(|| anyhow::format_err!("Unclosed predicate in query: {query}"))?;
+                preds.push(Predicate::parse(&pred[..end])?);
+                rest = &pred[end + 1..];
+            }
+
+            if pattern == "**" {
+                if !preds.is_empty() {
+                    anyhow::bail!("`**` can't have predicates: {query}");
+                }
+                // Consecutive `**` match the same entries
+                if segments.last() != Some(&Segment::Any) {
+                    segments.push(Segment::Any);
+                }
+            } else {
+                segments.push(Segment::Name {
+                    pattern: pattern.to_string(),
+                    preds,
+                });
+            }
+
+            if let Some(proj) = rest.strip_prefix('{') {
+                let proj = proj
+                    .strip_suffix('}')
+                    .ok_or_else(|| anyhow::format_err!("Projection must end the query: {query}"))?;
+                fields = proj
+                    .split(',')
+                    .map(|f| f.trim().to_string())
+                    .filter(|f| !f.is_empty())
+                    .collect();
+                break;
+            }
+
+            match rest.strip_prefix('/') {
+                Some(next) => rest = next,
+                None if rest.is_empty() => break,
+                None => anyhow::bail!("Unexpected `{rest}` in query: {query}"),
+            }
+        }
+
+        Ok(Self { segments, fields })
+    }
+
+    /// Matches the entries below the `root` value
+    pub fn eval<'a>(&self, root: &'a WzValue) -> Vec<WzQueryMatch<'a>> {
+        let mut matches = Vec::new();
+        self.eval_value(&self.start(), "", root, &mut |m| matches.push(m));
+        matches
+    }
+
+    /// Matches the entries of the fully loaded `tree`, images are read when the query
+    /// can match inside of them. Matches are passed to `f`, since values of an image are
+    /// dropped after It was searched. Images which can't be read are skipped and
+    /// returned with their error
+    pub fn eval_tree<R: WzIO>(
+        &self,
+        r: &mut WzReader<R>,
+        tree: &WzTree,
+        mut f: impl FnMut(WzQueryMatch<'_>),
+    ) -> anyhow::Result<Vec<(String, anyhow::Error)>> {
+        let mut t = TreeEval {
+            r,
+            tree,
+            skipped: Vec::new(),
+        };
+        if let Some(root) = tree.get_tree().root_node_id() {
+            self.eval_dir(&mut t, root, "", &self.start(), &mut f)?;
+        }
+        Ok(t.skipped)
+    }
+
+    fn eval_dir<R: WzIO>(
+        &self,
+        t: &mut TreeEval<'_, R>,
+        id: &NodeId,
+        path: &str,
+        states: &[usize],
+        f: &mut dyn FnMut(WzQueryMatch<'_>),
+    ) -> anyhow::Result<()> {
+        let tree = t.tree;
+        for child_id in tree.get_tree().children_ids(id)? {
+            let node = tree.get_tree().get(child_id)?.data();
+            let Some(name) = node.name() else {
+                continue;
+            };
+            let child_path = join_path(path, name);
+            let hdr = match node {
+                WzDirNode::Dir(_) => {
+                    let next = self.step(states, name, None);
+                    if !next.is_empty() {
+                        self.eval_dir(t, child_id, &child_path, &next, f)?;
+                    }
+                    continue;
+                }
+                WzDirNode::Img(hdr) => hdr.clone(),
+                WzDirNode::Link(link) => link.img_header(),
+                WzDirNode::Nil(_) => continue,
+            };
+
+            // Only read the image If a segment can match it
+            if !self.may_match(states, name) {
+                continue;
+            }
+            let val = match t
+                .r
+                .img_reader(&hdr)
+                .map_err(anyhow::Error::from)
+                .and_then(|img_r| WzValue::read(&mut img_r.with_path(&child_path)))
+            {
+                Ok(val) => val,
+                Err(err) => {
+                    t.skipped.push((child_path, err));
+                    continue;
+                }
+            };
+            let next = self.step(states, name, Some(&val));
+            if next.contains(&self.segments.len()) {
+                f(self.to_match(child_path.clone(), &val));
+            }
+            self.eval_value(&next, &child_path, &val, f);
+        }
+        Ok(())
+    }
+
+    fn eval_value<'a>(
+        &self,
+        states: &[usize],
+        path: &str,
+        val: &'a WzValue,
+        f: &mut dyn FnMut(WzQueryMatch<'a>),
+    ) {
+        let entries = match val {
+            WzValue::Object(obj) => obj,
+            WzValue::Canvas(canvas) => match canvas.sub.as_deref() {
+                Some(WzValue::Object(obj)) => obj,
+                _ => return,
+            },
+            _ => return,
+        };
+
+        for (name, child) in entries.0.iter() {
+            let next = self.step(states, name, Some(child));
+            if next.is_empty() {
+                continue;
+            }
+            let child_path = join_path(path, name);
+            if next.contains(&self.segments.len()) {
+                f(self.to_match(child_path.clone(), child));
+            }
+            self.eval_value(&next, &child_path, child, f);
+        }
+    }
+
+    /// Initial states, a state is the index of the next segment to match
+    fn start(&self) -> Vec<usize> {
+        let mut states = vec![0];
+        self.close(&mut states);
+        states
+    }
+
+    /// Adds the states after every `**`, since It may match no entry
+    fn close(&self, states: &mut Vec<usize>) {
+        let mut i = 0;
+        while i < states.len() {
+            let s = states[i];
+            if self.segments.get(s) == Some(&Segment::Any) && !states.contains(&(s + 1)) {
+                states.push(s + 1);
+            }
+            i += 1;
+        }
+    }
+
+    /// States after matching the entry with the `name` and the value, If there's one
+    fn step(&self, states: &[usize], name: &str, val: Option<&WzValue>) -> Vec<usize> {
+        let mut next = Vec::new();
+        for &s in states {
+            let matched = match self.segments.get(s) {
+                Some(Segment::Any) => Some(s),
+                Some(Segment::Name { pattern, preds }) if glob_match(pattern, name) => preds
+                    .iter()
+                    .all(|p| val.is_some_and(|v| p.test(v)))
+                    .then_some(s + 1),
+                _ => None,
+            };
+            if let Some(s) = matched.filter(|s| !next.contains(s)) {
+                next.push(s);
+            }
+        }
+        self.close(&mut next);
+        next
+    }
+
+    /// Checks whether a segment may match the entry, ignoring predicates
+    fn may_match(&self, states: &[usize], name: &str) -> bool {
+        states.iter().any(|&s| match self.segments.get(s) {
+            Some(Segment::Any) => true,
+            Some(Segment::Name { pattern, .. }) => glob_match(pattern, name),
+            None => false,
+        })
+    }
+
+    fn to_match<'a>(&self, path: String, value: &'a WzValue) -> WzQueryMatch<'a> {
+        let fields = self
+            .fields
+            .iter()
+            .filter_map(|field| value.get_path(field).map(|v| (field.clone(), v)))
+            .collect();
+        WzQueryMatch {
+            path,
+            value,
+            fields,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use indexmap::indexmap;
+
+    use crate::{
+        l0::tree::WzTree,
+        test_util::{build_archive, dir, img, img_blob, link_archive, obj, str_val, TestVal},
+        val::WzValue,
+        WzReader, GMS95,
+    };
+
+    use super::{glob_match, WzQuery};
+
+    fn paths(query: &str, root: &WzValue) -> Vec<String> {
+        let query = WzQuery::parse(query).unwrap();
+        query.eval(root).into_iter().map(|m| m.path).collect()
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*.img", "100100.img"));
+        assert!(glob_match("1?0*", "100100.img"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.img", "100100.img.bak"));
+        assert!(glob_match("*a*b", "xaxxab"));
+    }
+
+    #[test]
+    fn query_value() {
+        let mob = |level: i32, name: &str| {
+            WzValue::from(indexmap! {
+                "info".to_string() => WzValue::from(indexmap! {
+                    "level".to_string() => WzValue::Int(level),
+                    "name".to_string() => WzValue::String(name.to_string()),
+                    "maxHP".to_string() => WzValue::String(format!("{}", level * 10)),
+                }),
+            })
+        };
+        let root = WzValue::from(indexmap! {
+            "0.img".to_string() => mob(10, "Snail"),
+            "1.img".to_string() => mob(120, "Boss"),
+            "2.img".to_string() => mob(150, "Other"),
+        });
+
+        assert_eq!(
+            paths("*.img/info[level>100]/maxHP", &root),
+            ["1.img/info/maxHP", "2.img/info/maxHP"]
+        );
+        assert_eq!(
+            paths("*/info[maxHP<=1200]", &root),
+            ["0.img/info", "1.img/info"]
+        );
+        assert_eq!(paths("*[info/level=..100]", &root), ["0.img"]);
+        assert_eq!(paths("*/info[level=100..149]", &root), ["1.img/info"]);
+        assert_eq!(
+            paths("**/info[name='Snail']/level", &root),
+            ["0.img/info/level"]
+        );
+        assert_eq!(
+            paths("**/*[name!=Snail]", &root),
+            ["1.img/info", "2.img/info"]
+        );
+        assert_eq!(paths("**/**/name", &root).len(), 3);
+        assert!(paths("*/info[missing]", &root).is_empty());
+
+        let query = WzQuery::parse("0.img/info{name, level, missing}").unwrap();
+        let matches = query.eval(&root);
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            matches[0].fields,
+            [
+                ("name".to_string(), &WzValue::String("Snail".to_string())),
+                ("level".to_string(), &WzValue::Int(10))
+            ]
+        );
+
+        for invalid in ["a//b", "a[b", "**[a]", "a{b}/c", "a[=1]", "a[b<1..2]"] {
+            assert!(WzQuery::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn query_tree() -> anyhow::Result<()> {
+        let mob = |level| {
+            img_blob(
+                GMS95,
+                &obj([(
+                    "info",
+                    TestVal::Obj(obj([
+                        ("level", TestVal::Int(level)),
+                        ("icon", str_val("x")),
+                    ])),
+                )]),
+            )
+        };
+        let data = build_archive(
+            GMS95,
+            vec![
+                dir("Mob", vec![img("0.img", mob(10)), img("1.img", mob(120))]),
+                dir("Item", vec![dir("Etc", vec![img("2.img", mob(1))])]),
+                dir("Bad", vec![img("3.img", vec![0xFF; 16])]),
+            ],
+        );
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+
+        let eval = |r: &mut WzReader<_>, query: &str| -> anyhow::Result<Vec<String>> {
+            let mut paths = Vec::new();
+            let skipped = WzQuery::parse(query)?.eval_tree(r, &tree, |m| paths.push(m.path))?;
+            paths.extend(
+                skipped
+                    .into_iter()
+                    .map(|(path, _)| format!("skipped {path}")),
+            );
+            Ok(paths)
+        };
+        assert_eq!(
+            eval(&mut r, "Mob/*.img/info[level>100]/level")?,
+            ["Mob/1.img/info/level"]
+        );
+        assert_eq!(
+            eval(&mut r, "**/info/icon")?,
+            [
+                "Mob/0.img/info/icon",
+                "Mob/1.img/info/icon",
+                "Item/Etc/2.img/info/icon",
+                "skipped Bad/3.img"
+            ]
+        );
+        assert_eq!(eval(&mut r, "Item/**/*.img")?, ["Item/Etc/2.img"]);
+        Ok(())
+    }
+
+    #[test]
+    fn linked_images() -> anyhow::Result<()> {
+        let blob = |hp| img_blob(GMS95, &obj([("hp", TestVal::Int(hp))]));
+        let data = link_archive(GMS95, blob(1), blob(2));
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+
+        let mut hps = Vec::new();
+        let skipped = WzQuery::parse("**/hp")?.eval_tree(&mut r, &tree, |m| {
+            hps.push((m.path, m.value.clone()));
+        })?;
+        assert!(skipped.is_empty());
+        assert_eq!(
+            hps,
+            [
+                ("a.img/hp".to_string(), WzValue::Int(1)),
+                ("Link/a.img/hp".to_string(), WzValue::Int(2))
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,606 @@
+//! Full-text search index over the images of an archive.
+//!
+//! The index maps terms to the properties they appear in, terms are the keys, string
+//! values, numbers and UOLs of all properties. Terms are stored lowercase and sorted,
+//! so exact and prefix queries are a binary search. Strings with several words are
+//! also indexed by every word.
+//!
+//! Every image is guarded by It's size and checksum, so updating the index only reads
+//! the images which changed since the last build.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use binrw::{binrw, BinRead, BinResult, BinWrite};
+
+use crate::{file::WzIO, l0::WzImgHeader, val::WzValue, WzReader};
+
+/// Reads a count of items with at least `size` bytes each, the items must fit
+/// into the file up to `end`. So a corrupt count fails before anything is allocated
+#[binrw::parser(reader, endian)]
+fn read_count(size: u64, end: u64) -> BinResult<u32> {
+    let count = u32::read_options(reader, endian, ())?;
+    let pos = reader.stream_position()?;
+    if count as u64 * size > end.saturating_sub(pos) {
+        return Err(binrw::Error::AssertFail {
+            pos,
+            message: format!("Count {count} exceeds the index file"),
+        });
+    }
+    Ok(count)
+}
+
+/// Utf-8 string with a length prefix
+#[binrw]
+#[brw(little)]
+#[br(import(end: u64))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WzIndexStr {
+    #[br(parse_with = read_count, args(1, end))]
+    #[bw(calc = data.len() as u32)]
+    len: u32,
+    #[br(count = len)]
+    data: Vec<u8>,
+}
+
+impl WzIndexStr {
+    /// Smallest size of an encoded string
+    const MIN_SIZE: u64 = 4;
+
+    pub fn new(s: &str) -> Self {
+        Self {
+            data: s.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn as_str(&self) -> anyhow::Result<&str> {
+        Ok(std::str::from_utf8(&self.data)?)
+    }
+}
+
+/// Size and checksum of an indexed image
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WzIndexGuard {
+    pub size: u32,
+    pub checksum: i32,
+}
+
+impl From<&WzImgHeader> for WzIndexGuard {
+    fn from(hdr: &WzImgHeader) -> Self {
+        Self {
+            size: hdr.blob_size.0 as u32,
+            checksum: hdr.checksum.0,
+        }
+    }
+}
+
+/// Kind of the property part, which contains the term
+#[binrw]
+#[brw(little, repr = u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WzIndexKind {
+    Key = 0,
+    String = 1,
+    Number = 2,
+    Link = 3,
+}
+
+impl std::fmt::Display for WzIndexKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WzIndexKind::Key => "key",
+            WzIndexKind::String => "string",
+            WzIndexKind::Number => "number",
+            WzIndexKind::Link => "link",
+        })
+    }
+}
+
+#[binrw]
+#[brw(little)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct WzIndexHit {
+    /// Index into `WzSearchIndex::imgs`
+    pub img: u32,
+    /// Index into the props of the image
+    pub prop: u32,
+    pub kind: WzIndexKind,
+}
+
+impl WzIndexHit {
+    const SIZE: u64 = 9;
+}
+
+#[binrw]
+#[brw(little)]
+#[br(import(end: u64))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct WzIndexImg {
+    #[br(args(end), try_map = |s: WzIndexStr| s.as_str().map(str::to_string))]
+    #[bw(map = |s: &String| WzIndexStr::new(s))]
+    pub path: String,
+    pub guard: WzIndexGuard,
+    #[br(parse_with = read_count, args(WzIndexStr::MIN_SIZE, end))]
+    #[bw(calc = props.len() as u32)]
+    prop_count: u32,
+    /// Paths of the properties with hits
+    #[br(count = prop_count, args { inner: (end,) }, try_map = |props: Vec<WzIndexStr>| props
+        .iter()
+        .map(|s| s.as_str().map(str::to_string))
+        .collect::<anyhow::Result<Vec<_>>>())]
+    #[bw(map = |props: &Vec<String>| props.iter().map(|s| WzIndexStr::new(s)).collect::<Vec<_>>())]
+    pub props: Vec<String>,
+}
+
+impl WzIndexImg {
+    /// Smallest size of an encoded image
+    const MIN_SIZE: u64 = WzIndexStr::MIN_SIZE + 12;
+}
+
+#[binrw]
+#[brw(little)]
+#[br(import(end: u64))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct WzIndexTerm {
+    #[br(args(end), try_map = |s: WzIndexStr| s.as_str().map(str::to_string))]
+    #[bw(map = |s: &String| WzIndexStr::new(s))]
+    pub term: String,
+    #[br(parse_with = read_count, args(WzIndexHit::SIZE, end))]
+    #[bw(calc = hits.len() as u32)]
+    hit_count: u32,
+    #[br(count = hit_count)]
+    pub hits: Vec<WzIndexHit>,
+}
+
+impl WzIndexTerm {
+    /// Smallest size of an encoded term
+    const MIN_SIZE: u64 = WzIndexStr::MIN_SIZE + 4;
+}
+
+/// Counts are checked against `end`, the length of the index file
+#[binrw]
+#[brw(little, magic = b"WZIX")]
+#[br(import(end: u64))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct WzSearchIndex {
+    #[br(assert(version == WzSearchIndex::VERSION, "Unsupported index version: {}", version))]
+    pub version: u16,
+    #[br(parse_with = read_count, args(WzIndexImg::MIN_SIZE, end))]
+    #[bw(calc = imgs.len() as u32)]
+    img_count: u32,
+    #[br(count = img_count, args { inner: (end,) })]
+    pub imgs: Vec<WzIndexImg>,
+    #[br(parse_with = read_count, args(WzIndexTerm::MIN_SIZE, end))]
+    #[bw(calc = terms.len() as u32)]
+    term_count: u32,
+    /// Sorted by the term
+    #[br(count = term_count, args { inner: (end,) })]
+    pub terms: Vec<WzIndexTerm>,
+}
+
+/// Result of `WzSearchIndex::update`
+#[derive(Debug, Default)]
+pub struct WzIndexUpdate {
+    /// Number of re-indexed images
+    pub indexed: usize,
+    /// Images which couldn't be read with their error, they are left out of the index.
+    /// Directories which couldn't be read are reported with an empty path
+    pub skipped: Vec<(String, anyhow::Error)>,
+}
+
+/// Query result with the paths resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WzSearchHit<'a> {
+    pub term: &'a str,
+    pub img_path: &'a str,
+    pub prop_path: &'a str,
+    pub kind: WzIndexKind,
+}
+
+/// Terms of the value, the key is indexed separately
+fn value_terms(val: &WzValue) -> Option<(WzIndexKind, String)> {
+    Some(match val {
+        WzValue::String(s) => (WzIndexKind::String, s.clone()),
+        WzValue::Link(s) => (WzIndexKind::Link, s.clone()),
+        WzValue::Short(v) => (WzIndexKind::Number, v.to_string()),
+        WzValue::Int(v) => (WzIndexKind::Number, v.to_string()),
+        WzValue::Long(v) => (WzIndexKind::Number, v.to_string()),
+        WzValue::F32(v) => (WzIndexKind::Number, v.to_string()),
+        WzValue::F64(v) => (WzIndexKind::Number, v.to_string()),
+        _ => return None,
+    })
+}
+
+/// Builds the index of a single image
+#[derive(Default)]
+struct ImgIndexer {
+    props: Vec<String>,
+    prop_ix: HashMap<String, u32>,
+    /// Term, property index and kind
+    hits: Vec<(String, u32, WzIndexKind)>,
+}
+
+impl ImgIndexer {
+    fn add(&mut self, prop: &str, kind: WzIndexKind, text: &str) {
+        let text = text.to_lowercase();
+        if text.is_empty() {
+            return;
+        }
+        let prop = match self.prop_ix.get(prop) {
+            Some(ix) => *ix,
+            None => {
+                let ix = self.props.len() as u32;
+                self.props.push(prop.to_string());
+                self.prop_ix.insert(prop.to_string(), ix);
+                ix
+            }
+        };
+
+        let words = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>();
+        if words.len() > 1 || words.first() != Some(&text.as_str()) {
+            for word in words {
+                self.hits.push((word.to_string(), prop, kind));
+            }
+        }
+        self.hits.push((text, prop, kind));
+    }
+
+    fn index(mut self, root: &WzValue) -> (Vec<String>, Vec<(String, u32, WzIndexKind)>) {
+        for entry in root.walk().skip(1) {
+            self.add(&entry.path, WzIndexKind::Key, entry.name);
+            if let Some((kind, text)) = value_terms(entry.value) {
+                self.add(&entry.path, kind, &text);
+            }
+        }
+        (self.props, self.hits)
+    }
+}
+
+impl Default for WzSearchIndex {
+    fn default() -> Self {
+        Self {
+            version: Self::VERSION,
+            imgs: Vec::new(),
+            terms: Vec::new(),
+        }
+    }
+}
+
+impl WzSearchIndex {
+    pub const VERSION: u16 = 1;
+
+    /// Builds the index for all images of the archive, images which can't be read
+    /// are left out
+    pub fn build<R: WzIO>(r: &mut WzReader<R>) -> anyhow::Result<Self> {
+        let mut index = Self::default();
+        index.update(r)?;
+        Ok(index)
+    }
+
+    /// Updates the index for the archive, images with the same size and checksum
+    /// are kept. Images which can't be read are skipped and reported
+    pub fn update<R: WzIO>(&mut self, r: &mut WzReader<R>) -> anyhow::Result<WzIndexUpdate> {
+        let mut res = WzIndexUpdate::default();
+        let mut archive_imgs = Vec::new();
+        for img in r.walk_images() {
+            match img {
+                Ok(img) => archive_imgs.push(img),
+                Err(err) => res.skipped.push((String::new(), err)),
+            }
+        }
+        let old_imgs = self
+            .imgs
+            .iter()
+            .enumerate()
+            .map(|(ix, img)| (img.path.as_str(), (ix, img.guard)))
+            .collect::<HashMap<_, _>>();
+
+        // Hits of the kept images by their old index
+        let mut kept = HashMap::new();
+        for img in archive_imgs.iter() {
+            if let Some((ix, guard)) = old_imgs.get(img.path.as_str()) {
+                if *guard == WzIndexGuard::from(&img.hdr) {
+                    kept.insert(*ix as u32, Vec::new());
+                }
+            }
+        }
+        for term in self.terms.iter() {
+            for hit in term.hits.iter() {
+                if let Some(hits) = kept.get_mut(&hit.img) {
+                    hits.push((term.term.clone(), hit.prop, hit.kind));
+                }
+            }
+        }
+
+        let mut imgs = Vec::with_capacity(archive_imgs.len());
+        let mut terms: BTreeMap<String, Vec<WzIndexHit>> = BTreeMap::new();
+        for img in archive_imgs {
+            let guard = WzIndexGuard::from(&img.hdr);
+            let old_ix = old_imgs
+                .get(img.path.as_str())
+                .map(|(ix, _)| *ix as u32)
+                .filter(|ix| kept.contains_key(ix));
+            let (props, hits) = match old_ix {
+                Some(ix) => (
+                    self.imgs[ix as usize].props.clone(),
+                    kept.remove(&ix).unwrap_or_default(),
+                ),
+                None => {
+                    let root = r
+                        .img_reader(&img.hdr)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|img_r| WzValue::read(&mut img_r.with_path(&img.path)));
+                    match root {
+                        Ok(root) => {
+                            res.indexed += 1;
+                            ImgIndexer::default().index(&root)
+                        }
+                        Err(err) => {
+                            res.skipped.push((img.path, err));
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let img_ix = imgs.len() as u32;
+            for (term, prop, kind) in hits {
+                terms.entry(term).or_default().push(WzIndexHit {
+                    img: img_ix,
+                    prop,
+                    kind,
+                });
+            }
+            imgs.push(WzIndexImg {
+                path: img.path,
+                guard,
+                props,
+            });
+        }
+
+        self.imgs = imgs;
+        self.terms = terms
+            .into_iter()
+            .map(|(term, mut hits)| {
+                hits.sort();
+                hits.dedup();
+                WzIndexTerm { term, hits }
+            })
+            .collect();
+        Ok(res)
+    }
+
+    fn resolve<'a>(&'a self, term: &'a str, hit: &WzIndexHit) -> WzSearchHit<'a> {
+        let img = &self.imgs[hit.img as usize];
+        WzSearchHit {
+            term,
+            img_path: &img.path,
+            prop_path: &img.props[hit.prop as usize],
+            kind: hit.kind,
+        }
+    }
+
+    /// Finds the properties containing the term, case insensitive
+    pub fn search(&self, term: &str) -> Vec<WzSearchHit<'_>> {
+        let term = term.to_lowercase();
+        match self.terms.binary_search_by(|t| t.term.as_str().cmp(&term)) {
+            Ok(ix) => {
+                let term = &self.terms[ix];
+                term.hits
+                    .iter()
+                    .map(|hit| self.resolve(&term.term, hit))
+                    .collect()
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Finds the properties containing a term, which starts with the prefix.
+    /// Every property is returned once with the first matching term
+    pub fn search_prefix(&self, prefix: &str) -> Vec<WzSearchHit<'_>> {
+        let prefix = prefix.to_lowercase();
+        let start = self
+            .terms
+            .partition_point(|t| t.term.as_str() < prefix.as_str());
+        let mut hits = self.terms[start..]
+            .iter()
+            .take_while(|t| t.term.starts_with(&prefix))
+            .flat_map(|t| t.hits.iter().map(move |hit| (*hit, t.term.as_str())))
+            .collect::<Vec<_>>();
+        // Stable, so the first term is kept
+        hits.sort_by_key(|(hit, _)| *hit);
+        hits.dedup_by_key(|(hit, _)| *hit);
+        hits.into_iter()
+            .map(|(hit, term)| self.resolve(term, &hit))
+            .collect()
+    }
+
+    pub fn read_from<R: Read + Seek>(r: &mut R) -> anyhow::Result<Self> {
+        let pos = r.stream_position()?;
+        let end = r.seek(SeekFrom::End(0))?;
+        r.seek(SeekFrom::Start(pos))?;
+        Ok(Self::read_args(r, (end,))?)
+    }
+
+    pub fn write_to<W: Write + Seek>(&self, w: &mut W) -> anyhow::Result<()> {
+        Ok(self.write(w)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        test_util::{
+            build_archive, dir, img, img_blob, link_val, obj, shared_dir_archive, str_val, TestVal,
+        },
+        WzReader, GMS95,
+    };
+
+    use super::{WzIndexKind, WzSearchIndex};
+
+    fn archive(name: &str) -> Vec<u8> {
+        build_archive(
+            GMS95,
+            vec![
+                dir(
+                    "Npc",
+                    vec![img(
+                        "9000001.img",
+                        img_blob(
+                            GMS95,
+                            &obj([
+                                ("name", str_val(name)),
+                                ("link", link_val("../info")),
+                                ("id", TestVal::Int(9000001)),
+                            ]),
+                        ),
+                    )],
+                ),
+                img(
+                    "Map.img",
+                    img_blob(GMS95, &obj([("9000001", str_val("Victoria Road"))])),
+                ),
+            ],
+        )
+    }
+
+    fn hits(index: &WzSearchIndex, term: &str, prefix: bool) -> Vec<(String, String, WzIndexKind)> {
+        let hits = if prefix {
+            index.search_prefix(term)
+        } else {
+            index.search(term)
+        };
+        hits.into_iter()
+            .map(|h| (h.img_path.to_string(), h.prop_path.to_string(), h.kind))
+            .collect()
+    }
+
+    #[test]
+    fn search() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(archive("Henesys Guide")), GMS95)?;
+        let index = WzSearchIndex::build(&mut r)?;
+
+        assert_eq!(
+            hits(&index, "9000001", false),
+            [
+                (
+                    "Npc/9000001.img".to_string(),
+                    "id".to_string(),
+                    WzIndexKind::Number
+                ),
+                (
+                    "Map.img".to_string(),
+                    "9000001".to_string(),
+                    WzIndexKind::Key
+                ),
+            ]
+        );
+        let henesys = [(
+            "Npc/9000001.img".to_string(),
+            "name".to_string(),
+            WzIndexKind::String,
+        )];
+        assert_eq!(hits(&index, "HENESYS", false), henesys);
+        assert_eq!(hits(&index, "henesys guide", false), henesys);
+        assert_eq!(hits(&index, "hene", true), henesys);
+        assert!(hits(&index, "hene", false).is_empty());
+        assert_eq!(hits(&index, "../info", false)[0].2, WzIndexKind::Link);
+
+        let mut buf = Cursor::new(Vec::new());
+        index.write_to(&mut buf)?;
+        buf.set_position(0);
+        assert_eq!(WzSearchIndex::read_from(&mut buf)?, index);
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_counts() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(archive("Henesys Guide")), GMS95)?;
+        let mut buf = Cursor::new(Vec::new());
+        WzSearchIndex::build(&mut r)?.write_to(&mut buf)?;
+        let data = buf.into_inner();
+
+        // Image count after the magic and the version, then the length of the first path
+        for offset in [6, 10] {
+            let mut data = data.clone();
+            data[offset..offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            let err = WzSearchIndex::read_from(&mut Cursor::new(data)).unwrap_err();
+            assert!(format!("{err:#}").contains("exceeds"), "{err:#}");
+        }
+        let truncated = &data[..data.len() - 1];
+        assert!(WzSearchIndex::read_from(&mut Cursor::new(truncated)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn update() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(archive("Henesys Guide")), GMS95)?;
+        let mut index = WzSearchIndex::build(&mut r)?;
+        assert_eq!(index.update(&mut r)?.indexed, 0);
+
+        let mut r = WzReader::open(Cursor::new(archive("Ellinia Guide")), GMS95)?;
+        assert_eq!(index.update(&mut r)?.indexed, 1);
+        assert!(hits(&index, "henesys", false).is_empty());
+        assert_eq!(hits(&index, "ellinia", false).len(), 1);
+        assert_eq!(hits(&index, "victoria", false).len(), 1);
+        assert_eq!(index, WzSearchIndex::build(&mut r)?);
+        Ok(())
+    }
+
+    #[test]
+    fn skip_bad_images() -> anyhow::Result<()> {
+        let data = build_archive(
+            GMS95,
+            vec![
+                img("Bad.img", vec![0xFF; 16]),
+                img(
+                    "Map.img",
+                    img_blob(GMS95, &obj([("name", str_val("Henesys"))])),
+                ),
+            ],
+        );
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let mut index = WzSearchIndex::default();
+        let res = index.update(&mut r)?;
+        assert_eq!(res.indexed, 1);
+        assert_eq!(res.skipped.len(), 1);
+        assert_eq!(res.skipped[0].0, "Bad.img");
+        assert_eq!(hits(&index, "henesys", false).len(), 1);
+        assert_eq!(index.imgs.len(), 1);
+
+        // Skipped images are retried by the next update
+        assert_eq!(index.update(&mut r)?.skipped.len(), 1);
+
+        // The cyclic directory is skipped, the walk continues after It
+        let mut r = WzReader::open(Cursor::new(shared_dir_archive(GMS95, true)), GMS95)?;
+        let res = index.update(&mut r)?;
+        assert_eq!(res.indexed, 1);
+        assert_eq!(res.skipped.len(), 1);
+        assert_eq!(res.skipped[0].0, "");
+        assert_eq!(index.imgs.len(), 1);
+        assert_eq!(index.imgs[0].path, "A/a.img");
+        Ok(())
+    }
+}
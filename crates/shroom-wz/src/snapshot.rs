@@ -0,0 +1,925 @@
+//! Compact binary snapshot of a parsed archive.
+//!
+//! A snapshot holds the directory tree and the value trees of all images, canvas and
+//! sound data is not included but the offsets are kept, so the media can still be
+//! read from the source archive. The snapshot records the size and header of the
+//! source archive, so a stale snapshot can be detected.
+//!
+//! Layout: magic, format version, source info, payload length, crc32 of the payload,
+//! payload (string table, directory tree, images).
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use binrw::PosValue;
+use id_tree::{InsertBehavior, Node, NodeId, Tree};
+use indexmap::{IndexMap, IndexSet};
+
+use crate::{
+    file::{WzIO, WzReaderFile},
+    l0::{tree::WzTree, WzDirHeader, WzDirNode, WzImgHeader, WzLinkData, WzLinkHeader},
+    l1::{
+        canvas::{WzCanvas, WzCanvasDepth, WzCanvasScaling},
+        sound::{
+            MediaHeader, Mpeg3WaveHeader, SoundFormat, SoundHeader, WaveHeader, WzSound, GUID,
+        },
+        WzPosValue,
+    },
+    ty::{WzInt, WzOffset, WzStr},
+    val::{CanvasVal, ErrorVal, ObjectVal, SoundVal, Vec2Val, Vex2Val, WzValue},
+    version::WzRegion,
+    WzConfig, WzReader,
+};
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"WZSNAP\0\0";
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Identifies the archive a snapshot was built from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotSource {
+    /// Length of the archive file
+    pub file_len: u64,
+    /// Size from the archive header
+    pub file_size: u64,
+    pub data_offset: u32,
+    pub desc: String,
+    pub region: u8,
+    pub version: u16,
+}
+
+fn region_id(region: WzRegion) -> u8 {
+    match region {
+        WzRegion::GMS => 0,
+        WzRegion::SEA => 1,
+        WzRegion::Other => 2,
+        WzRegion::BmsSrv => 3,
+    }
+}
+
+impl SnapshotSource {
+    pub fn from_reader<R: WzIO>(r: &mut WzReader<R>, cfg: WzConfig) -> anyhow::Result<Self> {
+        let hdr = r.read_header()?;
+        Ok(Self {
+            file_len: r.file_len()?,
+            file_size: hdr.file_size,
+            data_offset: hdr.data_offset,
+            desc: hdr.desc.to_string(),
+            region: region_id(cfg.region),
+            version: cfg.version.0,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct WzSnapshot {
+    pub source: SnapshotSource,
+    pub tree: WzTree,
+    /// Value trees keyed by the image path
+    pub images: IndexMap<String, WzValue>,
+}
+
+impl WzSnapshot {
+    /// Parses the whole archive
+    pub fn build<R: WzIO>(r: &mut WzReader<R>, cfg: WzConfig) -> anyhow::Result<Self> {
+        let source = SnapshotSource::from_reader(r, cfg)?;
+        let tree = WzTree::from_reader(r, None)?;
+        let mut images = IndexMap::new();
+        for (path, hdr) in tree.images() {
+            let val = WzValue::read(&mut r.img_reader(&hdr)?.with_path(&path))?;
+            images.insert(path, val);
+        }
+
+        Ok(Self {
+            source,
+            tree,
+            images,
+        })
+    }
+
+    pub fn get_img(&self, path: &str) -> Option<&WzValue> {
+        self.images.get(path)
+    }
+
+    /// Checks If the snapshot was built from the given source
+    pub fn is_stale(&self, source: &SnapshotSource) -> bool {
+        &self.source != source
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> anyhow::Result<()> {
+        let mut enc = Encoder::default();
+        if let Some(root) = self.tree.get_tree().root_node_id() {
+            enc.dir_node(self.tree.get_tree(), root);
+        }
+        enc.var(self.images.len() as u64);
+        for (path, val) in self.images.iter() {
+            enc.str(path);
+            enc.value(val);
+        }
+
+        // The string table goes in front of the body
+        let mut payload = Encoder::default();
+        payload.var(enc.strings.len() as u64);
+        for s in enc.strings.iter() {
+            payload.bytes(s.as_bytes());
+        }
+        payload.buf.extend_from_slice(&enc.buf);
+        let payload = payload.buf;
+
+        let mut hdr = Encoder::default();
+        hdr.buf.extend_from_slice(SNAPSHOT_MAGIC);
+        hdr.buf.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        hdr.var(self.source.file_len);
+        hdr.var(self.source.file_size);
+        hdr.var(self.source.data_offset as u64);
+        hdr.bytes(self.source.desc.as_bytes());
+        hdr.u8(self.source.region);
+        hdr.var(self.source.version as u64);
+        hdr.buf
+            .extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        hdr.buf
+            .extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+
+        w.write_all(&hdr.buf)?;
+        w.write_all(&payload)?;
+        Ok(())
+    }
+
+    /// Reads only the source info of a snapshot, the payload is not read
+    pub fn read_source<R: Read>(mut r: R) -> anyhow::Result<SnapshotSource> {
+        read_header(&mut r)
+    }
+
+    pub fn read<R: Read>(mut r: R) -> anyhow::Result<Self> {
+        let source = read_header(&mut r)?;
+
+        let payload_len = u64::from_le_bytes(read_array(&mut r)?);
+        let checksum = u32::from_le_bytes(read_array(&mut r)?);
+        // The length is untrusted, so the payload grows with the read data
+        let mut payload = Vec::new();
+        r.take(payload_len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != payload_len {
+            anyhow::bail!("Truncated snapshot payload");
+        }
+        if crc32fast::hash(&payload) != checksum {
+            anyhow::bail!("Snapshot checksum mismatch");
+        }
+
+        let mut dec = Decoder::new(&payload);
+        let n = dec.len()?;
+        for _ in 0..n {
+            let s = dec.bytes()?;
+            dec.strings.push(String::from_utf8(s.to_vec())?);
+        }
+
+        let mut tree = Tree::new();
+        dec.dir_node(&mut tree, None)?;
+        let n = dec.len()?;
+        let mut images = IndexMap::with_capacity(n);
+        for _ in 0..n {
+            let path = dec.str()?.to_string();
+            images.insert(path, dec.value()?);
+        }
+
+        Ok(Self {
+            source,
+            tree: WzTree::from_tree(tree),
+            images,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    /// Loads the snapshot If It's valid for the archive, else the archive is parsed
+    /// and the snapshot is rebuilt
+    pub fn load_or_build(
+        snapshot_path: impl AsRef<Path>,
+        archive_path: impl AsRef<Path>,
+        cfg: WzConfig,
+    ) -> anyhow::Result<Self> {
+        let snapshot_path = snapshot_path.as_ref();
+        let mut r = WzReaderFile::open_file(archive_path, cfg)?;
+        let source = SnapshotSource::from_reader(&mut r, cfg)?;
+
+        if snapshot_path.exists() {
+            if let Ok(snapshot) = Self::load(snapshot_path) {
+                if !snapshot.is_stale(&source) {
+                    return Ok(snapshot);
+                }
+            }
+        }
+
+        let snapshot = Self::build(&mut r, cfg)?;
+        snapshot.save(snapshot_path)?;
+        Ok(snapshot)
+    }
+}
+
+fn read_array<R: Read, const N: usize>(r: &mut R) -> anyhow::Result<[u8; N]> {
+    let mut buf = [0; N];
+    r.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_var<R: Read>(r: &mut R) -> anyhow::Result<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let [b] = read_array(r)?;
+        v |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    anyhow::bail!("Invalid varint in snapshot header")
+}
+
+/// Reads the header in front of the payload, so the source can be checked without
+/// reading the whole snapshot
+fn read_header<R: Read>(r: &mut R) -> anyhow::Result<SnapshotSource> {
+    if &read_array::<R, 8>(r)? != SNAPSHOT_MAGIC {
+        anyhow::bail!("Invalid snapshot magic");
+    }
+    let version = u32::from_le_bytes(read_array(r)?);
+    if version != SNAPSHOT_VERSION {
+        anyhow::bail!("Unsupported snapshot version: {version}, expected: {SNAPSHOT_VERSION}");
+    }
+
+    let file_len = read_var(r)?;
+    let file_size = read_var(r)?;
+    let data_offset = read_var(r)?.try_into()?;
+    let desc_len = read_var(r)?;
+    let mut desc = Vec::new();
+    r.take(desc_len).read_to_end(&mut desc)?;
+    if desc.len() as u64 != desc_len {
+        anyhow::bail!("Truncated snapshot header");
+    }
+    let [region] = read_array(r)?;
+    Ok(SnapshotSource {
+        file_len,
+        file_size,
+        data_offset,
+        desc: String::from_utf8(desc)?,
+        region,
+        version: read_var(r)?.try_into()?,
+    })
+}
+
+// Value tags
+const TAG_NULL: u8 = 0;
+const TAG_F32: u8 = 1;
+const TAG_F64: u8 = 2;
+const TAG_SHORT: u8 = 3;
+const TAG_INT: u8 = 4;
+const TAG_LONG: u8 = 5;
+const TAG_STRING: u8 = 6;
+const TAG_VEC: u8 = 7;
+const TAG_CONVEX: u8 = 8;
+const TAG_SOUND: u8 = 9;
+const TAG_CANVAS: u8 = 10;
+const TAG_LINK: u8 = 11;
+const TAG_OBJECT: u8 = 12;
+const TAG_ERROR: u8 = 13;
+
+// Dir node tags
+const TAG_NODE_NIL: u8 = 0;
+const TAG_NODE_LINK: u8 = 1;
+const TAG_NODE_DIR: u8 = 2;
+const TAG_NODE_IMG: u8 = 3;
+
+// Sound format tags
+const TAG_SOUND_MPEG1: u8 = 0;
+const TAG_SOUND_MPEG3: u8 = 1;
+const TAG_SOUND_PCM: u8 = 2;
+
+/// Limit for the nesting of values and directory nodes, deeper data is corrupt
+const MAX_NESTING: usize = 256;
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+    strings: IndexSet<String>,
+}
+
+impl Encoder {
+    fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    /// LEB128 encoded integer
+    fn var(&mut self, mut v: u64) {
+        loop {
+            let b = (v & 0x7F) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.buf.push(b);
+                return;
+            }
+            self.buf.push(b | 0x80);
+        }
+    }
+
+    /// Zigzag encoded signed integer
+    fn ivar(&mut self, v: i64) {
+        self.var(((v << 1) ^ (v >> 63)) as u64);
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.var(b.len() as u64);
+        self.buf.extend_from_slice(b);
+    }
+
+    fn str(&mut self, s: &str) {
+        let ix = match self.strings.get_index_of(s) {
+            Some(ix) => ix,
+            None => self.strings.insert_full(s.to_string()).0,
+        };
+        self.var(ix as u64);
+    }
+
+    fn guid(&mut self, guid: &GUID) {
+        self.buf.extend_from_slice(&guid.0.to_bytes_le());
+    }
+
+    fn img_hdr(&mut self, hdr: &WzImgHeader) {
+        self.str(hdr.name.as_str());
+        self.ivar(hdr.blob_size.0 as i64);
+        self.ivar(hdr.checksum.0 as i64);
+        self.var(hdr.offset.0 as u64);
+    }
+
+    fn dir_node(&mut self, tree: &Tree<WzDirNode>, id: &NodeId) {
+        let node = tree.get(id).unwrap();
+        match node.data() {
+            WzDirNode::Nil(data) => {
+                self.u8(TAG_NODE_NIL);
+                self.buf.extend_from_slice(data);
+            }
+            WzDirNode::Link(link) => {
+                self.u8(TAG_NODE_LINK);
+                self.var(link.link.offset as u64);
+                self.img_hdr(&link.link.link_img);
+                self.ivar(link.blob_size.0 as i64);
+                self.ivar(link.checksum.0 as i64);
+                self.var(link.offset.0 as u64);
+            }
+            WzDirNode::Dir(dir) => {
+                self.u8(TAG_NODE_DIR);
+                self.str(dir.name.as_str());
+                self.ivar(dir.blob_size.0 as i64);
+                self.ivar(dir.checksum.0 as i64);
+                self.var(dir.offset.0 as u64);
+            }
+            WzDirNode::Img(img) => {
+                self.u8(TAG_NODE_IMG);
+                self.img_hdr(img);
+            }
+        }
+
+        self.var(node.children().len() as u64);
+        for child in node.children() {
+            self.dir_node(tree, child);
+        }
+    }
+
+    fn wave(&mut self, wave: &WaveHeader) {
+        self.var(wave.format as u64);
+        self.var(wave.channels as u64);
+        self.var(wave.samples_per_sec as u64);
+        self.var(wave.avg_bytes_per_sec as u64);
+        self.var(wave.block_align as u64);
+        self.var(wave.bits_per_sample as u64);
+        self.var(wave.extra_size as u64);
+    }
+
+    fn sound(&mut self, sound: &WzSound) {
+        self.u8(sound.unknown);
+        self.ivar(sound.size.0 as i64);
+        self.ivar(sound.len_ms.0 as i64);
+        let media = &sound.header.media_header;
+        self.u8(media.unknown1);
+        self.guid(&media.major_type);
+        self.guid(&media.sub_type);
+        self.var(media.sample_size as u64);
+        self.guid(&media.format_type);
+        match &sound.header.fmt {
+            SoundFormat::Mpeg1(data) => {
+                self.u8(TAG_SOUND_MPEG1);
+                self.buf.extend_from_slice(data);
+            }
+            SoundFormat::Mpeg3(mp3) => {
+                self.u8(TAG_SOUND_MPEG3);
+                self.wave(&mp3.wav);
+                self.var(mp3.id as u64);
+                self.var(mp3.flags as u64);
+                self.var(mp3.block_size as u64);
+                self.var(mp3.frames_per_block as u64);
+                self.var(mp3.codec_delay as u64);
+            }
+            SoundFormat::Pcm(wave) => {
+                self.u8(TAG_SOUND_PCM);
+                self.wave(wave);
+            }
+        }
+        self.var(sound.offset.pos);
+    }
+
+    fn canvas(&mut self, canvas: &CanvasVal) {
+        let c = &canvas.canvas;
+        self.u8(c.unknown);
+        self.ivar(c.width.0 as i64);
+        self.ivar(c.height.0 as i64);
+        self.ivar(WzInt::from(c.depth).0 as i64);
+        self.u8(c.scale.0);
+        self.var(c.unknown1 as u64);
+        self.var(c.len.val as u64);
+        self.var(c.len.pos);
+        match canvas.sub.as_deref() {
+            Some(sub) => {
+                self.u8(1);
+                self.value(sub);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn value(&mut self, val: &WzValue) {
+        match val {
+            WzValue::Null => self.u8(TAG_NULL),
+            WzValue::F32(v) => {
+                self.u8(TAG_F32);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            WzValue::F64(v) => {
+                self.u8(TAG_F64);
+                self.buf.extend_from_slice(&v.to_le_bytes());
+            }
+            WzValue::Short(v) => {
+                self.u8(TAG_SHORT);
+                self.ivar(*v as i64);
+            }
+            WzValue::Int(v) => {
+                self.u8(TAG_INT);
+                self.ivar(*v as i64);
+            }
+            WzValue::Long(v) => {
+                self.u8(TAG_LONG);
+                self.ivar(*v);
+            }
+            WzValue::String(v) => {
+                self.u8(TAG_STRING);
+                self.str(v);
+            }
+            WzValue::Vec(v) => {
+                self.u8(TAG_VEC);
+                self.ivar(v.x as i64);
+                self.ivar(v.y as i64);
+            }
+            WzValue::Convex(v) => {
+                self.u8(TAG_CONVEX);
+                self.var(v.0.len() as u64);
+                for v in v.0.iter() {
+                    self.ivar(v.x as i64);
+                    self.ivar(v.y as i64);
+                }
+            }
+            WzValue::Sound(v) => {
+                self.u8(TAG_SOUND);
+                self.sound(&v.sound);
+            }
+            WzValue::Canvas(v) => {
+                self.u8(TAG_CANVAS);
+                self.canvas(v);
+            }
+            WzValue::Link(v) => {
+                self.u8(TAG_LINK);
+                self.str(v);
+            }
+            WzValue::Error(v) => {
+                self.u8(TAG_ERROR);
+                self.var(v.offset);
+                self.str(&v.reason);
+            }
+            WzValue::Object(obj) => {
+                self.u8(TAG_OBJECT);
+                self.var(obj.0.len() as u64);
+                for (k, v) in obj.0.iter() {
+                    self.str(k);
+                    self.value(v);
+                }
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    strings: Vec<String>,
+    /// Nesting of the current value or directory node
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            strings: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    /// Decodes a nested value or node, so a crafted snapshot can't overflow the stack
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        if self.depth >= MAX_NESTING {
+            anyhow::bail!("Nesting exceeds the limit of {MAX_NESTING} at {}", self.pos);
+        }
+        self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| anyhow::anyhow!("Truncated snapshot at {}", self.pos))?;
+        let data = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(data)
+    }
+
+    fn array<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn var(&mut self) -> anyhow::Result<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            v |= ((b & 0x7F) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        anyhow::bail!("Invalid varint at {}", self.pos)
+    }
+
+    fn ivar(&mut self) -> anyhow::Result<i64> {
+        let v = self.var()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    fn var_u32(&mut self) -> anyhow::Result<u32> {
+        Ok(self.var()?.try_into()?)
+    }
+
+    fn var_u16(&mut self) -> anyhow::Result<u16> {
+        Ok(self.var()?.try_into()?)
+    }
+
+    fn ivar_i32(&mut self) -> anyhow::Result<i32> {
+        Ok(self.ivar()?.try_into()?)
+    }
+
+    /// Length of a collection, bounded by the remaining data
+    fn len(&mut self) -> anyhow::Result<usize> {
+        let n = self.var()? as usize;
+        if n > self.buf.len() - self.pos {
+            anyhow::bail!("Invalid length {n} at {}", self.pos);
+        }
+        Ok(n)
+    }
+
+    fn bytes(&mut self) -> anyhow::Result<&'a [u8]> {
+        let n = self.len()?;
+        self.take(n)
+    }
+
+    fn str(&mut self) -> anyhow::Result<&str> {
+        let ix = self.var()? as usize;
+        self.strings
+            .get(ix)
+            .map(|s| s.as_str())
+            .ok_or_else(|| anyhow::anyhow!("Invalid string index {ix}"))
+    }
+
+    fn guid(&mut self) -> anyhow::Result<GUID> {
+        Ok(GUID(uuid::Uuid::from_bytes_le(self.array()?)))
+    }
+
+    fn img_hdr(&mut self) -> anyhow::Result<WzImgHeader> {
+        Ok(WzImgHeader {
+            name: WzStr::new(self.str()?.to_string()),
+            blob_size: WzInt(self.ivar_i32()?),
+            checksum: WzInt(self.ivar_i32()?),
+            offset: WzOffset(self.var_u32()?),
+        })
+    }
+
+    fn dir_node(
+        &mut self,
+        tree: &mut Tree<WzDirNode>,
+        parent: Option<&NodeId>,
+    ) -> anyhow::Result<()> {
+        let node = match self.u8()? {
+            TAG_NODE_NIL => WzDirNode::Nil(self.array()?),
+            TAG_NODE_LINK => WzDirNode::Link(WzLinkHeader {
+                link: WzLinkData {
+                    offset: self.var_u32()?,
+                    link_img: self.img_hdr()?,
+                },
+                blob_size: WzInt(self.ivar_i32()?),
+                checksum: WzInt(self.ivar_i32()?),
+                offset: WzOffset(self.var_u32()?),
+            }),
+            TAG_NODE_DIR => WzDirNode::Dir(WzDirHeader {
+                name: WzStr::new(self.str()?.to_string()),
+                blob_size: WzInt(self.ivar_i32()?),
+                checksum: WzInt(self.ivar_i32()?),
+                offset: WzOffset(self.var_u32()?),
+            }),
+            TAG_NODE_IMG => WzDirNode::Img(self.img_hdr()?),
+            tag => anyhow::bail!("Invalid dir node tag: {tag}"),
+        };
+
+        let behavior = match parent {
+            Some(parent) => InsertBehavior::UnderNode(parent),
+            None => InsertBehavior::AsRoot,
+        };
+        let id = tree.insert(Node::new(node), behavior)?;
+        let n = self.len()?;
+        for _ in 0..n {
+            self.nested(|d| d.dir_node(tree, Some(&id)))?;
+        }
+        Ok(())
+    }
+
+    fn wave(&mut self) -> anyhow::Result<WaveHeader> {
+        Ok(WaveHeader {
+            format: self.var_u16()?,
+            channels: self.var_u16()?,
+            samples_per_sec: self.var_u32()?,
+            avg_bytes_per_sec: self.var_u32()?,
+            block_align: self.var_u16()?,
+            bits_per_sample: self.var_u16()?,
+            extra_size: self.var_u16()?,
+        })
+    }
+
+    fn sound(&mut self) -> anyhow::Result<WzSound> {
+        let unknown = self.u8()?;
+        let size = WzInt(self.ivar_i32()?);
+        let len_ms = WzInt(self.ivar_i32()?);
+        let media_header = MediaHeader {
+            unknown1: self.u8()?,
+            major_type: self.guid()?,
+            sub_type: self.guid()?,
+            sample_size: self.var_u16()?,
+            format_type: self.guid()?,
+        };
+        let fmt = match self.u8()? {
+            TAG_SOUND_MPEG1 => SoundFormat::Mpeg1(self.array()?),
+            TAG_SOUND_MPEG3 => SoundFormat::Mpeg3(Mpeg3WaveHeader {
+                wav: self.wave()?,
+                id: self.var_u16()?,
+                flags: self.var_u32()?,
+                block_size: self.var_u16()?,
+                frames_per_block: self.var_u16()?,
+                codec_delay: self.var_u16()?,
+            }),
+            TAG_SOUND_PCM => SoundFormat::Pcm(self.wave()?),
+            tag => anyhow::bail!("Invalid sound format tag: {tag}"),
+        };
+
+        Ok(WzSound {
+            unknown,
+            size,
+            len_ms,
+            header: SoundHeader { media_header, fmt },
+            offset: PosValue {
+                val: (),
+                pos: self.var()?,
+            },
+        })
+    }
+
+    fn canvas(&mut self) -> anyhow::Result<CanvasVal> {
+        let unknown = self.u8()?;
+        let width = WzInt(self.ivar_i32()?);
+        let height = WzInt(self.ivar_i32()?);
+        let depth = WzCanvasDepth::try_from(WzInt(self.ivar_i32()?))?;
+        let scale = WzCanvasScaling::try_from(self.u8()?)?;
+        let unknown1 = self.var_u32()?;
+        let len = WzPosValue {
+            val: self.var_u32()?,
+            pos: self.var()?,
+        };
+        let sub = match self.u8()? {
+            0 => None,
+            _ => Some(Box::new(self.nested(Self::value)?)),
+        };
+
+        Ok(CanvasVal {
+            // The raw property is not stored, It's available as `sub`
+            canvas: WzCanvas {
+                unknown,
+                has_property: 0,
+                property: None,
+                width,
+                height,
+                depth,
+                scale,
+                unknown1,
+                len,
+            },
+            sub,
+            bitmap: None,
+        })
+    }
+
+    fn value(&mut self) -> anyhow::Result<WzValue> {
+        Ok(match self.u8()? {
+            TAG_NULL => WzValue::Null,
+            TAG_F32 => WzValue::F32(f32::from_le_bytes(self.array()?)),
+            TAG_F64 => WzValue::F64(f64::from_le_bytes(self.array()?)),
+            TAG_SHORT => WzValue::Short(self.ivar()?.try_into()?),
+            TAG_INT => WzValue::Int(self.ivar_i32()?),
+            TAG_LONG => WzValue::Long(self.ivar()?),
+            TAG_STRING => WzValue::String(self.str()?.to_string()),
+            TAG_VEC => WzValue::Vec(Vec2Val {
+                x: self.ivar_i32()?,
+                y: self.ivar_i32()?,
+            }),
+            TAG_CONVEX => {
+                let n = self.len()?;
+                let mut v = Vec::with_capacity(n);
+                for _ in 0..n {
+                    v.push(Vec2Val {
+                        x: self.ivar_i32()?,
+                        y: self.ivar_i32()?,
+                    });
+                }
+                WzValue::Convex(Vex2Val(v))
+            }
+            TAG_SOUND => WzValue::Sound(SoundVal {
+                sound: self.sound()?,
+                data: None,
+            }),
+            TAG_CANVAS => WzValue::Canvas(self.canvas()?),
+            TAG_LINK => WzValue::Link(self.str()?.to_string()),
+            TAG_ERROR => WzValue::Error(ErrorVal {
+                offset: self.var()?,
+                reason: self.str()?.to_string(),
+            }),
+            TAG_OBJECT => {
+                let n = self.len()?;
+                let mut map = IndexMap::with_capacity(n);
+                for _ in 0..n {
+                    let key = self.str()?.to_string();
+                    map.insert(key, self.nested(Self::value)?);
+                }
+                WzValue::Object(ObjectVal(map))
+            }
+            tag => anyhow::bail!("Invalid value tag: {tag}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use id_tree::Tree;
+
+    use crate::{test_util::sample_archive, WzReader, GMS95};
+
+    use super::{
+        Decoder, SnapshotSource, WzSnapshot, MAX_NESTING, TAG_NODE_DIR, TAG_NULL, TAG_OBJECT,
+    };
+
+    #[test]
+    fn snapshot_roundtrip() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(sample_archive(GMS95)), GMS95)?;
+        let snapshot = WzSnapshot::build(&mut r, GMS95)?;
+
+        let mut data = Vec::new();
+        snapshot.write(&mut data)?;
+        let loaded = WzSnapshot::read(data.as_slice())?;
+
+        assert_eq!(loaded.source, snapshot.source);
+        assert_eq!(loaded.images, snapshot.images);
+        assert_eq!(
+            loaded.tree.images().collect::<Vec<_>>(),
+            snapshot.tree.images().collect::<Vec<_>>()
+        );
+
+        // Media can still be read from the archive
+        let hdr = loaded.tree.get_img_by_path("Item/0200.img").unwrap();
+        let icon = loaded.get_img("Item/0200.img").unwrap();
+        let icon = icon.get_path("info/icon").unwrap().as_canvas().unwrap();
+        let icon = icon.read_canvas(&mut r.img_reader(hdr)?)?;
+        assert_eq!(icon.to_raw_rgba_image()?.dimensions(), (4, 3));
+
+        let hdr = loaded.tree.get_img_by_path("Bgm.img").unwrap();
+        let bgm = loaded.get_img("Bgm.img").unwrap();
+        let bgm = bgm.get_path("bgm").unwrap().as_sound().unwrap();
+        assert_eq!(bgm.read_data(&mut r.img_reader(hdr)?)?.len(), 64);
+
+        assert_eq!(
+            WzSnapshot::read_source(data.as_slice())?,
+            SnapshotSource::from_reader(&mut r, GMS95)?
+        );
+        // The payload isn't needed for the source
+        assert_eq!(
+            WzSnapshot::read_source(&data[..data.len() / 2])?,
+            snapshot.source
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_invalid() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(sample_archive(GMS95)), GMS95)?;
+        let snapshot = WzSnapshot::build(&mut r, GMS95)?;
+        let mut data = Vec::new();
+        snapshot.write(&mut data)?;
+
+        let mut corrupt = data.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xFF;
+        assert!(WzSnapshot::read(corrupt.as_slice()).is_err());
+        assert!(WzSnapshot::read(&data[..data.len() / 2]).is_err());
+
+        let mut source = snapshot.source.clone();
+        assert!(!snapshot.is_stale(&source));
+        source.file_len += 1;
+        assert!(snapshot.is_stale(&source));
+        Ok(())
+    }
+
+    #[test]
+    fn snapshot_nesting() {
+        fn decoder(data: &[u8]) -> Decoder<'_> {
+            let mut d = Decoder::new(data);
+            d.strings.push("a".to_string());
+            d
+        }
+
+        // Objects with a single entry named `a`
+        let nested = |n: usize| {
+            let mut data = [TAG_OBJECT, 1, 0].repeat(n);
+            data.push(TAG_NULL);
+            data
+        };
+        assert!(decoder(&nested(MAX_NESTING)).value().is_ok());
+        assert!(decoder(&nested(100_000)).value().is_err());
+
+        // Directories named `a` with a single sub directory
+        let mut data = [TAG_NODE_DIR, 0, 0, 0, 0, 1].repeat(100_000);
+        data.extend([TAG_NODE_DIR, 0, 0, 0, 0, 0]);
+        assert!(decoder(&data).dir_node(&mut Tree::new(), None).is_err());
+    }
+
+    #[test]
+    fn snapshot_load_or_build() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("shroom-wz-snapshot-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir)?;
+        let archive = dir.join("Item.wz");
+        let snapshot_path = dir.join("Item.snap");
+        std::fs::write(&archive, sample_archive(GMS95))?;
+
+        let built = WzSnapshot::load_or_build(&snapshot_path, &archive, GMS95)?;
+        assert!(snapshot_path.exists());
+        let loaded = WzSnapshot::load_or_build(&snapshot_path, &archive, GMS95)?;
+        assert_eq!(built.images, loaded.images);
+
+        // A changed archive is detected and the snapshot is rebuilt
+        let mut data = sample_archive(GMS95);
+        data.extend_from_slice(&[0; 16]);
+        std::fs::write(&archive, data)?;
+        let rebuilt = WzSnapshot::load_or_build(&snapshot_path, &archive, GMS95)?;
+        assert_eq!(rebuilt.source.file_len, built.source.file_len + 16);
+        assert!(!WzSnapshot::load(&snapshot_path)?.is_stale(&rebuilt.source));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
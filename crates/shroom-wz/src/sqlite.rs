@@ -0,0 +1,439 @@
+//! Export of archives into a SQLite database.
+//!
+//! Tables:
+//! * `images`: path, size and checksum of every image
+//! * `props`: every property with It's path in the image, type and value. Numbers are
+//!   stored in `int_value` or `real_value`, strings, UOLs and errors in `text_value`,
+//!   vectors in `x` and `y` and convex shapes as json in `text_value`
+//! * `canvases`: size, depth and origin of the canvas properties with an optional png
+//!
+//! Images are read and written one by one, so only a single parsed image is kept
+//! in memory.
+
+use std::{
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use image::ImageFormat;
+use rusqlite::{params, Connection};
+
+use crate::{file::WzIO, l1::canvas::WzCanvasDepth, val::WzValue, WzReader};
+
+const SCHEMA: &str = "
+CREATE TABLE images (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL UNIQUE,
+    size INTEGER NOT NULL,
+    checksum INTEGER NOT NULL
+);
+CREATE TABLE props (
+    id INTEGER PRIMARY KEY,
+    image_id INTEGER NOT NULL REFERENCES images(id),
+    path TEXT NOT NULL,
+    type TEXT NOT NULL,
+    int_value INTEGER,
+    real_value REAL,
+    text_value TEXT,
+    x INTEGER,
+    y INTEGER
+);
+CREATE INDEX props_image_path ON props(image_id, path);
+CREATE TABLE canvases (
+    prop_id INTEGER PRIMARY KEY REFERENCES props(id),
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    depth TEXT NOT NULL,
+    origin_x INTEGER,
+    origin_y INTEGER,
+    png BLOB
+);
+";
+
+/// Columns of a row in `props`
+#[derive(Default)]
+struct PropRow {
+    ty: &'static str,
+    int: Option<i64>,
+    real: Option<f64>,
+    text: Option<String>,
+    xy: Option<(i32, i32)>,
+}
+
+impl PropRow {
+    fn new(val: &WzValue) -> anyhow::Result<Self> {
+        let row = |ty| Self {
+            ty,
+            ..Default::default()
+        };
+        Ok(match val {
+            WzValue::Object(_) => row("object"),
+            WzValue::Null => row("null"),
+            WzValue::F32(v) => Self {
+                real: Some(*v as f64),
+                ..row("f32")
+            },
+            WzValue::F64(v) => Self {
+                real: Some(*v),
+                ..row("f64")
+            },
+            WzValue::Short(v) => Self {
+                int: Some(*v as i64),
+                ..row("short")
+            },
+            WzValue::Int(v) => Self {
+                int: Some(*v as i64),
+                ..row("int")
+            },
+            WzValue::Long(v) => Self {
+                int: Some(*v),
+                ..row("long")
+            },
+            WzValue::String(v) => Self {
+                text: Some(v.clone()),
+                ..row("string")
+            },
+            WzValue::Vec(v) => Self {
+                xy: Some((v.x, v.y)),
+                ..row("vec")
+            },
+            WzValue::Convex(v) => Self {
+                text: Some(serde_json::to_string(v)?),
+                ..row("convex")
+            },
+            // The length in ms
+            WzValue::Sound(v) => Self {
+                int: Some(v.sound.len_ms.0 as i64),
+                ..row("sound")
+            },
+            WzValue::Canvas(_) => row("canvas"),
+            WzValue::Link(v) => Self {
+                text: Some(v.clone()),
+                ..row("link")
+            },
+            WzValue::Error(v) => Self {
+                text: Some(v.reason.clone()),
+                ..row("error")
+            },
+        })
+    }
+}
+
+fn depth_name(depth: WzCanvasDepth) -> &'static str {
+    match depth {
+        WzCanvasDepth::BGRA4444 => "BGRA4444",
+        WzCanvasDepth::BGRA8888 => "BGRA8888",
+        WzCanvasDepth::BGR565 => "BGR565",
+        WzCanvasDepth::DXT3 => "DXT3",
+        WzCanvasDepth::DXT5 => "DXT5",
+    }
+}
+
+/// Counts of the exported rows
+#[derive(Debug, Default)]
+pub struct WzSqliteStats {
+    pub images: usize,
+    pub props: usize,
+    pub canvases: usize,
+    /// Canvases, which couldn't be decoded, they are exported without png
+    pub canvas_errors: usize,
+    /// Images which couldn't be read with their error, they are left out
+    pub skipped: Vec<(String, anyhow::Error)>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct WzSqliteExporter {
+    pngs: bool,
+}
+
+impl WzSqliteExporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also stores the decoded canvases as png
+    pub fn with_pngs(mut self, pngs: bool) -> Self {
+        self.pngs = pngs;
+        self
+    }
+
+    /// Creates the database file, which must not exist yet. The database is written to
+    /// `<path>.part` and renamed when It's complete, so a failed export leaves nothing behind
+    pub fn export_file<R: WzIO>(
+        &self,
+        r: &mut WzReader<R>,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<WzSqliteStats> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".part");
+        let tmp = PathBuf::from(tmp);
+        // A leftover of a crashed export
+        if tmp.exists() {
+            std::fs::remove_file(&tmp)?;
+        }
+
+        // Reserves the path, the rename replaces only our own empty file
+        std::fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|err| match err.kind() {
+                std::io::ErrorKind::AlreadyExists => {
+                    anyhow::format_err!("Database already exists: {path:?}")
+                }
+                _ => err.into(),
+            })?;
+        let res = Connection::open(&tmp)
+            .map_err(anyhow::Error::from)
+            .and_then(|mut conn| {
+                let stats = self.export(r, &mut conn)?;
+                conn.close().map_err(|(_, err)| err)?;
+                std::fs::rename(&tmp, path)?;
+                Ok(stats)
+            });
+        if res.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+            let _ = std::fs::remove_file(path);
+        }
+        res
+    }
+
+    /// Creates the tables and exports all images of the archive in one transaction
+    pub fn export<R: WzIO>(
+        &self,
+        r: &mut WzReader<R>,
+        conn: &mut Connection,
+    ) -> anyhow::Result<WzSqliteStats> {
+        let tx = conn.transaction()?;
+        tx.execute_batch(SCHEMA)?;
+
+        // Only the headers are collected, the images are read one by one
+        let imgs = r.walk_images().collect::<anyhow::Result<Vec<_>>>()?;
+        let mut stats = WzSqliteStats::default();
+        {
+            let mut insert_img =
+                tx.prepare("INSERT INTO images (path, size, checksum) VALUES (?1, ?2, ?3)")?;
+            let mut insert_prop = tx.prepare(
+                "INSERT INTO props (image_id, path, type, int_value, real_value, text_value, x, y)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            let mut insert_canvas = tx.prepare(
+                "INSERT INTO canvases (prop_id, width, height, depth, origin_x, origin_y, png)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+
+            for img in imgs {
+                // Unreadable parts are exported as errors, images without a readable
+                // root are skipped
+                let res = r
+                    .img_reader(&img.hdr)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|img_r| {
+                        let mut img_r = img_r.with_path(&img.path);
+                        let (root, _) = WzValue::read_lenient(&mut img_r)?;
+                        Ok((img_r, root))
+                    });
+                let (mut img_r, root) = match res {
+                    Ok(res) => res,
+                    Err(err) => {
+                        stats.skipped.push((img.path, err));
+                        continue;
+                    }
+                };
+
+                let image_id = insert_img.insert(params![
+                    img.path,
+                    img.hdr.blob_size.0,
+                    img.hdr.checksum.0
+                ])?;
+                stats.images += 1;
+                for entry in root.walk().skip(1) {
+                    let row = PropRow::new(entry.value)?;
+                    let (x, y) = row.xy.unzip();
+                    let prop_id = insert_prop.insert(params![
+                        image_id, entry.path, row.ty, row.int, row.real, row.text, x, y
+                    ])?;
+                    stats.props += 1;
+
+                    let WzValue::Canvas(canvas) = entry.value else {
+                        continue;
+                    };
+                    let origin = match canvas.sub.as_deref().and_then(|s| s.get_path("origin")) {
+                        Some(WzValue::Vec(v)) => Some((v.x, v.y)),
+                        _ => None,
+                    };
+                    let (origin_x, origin_y) = origin.unzip();
+                    let png = if self.pngs {
+                        let png = canvas
+                            .read_canvas_resolved(&mut img_r, &root, None)
+                            .and_then(|canvas| {
+                                let mut png = Cursor::new(Vec::new());
+                                canvas
+                                    .to_raw_rgba_image()?
+                                    .write_to(&mut png, ImageFormat::Png)?;
+                                Ok(png.into_inner())
+                            });
+                        if png.is_err() {
+                            stats.canvas_errors += 1;
+                        }
+                        png.ok()
+                    } else {
+                        None
+                    };
+                    insert_canvas.execute(params![
+                        prop_id,
+                        canvas.canvas.width.0,
+                        canvas.canvas.height.0,
+                        depth_name(canvas.canvas.depth),
+                        origin_x,
+                        origin_y,
+                        png
+                    ])?;
+                    stats.canvases += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use rusqlite::Connection;
+
+    use crate::{
+        test_util::{
+            build_archive, canvas_val, dir, img, img_blob, link_val, obj, str_val, TestVal,
+        },
+        WzReader, GMS95,
+    };
+
+    use super::WzSqliteExporter;
+
+    #[test]
+    fn export() -> anyhow::Result<()> {
+        let data = build_archive(
+            GMS95,
+            vec![dir(
+                "Item",
+                vec![img(
+                    "0200.img",
+                    img_blob(
+                        GMS95,
+                        &obj([(
+                            "info",
+                            TestVal::Obj(obj([
+                                ("price", TestVal::Int(100)),
+                                ("name", str_val("Red Potion")),
+                                ("link", link_val("../price")),
+                                ("pos", TestVal::Vec2(3, -4)),
+                                (
+                                    "icon",
+                                    canvas_val(4, 3, obj([("origin", TestVal::Vec2(1, 2))])),
+                                ),
+                            ])),
+                        )]),
+                    ),
+                )],
+            )],
+        );
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let mut conn = Connection::open_in_memory()?;
+        let stats = WzSqliteExporter::new()
+            .with_pngs(true)
+            .export(&mut r, &mut conn)?;
+        assert_eq!((stats.images, stats.props, stats.canvases), (1, 7, 1));
+        assert_eq!(stats.canvas_errors, 0);
+
+        let path: String = conn.query_row("SELECT path FROM images", [], |row| row.get(0))?;
+        assert_eq!(path, "Item/0200.img");
+        let price: i64 = conn.query_row(
+            "SELECT int_value FROM props WHERE path = 'info/price' AND type = 'int'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(price, 100);
+        let pos: (i32, i32) = conn.query_row(
+            "SELECT x, y FROM props WHERE path = 'info/pos'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        assert_eq!(pos, (3, -4));
+        let link: String = conn.query_row(
+            "SELECT text_value FROM props WHERE type = 'link'",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(link, "../price");
+
+        let (w, h, origin, png): (u32, u32, (i32, i32), Vec<u8>) = conn.query_row(
+            "SELECT width, height, origin_x, origin_y, png FROM canvases
+            JOIN props ON props.id = canvases.prop_id WHERE props.path = 'info/icon'",
+            [],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    (row.get(2)?, row.get(3)?),
+                    row.get(4)?,
+                ))
+            },
+        )?;
+        assert_eq!((w, h, origin), (4, 3, (1, 2)));
+        let png = image::load_from_memory_with_format(&png, image::ImageFormat::Png)?;
+        assert_eq!((png.width(), png.height()), (4, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn export_file() -> anyhow::Result<()> {
+        let data = build_archive(
+            GMS95,
+            vec![img(
+                "0.img",
+                img_blob(GMS95, &obj([("a", TestVal::Int(1))])),
+            )],
+        );
+        let dir = std::env::temp_dir().join(format!("shroom-sqlite-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir)?;
+
+        // A failed export leaves no database behind
+        let mut broken = data.clone();
+        let data_offset = u32::from_le_bytes(broken[12..16].try_into()?) as usize;
+        // Root directory with more entries than the file has
+        broken[data_offset + 2] = 0x7F;
+        let mut r = WzReader::open(Cursor::new(broken), GMS95)?;
+        let db = dir.join("broken.db");
+        assert!(WzSqliteExporter::new().export_file(&mut r, &db).is_err());
+        assert!(!db.exists());
+        assert!(!dir.join("broken.db.part").exists());
+
+        // Unreadable images are skipped
+        let truncated = &data[..data.len() - 8];
+        let mut r = WzReader::open(Cursor::new(truncated), GMS95)?;
+        let db = dir.join("skipped.db");
+        let stats = WzSqliteExporter::new().export_file(&mut r, &db)?;
+        assert_eq!(stats.images, 0);
+        assert_eq!(stats.skipped.len(), 1);
+        assert_eq!(stats.skipped[0].0, "0.img");
+        assert!(db.exists());
+
+        let mut r = WzReader::open(Cursor::new(data.as_slice()), GMS95)?;
+        let db = dir.join("ok.db");
+        let stats = WzSqliteExporter::new().export_file(&mut r, &db)?;
+        assert_eq!(stats.images, 1);
+        assert!(db.exists());
+        assert!(!dir.join("ok.db.part").exists());
+        // An existing database is kept
+        let len = std::fs::metadata(&db)?.len();
+        assert!(WzSqliteExporter::new().export_file(&mut r, &db).is_err());
+        assert_eq!(std::fs::metadata(&db)?.len(), len);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
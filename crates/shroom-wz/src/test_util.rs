@@ -0,0 +1,324 @@
+//! Helpers to build small synthetic archives for the tests
+use std::io::{Cursor, Seek, SeekFrom, Write};
+
+use binrw::BinWrite;
+
+use crate::{
+    crypto::WzCrypto,
+    ctx::{WzContext, WzImgWriteCtx, WzStrWriteTable},
+    l0::{tree::WzTree, WzDirNode},
+    l1::{
+        obj::WzObject,
+        obj::{wz_ty_str, OBJ_TYPE_CANVAS, OBJ_TYPE_PROPERTY, OBJ_TYPE_SOUND_DX8},
+        prop::{WzPropValue, WzUOL, WzVector2D},
+        sound::{MediaHeader, GUID},
+        str::WzImgStr,
+    },
+    ty::WzInt,
+    util::WriteExt,
+    writer::{WzArchiveNode, WzArchiveWriter},
+    WzConfig, WzReader,
+};
+
+pub enum TestVal {
+    Int(i32),
+    Str(String),
+    Link(String),
+    Vec2(i32, i32),
+    Obj(Vec<(String, TestVal)>),
+    /// BGRA8888 canvas
+    Canvas {
+        w: u32,
+        h: u32,
+        bgra: Vec<u8>,
+        sub: Vec<(String, TestVal)>,
+    },
+    /// Mp3 sound
+    Sound {
+        data: Vec<u8>,
+        len_ms: i32,
+    },
+}
+
+pub fn obj<const N: usize>(entries: [(&str, TestVal); N]) -> Vec<(String, TestVal)> {
+    entries
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+}
+
+pub fn str_val(s: &str) -> TestVal {
+    TestVal::Str(s.to_string())
+}
+
+pub fn link_val(s: &str) -> TestVal {
+    TestVal::Link(s.to_string())
+}
+
+pub fn canvas_val(w: u32, h: u32, sub: Vec<(String, TestVal)>) -> TestVal {
+    let bgra = (0..w * h)
+        .flat_map(|i| [i as u8, (i >> 8) as u8, 0x7f, 0xff])
+        .collect();
+    TestVal::Canvas { w, h, bgra, sub }
+}
+
+pub type TestNode = WzArchiveNode<Vec<u8>>;
+
+pub fn dir(name: &str, entries: Vec<TestNode>) -> TestNode {
+    WzArchiveNode::dir(name, entries)
+}
+
+pub fn img(name: &str, blob: Vec<u8>) -> TestNode {
+    WzArchiveNode::img(name, blob)
+}
+
+fn write_obj_value<W: Write + Seek>(
+    w: &mut W,
+    f: impl FnOnce(&mut W) -> binrw::BinResult<()>,
+) -> binrw::BinResult<()> {
+    9u8.write_le(w)?;
+    let pos = w.stream_position()?;
+    0u32.write_le(w)?;
+    f(w)?;
+    let end = w.stream_position()?;
+    w.seek(SeekFrom::Start(pos))?;
+    ((end - pos - 4) as u32).write_le(w)?;
+    w.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+fn write_entries<W: Write + Seek>(
+    w: &mut W,
+    ctx: WzImgWriteCtx<'_>,
+    entries: &[(String, TestVal)],
+) -> binrw::BinResult<()> {
+    0u16.write_le(w)?;
+    WzInt(entries.len() as i32).write_le(w)?;
+    for (name, val) in entries {
+        WzImgStr::new(name.clone()).write_le_args(w, ctx)?;
+        write_val(w, ctx, val)?;
+    }
+    Ok(())
+}
+
+fn write_val<W: Write + Seek>(
+    w: &mut W,
+    ctx: WzImgWriteCtx<'_>,
+    val: &TestVal,
+) -> binrw::BinResult<()> {
+    match val {
+        TestVal::Int(v) => WzPropValue::Int1(WzInt(*v)).write_le_args(w, ctx),
+        TestVal::Str(v) => WzPropValue::Str(WzImgStr::new(v.clone())).write_le_args(w, ctx),
+        TestVal::Link(v) => write_obj_value(w, |w| {
+            WzObject::UOL(WzUOL {
+                unknown: 0,
+                entries: WzImgStr::new(v.clone()),
+            })
+            .write_le_args(w, ctx)
+        }),
+        TestVal::Vec2(x, y) => write_obj_value(w, |w| {
+            WzObject::Vec2(WzVector2D {
+                x: WzInt(*x),
+                y: WzInt(*y),
+            })
+            .write_le_args(w, ctx)
+        }),
+        TestVal::Obj(entries) => write_obj_value(w, |w| {
+            wz_ty_str(OBJ_TYPE_PROPERTY).write_le_args(w, ctx)?;
+            write_entries(w, ctx, entries)
+        }),
+        TestVal::Canvas {
+            w: cw,
+            h,
+            bgra,
+            sub,
+        } => write_obj_value(w, |w| {
+            wz_ty_str(OBJ_TYPE_CANVAS).write_le_args(w, ctx)?;
+            0u8.write_le(w)?;
+            if sub.is_empty() {
+                0u8.write_le(w)?;
+            } else {
+                1u8.write_le(w)?;
+                write_entries(w, ctx, sub)?;
+            }
+            WzInt(*cw as i32).write_le(w)?;
+            WzInt(*h as i32).write_le(w)?;
+            WzInt(2).write_le(w)?;
+            0u8.write_le(w)?;
+            0u32.write_le(w)?;
+
+            let mut data = Vec::new();
+            data.compress_flate(bgra)?;
+            (data.len() as u32 + 1).write_le(w)?;
+            0u8.write_le(w)?;
+            data.write_le(w)
+        }),
+        TestVal::Sound { data, len_ms } => write_obj_value(w, |w| {
+            wz_ty_str(OBJ_TYPE_SOUND_DX8).write_le_args(w, ctx)?;
+            0u8.write_le(w)?;
+            WzInt(data.len() as i32).write_le(w)?;
+            WzInt(*len_ms).write_le(w)?;
+            MediaHeader {
+                unknown1: 0,
+                major_type: GUID(uuid::uuid!("E436EB83-524F-11CE-9F53-0020AF0BA770")),
+                sub_type: GUID(uuid::uuid!("E436EB8B-524F-11CE-9F53-0020AF0BA770")),
+                sample_size: 0,
+                format_type: GUID(uuid::uuid!("05589f81-c356-11ce-bf01-00aa0055595a")),
+            }
+            .write_le(w)?;
+            // MPEGLAYER3WAVEFORMAT
+            30u8.write_le(w)?;
+            (0x55u16, 2u16, 44100u32, 16000u32, 1u16, 0u16, 12u16).write_le(w)?;
+            (1u16, 2u32, 417u16, 1u16, 1393u16).write_le(w)?;
+            data.write_le(w)
+        }),
+    }
+}
+
+/// Serializes an image with the given root entries
+pub fn img_blob(cfg: WzConfig, entries: &[(String, TestVal)]) -> Vec<u8> {
+    let crypto = WzCrypto::from_cfg(cfg, 0);
+    let str_table = WzStrWriteTable::default();
+    let ctx = WzImgWriteCtx::new(&crypto, &str_table);
+    let mut w = Cursor::new(Vec::new());
+    wz_ty_str(OBJ_TYPE_PROPERTY)
+        .write_le_args(&mut w, ctx)
+        .unwrap();
+    write_entries(&mut w, ctx, entries).unwrap();
+    w.into_inner()
+}
+
+/// Builds an archive with the given root directory entries
+pub fn build_archive(cfg: WzConfig, root: Vec<TestNode>) -> Vec<u8> {
+    let mut w = Cursor::new(Vec::new());
+    WzArchiveWriter::new(cfg)
+        .with_desc("Synthetic test archive")
+        .write(&mut w, &root)
+        .unwrap();
+    w.into_inner()
+}
+
+/// Archive with the images `a.img` and `Link/lnk`, the entry of `lnk` is turned into
+/// a link to the entry of `a.img`. The link keeps It's own blob, so It's `Link/a.img`
+/// with the value of `linked`
+pub fn link_archive(cfg: WzConfig, target: Vec<u8>, linked: Vec<u8>) -> Vec<u8> {
+    let mut data = build_archive(
+        cfg,
+        vec![img("a.img", target), dir("Link", vec![img("lnk", linked)])],
+    );
+    let mut r = WzReader::open(Cursor::new(data.as_slice()), cfg).unwrap();
+    let data_offset = r.read_header().unwrap().data_offset;
+    let tree = WzTree::from_reader(&mut r, None).unwrap();
+    let Some(WzDirNode::Dir(link_dir)) = tree.get_by_path("Link") else {
+        panic!("Missing link directory");
+    };
+
+    // Entries follow the version hash and the entry count, the name of `lnk` has the
+    // same size as the offset of the link
+    let target_entry = 3u32;
+    let lnk_entry = link_dir.offset.0 as usize + 1;
+    assert_eq!(data[lnk_entry..lnk_entry + 2], [4, (-3i8) as u8]);
+    data[lnk_entry] = 2;
+    data[lnk_entry + 1..lnk_entry + 5].copy_from_slice(&target_entry.to_le_bytes());
+    assert_eq!(data[data_offset as usize + target_entry as usize], 4);
+    data
+}
+
+/// Archive with the directories `A` and `B`, the entry of `B` points to the directory
+/// of `A`, so both share It. With `cycle` set `B` points to the root directory instead
+pub fn shared_dir_archive(cfg: WzConfig, cycle: bool) -> Vec<u8> {
+    let blob = img_blob(cfg, &obj([("hp", TestVal::Int(1))]));
+    let data = build_archive(
+        cfg,
+        vec![
+            dir("A", vec![img("a.img", blob.clone())]),
+            dir("B", vec![img("b.img", blob)]),
+        ],
+    );
+    let mut r = WzReader::open(Cursor::new(data.clone()), cfg).unwrap();
+    let data_offset = r.read_header().unwrap().data_offset;
+    let root_offset = r.root_offset();
+    let mut root = r.read_root_dir().unwrap();
+    let [WzDirNode::Dir(a), WzDirNode::Dir(b)] = root.entries.0.as_mut_slice() else {
+        panic!("Expected two directories");
+    };
+    b.offset = if cycle { root_offset } else { a.offset };
+
+    // The entries keep their size, so the directory is rewritten in place
+    let crypto = WzCrypto::from_cfg(cfg, data_offset);
+    let mut w = Cursor::new(data);
+    w.set_position(root_offset.0 as u64);
+    root.write_le_args(&mut w, WzContext::new(&crypto)).unwrap();
+    w.into_inner()
+}
+
+/// Small archive used by most of the tests
+pub fn sample_archive(cfg: WzConfig) -> Vec<u8> {
+    let item = img_blob(
+        cfg,
+        &obj([
+            (
+                "info",
+                TestVal::Obj(obj([
+                    (
+                        "icon",
+                        canvas_val(4, 3, obj([("origin", TestVal::Vec2(1, 2))])),
+                    ),
+                    ("price", TestVal::Int(100)),
+                    ("name", str_val("Red Potion")),
+                ])),
+            ),
+            ("iconLink", link_val("info/icon")),
+        ]),
+    );
+    let sound = img_blob(
+        cfg,
+        &obj([(
+            "bgm",
+            TestVal::Sound {
+                data: (0..64).collect(),
+                len_ms: 1000,
+            },
+        )]),
+    );
+    let mob = img_blob(
+        cfg,
+        &obj([(
+            "stand",
+            TestVal::Obj(obj([
+                ("0", canvas_val(2, 2, obj([("delay", TestVal::Int(120))]))),
+                ("1", canvas_val(3, 2, obj([("delay", TestVal::Int(80))]))),
+            ])),
+        )]),
+    );
+
+    build_archive(
+        cfg,
+        vec![
+            dir("Item", vec![img("0200.img", item), dir("Empty", vec![])]),
+            img("Bgm.img", sound),
+            dir("Mob", vec![img("100100.img", mob)]),
+        ],
+    )
+}
+
+/// Small deterministic xorshift rng for mutation tests
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Random number in `0..n`
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n.max(1) as u64) as usize
+    }
+}
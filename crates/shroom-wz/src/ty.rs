@@ -4,9 +4,14 @@ use std::{
     ops::{Deref, DerefMut, Neg},
 };
 
-use binrw::{BinRead, BinWrite, VecArgs};
+use binrw::{BinRead, BinWrite};
 
-use crate::{crypto::WzCrypto, ctx::WzContext, util::custom_binrw_error};
+use crate::{
+    crypto::WzCrypto,
+    ctx::WzContext,
+    error::WzError,
+    util::{custom_binrw_error, read_exact_vec, MAX_PREALLOC},
+};
 
 pub type RefWzCrypto<'a> = (&'a WzCrypto,);
 
@@ -190,6 +195,13 @@ impl DerefMut for WzStr {
     }
 }
 
+/// Reads the length of a long string
+fn read_str_len<R: Read + Seek>(reader: &mut R, endian: binrw::Endian) -> binrw::BinResult<usize> {
+    let ln = i32::read_options(reader, endian, ())?;
+    usize::try_from(ln)
+        .map_err(|_| custom_binrw_error(reader, anyhow::format_err!("Invalid string length: {ln}")))
+}
+
 impl BinRead for WzStr {
     type Args<'a> = WzContext<'a>;
 
@@ -201,30 +213,35 @@ impl BinRead for WzStr {
         let flag = i8::read_options(reader, endian, ())?;
         let str = if flag <= 0 {
             let ln = if flag == -128 {
-                i32::read_options(reader, endian, ())? as usize
+                read_str_len(reader, endian)?
             } else {
-                -flag as usize
+                -(flag as i32) as usize
             };
 
-            let mut data = vec![0; ln];
-            reader.read_exact(&mut data)?;
+            let mut data = read_exact_vec(&mut *reader, ln)?;
             xor_mask_ascii(&mut data);
             args.0.transform(data.as_mut_slice().into());
             encoding_rs::mem::decode_latin1(data.as_slice()).into_owned()
         } else {
             let ln = if flag == 127 {
-                i32::read_options(reader, endian, ())? as usize
+                read_str_len(reader, endian)?
             } else {
                 flag as usize
             };
 
-            let mut data = vec![0u16; ln];
-            reader.read_exact(bytemuck::cast_slice_mut(data.as_mut_slice()))?;
+            let data = read_exact_vec(&mut *reader, ln * 2)?;
+            let mut data = data
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>();
             xor_mask_unicode(&mut data);
             args.0
                 .transform(bytemuck::cast_slice_mut(data.as_mut_slice()).into());
 
-            String::from_utf16(&data).map_err(|err| custom_binrw_error(reader, err.into()))?
+            String::from_utf16(&data).map_err(|err| {
+                WzError::crypto(format!("Invalid string: {err}"))
+                    .into_binrw(reader.stream_position().unwrap_or(0))
+            })?
         };
 
         Ok(WzStr::new(str))
@@ -248,7 +265,7 @@ impl BinWrite for WzStr {
             let n = data.len();
             if n >= 128 {
                 i8::MIN.write_options(writer, endian, ())?;
-                (n as i32).neg().write_options(writer, endian, ())?;
+                (n as i32).write_options(writer, endian, ())?;
             } else {
                 (n as i8).neg().write_options(writer, endian, ())?;
             }
@@ -293,14 +310,19 @@ where
         args: Self::Args<'_>,
     ) -> binrw::BinResult<Self> {
         let n = WzInt::read_options(reader, endian, ())?;
-        Ok(Self(Vec::read_options(
-            reader,
-            endian,
-            VecArgs {
-                count: n.0 as usize,
-                inner: args,
-            },
-        )?))
+        let count = usize::try_from(n.0).map_err(|_| {
+            custom_binrw_error(
+                reader,
+                anyhow::format_err!("Invalid element count: {}", n.0),
+            )
+        })?;
+        // Elements are pushed as they are read, so a bogus count fails on EOF
+        // instead of allocating upfront
+        let mut v = Vec::with_capacity(count.min(MAX_PREALLOC / std::mem::size_of::<B>().max(1)));
+        for _ in 0..count {
+            v.push(B::read_options(reader, endian, args.clone())?);
+        }
+        Ok(Self(v))
     }
 }
 
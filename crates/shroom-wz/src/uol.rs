@@ -0,0 +1,292 @@
+//! Resolution of UOL links in value trees.
+//!
+//! A `WzValue::Link` holds a path relative to the parent of the link, like `../stand1/0`.
+//! Links may point to other links, so resolution follows them up to a depth limit and
+//! detects cycles.
+
+use crate::val::WzValue;
+
+/// Default limit for the nesting of links, which point to other links
+pub const DEFAULT_MAX_LINK_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum WzLinkError {
+    #[error("Path not found: {0}")]
+    NotFound(String),
+    /// The target of the link doesn't exist or escapes the root
+    #[error("Dangling link {link} -> {target}")]
+    Dangling { link: String, target: String },
+    #[error("Link cycle at {0}")]
+    Cycle(String),
+    #[error("Link depth exceeds {limit} at {link}")]
+    DepthExceeded { link: String, limit: usize },
+}
+
+/// A link which can't be resolved, see `WzLinkResolver::dangling_links`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WzDanglingLink {
+    /// Path of the link node
+    pub path: String,
+    pub link: String,
+    pub err: WzLinkError,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WzLinkResolver {
+    pub max_depth: usize,
+}
+
+impl Default for WzLinkResolver {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_LINK_DEPTH)
+    }
+}
+
+type Resolved<'a> = (&'a WzValue, Vec<String>);
+
+impl WzLinkResolver {
+    pub fn new(max_depth: usize) -> Self {
+        Self { max_depth }
+    }
+
+    /// Resolves the path from `root`, following all links on the way including the
+    /// last one
+    pub fn resolve_path<'a>(
+        &self,
+        root: &'a WzValue,
+        path: &str,
+    ) -> Result<&'a WzValue, WzLinkError> {
+        let mut visited = Vec::new();
+        self.resolve(root, split_path(path), &mut visited)
+            .map(|(v, _)| v)
+    }
+
+    /// Resolves the path from `root` like `resolve_path`, returns the target with It's
+    /// canonical path
+    pub fn resolve_target<'a>(
+        &self,
+        root: &'a WzValue,
+        path: &str,
+    ) -> Result<(&'a WzValue, String), WzLinkError> {
+        let mut visited = Vec::new();
+        self.resolve(root, split_path(path), &mut visited)
+            .map(|(v, path)| (v, path.join("/")))
+    }
+
+    /// Resolves the link node at `path`, returns the canonical path of the target
+    pub fn resolve_link_path(&self, root: &WzValue, path: &str) -> Result<String, WzLinkError> {
+        let mut visited = Vec::new();
+        self.resolve(root, split_path(path), &mut visited)
+            .map(|(_, path)| path.join("/"))
+    }
+
+    /// Collects all links in the tree, which can't be resolved
+    pub fn dangling_links(&self, root: &WzValue) -> Vec<WzDanglingLink> {
+        let mut out = Vec::new();
+        self.collect_dangling(root, root, &mut Vec::new(), &mut out);
+        out
+    }
+
+    fn collect_dangling(
+        &self,
+        root: &WzValue,
+        val: &WzValue,
+        path: &mut Vec<String>,
+        out: &mut Vec<WzDanglingLink>,
+    ) {
+        match val {
+            WzValue::Link(link) => {
+                if let Err(err) = self.resolve(root, path.clone(), &mut Vec::new()) {
+                    out.push(WzDanglingLink {
+                        path: path.join("/"),
+                        link: link.clone(),
+                        err,
+                    });
+                }
+            }
+            WzValue::Object(obj) => {
+                for (name, val) in obj.0.iter() {
+                    path.push(name.clone());
+                    self.collect_dangling(root, val, path, out);
+                    path.pop();
+                }
+            }
+            WzValue::Canvas(canvas) => {
+                if let Some(sub) = canvas.sub.as_deref() {
+                    self.collect_dangling(root, sub, path, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Resolves the absolute path, `visited` holds the links which are currently being
+    /// resolved, so It's only as long as the nesting of links
+    fn resolve<'a>(
+        &self,
+        root: &'a WzValue,
+        path: Vec<String>,
+        visited: &mut Vec<String>,
+    ) -> Result<Resolved<'a>, WzLinkError> {
+        let mut cur = root;
+        let mut cur_path: Vec<String> = Vec::with_capacity(path.len());
+
+        for part in path {
+            cur = child(cur, &part).ok_or_else(|| {
+                cur_path.push(part.clone());
+                WzLinkError::NotFound(cur_path.join("/"))
+            })?;
+            cur_path.push(part);
+
+            let WzValue::Link(link) = cur else {
+                continue;
+            };
+
+            let link_path = cur_path.join("/");
+            if visited.contains(&link_path) {
+                return Err(WzLinkError::Cycle(link_path));
+            }
+            if visited.len() >= self.max_depth {
+                return Err(WzLinkError::DepthExceeded {
+                    link: link_path,
+                    limit: self.max_depth,
+                });
+            }
+            visited.push(link_path.clone());
+
+            let dangling = || WzLinkError::Dangling {
+                link: link_path.clone(),
+                target: link.clone(),
+            };
+            // Relative to the parent of the link
+            let target =
+                join_relative(&cur_path[..cur_path.len() - 1], link).ok_or_else(dangling)?;
+            let (val, path) = match self.resolve(root, target, visited) {
+                Ok(v) => v,
+                Err(WzLinkError::NotFound(_)) => return Err(dangling()),
+                Err(err) => return Err(err),
+            };
+            visited.pop();
+            cur = val;
+            cur_path = path;
+        }
+
+        Ok((cur, cur_path))
+    }
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|p| !p.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Joins the relative link to the base path, `None` If It escapes the root
+fn join_relative(base: &[String], link: &str) -> Option<Vec<String>> {
+    let mut path = base.to_vec();
+    for part in link.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                path.pop()?;
+            }
+            part => path.push(part.to_string()),
+        }
+    }
+    Some(path)
+}
+
+/// Child of an object or the sub property of a canvas
+fn child<'a>(val: &'a WzValue, name: &str) -> Option<&'a WzValue> {
+    match val {
+        WzValue::Object(obj) => obj.get(name),
+        WzValue::Canvas(canvas) => match canvas.sub.as_deref() {
+            Some(WzValue::Object(obj)) => obj.get(name),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl WzValue {
+    /// Like `get_path`, but follows links with the default resolver
+    pub fn get_path_resolved(&self, path: &str) -> Option<&WzValue> {
+        WzLinkResolver::default().resolve_path(self, path).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::indexmap;
+
+    use crate::val::WzValue;
+
+    use super::{WzLinkError, WzLinkResolver};
+
+    fn link(s: &str) -> WzValue {
+        WzValue::Link(s.to_string())
+    }
+
+    #[test]
+    fn resolve() {
+        let root = WzValue::from(indexmap! {
+            "stand1".to_string() => WzValue::from(indexmap! {
+                "0".to_string() => WzValue::from(indexmap! {
+                    "delay".to_string() => WzValue::Int(100),
+                }),
+            }),
+            "attack".to_string() => WzValue::from(indexmap! {
+                "0".to_string() => link("../stand1/0"),
+                "1".to_string() => link("0"),
+                "2".to_string() => link("../../stand1"),
+                "3".to_string() => link("4"),
+                "4".to_string() => link("3"),
+                "5".to_string() => link("missing"),
+            }),
+        });
+
+        assert_eq!(root.get_path("attack/0/delay"), None);
+        assert_eq!(
+            root.get_path_resolved("attack/0/delay"),
+            Some(&WzValue::Int(100))
+        );
+        assert_eq!(
+            root.get_path_resolved("attack/1/delay"),
+            Some(&WzValue::Int(100))
+        );
+
+        let r = WzLinkResolver::default();
+        assert_eq!(r.resolve_link_path(&root, "attack/1").unwrap(), "stand1/0");
+        assert!(matches!(
+            r.resolve_path(&root, "attack/3"),
+            Err(WzLinkError::Cycle(_))
+        ));
+        assert!(matches!(
+            WzLinkResolver::new(1).resolve_path(&root, "attack/1"),
+            Err(WzLinkError::DepthExceeded { limit: 1, .. })
+        ));
+
+        let dangling = r.dangling_links(&root);
+        let paths = dangling.iter().map(|d| d.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["attack/2", "attack/3", "attack/4", "attack/5"]);
+    }
+
+    #[test]
+    fn resolve_siblings() {
+        // `base/x` resolves `base` again to get to It's sibling
+        let root = WzValue::from(indexmap! {
+            "base".to_string() => link("real"),
+            "real".to_string() => WzValue::from(indexmap! {
+                "x".to_string() => link("../base/y"),
+                "y".to_string() => WzValue::Int(1),
+            }),
+        });
+
+        // Three links are followed, but they are nested at most two deep
+        let r = WzLinkResolver::new(2);
+        assert_eq!(r.resolve_path(&root, "base/x"), Ok(&WzValue::Int(1)));
+        assert_eq!(r.resolve_link_path(&root, "base/x").unwrap(), "real/y");
+        assert!(r.dangling_links(&root).is_empty());
+    }
+}
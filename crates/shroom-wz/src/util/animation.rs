@@ -1,18 +1,45 @@
 use std::time::Duration;
 
-//use image::{imageops::overlay, EncodableLayout, RgbaImage};
+use image::{
+    imageops::{crop_imm, overlay},
+    RgbaImage,
+};
 
 use crate::{
     canvas::Canvas,
+    canvas_link::WzOutlinkResolver,
     file::{WzIO, WzImgReader},
     l1::canvas::WzCanvas,
-    val::{ObjectVal, Vec2Val, WzValue},
+    val::{CanvasVal, ObjectVal, Vec2Val, WzValue},
 };
 
+/// Delay of frames without a `delay`
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+/// Pixels with a lower alpha are transparent in a GIF, which has no partial transparency
+const GIF_ALPHA_THRESHOLD: u8 = 0x80;
+
+/// Number of times an exported animation is played
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AnimationRepeat {
+    /// Loops forever
+    #[default]
+    Infinite,
+    /// Played once and repeated the given number of times
+    Finite(u16),
+}
+
+/// Frame drawn onto the canvas of the whole animation
+pub struct RenderedFrame {
+    pub img: RgbaImage,
+    pub delay: Duration,
+}
+
 pub struct AnimationFrame {
     pub offset: Option<Vec2Val>,
     pub delay: Option<Duration>,
-    pub canvas: WzCanvas,
+    /// Canvas with It's sub property, which may link to the actual bitmap
+    pub canvas: CanvasVal,
 }
 
 pub struct Animation {
@@ -25,8 +52,8 @@ impl Animation {
         let mut dim_h = 0;
         let mut dim_w = 0;
         for frame in frames.iter() {
-            dim_h = dim_h.max(frame.canvas.height());
-            dim_w = dim_w.max(frame.canvas.width());
+            dim_h = dim_h.max(frame.canvas.canvas.height());
+            dim_w = dim_w.max(frame.canvas.canvas.width());
         }
         Self {
             frames,
@@ -65,7 +92,7 @@ impl Animation {
             frames.push(AnimationFrame {
                 offset: origin,
                 delay,
-                canvas: frame.canvas.clone(),
+                canvas: frame.clone(),
             });
         }
 
@@ -79,10 +106,20 @@ impl Animation {
         })
     }
 
-    pub fn load_all_frames<R: WzIO>(&self, r: &mut WzImgReader<R>) -> anyhow::Result<Vec<Canvas>> {
+    /// Reads the bitmaps of all frames, following canvas links like
+    /// `CanvasVal::read_canvas_resolved`. `root` is the root value of the image
+    pub fn load_all_frames<R: WzIO>(
+        &self,
+        r: &mut WzImgReader<R>,
+        root: &WzValue,
+        mut outlinks: Option<&mut dyn WzOutlinkResolver>,
+    ) -> anyhow::Result<Vec<Canvas>> {
         let mut v = vec![];
         for frame in self.frames.iter() {
-            v.push(r.read_canvas(&frame.canvas)?);
+            let outlinks = outlinks
+                .as_mut()
+                .map(|o| &mut **o as &mut dyn WzOutlinkResolver);
+            v.push(frame.canvas.read_canvas_resolved(r, root, outlinks)?);
         }
         Ok(v)
     }
@@ -96,7 +133,7 @@ impl Animation {
     }
 
     pub fn get_canvas_frame(&self, frame: usize) -> Option<&WzCanvas> {
-        self.frames.get(frame).map(|f| &f.canvas)
+        self.frames.get(frame).map(|f| &f.canvas.canvas)
     }
 
     pub fn frames(&self) -> &[AnimationFrame] {
@@ -107,10 +144,139 @@ impl Animation {
         self.dim
     }
 
+    /// Draws the frames onto a canvas, which fits all frames aligned by their origin
+    pub fn render_frames<R: WzIO>(
+        &self,
+        r: &mut WzImgReader<R>,
+        root: &WzValue,
+        outlinks: Option<&mut dyn WzOutlinkResolver>,
+    ) -> anyhow::Result<Vec<RenderedFrame>> {
+        let imgs = self
+            .load_all_frames(r, root, outlinks)?
+            .iter()
+            .map(Canvas::to_raw_rgba_image)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // Top left corner of every frame relative to the origin
+        let pos = self
+            .frames
+            .iter()
+            .map(|frame| frame.offset.map_or((0, 0), |o| (-o.x as i64, -o.y as i64)))
+            .collect::<Vec<_>>();
+        let min_x = pos.iter().map(|p| p.0).min().unwrap_or(0);
+        let min_y = pos.iter().map(|p| p.1).min().unwrap_or(0);
+        let max_x = pos
+            .iter()
+            .zip(&imgs)
+            .map(|(p, img)| p.0 + img.width() as i64)
+            .max()
+            .unwrap_or(0);
+        let max_y = pos
+            .iter()
+            .zip(&imgs)
+            .map(|(p, img)| p.1 + img.height() as i64)
+            .max()
+            .unwrap_or(0);
+        let (w, h) = ((max_x - min_x) as u32, (max_y - min_y) as u32);
+        if w == 0 || h == 0 {
+            anyhow::bail!("Empty animation");
+        }
+
+        Ok(self
+            .frames
+            .iter()
+            .zip(imgs)
+            .zip(pos)
+            .map(|((frame, img), (x, y))| {
+                let mut back = RgbaImage::new(w, h);
+                overlay(&mut back, &img, x - min_x, y - min_y);
+                RenderedFrame {
+                    img: back,
+                    delay: frame.delay.unwrap_or(DEFAULT_DELAY),
+                }
+            })
+            .collect())
+    }
+
+    /// Encodes the animation as GIF, every frame gets It's own quantized palette
+    pub fn to_gif<R: WzIO>(
+        &self,
+        r: &mut WzImgReader<R>,
+        root: &WzValue,
+        outlinks: Option<&mut dyn WzOutlinkResolver>,
+        repeat: AnimationRepeat,
+    ) -> anyhow::Result<Vec<u8>> {
+        let frames = self.render_frames(r, root, outlinks)?;
+        let (w, h) = frames[0].img.dimensions();
+        let (w, h) = (u16::try_from(w)?, u16::try_from(h)?);
+
+        let mut data = Vec::new();
+        let mut encoder = gif::Encoder::new(&mut data, w, h, &[])?;
+        // A loop count of 0 means forever, so It's left out to play the animation once
+        match repeat {
+            AnimationRepeat::Infinite => encoder.set_repeat(gif::Repeat::Infinite)?,
+            AnimationRepeat::Finite(0) => {}
+            AnimationRepeat::Finite(n) => encoder.set_repeat(gif::Repeat::Finite(n))?,
+        }
+        for frame in frames {
+            let mut pixels = frame.img.into_raw();
+            for px in pixels.chunks_exact_mut(4) {
+                if px[3] < GIF_ALPHA_THRESHOLD {
+                    px.copy_from_slice(&[0; 4]);
+                }
+            }
+            let mut gif_frame = gif::Frame::from_rgba_speed(w, h, &mut pixels, 10);
+            // The delay is in 10ms units, most viewers treat delays below 20ms as 100ms
+            gif_frame.delay = (frame.delay.as_millis() / 10).clamp(2, u16::MAX as u128) as u16;
+            gif_frame.dispose = gif::DisposalMethod::Background;
+            encoder.write_frame(&gif_frame)?;
+        }
+        drop(encoder);
+        Ok(data)
+    }
+
+    /// Encodes the animation as APNG with full alpha and the exact delays. With `crop`
+    /// the frames are cropped to the union of their non-transparent pixels
+    pub fn to_apng<R: WzIO>(
+        &self,
+        r: &mut WzImgReader<R>,
+        root: &WzValue,
+        outlinks: Option<&mut dyn WzOutlinkResolver>,
+        repeat: AnimationRepeat,
+        crop: bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut frames = self.render_frames(r, root, outlinks)?;
+        if crop {
+            crop_frames(&mut frames);
+        }
+        let (w, h) = frames[0].img.dimensions();
+
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, w, h);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        // 0 plays means forever
+        let plays = match repeat {
+            AnimationRepeat::Infinite => 0,
+            AnimationRepeat::Finite(n) => n as u32 + 1,
+        };
+        encoder.set_animated(frames.len() as u32, plays)?;
+        let mut writer = encoder.write_header()?;
+        for frame in frames {
+            let delay = frame.delay.as_millis().min(u16::MAX as u128) as u16;
+            writer.set_frame_delay(delay, 1000)?;
+            writer.write_image_data(frame.img.as_raw())?;
+        }
+        writer.finish()?;
+        Ok(data)
+    }
+
     #[cfg(feature = "webp")]
     pub fn to_webp<R: WzIO>(
         &self,
         r: &mut WzImgReader<R>,
+        root: &WzValue,
+        mut outlinks: Option<&mut dyn WzOutlinkResolver>,
     ) -> anyhow::Result<webp_animation::WebPData> {
         use image::imageops::overlay;
         use image::EncodableLayout;
@@ -123,7 +289,13 @@ impl Animation {
 
         for frame in self.frames.iter() {
             let mut back = RgbaImage::from_pixel(w, h, [0u8; 4].into());
-            let img = r.read_canvas(&frame.canvas)?;
+            let img = frame.canvas.read_canvas_resolved(
+                r,
+                root,
+                outlinks
+                    .as_mut()
+                    .map(|o| &mut **o as &mut dyn WzOutlinkResolver),
+            )?;
             let img = img.to_raw_rgba_image()?;
             overlay(&mut back, &img, 0, 0);
 
@@ -137,3 +309,185 @@ impl Animation {
         Ok(encoder.finalize(timestamp)?)
     }
 }
+
+/// Crops the frames to the union of their non-transparent pixels, fully transparent
+/// animations are kept as they are
+fn crop_frames(frames: &mut [RenderedFrame]) {
+    let mut bounds: Option<(u32, u32, u32, u32)> = None;
+    for frame in frames.iter() {
+        for (x, y, px) in frame.img.enumerate_pixels() {
+            if px[3] == 0 {
+                continue;
+            }
+            bounds = Some(match bounds {
+                None => (x, y, x, y),
+                Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+            });
+        }
+    }
+
+    let Some((x0, y0, x1, y1)) = bounds else {
+        return;
+    };
+    for frame in frames.iter_mut() {
+        frame.img = crop_imm(&frame.img, x0, y0, x1 - x0 + 1, y1 - y0 + 1).to_image();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        test_util::{canvas_val, img_blob, obj, str_val, TestVal},
+        val::WzValue,
+        WzReader, GMS95,
+    };
+
+    use super::{Animation, AnimationRepeat};
+
+    #[test]
+    fn gif() -> anyhow::Result<()> {
+        let blob = img_blob(
+            GMS95,
+            &obj([
+                (
+                    "0",
+                    canvas_val(
+                        2,
+                        2,
+                        obj([
+                            ("delay", TestVal::Int(120)),
+                            ("origin", TestVal::Vec2(0, 0)),
+                        ]),
+                    ),
+                ),
+                (
+                    "1",
+                    canvas_val(
+                        3,
+                        2,
+                        obj([("delay", TestVal::Int(80)), ("origin", TestVal::Vec2(1, 0))]),
+                    ),
+                ),
+            ]),
+        );
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img_r = r.root_img_reader()?;
+        let root = WzValue::read(&mut img_r)?;
+        let anim = Animation::from_obj_value(root.as_object().unwrap())?;
+        let data = anim.to_gif(&mut img_r, &root, None, AnimationRepeat::Infinite)?;
+
+        let mut opts = gif::DecodeOptions::new();
+        opts.set_color_output(gif::ColorOutput::RGBA);
+        let mut dec = opts.read_info(Cursor::new(data))?;
+        assert_eq!((dec.width(), dec.height()), (3, 2));
+
+        let mut frames = Vec::new();
+        while let Some(frame) = dec.read_next_frame()? {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].0, frames[1].0), (12, 8));
+        // The first frame is moved right by the origin of the second one
+        assert_eq!(frames[0].1[3], 0);
+        assert_eq!(frames[0].1[7], 0xff);
+        assert_eq!(frames[1].1[3], 0xff);
+        Ok(())
+    }
+
+    #[test]
+    fn gif_repeat() -> anyhow::Result<()> {
+        let blob = img_blob(GMS95, &obj([("0", canvas_val(1, 1, obj([])))]));
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img_r = r.root_img_reader()?;
+        let root = WzValue::read(&mut img_r)?;
+        let anim = Animation::from_obj_value(root.as_object().unwrap())?;
+
+        // The loop count is stored in the NETSCAPE2.0 extension, without It the GIF plays once
+        let loops = |data: &[u8]| {
+            data.windows(11)
+                .position(|w| w == b"NETSCAPE2.0")
+                .map(|i| u16::from_le_bytes([data[i + 13], data[i + 14]]))
+        };
+        let once = anim.to_gif(&mut img_r, &root, None, AnimationRepeat::Finite(0))?;
+        assert_eq!(loops(&once), None);
+        let twice = anim.to_gif(&mut img_r, &root, None, AnimationRepeat::Finite(2))?;
+        assert_eq!(loops(&twice), Some(2));
+        let forever = anim.to_gif(&mut img_r, &root, None, AnimationRepeat::Infinite)?;
+        assert_eq!(loops(&forever), Some(0));
+        Ok(())
+    }
+
+    #[test]
+    fn apng() -> anyhow::Result<()> {
+        // Only the center pixel is visible, with partial alpha
+        let mut bgra = vec![0; 3 * 3 * 4];
+        bgra[16..20].copy_from_slice(&[1, 2, 3, 0x40]);
+        let blob = img_blob(
+            GMS95,
+            &obj([
+                (
+                    "0",
+                    TestVal::Canvas {
+                        w: 3,
+                        h: 3,
+                        bgra,
+                        sub: obj([("delay", TestVal::Int(1234))]),
+                    },
+                ),
+                (
+                    "1",
+                    canvas_val(2, 2, obj([("origin", TestVal::Vec2(-1, -1))])),
+                ),
+            ]),
+        );
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img_r = r.root_img_reader()?;
+        let root = WzValue::read(&mut img_r)?;
+        let anim = Animation::from_obj_value(root.as_object().unwrap())?;
+        let data = anim.to_apng(&mut img_r, &root, None, AnimationRepeat::Finite(2), true)?;
+
+        let mut dec = png::Decoder::new(Cursor::new(data)).read_info()?;
+        let actl = dec.info().animation_control.unwrap();
+        assert_eq!((actl.num_frames, actl.num_plays), (2, 3));
+        // Cropped from 3x3 to the 2x2 visible pixels
+        assert_eq!((dec.info().width, dec.info().height), (2, 2));
+
+        let mut buf = vec![0; dec.output_buffer_size()];
+        dec.next_frame(&mut buf)?;
+        let fctl = dec.info().frame_control.unwrap();
+        assert_eq!((fctl.delay_num, fctl.delay_den), (1234, 1000));
+        assert_eq!(&buf[..4], [3, 2, 1, 0x40]);
+
+        dec.next_frame(&mut buf)?;
+        let fctl = dec.info().frame_control.unwrap();
+        assert_eq!((fctl.delay_num, fctl.delay_den), (100, 1000));
+        assert_eq!(buf[3], 0xff);
+        Ok(())
+    }
+
+    #[test]
+    fn linked_frames() -> anyhow::Result<()> {
+        let blob = img_blob(
+            GMS95,
+            &obj([
+                ("0", canvas_val(3, 2, obj([("delay", TestVal::Int(100))]))),
+                ("1", canvas_val(1, 1, obj([("_inlink", str_val("0"))]))),
+            ]),
+        );
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img_r = r.root_img_reader()?;
+        let root = WzValue::read(&mut img_r)?;
+        let anim = Animation::from_obj_value(root.as_object().unwrap())?;
+
+        // The second frame is a placeholder for the bitmap of the first one
+        let frames = anim.load_all_frames(&mut img_r, &root, None)?;
+        let dims = frames
+            .iter()
+            .map(|f| (f.width, f.height))
+            .collect::<Vec<_>>();
+        assert_eq!(dims, [(3, 2), (3, 2)]);
+        Ok(())
+    }
+}
@@ -13,6 +13,32 @@ pub fn custom_binrw_error<R: std::io::Read + std::io::Seek>(
     }
 }
 
+/// Reads exactly `n` bytes, the buffer grows with the data actually read,
+/// so a bogus length from the file can't cause a huge allocation
+pub fn read_exact_vec<R: Read>(r: R, n: usize) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(n.min(MAX_PREALLOC));
+    r.take(n as u64).read_to_end(&mut buf)?;
+    if buf.len() != n {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("Expected {n} bytes, got {}", buf.len()),
+        ));
+    }
+    Ok(buf)
+}
+
+/// Upper bound for allocations based on lengths from the file
+pub const MAX_PREALLOC: usize = 64 * 1024;
+
+/// Joins the name to the path of It's parent, the root has the empty path
+pub fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{parent}/{name}")
+    }
+}
+
 pub fn wz_checksum(seed: i32, data: &[u8]) -> i32 {
     data.iter()
         .fold(seed, |acc, &b| acc.overflowing_add(b as i32).0)
@@ -70,7 +96,7 @@ pub trait BufReadExt: BufRead {
     }
 
     fn read_chunked_data(&mut self, crypto: &WzCrypto, chunked_len: usize) -> io::Result<Vec<u8>> {
-        let mut buf = Vec::with_capacity(chunked_len);
+        let mut buf = Vec::with_capacity(chunked_len.min(MAX_PREALLOC));
         // Read chunks
         let mut i = 0;
         while i < chunked_len {
@@ -79,15 +105,14 @@ pub trait BufReadExt: BufRead {
 
             if chunk_size > chunked_len {
                 return Err(io::Error::new(
-                    io::ErrorKind::Other,
+                    io::ErrorKind::InvalidData,
                     format!("Bad chunk size {chunk_size}, max: {chunked_len}"),
                 ));
             }
             let n = buf.len();
-            buf.resize(n + chunk_size, 0);
+            buf.extend_from_slice(&read_exact_vec(&mut *self, chunk_size)?);
 
             let (_, tail) = buf.split_at_mut(n);
-            self.read_exact(tail)?;
             crypto.transform(tail.into());
             i += chunk_size
         }
@@ -100,8 +125,7 @@ pub trait BufReadExt: BufRead {
     }*/
 
     fn decompress_flate_size(&mut self, buf: &mut Vec<u8>, size: usize) -> io::Result<usize> {
-        buf.resize(size, 0);
-        flate2::bufread::ZlibDecoder::new(self).read_exact(buf)?;
+        *buf = read_exact_vec(flate2::bufread::ZlibDecoder::new(self), size)?;
         Ok(size)
         //self.decompress_flate(buf)
     }
@@ -175,12 +199,19 @@ where
     R: Seek,
 {
     fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid sub reader seek");
         let pos = match pos {
             SeekFrom::Current(p) => SeekFrom::Current(p),
-            SeekFrom::End(p) => SeekFrom::End((self.offset + self.size) as i64 + p),
-            SeekFrom::Start(p) => SeekFrom::Start(p + self.offset),
+            SeekFrom::End(p) => SeekFrom::Start(
+                self.offset
+                    .checked_add(self.size)
+                    .and_then(|end| end.checked_add_signed(p))
+                    .ok_or_else(invalid)?,
+            ),
+            SeekFrom::Start(p) => SeekFrom::Start(p.checked_add(self.offset).ok_or_else(invalid)?),
         };
-        self.inner.seek(pos).map(|p| p - self.offset)
+        let p = self.inner.seek(pos)?;
+        p.checked_sub(self.offset).ok_or_else(invalid)
     }
 }
 
@@ -1,21 +1,29 @@
 use std::{
     fmt::Display,
+    io::Cursor,
     ops::{Index, IndexMut},
+    sync::Arc,
     time::Duration,
 };
 
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use derive_more::IsVariant;
+use image::RgbaImage;
 use indexmap::IndexMap;
 
 use crate::{
     canvas::Canvas,
+    de::deserialize_passthrough,
+    error::WzDiagnostic,
     file::{WzIO, WzImgReader},
     l1::{
-        canvas::WzCanvas,
+        canvas::{WzCanvas, WzCanvasDepth, WzCanvasScaling},
         obj::WzObject,
         prop::{WzPropValue, WzProperty, WzVector2D},
-        sound::WzSound,
+        sound::{SoundHeader, WzSound},
+        WzPosValue,
     },
+    ty::WzInt,
 };
 
 use serde::ser::SerializeMap;
@@ -26,11 +34,21 @@ pub type Map = IndexMap<String, WzValue>;
 pub struct CanvasVal {
     pub canvas: WzCanvas,
     pub sub: Option<Box<WzValue>>,
+    /// Replaced bitmap, which takes precedence over the data in the image
+    pub bitmap: Option<Arc<Canvas>>,
 }
 
+/// Compares the content, the bitmap data in the image is only compared by It's size
 impl PartialEq for CanvasVal {
     fn eq(&self, other: &Self) -> bool {
-        self.canvas.len.pos == other.canvas.len.pos
+        let (a, b) = (&self.canvas, &other.canvas);
+        a.width == b.width
+            && a.height == b.height
+            && a.depth == b.depth
+            && a.scale == b.scale
+            && a.len.val == b.len.val
+            && self.sub == other.sub
+            && self.bitmap == other.bitmap
     }
 }
 
@@ -56,19 +74,70 @@ impl std::fmt::Debug for CanvasVal {
 }
 
 impl CanvasVal {
+    /// Canvas which isn't backed by an image, like a canvas deserialized from json.
+    /// The bitmap must be provided by a `WzMediaSource` to write It
+    pub fn detached(scale: WzCanvasScaling, sub: Option<Box<WzValue>>) -> Self {
+        Self {
+            canvas: WzCanvas {
+                unknown: 0,
+                has_property: sub.is_some() as u8,
+                property: None,
+                width: WzInt(0),
+                height: WzInt(0),
+                depth: WzCanvasDepth::BGRA8888,
+                scale,
+                unknown1: 0,
+                len: WzPosValue { val: 0, pos: 0 },
+            },
+            sub,
+            bitmap: None,
+        }
+    }
+
+    /// Reads the bitmap, a replaced bitmap is returned as It is
     pub fn read_canvas<R: WzIO>(&self, r: &mut WzImgReader<R>) -> anyhow::Result<Canvas> {
-        r.read_canvas(&self.canvas)
+        match self.bitmap.as_deref() {
+            Some(bitmap) => Ok(bitmap.clone()),
+            None => r.read_canvas(&self.canvas),
+        }
+    }
+
+    /// Replaces the bitmap with the pixels of the image, the sub property and the
+    /// scaling are kept. `img` has the raw dimensions
+    pub fn set_image(&mut self, img: &RgbaImage) {
+        self.set_bitmap(Canvas::from_rgba_image(img, self.canvas.scale));
+    }
+
+    /// Replaces the bitmap, the sub property is kept
+    pub fn set_bitmap(&mut self, bitmap: Canvas) {
+        self.canvas.scale = bitmap.scale;
+        self.canvas.width = WzInt(bitmap.width as i32);
+        self.canvas.height = WzInt(bitmap.height as i32);
+        self.canvas.depth = bitmap.depth();
+        self.bitmap = Some(Arc::new(bitmap));
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct SoundVal {
     pub sound: WzSound,
+    /// Replaced data, which takes precedence over the data in the image
+    pub data: Option<Arc<Vec<u8>>>,
 }
 
+/// Compares the content, the sound data in the image is only compared by It's size
 impl PartialEq for SoundVal {
     fn eq(&self, other: &Self) -> bool {
-        self.sound.offset.pos == other.sound.offset.pos
+        let header = |sound: &WzSound| {
+            let mut header = Cursor::new(Vec::new());
+            sound.header.write_header(&mut header).ok()?;
+            Some(header.into_inner())
+        };
+        let (a, b) = (&self.sound, &other.sound);
+        a.size == b.size
+            && a.len_ms == b.len_ms
+            && header(a) == header(b)
+            && self.data == other.data
     }
 }
 
@@ -79,16 +148,64 @@ impl serde::Serialize for SoundVal {
     {
         let mut s = serializer.serialize_map(Some(5))?;
 
+        let mut header = Cursor::new(Vec::new());
+        self.sound
+            .header
+            .write_header(&mut header)
+            .map_err(serde::ser::Error::custom)?;
+
         s.serialize_entry("$ty", "sound")?;
         s.serialize_entry("playTime", &self.sound.len_ms.0)?;
+        s.serialize_entry("header", &BASE64.encode(header.into_inner()))?;
 
         s.end()
     }
 }
 
 impl SoundVal {
+    /// Sound which isn't backed by an image, `data` is in the format returned
+    /// by `read_data`
+    pub fn detached(header: SoundHeader, len_ms: i32, data: Vec<u8>) -> anyhow::Result<Self> {
+        let mut sound = WzSound {
+            unknown: 0,
+            size: WzInt(0),
+            len_ms: WzInt(len_ms),
+            header,
+            offset: binrw::PosValue { val: (), pos: 0 },
+        };
+        // Pcm data contains a wave header, which is not part of the size
+        let size = data
+            .len()
+            .checked_sub(sound.data_size())
+            .ok_or_else(|| anyhow::format_err!("Sound data too short"))?;
+        sound.size = WzInt(size as i32);
+        Ok(Self {
+            sound,
+            data: Some(Arc::new(data)),
+        })
+    }
+
+    /// Sound which isn't backed by an image and has no data, like a sound deserialized
+    /// from json. The data must be provided by a `WzMediaSource` to write It
+    pub fn from_header(header: SoundHeader, len_ms: i32) -> Self {
+        Self {
+            sound: WzSound {
+                unknown: 0,
+                size: WzInt(0),
+                len_ms: WzInt(len_ms),
+                header,
+                offset: binrw::PosValue { val: (), pos: 0 },
+            },
+            data: None,
+        }
+    }
+
+    /// Reads the data, replaced data is returned as It is
     pub fn read_data<R: WzIO>(&self, r: &mut WzImgReader<R>) -> anyhow::Result<Vec<u8>> {
-        r.read_sound(&self.sound)
+        match self.data.as_deref() {
+            Some(data) => Ok(data.clone()),
+            None => r.read_sound(&self.sound),
+        }
     }
 
     pub fn duration(&self) -> Duration {
@@ -96,6 +213,25 @@ impl SoundVal {
     }
 }
 
+/// Placeholder for a value which couldn't be read, see `WzValue::read_lenient`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorVal {
+    /// Offset of the value in the image
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl serde::Serialize for ErrorVal {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_map(Some(3))?;
+        s.serialize_entry("$type", "error")?;
+        s.serialize_entry("offset", &self.offset)?;
+        s.serialize_entry("reason", &self.reason)?;
+
+        s.end()
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Vec2Val {
     pub x: i32,
@@ -150,7 +286,7 @@ impl serde::Serialize for Vex2Val {
     }
 }
 
-#[derive(Debug, serde::Serialize, Clone, PartialEq)]
+#[derive(Debug, serde::Serialize, Clone, PartialEq, Default)]
 pub struct ObjectVal(pub Map);
 
 impl ObjectVal {
@@ -215,6 +351,7 @@ pub enum WzValue {
     Sound(SoundVal),
     Canvas(CanvasVal),
     Link(String),
+    Error(ErrorVal),
 }
 
 impl From<Map> for WzValue {
@@ -388,6 +525,47 @@ impl WzValue {
         Self::read_obj(r, &obj)
     }
 
+    /// Reads the image like `read`, but unreadable properties are replaced by
+    /// `WzValue::Error` placeholders, so the remaining values can be salvaged.
+    /// Fails only If the root object itself is unreadable
+    pub fn read_lenient<R: WzIO>(
+        r: &mut WzImgReader<R>,
+    ) -> anyhow::Result<(WzValue, Vec<WzDiagnostic>)> {
+        let obj = r.read_root_obj_lenient()?;
+        let val = Self::read_obj(r, &obj)?;
+        let mut diagnostics = Vec::new();
+        val.collect_errors(String::new(), r.path(), &mut diagnostics);
+        Ok((val, diagnostics))
+    }
+
+    fn collect_errors(&self, path: String, img_path: Option<&str>, out: &mut Vec<WzDiagnostic>) {
+        let obj = match self {
+            WzValue::Error(err) => {
+                out.push(WzDiagnostic {
+                    img_path: img_path.map(str::to_string),
+                    prop_path: path,
+                    offset: err.offset,
+                    reason: err.reason.clone(),
+                });
+                return;
+            }
+            WzValue::Object(obj) => obj,
+            WzValue::Canvas(CanvasVal { sub: Some(sub), .. }) => {
+                return sub.collect_errors(path, img_path, out);
+            }
+            _ => return,
+        };
+
+        for (name, val) in obj.0.iter() {
+            let path = if path.is_empty() {
+                name.clone()
+            } else {
+                format!("{path}/{name}")
+            };
+            val.collect_errors(path, img_path, out);
+        }
+    }
+
     fn read_val<R: WzIO>(r: &mut WzImgReader<R>, val: &WzPropValue) -> anyhow::Result<WzValue> {
         Ok(match val {
             WzPropValue::Null => WzValue::Null,
@@ -421,6 +599,7 @@ impl WzValue {
                 WzValue::Canvas(CanvasVal {
                     canvas: canvas.clone(),
                     sub: prop,
+                    bitmap: None,
                 })
             }
             WzObject::UOL(link) => WzValue::Link(link.entries.0.to_string()),
@@ -430,11 +609,25 @@ impl WzValue {
             }
             WzObject::SoundDX8(sound) => WzValue::Sound(SoundVal {
                 sound: sound.clone(),
+                data: None,
+            }),
+            WzObject::Invalid(err) => WzValue::Error(ErrorVal {
+                offset: err.offset,
+                reason: err.reason.clone(),
             }),
         })
     }
 }
 
+/// Number which isn't an `Int` or `F64`, so the type is kept in self-describing formats
+#[derive(Debug, serde::Serialize)]
+pub struct WzValueNum<T> {
+    #[serde(rename = "$type")]
+    pub ty: &'static str,
+    #[serde(rename = "$val")]
+    pub val: T,
+}
+
 #[derive(Debug, serde::Serialize)]
 pub struct WzValueLink {
     #[serde(rename = "$type")]
@@ -448,21 +641,30 @@ impl serde::Serialize for WzValue {
         match self {
             WzValue::Object(v) => v.serialize(serializer),
             WzValue::Null => serializer.serialize_none(),
-            WzValue::F32(v) => serializer.serialize_f32(*v),
+            WzValue::F32(v) => WzValueNum { ty: "f32", val: *v }.serialize(serializer),
             WzValue::F64(v) => serializer.serialize_f64(*v),
-            WzValue::Short(v) => serializer.serialize_i16(*v),
+            WzValue::Short(v) => WzValueNum {
+                ty: "short",
+                val: *v,
+            }
+            .serialize(serializer),
             WzValue::Int(v) => serializer.serialize_i32(*v),
-            WzValue::Long(v) => serializer.serialize_i64(*v),
+            WzValue::Long(v) => WzValueNum {
+                ty: "long",
+                val: *v,
+            }
+            .serialize(serializer),
             WzValue::String(v) => serializer.serialize_str(v),
             WzValue::Vec(v) => v.serialize(serializer),
             WzValue::Convex(v) => v.serialize(serializer),
-            WzValue::Sound(_v) => serializer.serialize_str("SOUND"),
+            WzValue::Sound(v) => v.serialize(serializer),
             WzValue::Canvas(v) => v.serialize(serializer),
             WzValue::Link(v) => WzValueLink {
                 ty: "link",
                 link: v.to_string(),
             }
             .serialize(serializer),
+            WzValue::Error(v) => v.serialize(serializer),
         }
     }
 }
@@ -494,7 +696,29 @@ where
     return Ok((x, y).into());
 }
 
-struct WzValueVisitor;
+/// Sound without data, the data is provided by a `WzMediaSource`
+fn visit_sound<'de, A>(mut map: A) -> Result<WzValue, A::Error>
+where
+    A: serde::de::MapAccess<'de>,
+{
+    let (mut len_ms, mut header) = (0, None);
+    while let Some(key) = map.next_key::<&str>()? {
+        match key {
+            "playTime" => len_ms = map.next_value()?,
+            "header" => header = Some(map.next_value::<String>()?),
+            _ => {
+                map.next_value::<serde::de::IgnoredAny>()?;
+            }
+        }
+    }
+    let header = header.ok_or_else(|| serde::de::Error::missing_field("header"))?;
+    let header = BASE64.decode(header).map_err(serde::de::Error::custom)?;
+    let header =
+        SoundHeader::read_header(&mut Cursor::new(header)).map_err(serde::de::Error::custom)?;
+    Ok(WzValue::Sound(SoundVal::from_header(header, len_ms)))
+}
+
+pub(crate) struct WzValueVisitor;
 
 impl<'de> serde::de::Visitor<'de> for WzValueVisitor {
     type Value = WzValue;
@@ -507,12 +731,13 @@ impl<'de> serde::de::Visitor<'de> for WzValueVisitor {
         Ok(WzValue::Int(if v { 1 } else { 0 }))
     }
 
+    /// Untyped integers are `Int`s, unless they don't fit
     fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Self::Value, E> {
-        Ok(WzValue::Long(v))
+        Ok(i32::try_from(v).map_or(WzValue::Long(v), WzValue::Int))
     }
 
     fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
-        Ok(WzValue::Long(v as i64))
+        self.visit_i64(v as i64)
     }
 
     fn visit_i32<E: serde::de::Error>(self, v: i32) -> Result<Self::Value, E> {
@@ -655,11 +880,37 @@ impl<'de> serde::de::Visitor<'de> for WzValueVisitor {
                 return Ok(WzValue::Vec(visit_vec2(&self, map)?));
             }
 
+            if matches!(ty_val.as_str(), "short" | "long" | "f32") {
+                let _ = map.next_key::<&str>()?;
+                return Ok(match ty_val.as_str() {
+                    "short" => WzValue::Short(map.next_value()?),
+                    "long" => WzValue::Long(map.next_value()?),
+                    _ => WzValue::F32(map.next_value()?),
+                });
+            }
+
+            if ty_val == "error" {
+                let _ = map.next_key::<&str>()?;
+                let offset = map.next_value::<u64>()?;
+                let _ = map.next_key::<&str>()?;
+                let reason = map.next_value::<String>()?;
+                return Ok(WzValue::Error(ErrorVal { offset, reason }));
+            }
+
             if ty_val == "vex2" {
                 let _ = map.next_key::<&str>()?;
-                //let vex = map.next_value::<Vec<Vec2Val>>()?;
-                //return Ok(WzValue::Convex(Vex2Val(vex)));
-                todo!()
+                let vex = map
+                    .next_value::<Vec<WzValue>>()?
+                    .into_iter()
+                    .map(|v| match v {
+                        WzValue::Vec(v) => Ok(v),
+                        _ => Err(serde::de::Error::invalid_value(
+                            serde::de::Unexpected::Other("non vector"),
+                            &"vec2",
+                        )),
+                    })
+                    .collect::<Result<_, _>>()?;
+                return Ok(WzValue::Convex(Vex2Val(vex)));
             }
 
             return Err(serde::de::Error::invalid_value(
@@ -668,6 +919,35 @@ impl<'de> serde::de::Visitor<'de> for WzValueVisitor {
             ));
         }
 
+        if ty == "$ty" {
+            let ty_val = map.next_value::<String>()?;
+            if ty_val == "sound" {
+                return visit_sound(map);
+            }
+            if ty_val != "canvas" {
+                return Err(serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Other(&ty_val),
+                    &"canvas or sound",
+                ));
+            }
+
+            let (mut scale, mut sub) = (0u8, None);
+            while let Some(key) = map.next_key::<&str>()? {
+                match key {
+                    "scale" => scale = map.next_value()?,
+                    "sub" => sub = map.next_value::<Option<WzValue>>()?,
+                    _ => {
+                        map.next_value::<serde::de::IgnoredAny>()?;
+                    }
+                }
+            }
+            let scale = WzCanvasScaling::try_from(scale).map_err(serde::de::Error::custom)?;
+            return Ok(WzValue::Canvas(CanvasVal::detached(
+                scale,
+                sub.map(Box::new),
+            )));
+        }
+
         let mut m = Map::new();
         m.insert(ty.to_string(), map.next_value()?);
         while let Some((k, v)) = map.next_entry::<String, WzValue>()? {
@@ -679,10 +959,34 @@ impl<'de> serde::de::Visitor<'de> for WzValueVisitor {
 
 impl<'de> serde::Deserialize<'de> for WzValue {
     fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        deserializer.deserialize_any(WzValueVisitor)
+        deserialize_passthrough(deserializer)
     }
 }
 
+macro_rules! passthrough_deserialize {
+    ($($ty:ident => $variant:ident, $exp:literal),* $(,)?) => {
+        $(
+            impl<'de> serde::Deserialize<'de> for $ty {
+                fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                    match deserialize_passthrough(deserializer)? {
+                        WzValue::$variant(v) => Ok(v),
+                        _ => Err(serde::de::Error::invalid_type(
+                            serde::de::Unexpected::Other("value"),
+                            &$exp,
+                        )),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+passthrough_deserialize!(
+    CanvasVal => Canvas, "a canvas",
+    SoundVal => Sound, "a sound",
+    Vec2Val => Vec, "a vector",
+);
+
 #[cfg(test)]
 mod tests {
     use indexmap::indexmap;
@@ -703,5 +1007,53 @@ mod tests {
         }));
 
         check_val(WzValue::Vec((-1, 1).into()));
+        check_val(WzValue::from(indexmap! {
+            "short".to_string() => WzValue::Short(-3),
+            "int".to_string() => WzValue::Int(3),
+            "long".to_string() => WzValue::Long(3),
+            "f32".to_string() => WzValue::F32(1.5),
+            "f64".to_string() => WzValue::F64(1.5),
+        }));
+        check_val(WzValue::Convex(Vex2Val(vec![(0, 1).into(), (2, 3).into()])));
+        check_val(WzValue::Error(ErrorVal {
+            offset: 0x10,
+            reason: "Truncated data".to_string(),
+        }));
+    }
+
+    #[test]
+    fn media_eq() -> anyhow::Result<()> {
+        use crate::{
+            test_util::{canvas_val, img_blob, obj, TestVal},
+            WzReader, GMS95,
+        };
+
+        let sound = || TestVal::Sound {
+            data: vec![1, 2, 3],
+            len_ms: 10,
+        };
+        let blob = img_blob(
+            GMS95,
+            &obj([
+                ("a", canvas_val(2, 2, obj([("z", TestVal::Int(1))]))),
+                ("b", canvas_val(2, 2, obj([("z", TestVal::Int(1))]))),
+                ("c", canvas_val(2, 2, obj([("z", TestVal::Int(2))]))),
+                ("d", sound()),
+                ("e", sound()),
+            ]),
+        );
+        let mut r = WzReader::open_img(std::io::Cursor::new(blob), GMS95);
+        let root = WzValue::read(&mut r.root_img_reader()?)?;
+        let get = |path| root.get_path(path).unwrap();
+
+        // Equal content at different positions
+        assert_eq!(get("a"), get("b"));
+        assert_eq!(get("d"), get("e"));
+        assert_ne!(get("a"), get("c"));
+
+        let mut replaced = get("a").as_canvas().unwrap().clone();
+        replaced.set_image(&RgbaImage::new(2, 2));
+        assert_ne!(&WzValue::Canvas(replaced), get("a"));
+        Ok(())
     }
 }
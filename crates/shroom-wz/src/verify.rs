@@ -0,0 +1,324 @@
+//! Integrity verification of archives.
+//!
+//! Checks the header size, the bounds and checksums of all image blobs, overlapping
+//! blobs and whether every canvas and sound in the images can be decoded.
+
+use std::fmt;
+
+use crate::{
+    file::{WzIO, WzImgReader},
+    l0::{tree::WzTree, WzImgHeader},
+    util::join_path,
+    val::WzValue,
+    WzReader,
+};
+
+/// A problem found by `verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WzIssue {
+    /// `WzHeader.file_size` doesn't match the actual file
+    FileSize {
+        expected: u64,
+        found: u64,
+    },
+    /// The blob lies outside of the data section
+    OutOfBounds {
+        offset: u64,
+        size: i64,
+        end: u64,
+    },
+    ChecksumMismatch {
+        expected: i32,
+        found: i32,
+    },
+    /// The blob overlaps with the blob of another image
+    Overlap {
+        other: String,
+    },
+    /// The value tree of the image can't be read
+    Unreadable {
+        reason: String,
+    },
+    /// A canvas or sound can't be decoded
+    Media {
+        prop_path: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for WzIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileSize { expected, found } => {
+                write!(f, "file size mismatch: header {expected}, file {found}")
+            }
+            Self::OutOfBounds { offset, size, end } => write!(
+                f,
+                "blob out of bounds: offset {offset:#x}, size {size}, data end {end:#x}"
+            ),
+            Self::ChecksumMismatch { expected, found } => {
+                write!(f, "checksum mismatch: expected {expected}, found {found}")
+            }
+            Self::Overlap { other } => write!(f, "blob overlaps with {other}"),
+            Self::Unreadable { reason } => write!(f, "unreadable: {reason}"),
+            Self::Media { prop_path, reason } => write!(f, "{prop_path}: {reason}"),
+        }
+    }
+}
+
+/// Verification result of a single image
+#[derive(Debug, Clone)]
+pub struct WzImgReport {
+    pub path: String,
+    pub offset: u64,
+    pub size: i64,
+    pub issues: Vec<WzIssue>,
+}
+
+impl WzImgReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct WzVerifyReport {
+    /// Issues with the archive itself
+    pub issues: Vec<WzIssue>,
+    pub images: Vec<WzImgReport>,
+}
+
+impl WzVerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty() && self.images.iter().all(WzImgReport::is_ok)
+    }
+
+    pub fn failed_images(&self) -> impl Iterator<Item = &WzImgReport> {
+        self.images.iter().filter(|img| !img.is_ok())
+    }
+}
+
+/// Verifies the whole archive, only errors when the directory tree can't be read
+pub fn verify<R: WzIO>(r: &mut WzReader<R>) -> anyhow::Result<WzVerifyReport> {
+    let mut report = WzVerifyReport::default();
+
+    let hdr = r.read_header()?;
+    let file_len = r.file_len()?;
+    let data_end = hdr.data_offset as u64 + hdr.file_size;
+    if data_end != file_len {
+        report.issues.push(WzIssue::FileSize {
+            expected: hdr.file_size,
+            found: file_len.saturating_sub(hdr.data_offset as u64),
+        });
+    }
+    // Don't trust the header beyond the actual file
+    let data_end = data_end.min(file_len);
+
+    let tree = WzTree::from_reader(r, None)?;
+    // Links refer to the blob of another image, verify each blob once
+    let mut seen = std::collections::HashSet::new();
+    let imgs = tree
+        .images()
+        .filter(|(_, hdr)| seen.insert((hdr.offset.0, hdr.blob_size.0)))
+        .collect::<Vec<_>>();
+
+    for (path, img) in imgs.iter() {
+        report
+            .images
+            .push(verify_img(r, path, img, hdr.data_offset as u64, data_end));
+    }
+
+    let hdrs = imgs.iter().map(|(_, hdr)| hdr).collect::<Vec<_>>();
+    for (ix, other) in overlaps(&hdrs) {
+        report.images[ix].issues.push(WzIssue::Overlap {
+            other: imgs[other].0.clone(),
+        });
+    }
+
+    Ok(report)
+}
+
+/// Finds the blobs which start before the furthest end of the blobs in front of them,
+/// returns the index of the blob and the index of the blob It overlaps with
+fn overlaps(hdrs: &[&WzImgHeader]) -> Vec<(usize, usize)> {
+    let mut order = (0..hdrs.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&ix| hdrs[ix].offset.0);
+
+    let mut res = Vec::new();
+    let mut max_end: Option<(u64, usize)> = None;
+    for ix in order {
+        let start = hdrs[ix].offset.0 as u64;
+        let end = start + hdrs[ix].blob_size.0.max(0) as u64;
+        match max_end {
+            Some((max, other)) if start < max => res.push((ix, other)),
+            _ => {}
+        }
+        if max_end.is_none_or(|(max, _)| end > max) {
+            max_end = Some((end, ix));
+        }
+    }
+    res
+}
+
+/// Verifies a single image, `data_offset` and `data_end` are the bounds of the data section
+pub fn verify_img<R: WzIO>(
+    r: &mut WzReader<R>,
+    path: &str,
+    hdr: &WzImgHeader,
+    data_offset: u64,
+    data_end: u64,
+) -> WzImgReport {
+    let offset = hdr.offset.0 as u64;
+    let size = hdr.blob_size.0 as i64;
+    let mut report = WzImgReport {
+        path: path.to_string(),
+        offset,
+        size,
+        issues: Vec::new(),
+    };
+
+    if size < 0 || offset < data_offset || offset + size as u64 > data_end {
+        report.issues.push(WzIssue::OutOfBounds {
+            offset,
+            size,
+            end: data_end,
+        });
+        return report;
+    }
+
+    match r.checksum(offset, size as u64) {
+        Ok(found) if found != hdr.checksum.0 => report.issues.push(WzIssue::ChecksumMismatch {
+            expected: hdr.checksum.0,
+            found,
+        }),
+        Ok(_) => {}
+        Err(err) => report.issues.push(WzIssue::Unreadable {
+            reason: format!("{err:#}"),
+        }),
+    }
+
+    let mut img = match r.img_reader(hdr) {
+        Ok(img) => img.with_path(path),
+        Err(err) => {
+            report.issues.push(WzIssue::Unreadable {
+                reason: err.to_string(),
+            });
+            return report;
+        }
+    };
+    match WzValue::read(&mut img) {
+        Ok(val) => report.issues.extend(verify_media(&mut img, &val)),
+        Err(err) => report.issues.push(WzIssue::Unreadable {
+            reason: format!("{err:#}"),
+        }),
+    }
+
+    report
+}
+
+/// Decodes every canvas and sound of the value, returns the media which can't be decoded
+pub fn verify_media<R: WzIO>(img: &mut WzImgReader<R>, val: &WzValue) -> Vec<WzIssue> {
+    let mut issues = Vec::new();
+    verify_media_at(img, val, String::new(), &mut issues);
+    issues
+}
+
+fn verify_media_at<R: WzIO>(
+    img: &mut WzImgReader<R>,
+    val: &WzValue,
+    path: String,
+    issues: &mut Vec<WzIssue>,
+) {
+    let res = match val {
+        WzValue::Object(obj) => {
+            for (name, val) in obj.0.iter() {
+                verify_media_at(img, val, join_path(&path, name), issues);
+            }
+            return;
+        }
+        WzValue::Canvas(canvas) => {
+            if let Some(sub) = canvas.sub.as_deref() {
+                verify_media_at(img, sub, path.clone(), issues);
+            }
+            canvas
+                .read_canvas(img)
+                .and_then(|canvas| canvas.to_raw_rgba_image().map(|_| ()))
+        }
+        WzValue::Sound(sound) => sound.read_data(img).map(|_| ()),
+        _ => return,
+    };
+
+    if let Err(err) = res {
+        issues.push(WzIssue::Media {
+            prop_path: path,
+            reason: format!("{err:#}"),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        l0::WzImgHeader,
+        test_util::{build_archive, dir, img, img_blob, obj, sample_archive, TestVal},
+        ty::{WzInt, WzOffset, WzStr},
+        WzReader, GMS95,
+    };
+
+    use super::WzIssue;
+
+    #[test]
+    fn verify_sample() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(sample_archive(GMS95)), GMS95)?;
+        let report = super::verify(&mut r)?;
+        assert!(report.is_ok(), "{report:?}");
+        assert_eq!(report.images.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_corrupt() -> anyhow::Result<()> {
+        let blob = img_blob(GMS95, &obj([("price", TestVal::Int(100))]));
+        let mut data = build_archive(
+            GMS95,
+            vec![dir("Item", vec![img("0200.img", blob.clone())])],
+        );
+        // Flip a byte of the image
+        let ix = data.windows(blob.len()).position(|w| w == blob).unwrap();
+        *data.last_mut().unwrap() ^= 0xFF;
+        // Append trailing garbage, which doesn't match the header size
+        data.push(0);
+
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let report = super::verify(&mut r)?;
+        assert!(!report.is_ok());
+        assert!(matches!(report.issues[..], [WzIssue::FileSize { .. }]));
+        let img = &report.images[0];
+        assert_eq!(img.path, "Item/0200.img");
+        assert_eq!(img.offset, ix as u64);
+        assert!(matches!(img.issues[0], WzIssue::ChecksumMismatch { .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn overlaps() {
+        let hdr = |offset: u32, size: i32| WzImgHeader {
+            name: WzStr::new("a.img".to_string()),
+            blob_size: WzInt(size),
+            checksum: WzInt(0),
+            offset: WzOffset(offset),
+        };
+        // The first blob contains the next two, the last one starts where the first ends
+        // and doesn't overlap
+        let hdrs = [hdr(100, 100), hdr(110, 10), hdr(150, 10), hdr(200, 10)];
+        let hdrs = hdrs.iter().collect::<Vec<_>>();
+        assert_eq!(super::overlaps(&hdrs), vec![(1, 0), (2, 0)]);
+
+        // Unsorted, the second blob contains the others
+        let hdrs = [hdr(130, 10), hdr(100, 40), hdr(120, 5)];
+        let hdrs = hdrs.iter().collect::<Vec<_>>();
+        assert_eq!(super::overlaps(&hdrs), vec![(2, 1), (0, 1)]);
+    }
+}
@@ -0,0 +1,388 @@
+//! Walkers over value trees and archives.
+//!
+//! Value paths are slash-separated like for `WzValue::get_path`, the entries of a canvas
+//! are walked through It's sub property. Archive paths are relative to the root directory.
+
+use crate::{
+    file::WzIO,
+    l0::{WzDirHeader, WzDirNode, WzImgHeader},
+    util::join_path,
+    val::{ObjectVal, WzValue},
+    WzReader,
+};
+
+impl WzValue {
+    /// Entries of an object or the sub property of a canvas
+    pub(crate) fn walk_entries(&self) -> Option<&ObjectVal> {
+        match self {
+            WzValue::Object(obj) => Some(obj),
+            WzValue::Canvas(canvas) => canvas.sub.as_deref()?.walk_entries(),
+            _ => None,
+        }
+    }
+
+    /// Walks over the value and all It's children in pre-order, the root has the empty path
+    pub fn walk(&self) -> WzValueWalker<'_> {
+        WzValueWalker {
+            stack: vec![WzWalkEntry {
+                path: String::new(),
+                name: "",
+                depth: 0,
+                value: self,
+            }],
+            pending: None,
+        }
+    }
+
+    /// Walks mutably over the value and all It's children in pre-order,
+    /// the callback decides whether the children of a value are visited
+    pub fn walk_mut(&mut self, mut f: impl FnMut(&str, &mut WzValue) -> WzWalk) -> WzWalk {
+        self.walk_mut_inner(&mut String::new(), &mut f)
+    }
+
+    fn walk_mut_inner(
+        &mut self,
+        path: &mut String,
+        f: &mut impl FnMut(&str, &mut WzValue) -> WzWalk,
+    ) -> WzWalk {
+        match f(path, self) {
+            WzWalk::Continue => {}
+            WzWalk::Skip => return WzWalk::Continue,
+            WzWalk::Stop => return WzWalk::Stop,
+        }
+        let Some(obj) = self.entries_mut(false) else {
+            return WzWalk::Continue;
+        };
+
+        let len = path.len();
+        for (name, val) in obj.0.iter_mut() {
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(name);
+            let res = val.walk_mut_inner(path, f);
+            path.truncate(len);
+            if res == WzWalk::Stop {
+                return WzWalk::Stop;
+            }
+        }
+        WzWalk::Continue
+    }
+}
+
+/// Decision of the `walk_mut` callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WzWalk {
+    /// Visit the children of the value
+    Continue,
+    /// Skip the children of the value
+    Skip,
+    /// Stop the walk
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WzWalkEntry<'a> {
+    pub path: String,
+    /// Name of the entry in It's parent, empty for the root
+    pub name: &'a str,
+    pub depth: usize,
+    pub value: &'a WzValue,
+}
+
+/// Pre-order iterator over a value tree, see `WzValue::walk`
+pub struct WzValueWalker<'a> {
+    stack: Vec<WzWalkEntry<'a>>,
+    /// Last yielded entry, It's children are pushed on the next call
+    pending: Option<WzWalkEntry<'a>>,
+}
+
+impl WzValueWalker<'_> {
+    /// Skips the children of the last yielded entry
+    pub fn skip_children(&mut self) {
+        self.pending = None;
+    }
+}
+
+impl<'a> Iterator for WzValueWalker<'a> {
+    type Item = WzWalkEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(parent) = self.pending.take() {
+            if let Some(obj) = parent.value.walk_entries() {
+                // Reversed so the first child is popped first
+                self.stack
+                    .extend(obj.0.iter().rev().map(|(name, value)| WzWalkEntry {
+                        path: join_path(&parent.path, name),
+                        name,
+                        depth: parent.depth + 1,
+                        value,
+                    }));
+            }
+        }
+
+        let entry = self.stack.pop()?;
+        self.pending = Some(entry.clone());
+        Some(entry)
+    }
+}
+
+/// Image found by the `WzArchiveWalker`
+#[derive(Debug, Clone)]
+pub struct WzWalkImg {
+    pub path: String,
+    pub hdr: WzImgHeader,
+    /// Parsed image, only read with `WzArchiveWalker::with_values`
+    pub value: Option<WzValue>,
+}
+
+/// Pre-order iterator over the images of an archive, see `WzReader::walk_images`
+///
+/// Errors are yielded for the failing entry and the walk continues with the next one,
+/// this includes `Nil` entries, which are not valid in a directory.
+pub struct WzArchiveWalker<'r, R> {
+    r: &'r mut WzReader<R>,
+    /// Entries which are not visited yet, with the number of their ancestor directories
+    stack: Vec<(String, WzDirNode, usize)>,
+    /// Offsets of the directories on the current path, to detect cycles
+    ancestors: Vec<u32>,
+    values: bool,
+}
+
+impl<'r, R: WzIO> WzArchiveWalker<'r, R> {
+    pub fn new(r: &'r mut WzReader<R>) -> Self {
+        let root = WzDirNode::Dir(WzDirHeader::root("root", 1, r.root_offset()));
+        Self {
+            r,
+            stack: vec![(String::new(), root, 0)],
+            ancestors: Vec::new(),
+            values: false,
+        }
+    }
+
+    /// Also reads the value of every image
+    pub fn with_values(mut self) -> Self {
+        self.values = true;
+        self
+    }
+
+    fn read_dir(&mut self, path: String, dir: &WzDirHeader) -> anyhow::Result<()> {
+        // Directories can be shared between entries, only an ancestor forms a cycle
+        if self.ancestors.contains(&dir.offset.0) {
+            anyhow::bail!("Cyclic directory {path} at {:#x}", dir.offset.0);
+        }
+        let node = self.r.read_dir_node(dir)?;
+        self.ancestors.push(dir.offset.0);
+        let depth = self.ancestors.len();
+        // Reversed so the first entry is popped first
+        for (ix, entry) in node.entries.0.into_iter().enumerate().rev() {
+            let entry_path = match entry.name() {
+                Some(name) => join_path(&path, name),
+                // Nil entries have no name, so they are labeled with their index
+                None => join_path(&path, &format!("#{ix}")),
+            };
+            self.stack.push((entry_path, entry, depth));
+        }
+        Ok(())
+    }
+
+    fn read_img(&mut self, path: String, hdr: WzImgHeader) -> anyhow::Result<WzWalkImg> {
+        let value = if self.values {
+            let mut img = self.r.img_reader(&hdr)?.with_path(&path);
+            Some(WzValue::read(&mut img)?)
+        } else {
+            None
+        };
+        Ok(WzWalkImg { path, hdr, value })
+    }
+}
+
+impl<R: WzIO> Iterator for WzArchiveWalker<'_, R> {
+    type Item = anyhow::Result<WzWalkImg>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((path, node, depth)) = self.stack.pop() {
+            self.ancestors.truncate(depth);
+            let hdr = match node {
+                WzDirNode::Dir(dir) => {
+                    if let Err(err) = self.read_dir(path, &dir) {
+                        return Some(Err(err));
+                    }
+                    continue;
+                }
+                WzDirNode::Img(img) => img,
+                WzDirNode::Link(link) => link.img_header(),
+                WzDirNode::Nil(_) => {
+                    return Some(Err(anyhow::format_err!("Nil entry at {path}")));
+                }
+            };
+            return Some(self.read_img(path, hdr));
+        }
+        None
+    }
+}
+
+impl<R: WzIO> WzReader<R> {
+    /// Walks over all images of the archive in pre-order
+    pub fn walk_images(&mut self) -> WzArchiveWalker<'_, R> {
+        WzArchiveWalker::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indexmap::indexmap;
+
+    use std::io::Cursor;
+
+    use crate::{
+        l1::canvas::WzCanvasScaling,
+        test_util::{
+            build_archive, dir, img, img_blob, link_archive, obj, shared_dir_archive, TestVal,
+        },
+        val::{CanvasVal, WzValue},
+        WzReader, GMS95,
+    };
+
+    use super::WzWalk;
+
+    fn sample() -> WzValue {
+        let mut icon = CanvasVal::detached(WzCanvasScaling(0), None);
+        icon.sub = Some(Box::new(WzValue::from(indexmap! {
+            "origin".to_string() => WzValue::Vec((1, 2).into()),
+        })));
+        WzValue::from(indexmap! {
+            "info".to_string() => WzValue::from(indexmap! {
+                "icon".to_string() => WzValue::Canvas(icon),
+                "price".to_string() => WzValue::Int(10),
+            }),
+            "skip".to_string() => WzValue::from(indexmap! {
+                "a".to_string() => WzValue::Int(1),
+            }),
+            "name".to_string() => WzValue::String("x".to_string()),
+        })
+    }
+
+    #[test]
+    fn walk_value() {
+        let root = sample();
+        let mut paths = Vec::new();
+        let mut walker = root.walk();
+        while let Some(entry) = walker.next() {
+            if entry.name == "skip" {
+                walker.skip_children();
+            }
+            if entry.depth > 0 {
+                assert_eq!(root.get_path(&entry.path), Some(entry.value));
+            }
+            paths.push((entry.path, entry.depth));
+        }
+        assert_eq!(
+            paths,
+            [
+                ("".to_string(), 0),
+                ("info".to_string(), 1),
+                ("info/icon".to_string(), 2),
+                ("info/icon/origin".to_string(), 3),
+                ("info/price".to_string(), 2),
+                ("skip".to_string(), 1),
+                ("name".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn walk_value_mut() {
+        let mut root = sample();
+        let mut paths = Vec::new();
+        root.walk_mut(|path, val| {
+            paths.push(path.to_string());
+            if let WzValue::Int(v) = val {
+                *v += 1;
+            }
+            match path {
+                "skip" => WzWalk::Skip,
+                "info/price" => WzWalk::Stop,
+                _ => WzWalk::Continue,
+            }
+        });
+        assert_eq!(
+            paths,
+            ["", "info", "info/icon", "info/icon/origin", "info/price"]
+        );
+        assert_eq!(root.get_path("info/price"), Some(&WzValue::Int(11)));
+        assert_eq!(root.get_path("skip/a"), Some(&WzValue::Int(1)));
+    }
+
+    #[test]
+    fn walk_archive() -> anyhow::Result<()> {
+        let data = build_archive(
+            GMS95,
+            vec![
+                img(
+                    "Bgm.img",
+                    img_blob(GMS95, &obj([("name", TestVal::Int(1))])),
+                ),
+                dir(
+                    "Item",
+                    vec![img(
+                        "0200.img",
+                        img_blob(
+                            GMS95,
+                            &obj([("info", TestVal::Obj(obj([("price", TestVal::Int(5))])))]),
+                        ),
+                    )],
+                ),
+            ],
+        );
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+        let imgs = r
+            .walk_images()
+            .with_values()
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let paths = imgs.iter().map(|img| img.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["Bgm.img", "Item/0200.img"]);
+        assert_eq!(
+            imgs[1].value.as_ref().unwrap().get_path("info/price"),
+            Some(&WzValue::Int(5))
+        );
+        assert!(r.walk_images().all(|img| img.unwrap().value.is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn linked_images() -> anyhow::Result<()> {
+        let blob = |hp| img_blob(GMS95, &obj([("hp", TestVal::Int(hp))]));
+        let data = link_archive(GMS95, blob(1), blob(2));
+        let mut r = WzReader::open(Cursor::new(data), GMS95)?;
+
+        let walked = r
+            .walk_images()
+            .with_values()
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(walked[1].path, "Link/a.img");
+        assert_eq!(
+            walked[1].value.as_ref().unwrap().get_path("hp"),
+            Some(&WzValue::Int(2))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn shared_dir() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(shared_dir_archive(GMS95, false)), GMS95)?;
+        let paths = r
+            .walk_images()
+            .map(|img| img.map(|img| img.path))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        assert_eq!(paths, ["A/a.img", "B/a.img"]);
+
+        let mut r = WzReader::open(Cursor::new(shared_dir_archive(GMS95, true)), GMS95)?;
+        let walked = r.walk_images().collect::<Vec<_>>();
+        // The root is read again under `B` and the walk continues after the cycle
+        assert_eq!(walked.len(), 2);
+        assert!(walked[0].is_ok());
+        assert!(walked[1].is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,695 @@
+//! Writing of images and archives.
+//!
+//! `WzImgBuilder` serializes a value tree into an image blob, the bitmaps and sounds
+//! are provided by a `WzMediaSource`. `WzArchiveWriter` lays out a directory tree of
+//! image blobs as an archive. Blobs don't depend on their position in the archive, so
+//! they can be copied from other archives of the same version as they are.
+
+use std::io::{self, Seek, SeekFrom, Write};
+
+use binrw::{BinWrite, NullString, PosValue};
+
+use crate::{
+    canvas::Canvas,
+    canvas_link::CanvasTarget,
+    crypto::WzCrypto,
+    ctx::{WzContext, WzImgWriteCtx, WzStrWriteTable},
+    file::{WzIO, WzImgReader},
+    l0::{WzDir, WzDirHeader, WzDirNode, WzHeader, WzImgHeader},
+    l1::{
+        obj::{wz_ty_str, WzObject, OBJ_TYPE_CANVAS, OBJ_TYPE_PROPERTY, OBJ_TYPE_SOUND_DX8},
+        prop::{WzConvex2D, WzPropValue, WzUOL, WzVector2D},
+        sound::WzSound,
+        str::WzImgStr,
+    },
+    ty::{WzF32, WzInt, WzLong, WzOffset, WzStr, WzVec},
+    util::{wz_checksum, WriteExt},
+    val::{CanvasVal, ObjectVal, SoundVal, WzValue},
+    WzConfig, GMS95,
+};
+
+/// Magic of an object value in a property
+const OBJ_VALUE_MAGIC: u8 = 9;
+
+/// Description in the header of written archives
+pub const DEFAULT_ARCHIVE_DESC: &str = "Package file v1.0 Copyright 2002 Wizet, ZMS";
+
+/// Provides the bitmap and sound data for `WzImgBuilder`
+pub trait WzMediaSource {
+    /// Bitmap of the canvas at the property path
+    fn canvas(&mut self, path: &str, canvas: &CanvasVal) -> anyhow::Result<Canvas>;
+    /// Data of the sound at the property path, as returned by `SoundVal::read_data`
+    fn sound(&mut self, path: &str, sound: &SoundVal) -> anyhow::Result<Vec<u8>>;
+    /// Undecoded bitmap data of the canvas, If the source has It
+    fn raw_canvas(&mut self, path: &str, _canvas: &CanvasVal) -> anyhow::Result<Vec<u8>> {
+        anyhow::bail!("No raw data for canvas: {path}")
+    }
+}
+
+/// Reads the media from the image, which the values were read from.
+/// Canvas inlinks are followed, outlinked canvases keep their placeholder bitmap
+impl<R: WzIO> WzMediaSource for WzImgReader<R> {
+    fn canvas(&mut self, _path: &str, canvas: &CanvasVal) -> anyhow::Result<Canvas> {
+        if canvas.link().is_none() {
+            return canvas.read_canvas(self);
+        }
+
+        // Links are paths into the value tree, which isn't passed to the source
+        let root = WzValue::read(self)?;
+        let mut depth = 0;
+        match canvas.follow_inlinks(&root, &mut depth)? {
+            CanvasTarget::Canvas(_) => canvas.read_canvas_resolved(self, &root, None),
+            CanvasTarget::Outlink(_) => canvas.read_canvas(self),
+        }
+    }
+
+    fn sound(&mut self, _path: &str, sound: &SoundVal) -> anyhow::Result<Vec<u8>> {
+        sound.read_data(self)
+    }
+
+    fn raw_canvas(&mut self, path: &str, canvas: &CanvasVal) -> anyhow::Result<Vec<u8>> {
+        if canvas.bitmap.is_some() {
+            anyhow::bail!("Replaced canvas has no raw data: {path}");
+        }
+        self.read_canvas_raw(&canvas.canvas)
+    }
+}
+
+/// Media source for value trees without canvases and sounds
+pub struct WzNoMedia;
+
+impl WzMediaSource for WzNoMedia {
+    fn canvas(&mut self, path: &str, _canvas: &CanvasVal) -> anyhow::Result<Canvas> {
+        anyhow::bail!("No bitmap for canvas: {path}")
+    }
+
+    fn sound(&mut self, path: &str, _sound: &SoundVal) -> anyhow::Result<Vec<u8>> {
+        anyhow::bail!("No data for sound: {path}")
+    }
+}
+
+/// Serializes a value tree into an image
+pub struct WzImgBuilder<W> {
+    crypto: WzCrypto,
+    string_table: WzStrWriteTable,
+    writer: W,
+}
+
+impl<W: Write + Seek> WzImgBuilder<W> {
+    pub fn new(writer: W) -> Self {
+        Self::with_cfg(writer, GMS95)
+    }
+
+    pub fn with_cfg(writer: W, cfg: WzConfig) -> Self {
+        Self {
+            crypto: WzCrypto::from_cfg(cfg, 0),
+            string_table: WzStrWriteTable::default(),
+            writer,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Writes the image with the root object, strings are deduplicated by their
+    /// offset so the writer must start at the beginning of the image
+    pub fn write_img(
+        &mut self,
+        root: &ObjectVal,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        wz_ty_str(OBJ_TYPE_PROPERTY).write_le_args(
+            &mut self.writer,
+            WzImgWriteCtx::new(&self.crypto, &self.string_table),
+        )?;
+        self.write_entries(root, "", media)
+    }
+
+    /// Writes the value without canvas and sound data, an object is written as image root
+    pub fn write_value(&mut self, value: &WzValue) -> anyhow::Result<()> {
+        match value {
+            WzValue::Object(obj) => self.write_img(obj, &mut WzNoMedia),
+            _ => self.write_value_at(value, "", &mut WzNoMedia),
+        }
+    }
+
+    fn write_entries(
+        &mut self,
+        obj: &ObjectVal,
+        path: &str,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        0u16.write_le(&mut self.writer)?;
+        WzInt(obj.0.len() as i32).write_le(&mut self.writer)?;
+        for (key, value) in obj.0.iter() {
+            WzImgStr::new(key.clone()).write_le_args(
+                &mut self.writer,
+                WzImgWriteCtx::new(&self.crypto, &self.string_table),
+            )?;
+            let path = if path.is_empty() {
+                key.clone()
+            } else {
+                format!("{path}/{key}")
+            };
+            self.write_value_at(value, &path, media)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes an object value, the length is patched after the object is written
+    fn write_obj_value(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        OBJ_VALUE_MAGIC.write_le(&mut self.writer)?;
+        let pos = self.writer.stream_position()?;
+        0u32.write_le(&mut self.writer)?;
+        f(self)?;
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(pos))?;
+        ((end - pos - 4) as u32).write_le(&mut self.writer)?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+
+    fn write_obj(&mut self, obj: WzObject) -> anyhow::Result<()> {
+        self.write_obj_value(|b| {
+            obj.write_le_args(
+                &mut b.writer,
+                WzImgWriteCtx::new(&b.crypto, &b.string_table),
+            )?;
+            Ok(())
+        })
+    }
+
+    fn write_canvas(
+        &mut self,
+        canvas: &CanvasVal,
+        path: &str,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        let read;
+        let bitmap = match canvas.bitmap.as_deref() {
+            Some(bitmap) => bitmap,
+            None => {
+                read = media.canvas(path, canvas)?;
+                &read
+            }
+        };
+        let mut data = Vec::new();
+        data.compress_flate(bitmap.data())?;
+
+        self.write_obj_value(|b| {
+            wz_ty_str(OBJ_TYPE_CANVAS).write_le_args(
+                &mut b.writer,
+                WzImgWriteCtx::new(&b.crypto, &b.string_table),
+            )?;
+            0u8.write_le(&mut b.writer)?;
+            match canvas.sub.as_deref() {
+                Some(WzValue::Object(sub)) => {
+                    1u8.write_le(&mut b.writer)?;
+                    b.write_entries(sub, path, media)?;
+                }
+                Some(_) => anyhow::bail!("Canvas property must be an object: {path}"),
+                None => 0u8.write_le(&mut b.writer)?,
+            }
+            WzInt(bitmap.width as i32).write_le(&mut b.writer)?;
+            WzInt(bitmap.height as i32).write_le(&mut b.writer)?;
+            WzInt::from(bitmap.depth()).write_le(&mut b.writer)?;
+            u8::from(bitmap.scale).write_le(&mut b.writer)?;
+            0u32.write_le(&mut b.writer)?;
+            // The length includes the leading zero byte
+            (data.len() as u32 + 1).write_le(&mut b.writer)?;
+            0u8.write_le(&mut b.writer)?;
+            b.writer.write_all(&data)?;
+            Ok(())
+        })
+    }
+
+    fn write_sound(
+        &mut self,
+        sound: &SoundVal,
+        path: &str,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        let data = match sound.data.as_deref() {
+            Some(data) => data.clone(),
+            None => media.sound(path, sound)?,
+        };
+        // Pcm data contains a wave header, which is not part of the size
+        let extra = sound.sound.data_size() - sound.sound.size.0.max(0) as usize;
+        let size = data
+            .len()
+            .checked_sub(extra)
+            .ok_or_else(|| anyhow::format_err!("Sound data too short: {path}"))?;
+
+        self.write_obj_value(|b| {
+            wz_ty_str(OBJ_TYPE_SOUND_DX8).write_le_args(
+                &mut b.writer,
+                WzImgWriteCtx::new(&b.crypto, &b.string_table),
+            )?;
+            WzSound {
+                unknown: 0,
+                size: WzInt(size as i32),
+                len_ms: sound.sound.len_ms,
+                header: sound.sound.header.clone(),
+                offset: PosValue { val: (), pos: 0 },
+            }
+            .write_le_args(
+                &mut b.writer,
+                WzImgWriteCtx::new(&b.crypto, &b.string_table),
+            )?;
+            b.writer.write_all(&data)?;
+            Ok(())
+        })
+    }
+
+    fn write_value_at(
+        &mut self,
+        value: &WzValue,
+        path: &str,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        let prop = match value {
+            WzValue::Object(obj) => {
+                return self.write_obj_value(|b| {
+                    wz_ty_str(OBJ_TYPE_PROPERTY).write_le_args(
+                        &mut b.writer,
+                        WzImgWriteCtx::new(&b.crypto, &b.string_table),
+                    )?;
+                    b.write_entries(obj, path, media)
+                })
+            }
+            WzValue::Canvas(canvas) => return self.write_canvas(canvas, path, media),
+            WzValue::Sound(sound) => return self.write_sound(sound, path, media),
+            WzValue::Link(link) => {
+                return self.write_obj(WzObject::UOL(WzUOL {
+                    unknown: 0,
+                    entries: WzImgStr::new(link.clone()),
+                }))
+            }
+            WzValue::Convex(v) => {
+                return self.write_obj(WzObject::Convex2D(WzConvex2D(
+                    v.0.iter()
+                        .map(|v| WzVector2D {
+                            x: WzInt(v.x),
+                            y: WzInt(v.y),
+                        })
+                        .collect(),
+                )))
+            }
+            WzValue::Vec(v) => {
+                return self.write_obj(WzObject::Vec2(WzVector2D {
+                    x: WzInt(v.x),
+                    y: WzInt(v.y),
+                }))
+            }
+            WzValue::Null => WzPropValue::Null,
+            WzValue::F32(v) => WzPropValue::F32(WzF32(*v)),
+            WzValue::F64(v) => WzPropValue::F64(*v),
+            WzValue::Short(v) => WzPropValue::Short1(*v),
+            WzValue::Int(v) => WzPropValue::Int1(WzInt(*v)),
+            WzValue::Long(v) => WzPropValue::Long(WzLong(*v)),
+            WzValue::String(v) => WzPropValue::Str(WzImgStr::new(v.clone())),
+            WzValue::Error(err) => anyhow::bail!("Can't write invalid value: {}", err.reason),
+        };
+
+        prop.write_le_args(
+            &mut self.writer,
+            WzImgWriteCtx::new(&self.crypto, &self.string_table),
+        )?;
+        Ok(())
+    }
+}
+
+/// Node of the directory tree written by `WzArchiveWriter`, `B` refers to the blob
+/// of an image
+#[derive(Debug, Clone)]
+pub enum WzArchiveNode<B> {
+    Dir(String, Vec<WzArchiveNode<B>>),
+    Img(WzArchiveImg<B>),
+}
+
+#[derive(Debug, Clone)]
+pub struct WzArchiveImg<B> {
+    pub name: String,
+    pub size: u32,
+    pub checksum: i32,
+    pub blob: B,
+}
+
+impl WzArchiveNode<Vec<u8>> {
+    /// Image node with the blob in memory
+    pub fn img(name: impl Into<String>, blob: Vec<u8>) -> Self {
+        Self::Img(WzArchiveImg {
+            name: name.into(),
+            size: blob.len() as u32,
+            checksum: wz_checksum(0, &blob),
+            blob,
+        })
+    }
+}
+
+impl<B> WzArchiveNode<B> {
+    pub fn dir(name: impl Into<String>, entries: Vec<WzArchiveNode<B>>) -> Self {
+        Self::Dir(name.into(), entries)
+    }
+
+    /// Size and checksum, directories sum up their entries
+    fn sizes(&self) -> (i32, i32) {
+        match self {
+            Self::Img(img) => (img.size as i32, img.checksum),
+            Self::Dir(_, entries) => entries.iter().map(Self::sizes).fold((0, 0), |a, b| {
+                (a.0.wrapping_add(b.0), a.1.wrapping_add(b.1))
+            }),
+        }
+    }
+}
+
+/// Lays out directories and image blobs as an archive
+#[derive(Debug, Clone)]
+pub struct WzArchiveWriter {
+    cfg: WzConfig,
+    desc: String,
+}
+
+impl WzArchiveWriter {
+    pub fn new(cfg: WzConfig) -> Self {
+        Self {
+            cfg,
+            desc: DEFAULT_ARCHIVE_DESC.to_string(),
+        }
+    }
+
+    /// Sets the description in the header
+    pub fn with_desc(mut self, desc: impl Into<String>) -> Self {
+        self.desc = desc.into();
+        self
+    }
+
+    /// Writes the archive with the in-memory blobs
+    pub fn write<W: Write + Seek, B: AsRef<[u8]>>(
+        &self,
+        w: &mut W,
+        root: &[WzArchiveNode<B>],
+    ) -> anyhow::Result<()> {
+        self.write_with(w, root, |blob, w| Ok(w.write_all(blob.as_ref())?))
+    }
+
+    /// Writes the archive, `write_blob` writes the blob of an image, which must have the
+    /// size of the node. Offsets are absolute, so `w` must be at the start of the file
+    pub fn write_with<W: Write + Seek, B>(
+        &self,
+        w: &mut W,
+        root: &[WzArchiveNode<B>],
+        mut write_blob: impl FnMut(&B, &mut W) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let data_offset = 4 + 8 + 4 + self.desc.len() as u32 + 1;
+        let crypto = WzCrypto::from_cfg(self.cfg, data_offset);
+        let ctx = WzContext::new(&crypto);
+
+        // Flatten the directories in BFS order
+        let mut dirs = vec![root];
+        let mut i = 0;
+        while i < dirs.len() {
+            for node in dirs[i] {
+                if let WzArchiveNode::Dir(_, entries) = node {
+                    dirs.push(entries);
+                }
+            }
+            i += 1;
+        }
+
+        // Compute the directory offsets, the size of a directory doesn't depend on the
+        // offsets. Offsets are encrypted relative to their position, so It must be valid
+        let mut dir_offsets = Vec::with_capacity(dirs.len());
+        let mut off = data_offset as u64 + 2;
+        for entries in dirs.iter() {
+            dir_offsets.push(off as u32);
+            let dir = build_dir(
+                entries,
+                &mut std::iter::repeat(0),
+                &mut std::iter::repeat(0),
+            );
+            let mut counter = PosCounter(off);
+            dir.write_le_args(&mut counter, ctx)?;
+            off = counter.0;
+        }
+
+        let imgs = dirs
+            .iter()
+            .flat_map(|entries| entries.iter())
+            .filter_map(|node| match node {
+                WzArchiveNode::Img(img) => Some(img),
+                WzArchiveNode::Dir(..) => None,
+            })
+            .collect::<Vec<_>>();
+        let img_offsets = imgs
+            .iter()
+            .scan(off, |off, img| {
+                let cur = *off;
+                *off += img.size as u64;
+                Some(cur as u32)
+            })
+            .collect::<Vec<_>>();
+        if off > u32::MAX as u64 {
+            anyhow::bail!("Archive too large: {off} bytes");
+        }
+
+        WzHeader {
+            file_size: 0,
+            data_offset,
+            desc: NullString::from(self.desc.as_str()),
+        }
+        .write_le(w)?;
+        self.cfg.version.encrypted_version().write_le(w)?;
+
+        let mut sub_dirs = dir_offsets.iter().skip(1).copied();
+        let mut img_offs = img_offsets.iter().copied();
+        for (entries, off) in dirs.iter().zip(dir_offsets.iter()) {
+            debug_assert_eq!(w.stream_position()?, *off as u64);
+            build_dir(entries, &mut sub_dirs, &mut img_offs).write_le_args(w, ctx)?;
+        }
+
+        for (img, off) in imgs.iter().zip(img_offsets) {
+            write_blob(&img.blob, w)?;
+            if w.stream_position()? != off as u64 + img.size as u64 {
+                anyhow::bail!("Blob of {} doesn't match It's size {}", img.name, img.size);
+            }
+        }
+
+        let end = w.stream_position()?;
+        w.seek(SeekFrom::Start(4))?;
+        (end - data_offset as u64).write_le(w)?;
+        w.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+/// Directory with the entries, offsets of sub directories and images are taken
+/// from the iterators in order
+fn build_dir<B>(
+    entries: &[WzArchiveNode<B>],
+    dir_offsets: &mut dyn Iterator<Item = u32>,
+    img_offsets: &mut dyn Iterator<Item = u32>,
+) -> WzDir {
+    let nodes = entries
+        .iter()
+        .map(|node| {
+            let (size, checksum) = node.sizes();
+            match node {
+                WzArchiveNode::Dir(name, _) => WzDirNode::Dir(WzDirHeader {
+                    name: WzStr::new(name.clone()),
+                    blob_size: WzInt(size),
+                    checksum: WzInt(checksum),
+                    offset: WzOffset(dir_offsets.next().unwrap_or(0)),
+                }),
+                WzArchiveNode::Img(img) => WzDirNode::Img(WzImgHeader {
+                    name: WzStr::new(img.name.clone()),
+                    blob_size: WzInt(size),
+                    checksum: WzInt(checksum),
+                    offset: WzOffset(img_offsets.next().unwrap_or(0)),
+                }),
+            }
+        })
+        .collect();
+    WzDir {
+        entries: WzVec(nodes),
+    }
+}
+
+/// Discards the written data, only tracks the position
+struct PosCounter(u64);
+
+impl Write for PosCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for PosCounter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Start(pos) => self.0 = pos,
+            SeekFrom::Current(off) => self.0 = self.0.wrapping_add_signed(off),
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Can't seek from the end",
+                ))
+            }
+        }
+        Ok(self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use indexmap::indexmap;
+
+    use crate::{
+        canvas::Canvas,
+        l0::tree::WzTree,
+        l1::canvas::WzCanvasScaling,
+        test_util::{canvas_val, img_blob, obj, sample_archive, str_val},
+        val::{CanvasVal, SoundVal, WzValue},
+        WzReader, GMS95,
+    };
+
+    use super::{WzArchiveNode, WzArchiveWriter, WzImgBuilder, WzMediaSource};
+
+    struct Checker;
+
+    impl WzMediaSource for Checker {
+        fn canvas(&mut self, path: &str, _canvas: &CanvasVal) -> anyhow::Result<Canvas> {
+            assert_eq!(path, "icon");
+            let img = image::RgbaImage::from_fn(3, 2, |x, y| [x as u8, y as u8, 7, 255].into());
+            Ok(Canvas::from_rgba_image(&img, WzCanvasScaling(0)))
+        }
+
+        fn sound(&mut self, _path: &str, _sound: &SoundVal) -> anyhow::Result<Vec<u8>> {
+            unreachable!()
+        }
+    }
+
+    #[test]
+    fn write_img_archive() -> anyhow::Result<()> {
+        let root = WzValue::from(indexmap! {
+            "info".to_string() => WzValue::from(indexmap! {
+                "price".to_string() => WzValue::Int(100),
+                "name".to_string() => WzValue::String("Red Potion".to_string()),
+                "pos".to_string() => WzValue::Vec((1, -2).into()),
+            }),
+            "icon".to_string() => WzValue::Canvas(CanvasVal::detached(
+                WzCanvasScaling(0),
+                Some(Box::new(WzValue::from(indexmap! {
+                    "z".to_string() => WzValue::Short(3),
+                }))),
+            )),
+            "link".to_string() => WzValue::Link("info/price".to_string()),
+        });
+
+        let mut b = WzImgBuilder::with_cfg(Cursor::new(Vec::new()), GMS95);
+        b.write_img(root.as_object().unwrap(), &mut Checker)?;
+        let blob = b.into_inner().into_inner();
+
+        let mut data = Cursor::new(Vec::new());
+        WzArchiveWriter::new(GMS95).write(
+            &mut data,
+            &[WzArchiveNode::dir(
+                "Item",
+                vec![WzArchiveNode::img("0200.img", blob)],
+            )],
+        )?;
+
+        let mut r = WzReader::open(Cursor::new(data.into_inner()), GMS95)?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+        let hdr = tree.get_img_by_path("Item/0200.img").unwrap().clone();
+        let mut img = r.img_reader(&hdr)?;
+        let val = WzValue::read(&mut img)?;
+
+        assert_eq!(val.get_path("info"), root.get_path("info"));
+        assert_eq!(val.get_path("link"), root.get_path("link"));
+        assert_eq!(val.get_path("icon/z"), Some(&WzValue::Short(3)));
+        let icon = val.get_path("icon").unwrap().as_canvas().unwrap();
+        let icon = icon.read_canvas(&mut img)?.to_raw_rgba_image()?;
+        assert_eq!(icon.get_pixel(2, 1).0, [2, 1, 7, 255]);
+        Ok(())
+    }
+
+    #[test]
+    fn write_value() -> anyhow::Result<()> {
+        let root = WzValue::from(indexmap! {
+            "hp".to_string() => WzValue::Int(10),
+            "exp".to_string() => WzValue::Long(1 << 40),
+        });
+        let mut b = WzImgBuilder::new(Cursor::new(Vec::new()));
+        b.write_value(&root)?;
+        let mut r = WzReader::open_img(b.into_inner(), GMS95);
+        let val = WzValue::read(&mut r.root_img_reader()?)?;
+        assert_eq!(val, root);
+
+        let canvas = WzValue::Canvas(CanvasVal::detached(WzCanvasScaling(0), None));
+        let mut b = WzImgBuilder::new(Cursor::new(Vec::new()));
+        assert!(b.write_value(&canvas).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_sample() -> anyhow::Result<()> {
+        let mut r = WzReader::open(Cursor::new(sample_archive(GMS95)), GMS95)?;
+        let tree = WzTree::from_reader(&mut r, None)?;
+        for (path, hdr) in tree.images() {
+            let mut img = r.img_reader(&hdr)?;
+            let val = WzValue::read(&mut img)?;
+            let mut b = WzImgBuilder::with_cfg(Cursor::new(Vec::new()), GMS95);
+            b.write_img(val.as_object().unwrap(), &mut img)?;
+
+            let mut rebuilt = WzReader::open_img(b.into_inner(), GMS95);
+            let mut rebuilt = rebuilt.root_img_reader()?;
+            let rebuilt_val = WzValue::read(&mut rebuilt)?;
+            assert_eq!(format!("{val:?}"), format!("{rebuilt_val:?}"), "{path}");
+            if let Some(bgm) = rebuilt_val.get_path("bgm").and_then(WzValue::as_sound) {
+                let orig = val.get_path("bgm").unwrap().as_sound().unwrap();
+                assert_eq!(bgm.read_data(&mut rebuilt)?, orig.read_data(&mut img)?);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn media_inlinks() -> anyhow::Result<()> {
+        let blob = img_blob(
+            GMS95,
+            &obj([
+                ("0", canvas_val(3, 2, Vec::new())),
+                ("1", canvas_val(1, 1, obj([("_inlink", str_val("0"))]))),
+                (
+                    "2",
+                    canvas_val(1, 1, obj([("_outlink", str_val("Mob/a.img/0"))])),
+                ),
+            ]),
+        );
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img = r.root_img_reader()?;
+        let val = WzValue::read(&mut img)?;
+
+        let dim = |path: &str, img: &mut _| -> anyhow::Result<_> {
+            let canvas = val.get_path(path).unwrap().as_canvas().unwrap();
+            let canvas = WzMediaSource::canvas(img, path, canvas)?;
+            Ok((canvas.width, canvas.height))
+        };
+        assert_eq!(dim("1", &mut img)?, (3, 2));
+        assert_eq!(dim("2", &mut img)?, (1, 1));
+        Ok(())
+    }
+}
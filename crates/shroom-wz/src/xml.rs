@@ -0,0 +1,504 @@
+//! Classic XML dumps of images.
+//!
+//! An image is written as `<imgdir>` with an element per property, the tag is the
+//! exact type: `imgdir`, `null`, `short`, `int`, `long`, `float`, `double`, `string`,
+//! `vector`, `extended` (convex), `uol`, `canvas` and `sound`. Canvases hold their
+//! bitmap as base64 png in `basedata`, sounds their header and data as base64 in
+//! `basehead` and `basedata`.
+//!
+//! The writer streams the elements, so the dump is never kept in memory. The reader
+//! returns values which carry their media, so they can be written by `WzImgBuilder`
+//! without a media source.
+
+use std::{
+    io::{BufRead, Cursor, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use image::ImageFormat;
+use quick_xml::{
+    events::{attributes::Attributes, BytesDecl, BytesEnd, BytesStart, Event},
+    Reader, Writer,
+};
+
+use crate::{
+    l1::{canvas::WzCanvasScaling, sound::SoundHeader},
+    util::join_path,
+    val::{CanvasVal, ObjectVal, SoundVal, Vec2Val, Vex2Val, WzValue},
+    writer::{WzImgBuilder, WzMediaSource, WzNoMedia},
+    WzConfig,
+};
+
+/// Extension of XML dumps, the file name is the image name with this extension
+pub const XML_IMG_EXT: &str = "xml";
+
+/// Streaming writer of XML dumps
+pub struct WzXmlWriter<W: Write> {
+    w: Writer<W>,
+}
+
+impl<W: Write> WzXmlWriter<W> {
+    pub fn new(w: W) -> Self {
+        Self {
+            w: Writer::new_with_indent(w, b' ', 2),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w.into_inner()
+    }
+
+    /// Writes the image, the bitmaps and sounds are taken from the media source
+    /// unless they are replaced in the value
+    pub fn write_img(
+        &mut self,
+        name: &str,
+        root: &ObjectVal,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        self.w.write_event(Event::Decl(BytesDecl::new(
+            "1.0",
+            Some("UTF-8"),
+            Some("yes"),
+        )))?;
+        self.write_obj("imgdir", name, &[], root, "", media)
+    }
+
+    fn write_obj(
+        &mut self,
+        tag: &str,
+        name: &str,
+        attrs: &[(&str, &str)],
+        obj: &ObjectVal,
+        path: &str,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        let mut start = BytesStart::new(tag);
+        start.push_attribute(("name", name));
+        start.extend_attributes(attrs.iter().copied());
+        if obj.0.is_empty() {
+            self.w.write_event(Event::Empty(start))?;
+            return Ok(());
+        }
+
+        self.w.write_event(Event::Start(start))?;
+        for (key, val) in obj.0.iter() {
+            self.write_value(key, val, &join_path(path, key), media)?;
+        }
+        self.w.write_event(Event::End(BytesEnd::new(tag)))?;
+        Ok(())
+    }
+
+    fn write_leaf(&mut self, tag: &str, name: &str, attrs: &[(&str, &str)]) -> anyhow::Result<()> {
+        let mut elem = BytesStart::new(tag);
+        elem.push_attribute(("name", name));
+        elem.extend_attributes(attrs.iter().copied());
+        self.w.write_event(Event::Empty(elem))?;
+        Ok(())
+    }
+
+    fn write_value(
+        &mut self,
+        name: &str,
+        val: &WzValue,
+        path: &str,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        let (tag, value) = match val {
+            WzValue::Object(obj) => return self.write_obj("imgdir", name, &[], obj, path, media),
+            WzValue::Canvas(canvas) => return self.write_canvas(name, canvas, path, media),
+            WzValue::Sound(sound) => return self.write_sound(name, sound, path, media),
+            WzValue::Vec(v) => {
+                return self.write_leaf(
+                    "vector",
+                    name,
+                    &[("x", &v.x.to_string()), ("y", &v.y.to_string())],
+                )
+            }
+            WzValue::Convex(v) => {
+                let mut start = BytesStart::new("extended");
+                start.push_attribute(("name", name));
+                self.w.write_event(Event::Start(start))?;
+                for (i, v) in v.0.iter().enumerate() {
+                    self.write_leaf(
+                        "vector",
+                        &i.to_string(),
+                        &[("x", &v.x.to_string()), ("y", &v.y.to_string())],
+                    )?;
+                }
+                self.w.write_event(Event::End(BytesEnd::new("extended")))?;
+                return Ok(());
+            }
+            WzValue::Null => return self.write_leaf("null", name, &[]),
+            WzValue::Short(v) => ("short", v.to_string()),
+            WzValue::Int(v) => ("int", v.to_string()),
+            WzValue::Long(v) => ("long", v.to_string()),
+            WzValue::F32(v) => ("float", v.to_string()),
+            WzValue::F64(v) => ("double", v.to_string()),
+            WzValue::String(v) => ("string", v.clone()),
+            WzValue::Link(v) => ("uol", v.clone()),
+            WzValue::Error(err) => {
+                anyhow::bail!("Can't write invalid value {path}: {}", err.reason)
+            }
+        };
+        self.write_leaf(tag, name, &[("value", &value)])
+    }
+
+    fn write_canvas(
+        &mut self,
+        name: &str,
+        canvas: &CanvasVal,
+        path: &str,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        let bitmap = match canvas.bitmap.as_deref() {
+            Some(bitmap) => bitmap.clone(),
+            None => media.canvas(path, canvas)?,
+        };
+        let mut png = Cursor::new(Vec::new());
+        bitmap
+            .to_raw_rgba_image()?
+            .write_to(&mut png, ImageFormat::Png)?;
+
+        let (width, height) = (
+            canvas.canvas.width.0.to_string(),
+            canvas.canvas.height.0.to_string(),
+        );
+        let scale = canvas.canvas.scale.0.to_string();
+        let data = BASE64.encode(png.into_inner());
+        let mut attrs = vec![("width", width.as_str()), ("height", height.as_str())];
+        // Only written for scaled canvases, the dumps of other tools don't have It
+        if canvas.canvas.scale.0 != 0 {
+            attrs.push(("scale", scale.as_str()));
+        }
+        attrs.push(("basedata", data.as_str()));
+
+        let empty = ObjectVal::default();
+        let sub = match canvas.sub.as_deref() {
+            Some(WzValue::Object(sub)) => sub,
+            _ => &empty,
+        };
+        self.write_obj("canvas", name, &attrs, sub, path, media)
+    }
+
+    fn write_sound(
+        &mut self,
+        name: &str,
+        sound: &SoundVal,
+        path: &str,
+        media: &mut dyn WzMediaSource,
+    ) -> anyhow::Result<()> {
+        let data = match sound.data.as_deref() {
+            Some(data) => data.clone(),
+            None => media.sound(path, sound)?,
+        };
+        let mut head = Cursor::new(Vec::new());
+        sound.sound.header.write_header(&mut head)?;
+
+        self.write_leaf(
+            "sound",
+            name,
+            &[
+                ("length", &sound.sound.len_ms.0.to_string()),
+                ("basehead", &BASE64.encode(head.into_inner())),
+                ("basedata", &BASE64.encode(data)),
+            ],
+        )
+    }
+}
+
+/// Element, which is still open while reading
+struct Frame {
+    tag: String,
+    name: String,
+    /// Value of leaf elements and canvases
+    val: Option<WzValue>,
+    children: ObjectVal,
+}
+
+fn attrs(attrs: Attributes) -> anyhow::Result<Vec<(String, String)>> {
+    attrs
+        .map(|attr| {
+            let attr = attr?;
+            Ok((
+                String::from_utf8(attr.key.as_ref().to_vec())?,
+                attr.unescape_value()?.into_owned(),
+            ))
+        })
+        .collect()
+}
+
+fn attr<'a>(attrs: &'a [(String, String)], key: &str) -> anyhow::Result<&'a str> {
+    attrs
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.as_str())
+        .ok_or_else(|| anyhow::format_err!("Missing attribute {key}"))
+}
+
+fn parse_attr<T>(attrs: &[(String, String)], key: &str) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let v = attr(attrs, key)?;
+    v.parse()
+        .with_context(|| format!("Invalid attribute {key}: {v}"))
+}
+
+/// Value of the element, containers get their children when they are closed
+fn element_value(tag: &str, attrs: &[(String, String)]) -> anyhow::Result<Option<WzValue>> {
+    Ok(Some(match tag {
+        "imgdir" | "extended" => return Ok(None),
+        "null" => WzValue::Null,
+        "short" => WzValue::Short(parse_attr(attrs, "value")?),
+        "int" => WzValue::Int(parse_attr(attrs, "value")?),
+        "long" => WzValue::Long(parse_attr(attrs, "value")?),
+        "float" => WzValue::F32(parse_attr(attrs, "value")?),
+        "double" => WzValue::F64(parse_attr(attrs, "value")?),
+        "string" => WzValue::String(attr(attrs, "value")?.to_string()),
+        "uol" => WzValue::Link(attr(attrs, "value")?.to_string()),
+        "vector" => WzValue::Vec(Vec2Val {
+            x: parse_attr(attrs, "x")?,
+            y: parse_attr(attrs, "y")?,
+        }),
+        "canvas" => {
+            let scale = match attrs.iter().find(|(k, _)| k == "scale") {
+                Some(_) => WzCanvasScaling(parse_attr(attrs, "scale")?),
+                None => WzCanvasScaling(0),
+            };
+            let png = BASE64.decode(attr(attrs, "basedata")?)?;
+            let img = image::load_from_memory_with_format(&png, ImageFormat::Png)?.into_rgba8();
+            let mut canvas = CanvasVal::detached(scale, None);
+            canvas.set_image(&img);
+            WzValue::Canvas(canvas)
+        }
+        "sound" => {
+            let head = BASE64.decode(attr(attrs, "basehead")?)?;
+            let header = SoundHeader::read_header(&mut Cursor::new(head))?;
+            let data = BASE64.decode(attr(attrs, "basedata")?)?;
+            WzValue::Sound(SoundVal::detached(
+                header,
+                parse_attr(attrs, "length")?,
+                data,
+            )?)
+        }
+        _ => anyhow::bail!("Unknown element: {tag}"),
+    }))
+}
+
+/// Completes the value of the closed element
+fn close_frame(frame: Frame) -> anyhow::Result<WzValue> {
+    Ok(match (frame.tag.as_str(), frame.val) {
+        ("imgdir", _) => WzValue::Object(frame.children),
+        ("extended", _) => WzValue::Convex(Vex2Val(
+            frame
+                .children
+                .0
+                .into_values()
+                .map(|v| match v {
+                    WzValue::Vec(v) => Ok(v),
+                    _ => anyhow::bail!("Convex {} must only contain vectors", frame.name),
+                })
+                .collect::<anyhow::Result<_>>()?,
+        )),
+        (_, Some(WzValue::Canvas(mut canvas))) => {
+            if !frame.children.0.is_empty() {
+                canvas.sub = Some(Box::new(WzValue::Object(frame.children)));
+                canvas.canvas.has_property = 1;
+            }
+            WzValue::Canvas(canvas)
+        }
+        (tag, Some(val)) => {
+            if !frame.children.0.is_empty() {
+                anyhow::bail!("Element {tag} {} can't have children", frame.name);
+            }
+            val
+        }
+        (tag, None) => unreachable!("Element {tag} without value"),
+    })
+}
+
+/// Reads an XML dump, returns the name and the root of the image
+pub fn read_xml_img<R: BufRead>(r: R) -> anyhow::Result<(String, ObjectVal)> {
+    let mut reader = Reader::from_reader(r);
+    let mut buf = Vec::new();
+    let mut stack: Vec<Frame> = Vec::new();
+
+    loop {
+        let (elem, empty) = match reader.read_event_into(&mut buf)? {
+            Event::Start(elem) => (elem, false),
+            Event::Empty(elem) => (elem, true),
+            Event::End(_) => {
+                let frame = stack
+                    .pop()
+                    .ok_or_else(|| anyhow::format_err!("Unexpected end of element"))?;
+                let name = frame.name.clone();
+                let val = close_frame(frame)?;
+                match stack.last_mut() {
+                    Some(parent) => {
+                        parent.children.0.insert(name, val);
+                    }
+                    None => {
+                        let WzValue::Object(root) = val else {
+                            unreachable!()
+                        };
+                        return Ok((name, root));
+                    }
+                }
+                buf.clear();
+                continue;
+            }
+            Event::Eof => anyhow::bail!("Unexpected end of the dump"),
+            _ => {
+                buf.clear();
+                continue;
+            }
+        };
+
+        let tag = String::from_utf8(elem.name().as_ref().to_vec())?;
+        let attrs = attrs(elem.attributes())?;
+        let name = attr(&attrs, "name")?.to_string();
+        if stack.is_empty() && tag != "imgdir" {
+            anyhow::bail!("Root element must be imgdir, got {tag}");
+        }
+        let path = stack
+            .iter()
+            .skip(1)
+            .map(|f| f.name.as_str())
+            .chain([name.as_str()])
+            .collect::<Vec<_>>()
+            .join("/");
+        let frame = Frame {
+            val: element_value(&tag, &attrs).with_context(|| format!("Element {path}"))?,
+            tag,
+            name,
+            children: ObjectVal::default(),
+        };
+
+        if empty {
+            let name = frame.name.clone();
+            let val = close_frame(frame)?;
+            match stack.last_mut() {
+                Some(parent) => {
+                    parent.children.0.insert(name, val);
+                }
+                None => return Ok((name, ObjectVal::default())),
+            }
+        } else {
+            stack.push(frame);
+        }
+        buf.clear();
+    }
+}
+
+/// Builds the image blob of an XML dump
+pub fn build_xml_img(file: &Path, cfg: WzConfig) -> anyhow::Result<Vec<u8>> {
+    let (_, root) = read_xml_img(std::io::BufReader::new(std::fs::File::open(file)?))?;
+    let mut b = WzImgBuilder::with_cfg(Cursor::new(Vec::new()), cfg);
+    b.write_img(&root, &mut WzNoMedia)?;
+    Ok(b.into_inner().into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        test_util::{canvas_val, img_blob, link_val, obj, str_val, TestVal},
+        val::WzValue,
+        writer::WzNoMedia,
+        WzImgBuilder, WzReader, GMS95,
+    };
+
+    use super::{read_xml_img, WzXmlWriter};
+
+    /// Dumps the image blob
+    fn dump(blob: Vec<u8>) -> anyhow::Result<String> {
+        let mut r = WzReader::open_img(Cursor::new(blob), GMS95);
+        let mut img_r = r.root_img_reader()?;
+        let root = WzValue::read(&mut img_r)?;
+        let mut w = WzXmlWriter::new(Vec::new());
+        w.write_img("test.img", root.as_object().unwrap(), &mut img_r)?;
+        Ok(String::from_utf8(w.into_inner())?)
+    }
+
+    #[test]
+    fn round_trip() -> anyhow::Result<()> {
+        let blob = img_blob(
+            GMS95,
+            &obj([
+                ("id", TestVal::Int(9000001)),
+                ("name", str_val("A & <B>")),
+                ("link", link_val("../id")),
+                ("pos", TestVal::Vec2(1, -2)),
+                (
+                    "icon",
+                    canvas_val(3, 2, obj([("origin", TestVal::Vec2(1, 2))])),
+                ),
+                (
+                    "bgm",
+                    TestVal::Sound {
+                        data: vec![1, 2, 3, 4],
+                        len_ms: 1000,
+                    },
+                ),
+                ("empty", TestVal::Obj(Vec::new())),
+            ]),
+        );
+        let xml = dump(blob)?;
+        assert!(xml.contains(r#"<int name="id" value="9000001"/>"#));
+        assert!(xml.contains(r#"<uol name="link" value="../id"/>"#));
+
+        let (name, root) = read_xml_img(Cursor::new(xml.as_bytes()))?;
+        assert_eq!(name, "test.img");
+        let root = WzValue::Object(root);
+        assert_eq!(
+            root.get_path("name"),
+            Some(&WzValue::String("A & <B>".to_string()))
+        );
+        assert_eq!(
+            root.get_path("icon/origin"),
+            Some(&WzValue::Vec((1, 2).into()))
+        );
+
+        // The parsed values carry their media
+        let mut b = WzImgBuilder::new(Cursor::new(Vec::new()));
+        b.write_img(root.as_object().unwrap(), &mut WzNoMedia)?;
+        assert_eq!(dump(b.into_inner().into_inner())?, xml);
+        Ok(())
+    }
+
+    #[test]
+    fn read_types() -> anyhow::Result<()> {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>
+            <imgdir name="a.img">
+                <short name="s" value="-3"/>
+                <long name="l" value="5000000000"/>
+                <float name="f" value="0.5"/>
+                <double name="d" value="1.25"/>
+                <null name="n"/>
+                <extended name="c">
+                    <vector name="0" x="1" y="2"/>
+                    <vector name="1" x="3" y="4"/>
+                </extended>
+            </imgdir>"#;
+        let (_, root) = read_xml_img(Cursor::new(xml.as_bytes()))?;
+        let vals = root.0.values().cloned().collect::<Vec<_>>();
+        assert_eq!(
+            vals,
+            [
+                WzValue::Short(-3),
+                WzValue::Long(5000000000),
+                WzValue::F32(0.5),
+                WzValue::F64(1.25),
+                WzValue::Null,
+                WzValue::Convex(crate::val::Vex2Val(vec![(1, 2).into(), (3, 4).into()])),
+            ]
+        );
+        assert!(read_xml_img(Cursor::new(r#"<imgdir name="a"><int name="x"/></imgdir>"#)).is_err());
+        Ok(())
+    }
+}